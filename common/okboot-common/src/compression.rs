@@ -0,0 +1,367 @@
+//! Codecs that programs can be compressed with for upload.
+//!
+//! The device advertises the [`Codecs`] it was built with in its
+//! [`MetadataReq`](crate::device::MetadataReq); the host picks one of them, names it in the
+//! [`Metadata`](crate::host::Metadata), and compresses the program with [`encode`]. The device
+//! then decompresses the chunks as they arrive with a [`Decoder`].
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "alloc")]
+use miniz_oxide::inflate::stream::InflateState;
+use miniz_oxide::MZError;
+#[cfg(feature = "alloc")]
+use miniz_oxide::{DataFormat, MZFlush, MZStatus};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Streaming LZSS codec for small devices, compatible with the `heatshrink` library.
+#[cfg(feature = "heatshrink")]
+pub mod heatshrink;
+/// LZ4 blocks, chained so that they can be decompressed a little at a time.
+#[cfg(feature = "lz4")]
+pub mod lz4;
+
+/// How the program data in the [`Chunk`](crate::host::Chunk)s is compressed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[repr(C)]
+pub enum Compression {
+    /// Sent as-is.
+    None,
+    /// Raw deflate stream, without a zlib header. The level only matters to the host.
+    Deflate { level: u8 },
+    /// See [`lz4`].
+    Lz4,
+    /// Heatshrink with a window of `2^window` bytes, and backreferences of up to `2^lookahead`
+    /// bytes.
+    Heatshrink { window: u8, lookahead: u8 },
+}
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Deflate { level } => write!(f, "deflate:{level}"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Heatshrink { window, lookahead } => {
+                write!(f, "heatshrink:{window},{lookahead}")
+            }
+        }
+    }
+}
+impl Compression {
+    fn bit(self) -> u32 {
+        match self {
+            Compression::None => Codecs::NONE,
+            Compression::Deflate { .. } => Codecs::DEFLATE,
+            Compression::Lz4 => Codecs::LZ4,
+            Compression::Heatshrink { .. } => Codecs::HEATSHRINK,
+        }
+    }
+
+    /// Whether the parameters are within the range that the codec allows.
+    pub fn is_valid(self) -> bool {
+        match self {
+            Compression::None | Compression::Lz4 => true,
+            Compression::Deflate { level } => level <= 10,
+            Compression::Heatshrink { window, lookahead } => {
+                (4..=15).contains(&window) && (3..window).contains(&lookahead)
+            }
+        }
+    }
+}
+
+/// Set of codecs that a device is able to decode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Codecs(u32);
+impl Codecs {
+    const NONE: u32 = 1 << 0;
+    const DEFLATE: u32 = 1 << 1;
+    const LZ4: u32 = 1 << 2;
+    const HEATSHRINK: u32 = 1 << 3;
+
+    /// The codecs that this build of `okboot-common` has decoders for.
+    pub const fn built_in() -> Self {
+        let mut bits = Self::NONE | Self::DEFLATE;
+        if cfg!(feature = "lz4") {
+            bits |= Self::LZ4;
+        }
+        if cfg!(feature = "heatshrink") {
+            bits |= Self::HEATSHRINK;
+        }
+        Self(bits)
+    }
+
+    pub fn supports(self, compression: Compression) -> bool {
+        compression.is_valid() && self.0 & compression.bit() != 0
+    }
+}
+impl Display for Codecs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let names = [
+            (Self::NONE, "none"),
+            (Self::DEFLATE, "deflate"),
+            (Self::LZ4, "lz4"),
+            (Self::HEATSHRINK, "heatshrink"),
+        ];
+        let mut names = names.iter().filter(|(bit, _)| self.0 & bit != 0);
+        if let Some((_, name)) = names.next() {
+            write!(f, "{name}")?;
+        }
+        names.try_for_each(|(_, name)| write!(f, ", {name}"))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum CodecError {
+    #[error("{0} is not supported")]
+    Unsupported(Compression),
+    #[error("corrupt deflate stream: {0:?}")]
+    Deflate(MZError),
+    #[error("corrupt LZ4 block")]
+    Lz4,
+}
+
+/// Compress `data` with `compression`.
+#[cfg(feature = "alloc")]
+pub fn encode(data: &[u8], compression: Compression) -> Result<Vec<u8>, CodecError> {
+    if !Codecs::built_in().supports(compression) {
+        return Err(CodecError::Unsupported(compression));
+    }
+    Ok(match compression {
+        Compression::None => data.to_vec(),
+        Compression::Deflate { level } => miniz_oxide::deflate::compress_to_vec(data, level),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4::encode(data),
+        #[cfg(feature = "heatshrink")]
+        Compression::Heatshrink { window, lookahead } => {
+            heatshrink::encode(data, window, lookahead)
+        }
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("checked against the built-in codecs"),
+    })
+}
+
+/// Outcome of a call to [`Decoder::decode`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Decoded {
+    /// Bytes of the input that were used up; the rest has to be passed in again.
+    pub consumed: usize,
+    /// Bytes of decompressed data written to the output.
+    pub written: usize,
+    /// Whether the compressed stream has ended. Only deflate streams know where they end; for the
+    /// other codecs, the stream is over once all of it has been passed in and no more output
+    /// comes out.
+    pub finished: bool,
+}
+
+/// Streaming decompression of whichever codec the host chose.
+#[cfg(feature = "alloc")]
+pub enum Decoder {
+    None,
+    Deflate(Box<InflateState>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4::Decoder),
+    #[cfg(feature = "heatshrink")]
+    Heatshrink(heatshrink::Decoder),
+}
+#[cfg(feature = "alloc")]
+impl core::fmt::Debug for Decoder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Decoder::None => "Decoder::None",
+            Decoder::Deflate(_) => "Decoder::Deflate",
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(_) => "Decoder::Lz4",
+            #[cfg(feature = "heatshrink")]
+            Decoder::Heatshrink(_) => "Decoder::Heatshrink",
+        })
+    }
+}
+#[cfg(feature = "alloc")]
+impl Decoder {
+    pub fn new(compression: Compression) -> Result<Self, CodecError> {
+        if !Codecs::built_in().supports(compression) {
+            return Err(CodecError::Unsupported(compression));
+        }
+        Ok(match compression {
+            Compression::None => Decoder::None,
+            Compression::Deflate { .. } => {
+                Decoder::Deflate(InflateState::new_boxed(DataFormat::Raw))
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Decoder::Lz4(lz4::Decoder::new()),
+            #[cfg(feature = "heatshrink")]
+            Compression::Heatshrink { window, lookahead } => {
+                Decoder::Heatshrink(heatshrink::Decoder::new(window, lookahead))
+            }
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("checked against the built-in codecs"),
+        })
+    }
+
+    /// Decompress as much of `input` into `output` as fits. Writes nothing only if no more output
+    /// can be had without further input.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> Result<Decoded, CodecError> {
+        let (consumed, written) = match self {
+            Decoder::None => {
+                let n = input.len().min(output.len());
+                output[..n].copy_from_slice(&input[..n]);
+                (n, n)
+            }
+            Decoder::Deflate(state) => {
+                let result =
+                    miniz_oxide::inflate::stream::inflate(state, input, output, MZFlush::None);
+                let finished = match result.status {
+                    Ok(MZStatus::Ok) => false,
+                    Ok(MZStatus::StreamEnd) => true,
+                    Ok(MZStatus::NeedDict) => unreachable!(), // unused
+                    // no progress possible without more input
+                    Err(MZError::Buf) => false,
+                    Err(e) => return Err(CodecError::Deflate(e)),
+                };
+                return Ok(Decoded {
+                    consumed: result.bytes_consumed,
+                    written: result.bytes_written,
+                    finished,
+                });
+            }
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(decoder) => decoder.decode(input, output)?,
+            #[cfg(feature = "heatshrink")]
+            Decoder::Heatshrink(decoder) => decoder.decode(input, output),
+        };
+        Ok(Decoded {
+            consumed,
+            written,
+            finished: false,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "lz4", feature = "heatshrink"))]
+mod tests {
+    use super::*;
+    use rand::{Rng, RngCore, SeedableRng};
+    use std::prelude::rust_2021::*;
+    use std::vec;
+
+    const ALL: [Compression; 5] = [
+        Compression::None,
+        Compression::Deflate { level: 5 },
+        Compression::Lz4,
+        Compression::Heatshrink {
+            window: 8,
+            lookahead: 4,
+        },
+        Compression::Heatshrink {
+            window: 13,
+            lookahead: 6,
+        },
+    ];
+
+    /// Something vaguely program-like: runs of similar instructions, some zeros, some noise.
+    fn program(rng: &mut impl RngCore, len: usize) -> Vec<u8> {
+        let mut program = vec![];
+        while program.len() < len {
+            match rng.gen_range(0..3) {
+                0 => program.extend(core::iter::repeat_n(0, rng.gen_range(1..64))),
+                1 => {
+                    let word: u32 = rng.gen();
+                    for _ in 0..rng.gen_range(1..32) {
+                        let jitter: u32 = rng.gen_range(0..16);
+                        program.extend_from_slice(&(word ^ jitter).to_le_bytes());
+                    }
+                }
+                _ => {
+                    let at = program.len().saturating_sub(rng.gen_range(1..0x8000));
+                    let len = rng.gen_range(0..256).min(program.len() - at);
+                    program.extend_from_within(at..at + len);
+                    program.push(rng.gen());
+                }
+            }
+        }
+        program.truncate(len);
+        program
+    }
+
+    /// Decode `encoded` in randomly sized pieces into randomly sized output buffers, the way the
+    /// device does as chunks trickle in.
+    fn decode(compression: Compression, encoded: &[u8], rng: &mut impl RngCore) -> Vec<u8> {
+        let mut decoder = Decoder::new(compression).unwrap();
+        let mut decoded = vec![];
+        let mut staged = vec![];
+        let mut input = encoded;
+        loop {
+            let piece = rng.gen_range(0..=input.len().min(300));
+            staged.extend_from_slice(&input[..piece]);
+            input = &input[piece..];
+            let mut output = vec![0; rng.gen_range(1..700)];
+            let result = decoder.decode(&staged, &mut output).unwrap();
+            staged.drain(..result.consumed);
+            decoded.extend_from_slice(&output[..result.written]);
+            let stalled = result.consumed == 0 && result.written == 0;
+            if result.finished || (input.is_empty() && stalled) {
+                return decoded;
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_in_pieces() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for len in [0, 1, 1000, 0x12345] {
+            let data = program(&mut rng, len);
+            for compression in ALL {
+                let encoded = encode(&data, compression).unwrap();
+                assert_eq!(
+                    decode(compression, &encoded, &mut rng),
+                    data,
+                    "{compression} of {len} bytes"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_programs() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let data = program(&mut rng, 0x10000);
+        for compression in &ALL[1..] {
+            let encoded = encode(&data, *compression).unwrap();
+            assert!(
+                encoded.len() < data.len() / 2,
+                "{compression} only got {} bytes down to {}",
+                data.len(),
+                encoded.len()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        for compression in [
+            Compression::Deflate { level: 11 },
+            Compression::Heatshrink {
+                window: 3,
+                lookahead: 2,
+            },
+            Compression::Heatshrink {
+                window: 8,
+                lookahead: 8,
+            },
+            Compression::Heatshrink {
+                window: 16,
+                lookahead: 4,
+            },
+        ] {
+            assert_eq!(
+                encode(&[], compression),
+                Err(CodecError::Unsupported(compression))
+            );
+            assert!(Decoder::new(compression).is_err());
+        }
+    }
+}
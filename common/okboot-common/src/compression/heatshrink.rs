@@ -0,0 +1,276 @@
+//! Heatshrink is LZSS over a bit stream, with a window of `2^window` bytes:
+//! ```txt
+//! | 1 | byte:8                                 |   literal
+//! | 0 | distance - 1:window | len - 1:lookahead |   copy len bytes from distance bytes back
+//! ```
+//! Fields are packed most significant bit first, and the last byte is padded with zeros. The
+//! padding is always too short to be mistaken for another literal or backreference.
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// How many earlier occurrences of a two-byte prefix [`encode`] looks at for a longer match.
+const MAX_CHAIN: usize = 256;
+
+#[derive(Debug, Copy, Clone)]
+enum State {
+    Tag,
+    Literal,
+    Distance,
+    Len { distance: usize },
+    Copy { distance: usize, remaining: usize },
+}
+
+/// Streaming heatshrink decoder.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    window_bits: u8,
+    lookahead_bits: u8,
+    window: Vec<u8>,
+    /// Total number of bytes output so far; the window is indexed modulo its size.
+    head: usize,
+    /// Input bits that have been read but not yet used up, right-aligned.
+    bits: u32,
+    bit_count: u8,
+    state: State,
+}
+impl Decoder {
+    /// The parameters have to be [valid](super::Compression::is_valid).
+    pub fn new(window: u8, lookahead: u8) -> Self {
+        Self {
+            window_bits: window,
+            lookahead_bits: lookahead,
+            window: vec![0; 1 << window],
+            head: 0,
+            bits: 0,
+            bit_count: 0,
+            state: State::Tag,
+        }
+    }
+
+    /// Take the next `count` bits from `input`, or `None` if there aren't enough yet.
+    fn take(&mut self, count: u8, input: &mut &[u8]) -> Option<usize> {
+        while self.bit_count < count {
+            let (&byte, rest) = input.split_first()?;
+            *input = rest;
+            self.bits = (self.bits << 8) | byte as u32;
+            self.bit_count += 8;
+        }
+        self.bit_count -= count;
+        let value = self.bits >> self.bit_count;
+        self.bits &= (1 << self.bit_count) - 1;
+        Some(value as usize)
+    }
+
+    /// Returns how many bytes of `input` were consumed and how many were written to `output`.
+    pub fn decode(&mut self, mut input: &[u8], output: &mut [u8]) -> (usize, usize) {
+        let total = input.len();
+        let mask = self.window.len() - 1;
+        let mut written = 0;
+        while written < output.len() {
+            let byte = match self.state {
+                State::Tag => {
+                    let Some(tag) = self.take(1, &mut input) else {
+                        break;
+                    };
+                    self.state = if tag == 1 {
+                        State::Literal
+                    } else {
+                        State::Distance
+                    };
+                    continue;
+                }
+                State::Literal => {
+                    let Some(byte) = self.take(8, &mut input) else {
+                        break;
+                    };
+                    self.state = State::Tag;
+                    byte as u8
+                }
+                State::Distance => {
+                    let Some(index) = self.take(self.window_bits, &mut input) else {
+                        break;
+                    };
+                    self.state = State::Len {
+                        distance: index + 1,
+                    };
+                    continue;
+                }
+                State::Len { distance } => {
+                    let Some(count) = self.take(self.lookahead_bits, &mut input) else {
+                        break;
+                    };
+                    self.state = State::Copy {
+                        distance,
+                        remaining: count + 1,
+                    };
+                    continue;
+                }
+                State::Copy {
+                    distance,
+                    remaining,
+                } => {
+                    self.state = match remaining - 1 {
+                        0 => State::Tag,
+                        remaining => State::Copy {
+                            distance,
+                            remaining,
+                        },
+                    };
+                    self.window[self.head.wrapping_sub(distance) & mask]
+                }
+            };
+            output[written] = byte;
+            self.window[self.head & mask] = byte;
+            self.head += 1;
+            written += 1;
+        }
+        (total - input.len(), written)
+    }
+}
+
+/// Compress `data` with a window of `2^window` bytes and backreferences of up to `2^lookahead`
+/// bytes.
+pub fn encode(data: &[u8], window: u8, lookahead: u8) -> Vec<u8> {
+    let max_distance = 1usize << window;
+    let max_len = 1usize << lookahead;
+    // worth it once a backreference takes fewer bits than the literals it replaces
+    let backref_bits = 1 + window as usize + lookahead as usize;
+
+    let mut matches = MatchFinder::new(data);
+    let mut out = BitWriter::default();
+    let mut i = 0;
+    while i < data.len() {
+        let (len, distance) = matches.longest(i, max_distance, max_len);
+        if len >= 2 && len * 9 > backref_bits {
+            out.push(0, 1);
+            out.push(distance - 1, window);
+            out.push(len - 1, lookahead);
+            for j in i..i + len {
+                matches.insert(j);
+            }
+            i += len;
+        } else {
+            out.push(1, 1);
+            out.push(data[i] as usize, 8);
+            matches.insert(i);
+            i += 1;
+        }
+    }
+    out.finish()
+}
+
+/// Hash chains over the two-byte prefixes of `data`.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    /// Latest position at which each prefix was seen.
+    heads: Vec<u32>,
+    /// For each position, the previous one with the same prefix.
+    chain: Vec<u32>,
+}
+impl<'a> MatchFinder<'a> {
+    const NONE: u32 = u32::MAX;
+
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            heads: vec![Self::NONE; 1 << 16],
+            chain: vec![Self::NONE; data.len()],
+        }
+    }
+
+    fn prefix(&self, i: usize) -> usize {
+        self.data[i] as usize | (self.data[i + 1] as usize) << 8
+    }
+
+    fn insert(&mut self, i: usize) {
+        if i + 1 < self.data.len() {
+            let prefix = self.prefix(i);
+            self.chain[i] = self.heads[prefix];
+            self.heads[prefix] = i as u32;
+        }
+    }
+
+    /// Length and distance of the longest match for the bytes at `i` among the ones inserted so
+    /// far; the length is 0 if there isn't any.
+    fn longest(&self, i: usize, max_distance: usize, max_len: usize) -> (usize, usize) {
+        let data = self.data;
+        let mut best = (0, 0);
+        if i + 1 >= data.len() {
+            return best;
+        }
+        let limit = max_len.min(data.len() - i);
+        let mut candidate = self.heads[self.prefix(i)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == Self::NONE || i - candidate as usize > max_distance {
+                break;
+            }
+            let from = candidate as usize;
+            // may run into the bytes being matched, which the decoder copies one at a time
+            let len = (0..limit)
+                .take_while(|&k| data[from + k] == data[i + k])
+                .count();
+            if len > best.0 {
+                best = (len, i - from);
+                if len == limit {
+                    break;
+                }
+            }
+            candidate = self.chain[from];
+        }
+        best
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    bit_count: u8,
+}
+impl BitWriter {
+    fn push(&mut self, value: usize, count: u8) {
+        self.bits = (self.bits << count) | value as u32;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.bytes.push((self.bits >> self.bit_count) as u8);
+        }
+        self.bits &= (1 << self.bit_count) - 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.bytes.push((self.bits << (8 - self.bit_count)) as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn decodes_known_stream() {
+        // "abcabcabc" with -w 8 -l 4: three literals, then a backreference of 6 bytes
+        // from 3 bytes back
+        let mut out = BitWriter::default();
+        for &byte in b"abc" {
+            out.push(1, 1);
+            out.push(byte as usize, 8);
+        }
+        out.push(0, 1);
+        out.push(2, 8);
+        out.push(5, 4);
+        let encoded = out.finish();
+        assert_eq!(encoded, encode(b"abcabcabc", 8, 4));
+
+        let mut output = vec![0; 16];
+        let (consumed, written) = Decoder::new(8, 4).decode(&encoded, &mut output);
+        assert_eq!(
+            (consumed, &output[..written]),
+            (encoded.len(), &b"abcabcabc"[..])
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! The program is cut into blocks of [`BLOCK_SIZE`] bytes, which are compressed separately, each
+//! with the [`DICT_SIZE`] bytes before it as a dictionary:
+//! ```txt
+//! | len:u16 | LZ4 block of len bytes |   repeated
+//! ```
+//! `len` is little-endian. Small blocks keep the time the device spends decompressing any one of
+//! them short, while the dictionary keeps the compression ratio close to that of one big block.
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::CodecError;
+
+/// Decompressed size of every block but the last.
+pub const BLOCK_SIZE: usize = 0x400;
+/// How far back blocks may refer to earlier output.
+pub const DICT_SIZE: usize = 0x4000;
+/// Upper bound on the compressed size of a block, per the LZ4 block format.
+const MAX_BLOCK_LEN: usize = BLOCK_SIZE + BLOCK_SIZE / 255 + 16;
+/// Size of the buffer that the device decompresses into; the dictionary is moved back to its
+/// start whenever the next block doesn't fit anymore.
+const HISTORY_SIZE: usize = 2 * DICT_SIZE;
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for start in (0..data.len()).step_by(BLOCK_SIZE) {
+        let block = &data[start..(start + BLOCK_SIZE).min(data.len())];
+        let dict = &data[start.saturating_sub(DICT_SIZE)..start];
+        let compressed = lz4_flex::block::compress_with_dict(block, dict);
+        out.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+        out.extend_from_slice(&compressed);
+    }
+    out
+}
+
+/// Streaming decoder for the block format described [above](self).
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    /// Length prefix and compressed bytes of the block being received.
+    block: Vec<u8>,
+    /// Recent output, serving as the dictionary for the next block.
+    history: Vec<u8>,
+    /// Part of `history` that hasn't been passed on yet.
+    pending: core::ops::Range<usize>,
+}
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            block: Vec::with_capacity(2 + MAX_BLOCK_LEN),
+            history: vec![0; HISTORY_SIZE],
+            pending: 0..0,
+        }
+    }
+
+    /// Returns how many bytes of `input` were consumed and how many were written to `output`.
+    pub fn decode(
+        &mut self,
+        mut input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(usize, usize), CodecError> {
+        let total = input.len();
+        let mut written = 0;
+        loop {
+            if !self.pending.is_empty() {
+                let n = self.pending.len().min(output.len() - written);
+                if n == 0 {
+                    break;
+                }
+                output[written..written + n]
+                    .copy_from_slice(&self.history[self.pending.start..self.pending.start + n]);
+                self.pending.start += n;
+                written += n;
+                continue;
+            }
+
+            if self.block.len() < 2 {
+                let n = (2 - self.block.len()).min(input.len());
+                self.block.extend_from_slice(&input[..n]);
+                input = &input[n..];
+                if self.block.len() < 2 {
+                    break;
+                }
+            }
+            let len = u16::from_le_bytes([self.block[0], self.block[1]]) as usize;
+            if len == 0 || len > MAX_BLOCK_LEN {
+                return Err(CodecError::Lz4);
+            }
+            let n = (2 + len - self.block.len()).min(input.len());
+            self.block.extend_from_slice(&input[..n]);
+            input = &input[n..];
+            if self.block.len() < 2 + len {
+                break;
+            }
+
+            let mut end = self.pending.end;
+            if end + BLOCK_SIZE > self.history.len() {
+                self.history.copy_within(end - DICT_SIZE..end, 0);
+                end = DICT_SIZE;
+            }
+            let (dict, rest) = self.history.split_at_mut(end);
+            let len = lz4_flex::block::decompress_into_with_dict(
+                &self.block[2..],
+                &mut rest[..BLOCK_SIZE],
+                &dict[end.saturating_sub(DICT_SIZE)..],
+            )
+            .map_err(|_| CodecError::Lz4)?;
+            self.pending = end..end + len;
+            self.block.clear();
+        }
+        Ok((total - input.len(), written))
+    }
+}
@@ -0,0 +1,359 @@
+//! Binary delta format, used to upload a program as a diff against the image the device booted
+//! last.
+//!
+//! A delta is a sequence of operations which, applied in order, produce the new image:
+//! ```txt
+//! | 0x00 | len:u32 | len bytes       |   literal: append the following bytes
+//! | 0x01 | offset:u32 | len:u32      |   copy: append base[offset..offset + len]
+//! ```
+//! All integers are little-endian. Deltas are produced on the host with [`diff`], and applied on
+//! the device with a [`Patcher`], as the (inflated) delta streams in.
+#[cfg(feature = "alloc")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use thiserror::Error;
+
+const OP_LITERAL: u8 = 0x00;
+const OP_COPY: u8 = 0x01;
+
+/// Size of the blocks that [`diff`] looks for in the base image.
+#[cfg(feature = "alloc")]
+const BLOCK_SIZE: usize = 32;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum DeltaError {
+    #[error("unknown delta operation {0:#04x}")]
+    UnknownOp(u8),
+    #[error("copy of {len} bytes at {offset:#x} is outside of the base image")]
+    OutOfBounds { offset: u32, len: u32 },
+}
+
+#[derive(Debug, Copy, Clone)]
+enum State {
+    /// Reading the opcode and operands of the next operation into `Patcher::header`.
+    Header {
+        filled: usize,
+    },
+    Literal {
+        remaining: usize,
+    },
+    Copy {
+        offset: usize,
+        remaining: usize,
+    },
+}
+
+/// Streaming application of a delta against a base image.
+#[derive(Debug, Clone)]
+pub struct Patcher {
+    state: State,
+    header: [u8; 9],
+}
+impl Default for Patcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Patcher {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Header { filled: 0 },
+            header: [0; 9],
+        }
+    }
+
+    /// Whether a copy is underway, i.e. whether [`feed`](Patcher::feed) can produce output even
+    /// without further input.
+    pub fn has_output(&self) -> bool {
+        matches!(self.state, State::Copy { .. })
+    }
+
+    /// Apply as much of the delta in `input` as possible, passing the resulting image bytes to
+    /// `emit`. At most `budget` bytes are emitted per call. Returns the number of bytes of `input`
+    /// that were consumed; the rest has to be passed in again on the next call.
+    pub fn feed(
+        &mut self,
+        mut input: &[u8],
+        base: &[u8],
+        mut budget: usize,
+        mut emit: impl FnMut(&[u8]),
+    ) -> Result<usize, DeltaError> {
+        let total = input.len();
+        loop {
+            match &mut self.state {
+                State::Header { filled } => {
+                    let Some((&byte, rest)) = input.split_first() else {
+                        break;
+                    };
+                    self.header[*filled] = byte;
+                    *filled += 1;
+                    input = rest;
+                    let operands = match self.header[0] {
+                        OP_LITERAL => 4,
+                        OP_COPY => 8,
+                        op => return Err(DeltaError::UnknownOp(op)),
+                    };
+                    if *filled == 1 + operands {
+                        self.state = self.decode_header(base)?;
+                    }
+                }
+                State::Literal { remaining } => {
+                    let n = (*remaining).min(input.len()).min(budget);
+                    if n == 0 {
+                        break;
+                    }
+                    emit(&input[..n]);
+                    input = &input[n..];
+                    budget -= n;
+                    *remaining -= n;
+                    if *remaining == 0 {
+                        self.state = State::Header { filled: 0 };
+                    }
+                }
+                State::Copy { offset, remaining } => {
+                    let n = (*remaining).min(budget);
+                    if n == 0 {
+                        break;
+                    }
+                    emit(&base[*offset..*offset + n]);
+                    budget -= n;
+                    *offset += n;
+                    *remaining -= n;
+                    if *remaining == 0 {
+                        self.state = State::Header { filled: 0 };
+                    }
+                }
+            }
+        }
+        Ok(total - input.len())
+    }
+
+    fn decode_header(&self, base: &[u8]) -> Result<State, DeltaError> {
+        let operand = |i: usize| {
+            u32::from_le_bytes(
+                self.header[1 + 4 * i..5 + 4 * i]
+                    .try_into()
+                    .expect("impossible"),
+            )
+        };
+        Ok(match self.header[0] {
+            OP_LITERAL => match operand(0) as usize {
+                0 => State::Header { filled: 0 },
+                len => State::Literal { remaining: len },
+            },
+            _ => {
+                let (offset, len) = (operand(0), operand(1));
+                if (offset as usize)
+                    .checked_add(len as usize)
+                    .is_none_or(|end| end > base.len())
+                {
+                    return Err(DeltaError::OutOfBounds { offset, len });
+                }
+                match len {
+                    0 => State::Header { filled: 0 },
+                    len => State::Copy {
+                        offset: offset as usize,
+                        remaining: len as usize,
+                    },
+                }
+            }
+        })
+    }
+}
+
+/// Compute a delta that turns `base` into `new`.
+///
+/// Looks up every [`BLOCK_SIZE`]-byte window of `new` among the aligned blocks of `base` with a
+/// rolling checksum (as in rsync), and extends matches byte by byte in both directions, so that
+/// insertions and deletions only cost about as much as the bytes that actually changed.
+#[cfg(feature = "alloc")]
+pub fn diff(base: &[u8], new: &[u8]) -> Vec<u8> {
+    assert!(
+        base.len() <= u32::MAX as usize && new.len() <= u32::MAX as usize,
+        "images must be smaller than 4GiB"
+    );
+    let mut blocks = BTreeMap::new();
+    for (i, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+        blocks
+            .entry(RollingChecksum::new(block).value())
+            .or_insert(i * BLOCK_SIZE);
+    }
+
+    let mut delta = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    let mut checksum = RollingChecksum::new(&new[..BLOCK_SIZE.min(new.len())]);
+    while i + BLOCK_SIZE <= new.len() {
+        let matched = blocks
+            .get(&checksum.value())
+            .copied()
+            .filter(|&j| base[j..j + BLOCK_SIZE] == new[i..i + BLOCK_SIZE]);
+        if let Some(j) = matched {
+            let (mut start, mut from) = (i, j);
+            while start > literal_start && from > 0 && new[start - 1] == base[from - 1] {
+                start -= 1;
+                from -= 1;
+            }
+            let (mut end, mut to) = (i + BLOCK_SIZE, j + BLOCK_SIZE);
+            while end < new.len() && to < base.len() && new[end] == base[to] {
+                end += 1;
+                to += 1;
+            }
+            push_literal(&mut delta, &new[literal_start..start]);
+            push_copy(&mut delta, from, end - start);
+            literal_start = end;
+            i = end;
+            checksum = RollingChecksum::new(&new[i..(i + BLOCK_SIZE).min(new.len())]);
+            continue;
+        }
+        if i + BLOCK_SIZE < new.len() {
+            checksum.roll(new[i], new[i + BLOCK_SIZE]);
+        }
+        i += 1;
+    }
+    push_literal(&mut delta, &new[literal_start..]);
+    delta
+}
+
+#[cfg(feature = "alloc")]
+fn push_literal(delta: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    delta.push(OP_LITERAL);
+    delta.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    delta.extend_from_slice(bytes);
+}
+
+#[cfg(feature = "alloc")]
+fn push_copy(delta: &mut Vec<u8>, offset: usize, len: usize) {
+    delta.push(OP_COPY);
+    delta.extend_from_slice(&(offset as u32).to_le_bytes());
+    delta.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+/// Adler-32-like checksum over a window of [`BLOCK_SIZE`] bytes, which can be slid along by one
+/// byte in constant time.
+#[cfg(feature = "alloc")]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+}
+#[cfg(feature = "alloc")]
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((BLOCK_SIZE - i) as u32 * byte as u32);
+        }
+        Self { a, b }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self
+            .b
+            .wrapping_sub(BLOCK_SIZE as u32 * out as u32)
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use rand::{Rng, RngCore, SeedableRng};
+    use std::prelude::rust_2021::*;
+    use std::vec;
+
+    fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+        let mut out = vec![];
+        let consumed =
+            Patcher::new().feed(delta, base, usize::MAX, |b| out.extend_from_slice(b))?;
+        assert_eq!(consumed, delta.len());
+        Ok(out)
+    }
+
+    fn random_bytes(rng: &mut impl RngCore, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        rng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn identical_images_are_one_copy() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let image = random_bytes(&mut rng, 0x10000);
+        let delta = diff(&image, &image);
+        assert_eq!(delta.len(), 9);
+        assert_eq!(apply(&image, &delta).unwrap(), image);
+    }
+
+    #[test]
+    fn edits_cost_about_as_much_as_they_change() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let base = random_bytes(&mut rng, 0x10000);
+        let mut new = base.clone();
+        // overwrite, insert and delete a few bytes in different places
+        new[0x100..0x110].copy_from_slice(&random_bytes(&mut rng, 0x10));
+        new.splice(0x4000..0x4000, random_bytes(&mut rng, 0x30));
+        new.drain(0x9000..0x9100);
+        let delta = diff(&base, &new);
+        assert!(delta.len() < 0x100, "delta is {} bytes", delta.len());
+        assert_eq!(apply(&base, &delta).unwrap(), new);
+    }
+
+    #[test]
+    fn round_trips_unrelated_and_empty_images() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+        let a = random_bytes(&mut rng, 1000);
+        let b = random_bytes(&mut rng, 777);
+        for (base, new) in [
+            (&a[..], &b[..]),
+            (&[], &a),
+            (&a, &[]),
+            (&[], &[]),
+            (&a, &a[..5]),
+        ] {
+            assert_eq!(apply(base, &diff(base, new)).unwrap(), new);
+        }
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let base = random_bytes(&mut rng, 0x8000);
+        let mut new = base.clone();
+        new[0x2000..0x2400].copy_from_slice(&random_bytes(&mut rng, 0x400));
+        let delta = diff(&base, &new);
+
+        let mut patcher = Patcher::new();
+        let mut out = vec![];
+        let mut input = &delta[..];
+        while !input.is_empty() || patcher.has_output() {
+            let piece = rng.gen_range(0..=input.len().min(16));
+            let budget = rng.gen_range(0..64);
+            let consumed = patcher
+                .feed(&input[..piece], &base, budget, |b| out.extend_from_slice(b))
+                .unwrap();
+            input = &input[consumed..];
+        }
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn rejects_malformed_deltas() {
+        let base = [0; 16];
+        assert_eq!(apply(&base, &[0x02]), Err(DeltaError::UnknownOp(0x02)));
+        let copy = [OP_COPY, 8, 0, 0, 0, 9, 0, 0, 0];
+        assert_eq!(
+            apply(&base, &copy),
+            Err(DeltaError::OutOfBounds { offset: 8, len: 9 })
+        );
+    }
+}
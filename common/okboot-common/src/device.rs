@@ -1,5 +1,13 @@
+use crate::compression::Codecs;
+use crate::fat::FatError;
+use crate::frame::write_frame;
+use crate::host::{FormatDetails, Formats};
+use crate::monitor::{MemoryMap, Refusal};
+use crate::persist::Slot;
+use crate::segments::Segment;
 use crate::{EncodeMessageType, MessageType};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 // /// Send a string to the host to be printed out. Messages will be line-buffered in a timeout-limited
 // /// manner.
 // #[derive(Debug, Serialize, Deserialize)]
@@ -8,6 +16,54 @@ use serde::{Deserialize, Serialize};
 //     pub string: &'a str,
 // }
 
+/// Severity of a [`Log`] record; numbered the same way as the `log` crate's `Level`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A structured log record, to be shown by the host alongside its own logs.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Log<'a> {
+    pub level: LogLevel,
+    /// Microseconds since the device's system timer started.
+    pub timestamp_us: u64,
+    /// Where the record came from; usually a module path.
+    pub target: &'a str,
+    pub message: &'a str,
+}
+impl EncodeMessageType for Log<'_> {
+    const TYPE: MessageType = MessageType::Log;
+}
+
+/// Sent by the booted program, rather than by okboot, once it has finished; `okdude --run-until`
+/// exits with `code`.
+///
+/// The payload is just `code` in little-endian order; see [`write_frame`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Exit {
+    pub code: i32,
+}
+impl Exit {
+    /// Pass the frame for this message to `write`, piece by piece.
+    pub fn frame(&self, write: impl FnMut(&[u8])) {
+        write_frame(MessageType::Exit, &i32::to_le_bytes(self.code), write);
+    }
+
+    /// Read the message back from the payload of an [`Exit`](MessageType::Exit) frame.
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        Some(Self {
+            code: i32::from_le_bytes(payload.try_into().ok()?),
+        })
+    }
+}
+
 /// Indicates the protocol versions that the device can speak.
 // This requires a bit of song-and-dance because of a limitation of musli.
 // Specifically, we can't [`Deserialize`] &[u32].
@@ -60,10 +116,194 @@ impl EncodeMessageType for AllowedVersions<'_> {
     const TYPE: MessageType = MessageType::AllowedVersions;
 }
 
-/// Signals that the host should send program [`Metadata`](crate::host::Metadata).
+/// Answer to [`ProposeBaudRates`](crate::host::ProposeBaudRates): the baud rate the device will
+/// switch to once this message has been sent. If none of the proposed rates were usable, this is
+/// [`INITIAL_BAUD_RATE`](crate::INITIAL_BAUD_RATE).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct BaudRateChoice {
+    pub baud_rate: u32,
+}
+impl EncodeMessageType for BaudRateChoice {
+    const TYPE: MessageType = MessageType::BaudRateChoice;
+}
+
+/// Echo of a [`BaudProbe`](crate::host::BaudProbe), confirming that the new baud rate works.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct BaudProbeAck {
+    pub nonce: u32,
+}
+impl EncodeMessageType for BaudProbeAck {
+    const TYPE: MessageType = MessageType::BaudProbeAck;
+}
+
+/// Answer to [`Identify`](crate::host::Identify): which board this is, which okboot it's running,
+/// and what it can load. Tells boards apart when several of them are attached to the same host,
+/// and lets the host turn down programs that wouldn't fit before sending any of them.
+///
+/// `serial` and `board_revision` are as the firmware reports them, or zero if they couldn't be
+/// read.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct DeviceInfo<'a> {
+    pub serial: u64,
+    pub board_revision: u32,
+    /// The CPU's Main ID register.
+    pub cpu_id: u32,
+    /// okboot's crate version.
+    pub version: &'a str,
+    /// Commit that okboot was built from; empty if it isn't known.
+    pub git_hash: &'a str,
+    /// First address past okboot's own image. Programs loaded below it are received into a side
+    /// buffer above it, and moved into place just before they're booted.
+    pub image_end: u32,
+    /// Programs have to be loaded below this address.
+    pub load_end: u32,
+    /// Largest (inflated) ELF file that the device can hold on to while loading it.
+    pub max_elf_len: u32,
+    pub formats: Formats,
+    pub codecs: Codecs,
+    /// What okdude's `mem` commands may read and write.
+    pub memory_map: MemoryMap,
+    /// Largest (inflated) program that can be [kept](crate::persist) on the SD card; zero if
+    /// there's no card.
+    pub max_persist_len: u32,
+}
+impl EncodeMessageType for DeviceInfo<'_> {
+    const TYPE: MessageType = MessageType::DeviceInfo;
+}
+impl DeviceInfo<'_> {
+    /// Check that a program of `len` (inflated) bytes, in `format`, can be received. ELF files'
+    /// segments are checked separately, by [`executable::validate`](crate::executable::validate)
+    /// with [`load_end`](Self::load_end), and so are segment tables, by
+    /// [`check_segments`](Self::check_segments).
+    pub fn check(&self, format: FormatDetails, len: u32) -> Result<(), Misfit> {
+        if !self.formats.supports(format) {
+            return Err(Misfit::Format(format));
+        }
+        match format {
+            FormatDetails::Bin { load_address } => {
+                if load_address & 3 != 0 {
+                    return Err(Misfit::Unaligned(load_address));
+                }
+                self.check_segment(load_address, len as u64)?;
+                self.check_relocation(load_address, len as u64)
+            }
+            FormatDetails::Elf if len > self.max_elf_len => Err(Misfit::TooLarge {
+                len,
+                max: self.max_elf_len,
+            }),
+            FormatDetails::Elf => Ok(()),
+            FormatDetails::Segments { entry } if entry & 3 != 0 => Err(Misfit::Unaligned(entry)),
+            FormatDetails::Segments { .. } => Ok(()),
+        }
+    }
+
+    /// Check that a program of `len` (inflated) bytes can be kept on the SD card.
+    pub fn check_persist(&self, len: u32) -> Result<(), Misfit> {
+        match self.max_persist_len {
+            0 => Err(Misfit::NoStorage),
+            max if len > max => Err(Misfit::TooLargeToPersist { len, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the `segments` of a [`FormatDetails::Segments`] program, in ascending order of
+    /// address, stay below [`load_end`](Self::load_end). Those below okboot's end are received
+    /// into a side buffer like a flat binary spanning all of them would be.
+    pub fn check_segments(
+        &self,
+        segments: impl Iterator<Item = Segment> + Clone,
+    ) -> Result<(), Misfit> {
+        for segment in segments.clone() {
+            self.check_segment(segment.address as u64, segment.len as u64)?;
+        }
+        let start = segments.clone().map(|s| s.address as u64).min();
+        let end = segments.map(|s| s.end()).max();
+        match start.zip(end) {
+            Some((start, end)) => self.check_relocation(start, end - start),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that `len` bytes loaded at `address` stay below [`load_end`](Self::load_end).
+    fn check_segment(&self, address: u64, len: u64) -> Result<(), Misfit> {
+        match address.checked_add(len) {
+            Some(end) if end <= self.load_end as u64 => Ok(()),
+            _ => Err(Misfit::OutOfRange {
+                address,
+                len,
+                load_end: self.load_end,
+            }),
+        }
+    }
+
+    /// A flat binary that overlaps okboot is received into a side buffer that starts at the first
+    /// page boundary past both okboot and the program, followed by the relocation stub; all of
+    /// that has to stay below `load_end` too. Mirrors okboot's `Relocation::calculate`.
+    fn check_relocation(&self, address: u64, len: u64) -> Result<(), Misfit> {
+        let image_end = self.image_end as u64;
+        if address >= image_end {
+            return Ok(());
+        }
+        let end = address + len;
+        let side_buffer = end.max(image_end).next_multiple_of(RELOCATION_PAGE_SIZE);
+        let relocated = end.min(image_end) - address;
+        if side_buffer + relocated + RELOCATION_STUB_ROOM > self.load_end as u64 {
+            return Err(Misfit::NoRoomToRelocate {
+                address,
+                len,
+                image_end: self.image_end,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Side buffers for relocated programs start on a page boundary.
+const RELOCATION_PAGE_SIZE: u64 = 0x4000;
+/// Room for the relocation stub, which is copied in right after the side buffer, and the segment
+/// table that the one for segmented programs takes after it.
+const RELOCATION_STUB_ROOM: u64 = 0x1000;
+
+/// Why a program can't be loaded by the device that sent a [`DeviceInfo`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum Misfit {
+    #[error("{0} programs aren't supported")]
+    Format(FormatDetails),
+    #[error("load address {0:#x} isn't 4-byte aligned")]
+    Unaligned(u64),
+    #[error("{len} bytes at {address:#x} would run past {load_end:#x}")]
+    OutOfRange {
+        address: u64,
+        len: u64,
+        load_end: u32,
+    },
+    #[error(
+        "{len} bytes at {address:#x} overlap okboot, which ends at {image_end:#x}, and there's no \
+         room left to receive them elsewhere first"
+    )]
+    NoRoomToRelocate {
+        address: u64,
+        len: u64,
+        image_end: u32,
+    },
+    #[error("the ELF file is {len} bytes, but at most {max} fit")]
+    TooLarge { len: u32, max: u32 },
+    #[error("the device has no SD card to keep programs on")]
+    NoStorage,
+    #[error("the program is {len} bytes, but at most {max} can be kept on the SD card")]
+    TooLargeToPersist { len: u32, max: u32 },
+}
+
+/// Signals that the host should send program [`Metadata`](crate::host::Metadata), compressed with
+/// one of `codecs`.
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(C)]
-pub struct MetadataReq {}
+pub struct MetadataReq {
+    pub codecs: Codecs,
+}
 impl EncodeMessageType for MetadataReq {
     const TYPE: MessageType = MessageType::MetadataReq;
 }
@@ -80,6 +320,53 @@ impl EncodeMessageType for MetadataAck {
     const TYPE: MessageType = MessageType::MetadataAck;
 }
 
+/// [`MetadataAck`] for the pipelined protocol; additionally advertises how many chunks the host may
+/// have in flight at once.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct PipelinedMetadataAck {
+    pub chunk_size: u32,
+    pub window_size: u32,
+    pub metadata: crate::host::Metadata,
+}
+impl EncodeMessageType for PipelinedMetadataAck {
+    const TYPE: MessageType = MessageType::PipelinedMetadataAck;
+}
+
+/// Sent instead of [`MetadataReq`] when the device still holds a transfer that was interrupted
+/// less than a session ago, offering to continue it at chunk `next`.
+///
+/// `inflated` is the number of decompressed bytes loaded so far. `window_size` is zero unless the
+/// transfer is pipelined.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Resume {
+    pub chunk_size: u32,
+    pub window_size: u32,
+    pub next: u32,
+    pub inflated: u32,
+    pub metadata: crate::host::Metadata,
+}
+impl EncodeMessageType for Resume {
+    const TYPE: MessageType = MessageType::Resume;
+}
+
+/// Describes the image that the device kept from the last successful upload, sent ahead of
+/// [`MetadataReq`] and [`Resume`] so that the host can send a
+/// [`DeltaMetadata`](crate::host::DeltaMetadata) and a delta against it instead of the whole
+/// program.
+///
+/// `hash` is the SHA-256 digest of the `len` bytes of the (inflated) image.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct BaseImage {
+    pub len: u32,
+    pub hash: [u8; 32],
+}
+impl EncodeMessageType for BaseImage {
+    const TYPE: MessageType = MessageType::BaseImage;
+}
+
 /// Request a specific chunk from the host.
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(C)]
@@ -90,6 +377,21 @@ impl EncodeMessageType for ChunkReq {
     const TYPE: MessageType = MessageType::ChunkReq;
 }
 
+/// Acknowledge chunks received under the pipelined protocol.
+///
+/// Every chunk below `next` has been received (cumulative acknowledgement); bit `i` of `received`
+/// is set if chunk `next + 1 + i` has also been received out of order (selective acknowledgement).
+/// The host may have chunks up to (but not including) `next + window_size` in flight.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ChunkAck {
+    pub next: u32,
+    pub received: u32,
+}
+impl EncodeMessageType for ChunkAck {
+    const TYPE: MessageType = MessageType::ChunkAck;
+}
+
 /// Indicate that the device has finished downloading.
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(C)]
@@ -97,3 +399,260 @@ pub struct Booting {}
 impl EncodeMessageType for Booting {
     const TYPE: MessageType = MessageType::Booting;
 }
+
+/// Why a program's [`ImageSignature`](crate::host::ImageSignature) didn't check out against the
+/// key that the device verifies programs with.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Error)]
+pub enum SignatureError {
+    #[error("program is not signed")]
+    Missing,
+    #[error("the device's verifying key is not a valid Ed25519 public key")]
+    Key,
+    #[error("signature does not match program")]
+    Mismatch,
+}
+
+/// Sent instead of [`Booting`] when the program's signature didn't check out; the device waits for
+/// another program rather than boot it.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct BootRefused {
+    pub error: SignatureError,
+}
+impl EncodeMessageType for BootRefused {
+    const TYPE: MessageType = MessageType::BootRefused;
+}
+
+/// The bytes that a [`MemRead`](crate::host::MemRead) asked for.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemData<'a> {
+    pub address: u32,
+    pub bytes: &'a [u8],
+}
+impl EncodeMessageType for MemData<'_> {
+    const TYPE: MessageType = MessageType::MemData;
+}
+
+/// CRC of the `len` bytes at `address`, in answer to a [`MemCrc`](crate::host::MemCrc) or a
+/// [`MemWrite`](crate::host::MemWrite).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemCrcResult {
+    pub address: u32,
+    pub len: u32,
+    pub crc: u32,
+}
+impl EncodeMessageType for MemCrcResult {
+    const TYPE: MessageType = MessageType::MemCrcResult;
+}
+
+/// Signals that okboot is about to jump to `address`, as the host asked with a
+/// [`Jump`](crate::host::Jump).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Jumping {
+    pub address: u32,
+}
+impl EncodeMessageType for Jumping {
+    const TYPE: MessageType = MessageType::Jumping;
+}
+
+/// Answers a [`MemRead`](crate::host::MemRead), [`MemWrite`](crate::host::MemWrite),
+/// [`MemCrc`](crate::host::MemCrc) or [`Jump`](crate::host::Jump) that okboot won't carry out.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemRefused {
+    pub refusal: Refusal,
+}
+impl EncodeMessageType for MemRefused {
+    const TYPE: MessageType = MessageType::MemRefused;
+}
+
+/// An image kept on the SD card, one of the answers to a
+/// [`ListImages`](crate::host::ListImages).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct StoredImage {
+    pub slot: Slot,
+    /// Length of the inflated image.
+    pub len: u32,
+    /// CRC-32 of the image.
+    pub crc: u32,
+    pub format_details: FormatDetails,
+    pub signed: bool,
+}
+impl EncodeMessageType for StoredImage {
+    const TYPE: MessageType = MessageType::StoredImage;
+}
+
+/// Follows the [`StoredImage`]s sent in answer to a [`ListImages`](crate::host::ListImages):
+/// there were `count` of them.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ImagesListed {
+    pub count: u32,
+}
+impl EncodeMessageType for ImagesListed {
+    const TYPE: MessageType = MessageType::ImagesListed;
+}
+
+/// Answers a [`DeleteImage`](crate::host::DeleteImage): whether there was an image in `slot` to
+/// delete.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ImageDeleted {
+    pub slot: Slot,
+    pub existed: bool,
+}
+impl EncodeMessageType for ImageDeleted {
+    const TYPE: MessageType = MessageType::ImageDeleted;
+}
+
+/// Signals that the SD card couldn't be read or written: in answer to a
+/// [`ListImages`](crate::host::ListImages) or [`DeleteImage`](crate::host::DeleteImage), or
+/// instead of a [`Persisted`] when a program was to be kept, in which case the device doesn't
+/// boot it.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct StorageFailed {
+    pub error: FatError,
+}
+impl EncodeMessageType for StorageFailed {
+    const TYPE: MessageType = MessageType::StorageFailed;
+}
+
+/// Sent every so often while a program that was to be kept is written to the SD card, after it's
+/// been received and before [`Booting`]: `written` of its `len` bytes are on the card.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Persisting {
+    pub written: u32,
+    pub len: u32,
+}
+impl EncodeMessageType for Persisting {
+    const TYPE: MessageType = MessageType::Persisting;
+}
+
+/// Signals that the program is kept on the SD card in `slot`; [`Booting`] follows.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Persisted {
+    pub slot: Slot,
+}
+impl EncodeMessageType for Persisted {
+    const TYPE: MessageType = MessageType::Persisted;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::Region;
+
+    fn info() -> DeviceInfo<'static> {
+        DeviceInfo {
+            serial: 0,
+            board_revision: 0,
+            cpu_id: 0,
+            version: "",
+            git_hash: "",
+            image_end: 0x10_0000,
+            load_end: 0x1000_0000,
+            max_elf_len: 0x0700_0000,
+            formats: Formats::all(),
+            codecs: Codecs::built_in(),
+            memory_map: MemoryMap {
+                ram: Region::new(0, 0x1100_0000),
+                peripherals: Region::EMPTY,
+                okboot: Region::new(0x8000, 0x10_0000),
+                heap: Region::EMPTY,
+                uart: Region::EMPTY,
+            },
+            max_persist_len: 0x0080_0000,
+        }
+    }
+
+    /// Test that flat binaries have to end below `load_end`, side buffer included
+    #[test]
+    fn test_check_bin() {
+        let info = info();
+        let bin = |load_address| FormatDetails::Bin { load_address };
+        assert_eq!(info.check(bin(0x8000), 0x1_0000), Ok(()));
+        assert_eq!(
+            info.check(bin(0x8002), 0x1_0000),
+            Err(Misfit::Unaligned(0x8002))
+        );
+        assert_eq!(info.check(bin(0x20_0000), 0x0fe0_0000), Ok(()));
+        assert!(matches!(
+            info.check(bin(0x20_0000), 0x0fe0_0004),
+            Err(Misfit::OutOfRange { .. })
+        ));
+        // ends below load_end, but overlaps okboot with no room left for the side buffer
+        assert!(matches!(
+            info.check(bin(0x8000), 0x0ff0_0000),
+            Err(Misfit::NoRoomToRelocate { .. })
+        ));
+    }
+
+    /// Test that segments have to end below `load_end`, with room to relocate them all
+    #[test]
+    fn test_check_segments() {
+        let info = info();
+        let segment = |address, len| Segment {
+            address,
+            len,
+            crc: 0,
+        };
+        let entry = |entry| FormatDetails::Segments { entry };
+        assert_eq!(info.check(entry(0x8000), 0x100), Ok(()));
+        assert_eq!(
+            info.check(entry(0x8001), 0x100),
+            Err(Misfit::Unaligned(0x8001))
+        );
+        let sparse = [segment(0x8000, 0x1000), segment(0x0800_0000, 0x1000)];
+        assert_eq!(info.check_segments(sparse.into_iter()), Ok(()));
+        assert!(matches!(
+            info.check_segments(
+                [segment(0x8000, 0x1000), segment(0x0fff_f000, 0x1001)].into_iter()
+            ),
+            Err(Misfit::OutOfRange { .. })
+        ));
+        // the side buffer goes past the highest segment
+        assert!(matches!(
+            info.check_segments(
+                [segment(0x8000, 0x1000), segment(0x0ffe_0000, 0x1000)].into_iter()
+            ),
+            Err(Misfit::NoRoomToRelocate { .. })
+        ));
+    }
+
+    /// Test that ELF files are only limited by their size
+    #[test]
+    fn test_check_elf() {
+        let info = info();
+        assert_eq!(info.check(FormatDetails::Elf, 0x0700_0000), Ok(()));
+        assert_eq!(
+            info.check(FormatDetails::Elf, 0x0700_0001),
+            Err(Misfit::TooLarge {
+                len: 0x0700_0001,
+                max: 0x0700_0000
+            })
+        );
+    }
+
+    /// Test that programs can only be kept if there's a card, and room for them
+    #[test]
+    fn test_check_persist() {
+        let mut info = info();
+        assert_eq!(info.check_persist(0x0080_0000), Ok(()));
+        assert_eq!(
+            info.check_persist(0x0080_0001),
+            Err(Misfit::TooLargeToPersist {
+                len: 0x0080_0001,
+                max: 0x0080_0000
+            })
+        );
+        info.max_persist_len = 0;
+        assert_eq!(info.check_persist(1), Err(Misfit::NoStorage));
+    }
+}
@@ -0,0 +1,223 @@
+//! Checks that an ELF file is one that okboot can load.
+//!
+//! okboot makes them once the whole file has arrived, just before booting it; okdude makes the
+//! same ones before sending anything, so that a file that okboot would turn down is caught before
+//! it has been uploaded.
+use core::ops::Range;
+use elf::abi::{EM_ARM, ET_EXEC, PT_GNU_STACK, PT_LOAD, PT_NOTE, PT_TLS};
+use elf::endian::{EndianParse, LittleEndian};
+use elf::file::Class;
+use elf::segment::ProgramHeader;
+use elf::ElfBytes;
+use thiserror::Error;
+
+/// Where the Raspberry Pi's firmware loads okboot, and so where okboot's image starts.
+pub const IMAGE_START: u64 = 0x8000;
+/// Where okboot's ELF stub keeps the registers it saves while it copies segments into place: the
+/// stub's stack starts just below [`IMAGE_START`].
+pub const STUB_STACK: Range<u64> = IMAGE_START - 0x40..IMAGE_START;
+
+#[derive(Debug, Error)]
+pub enum ElfError {
+    #[error("error parsing ELF header: {0}")]
+    Parse(elf::ParseError),
+    #[error("expected ELF32, found ELF64")]
+    Class,
+    #[error("expected EM_ARM")]
+    Machine,
+    #[error("expected ET_EXEC")]
+    Type,
+    #[error("expected ELF v1")]
+    Version,
+    #[error("expected entry below {load_end:#x}, found {entry:#x}")]
+    Entry { entry: u64, load_end: u64 },
+    #[error("expected little-endian ELF binary")]
+    Endianness,
+    #[error("expected e_ident[EI_OSABI] to be 0 (none/sysv)")]
+    OsAbi,
+    #[error("expected a segment table")]
+    NoSegmentTable,
+    #[error("segment {index}: ELF TLS is not supported yet")]
+    Tls { index: usize },
+    #[error("segment {index}: expected PT_LOAD, PT_GNU_STACK or PT_NOTE, found type {p_type:#x}")]
+    SegmentType { index: usize, p_type: u32 },
+    #[error(
+        "segment {index}: PT_LOAD must have p_filesz=p_memsz or p_filesz=0, found \
+         p_filesz={filesz:#x} and p_memsz={memsz:#x}"
+    )]
+    SegmentSize {
+        index: usize,
+        filesz: u64,
+        memsz: u64,
+    },
+    #[error("segment {index}: {start:#x}..{end:#x} runs past {load_end:#x}")]
+    SegmentRange {
+        index: usize,
+        start: u64,
+        end: u64,
+        load_end: u64,
+    },
+}
+
+/// Check that `data` is an ELF file that okboot can load, with its entry point and every
+/// `PT_LOAD` segment below `load_end`, and pass each `PT_LOAD` segment to `segment`, along with
+/// its index in the segment table. Returns the entry point.
+pub fn validate(
+    data: &[u8],
+    load_end: u64,
+    mut segment: impl FnMut(usize, ProgramHeader),
+) -> Result<u64, ElfError> {
+    let elf = ElfBytes::<LittleEndian>::minimal_parse(data).map_err(ElfError::Parse)?;
+    let ehdr = &elf.ehdr;
+    if !matches!(ehdr.class, Class::ELF32) {
+        return Err(ElfError::Class);
+    }
+    if !ehdr.endianness.is_little() {
+        return Err(ElfError::Endianness);
+    }
+    if ehdr.osabi != 0 {
+        return Err(ElfError::OsAbi);
+    }
+    if ehdr.e_type != ET_EXEC {
+        return Err(ElfError::Type);
+    }
+    if ehdr.e_machine != EM_ARM {
+        return Err(ElfError::Machine);
+    }
+    if ehdr.version != 1 {
+        return Err(ElfError::Version);
+    }
+    let entry = ehdr.e_entry;
+    if entry >= load_end {
+        return Err(ElfError::Entry { entry, load_end });
+    }
+
+    let segments = elf.segments().ok_or(ElfError::NoSegmentTable)?;
+    for (index, phdr) in segments.iter().enumerate() {
+        match phdr.p_type {
+            PT_LOAD => {
+                // the stub copies p_memsz bytes from the file, or zeroes them if p_filesz is 0
+                if phdr.p_filesz != phdr.p_memsz && phdr.p_filesz != 0 {
+                    return Err(ElfError::SegmentSize {
+                        index,
+                        filesz: phdr.p_filesz,
+                        memsz: phdr.p_memsz,
+                    });
+                }
+                let (start, end) = extent(&phdr);
+                if end > load_end {
+                    return Err(ElfError::SegmentRange {
+                        index,
+                        start,
+                        end,
+                        load_end,
+                    });
+                }
+                segment(index, phdr);
+            }
+            PT_TLS => return Err(ElfError::Tls { index }),
+            PT_GNU_STACK | PT_NOTE => {}
+            p_type => return Err(ElfError::SegmentType { index, p_type }),
+        }
+    }
+    Ok(entry)
+}
+
+/// Something about a `PT_LOAD` segment that okboot allows, but that may not be what was meant.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum Hazard {
+    /// Harmless as far as okboot is concerned, since segments are only copied into place once it's
+    /// done, but the program can't expect to find anything of okboot's there.
+    #[error(
+        "segment {index} ({start:#x}..{end:#x}) overlaps okboot's image, which ends at \
+         {image_end:#x}"
+    )]
+    OverlapsOkboot {
+        index: usize,
+        start: u64,
+        end: u64,
+        image_end: u64,
+    },
+    /// The stub would overwrite the registers it saved, and crash once it's done copying.
+    #[error(
+        "segment {index} ({start:#x}..{end:#x}) overlaps the stack that okboot's ELF stub uses \
+         while it loads segments ({:#x}..{:#x})",
+        STUB_STACK.start,
+        STUB_STACK.end
+    )]
+    OverlapsStub { index: usize, start: u64, end: u64 },
+}
+
+/// What's hazardous about the `PT_LOAD` segment `phdr`, at `index` in the segment table, when
+/// okboot's image ends at `image_end`.
+pub fn hazards(index: usize, phdr: &ProgramHeader, image_end: u64) -> impl Iterator<Item = Hazard> {
+    let (start, end) = extent(phdr);
+    let overlaps = |range: Range<u64>| start < range.end && range.start < end;
+    let okboot = overlaps(IMAGE_START..image_end).then_some(Hazard::OverlapsOkboot {
+        index,
+        start,
+        end,
+        image_end,
+    });
+    let stub = overlaps(STUB_STACK).then_some(Hazard::OverlapsStub { index, start, end });
+    [stub, okboot].into_iter().flatten()
+}
+
+/// Where the segment goes in memory; saturates instead of overflowing, so that it's still caught
+/// by the range check.
+fn extent(phdr: &ProgramHeader) -> (u64, u64) {
+    (phdr.p_vaddr, phdr.p_vaddr.saturating_add(phdr.p_memsz))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn load(vaddr: u64, memsz: u64) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_LOAD,
+            p_offset: 0,
+            p_vaddr: vaddr,
+            p_paddr: vaddr,
+            p_filesz: memsz,
+            p_memsz: memsz,
+            p_flags: 7,
+            p_align: 4,
+        }
+    }
+
+    /// Test that segments are only hazardous where they overlap okboot or the stub's stack
+    #[test]
+    fn test_hazards() {
+        let hazards = |phdr| hazards(1, &phdr, 0x10_0000).collect::<Vec<_>>();
+        assert_eq!(hazards(load(0x20_0000, 0x1000)), []);
+        assert_eq!(hazards(load(0x7000, 0xfc0)), []);
+        assert_eq!(
+            hazards(load(0x8000, 0x1000)),
+            [Hazard::OverlapsOkboot {
+                index: 1,
+                start: 0x8000,
+                end: 0x9000,
+                image_end: 0x10_0000
+            }]
+        );
+        assert_eq!(
+            hazards(load(0x7000, 0x1000)),
+            [Hazard::OverlapsStub {
+                index: 1,
+                start: 0x7000,
+                end: 0x8000
+            }]
+        );
+    }
+
+    /// Test that files that aren't ELF at all are turned down
+    #[test]
+    fn test_validate() {
+        assert!(matches!(
+            validate(b"not an ELF file", 0x1000_0000, |_, _| {}),
+            Err(ElfError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,889 @@
+//! Just enough FAT32 for okboot to keep images in the root directory of the SD card's boot
+//! partition, next to the firmware: files with 8.3 names in the root directory, read, written
+//! whole, and deleted.
+//!
+//! Nothing here allocates; a [`Volume`] is only the filesystem's geometry, and every operation
+//! goes through the [`BlockDevice`] that it's given. There's no clock either, so files are all
+//! dated 1980-01-01.
+use core::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Bytes in a block, as far as both the card and the filesystem are concerned.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Something that reads and writes whole [`SECTOR_SIZE`]-byte blocks, e.g. an SD card.
+pub trait BlockDevice {
+    /// Read the blocks starting at `lba` into `buf`, whose length is a multiple of
+    /// [`SECTOR_SIZE`].
+    fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), BlockError>;
+    /// Write `buf`, whose length is a multiple of [`SECTOR_SIZE`], to the blocks starting at
+    /// `lba`.
+    fn write(&self, lba: u32, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Why a [`BlockDevice`] couldn't read or write.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error, Serialize, Deserialize)]
+#[repr(C)]
+pub enum BlockError {
+    #[error("there's no SD card")]
+    NoCard,
+    #[error("CMD{command} timed out")]
+    Timeout { command: u32 },
+    #[error("CMD{command} failed with interrupt status {status:#010x}")]
+    Failed { command: u32, status: u32 },
+    #[error("block {lba} is past the end of the card")]
+    OutOfRange { lba: u32 },
+}
+
+/// Why a [`Volume`] couldn't be opened, read or written.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error, Serialize, Deserialize)]
+#[repr(C)]
+pub enum FatError {
+    #[error("{0}")]
+    Device(#[from] BlockError),
+    #[error("no FAT32 filesystem found")]
+    NotFat32,
+    #[error("not enough free space")]
+    Full,
+    #[error("the filesystem is corrupt")]
+    Corrupt,
+}
+
+/// A name in the root directory: eight characters, then a three-character extension, each padded
+/// with spaces, as they're stored.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ShortName(pub [u8; 11]);
+impl ShortName {
+    /// `name`.`extension`, which have to be short enough, and already in upper case.
+    pub fn new(name: &[u8], extension: &[u8]) -> Self {
+        let mut bytes = [b' '; 11];
+        bytes[..name.len()].copy_from_slice(name);
+        bytes[8..8 + extension.len()].copy_from_slice(extension);
+        Self(bytes)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        trim(&self.0[..8])
+    }
+
+    pub fn extension(&self) -> &[u8] {
+        trim(&self.0[8..])
+    }
+}
+impl Display for ShortName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        fn text(bytes: &[u8]) -> &str {
+            core::str::from_utf8(bytes).unwrap_or("?")
+        }
+        match self.extension() {
+            [] => write!(f, "{}", text(self.name())),
+            extension => write!(f, "{}.{}", text(self.name()), text(extension)),
+        }
+    }
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    &bytes[..len]
+}
+
+/// A file in the root directory.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: ShortName,
+    pub len: u32,
+    /// First cluster of the file's data; zero if it's empty.
+    cluster: u32,
+    /// Where the entry itself is: the sector, and the entry within it.
+    location: (u32, usize),
+}
+
+const ENTRY_LEN: usize = 32;
+/// First byte of the name of an entry that has been deleted.
+const DELETED: u8 = 0xe5;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// 1980-01-01, the earliest date FAT can express.
+const DATE: u16 = (1 << 5) | 1;
+/// Any FAT entry at least this marks the end of a chain.
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+/// Partition types for FAT32, with CHS and LBA addressing.
+const PARTITION_TYPES: [u8; 2] = [0x0b, 0x0c];
+/// Sectors staged by a [`FileWriter`] before they're written, if the cluster is large enough.
+const WRITE_SECTORS: usize = 8;
+
+/// An opened FAT32 filesystem: where its FATs, root directory and clusters are.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Volume {
+    fsinfo: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    fats: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+    clusters: u32,
+    root: u32,
+}
+impl Volume {
+    /// Find the filesystem on `device`: in the first FAT32 partition if it has a partition table,
+    /// or else filling the whole device.
+    pub fn open(device: &dyn BlockDevice) -> Result<Self, FatError> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read(0, &mut sector)?;
+        if sector[510..] != [0x55, 0xaa] {
+            return Err(FatError::NotFat32);
+        }
+        let start = if is_boot_sector(&sector) {
+            0
+        } else {
+            let partition = sector[446..510]
+                .chunks_exact(16)
+                .find(|entry| PARTITION_TYPES.contains(&entry[4]))
+                .ok_or(FatError::NotFat32)?;
+            let start = u32::from_le_bytes(partition[8..12].try_into().unwrap());
+            device.read(start, &mut sector)?;
+            if !is_boot_sector(&sector) {
+                return Err(FatError::NotFat32);
+            }
+            start
+        };
+
+        let half = |at: usize| u16::from_le_bytes([sector[at], sector[at + 1]]) as u32;
+        let word = |at: usize| u32::from_le_bytes(sector[at..at + 4].try_into().unwrap());
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved = half(14);
+        let fats = sector[16] as u32;
+        let total = word(32);
+        let fat_sectors = word(36);
+        // FAT12 and FAT16 give the size of a FAT here instead, and have no root cluster
+        if half(22) != 0 || fat_sectors == 0 || reserved == 0 {
+            return Err(FatError::NotFat32);
+        }
+        let data_start = reserved + fats * fat_sectors;
+        let clusters = (total.checked_sub(data_start).ok_or(FatError::Corrupt)?
+            / sectors_per_cluster)
+            .min(fat_sectors * (SECTOR_SIZE / 4) as u32 - 2);
+        let volume = Self {
+            fsinfo: start + half(48),
+            fat_start: start + reserved,
+            fat_sectors,
+            fats,
+            data_start: start + data_start,
+            sectors_per_cluster,
+            clusters,
+            root: word(44),
+        };
+        volume.check_cluster(volume.root)?;
+        Ok(volume)
+    }
+
+    /// Bytes in a cluster.
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// Call `visit` with each file in the root directory, until it returns `false`.
+    pub fn files(
+        &self,
+        device: &dyn BlockDevice,
+        mut visit: impl FnMut(&DirEntry) -> bool,
+    ) -> Result<(), FatError> {
+        self.scan(device, |location, entry| {
+            match parse_entry(location, entry) {
+                Some(file) => visit(&file),
+                None => true,
+            }
+        })
+        .map(|_| ())
+    }
+
+    /// The file called `name` in the root directory, if there is one.
+    pub fn find(
+        &self,
+        device: &dyn BlockDevice,
+        name: &ShortName,
+    ) -> Result<Option<DirEntry>, FatError> {
+        let mut found = None;
+        self.files(device, |file| {
+            if file.name == *name {
+                found = Some(*file);
+            }
+            found.is_none()
+        })?;
+        Ok(found)
+    }
+
+    /// Pass `file`'s contents to `sink`, a few sectors at a time, until they're all read or it
+    /// returns `false`.
+    pub fn read_file(
+        &self,
+        device: &dyn BlockDevice,
+        file: &DirEntry,
+        mut sink: impl FnMut(&[u8]) -> bool,
+    ) -> Result<(), FatError> {
+        let mut buf = [0; WRITE_SECTORS * SECTOR_SIZE];
+        let mut fat = FatSector::default();
+        let mut left = file.len as usize;
+        let mut cluster = file.cluster;
+        while left > 0 {
+            self.check_cluster(cluster)?;
+            let mut sector = 0;
+            while sector < self.sectors_per_cluster && left > 0 {
+                let count = (WRITE_SECTORS as u32)
+                    .min(self.sectors_per_cluster - sector)
+                    .min(left.div_ceil(SECTOR_SIZE) as u32);
+                let bytes = &mut buf[..count as usize * SECTOR_SIZE];
+                device.read(self.cluster_lba(cluster) + sector, bytes)?;
+                let len = bytes.len().min(left);
+                if !sink(&bytes[..len]) {
+                    return Ok(());
+                }
+                left -= len;
+                sector += count;
+            }
+            if left > 0 {
+                cluster = fat.get(self, device, cluster)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the file called `name` from the root directory; `false` if there wasn't one.
+    pub fn delete(&self, device: &dyn BlockDevice, name: &ShortName) -> Result<bool, FatError> {
+        let Some(file) = self.find(device, name)? else {
+            return Ok(false);
+        };
+        self.update_entry(device, file.location, |entry| entry[0] = DELETED)?;
+        let mut fat = FatSector::default();
+        self.free_chain(device, &mut fat, file.cluster)?;
+        fat.flush(self, device)?;
+        self.forget_free_count(device)?;
+        Ok(true)
+    }
+
+    /// Start writing a file of `len` bytes, to be called `name` once it's
+    /// [finished](FileWriter::finish); until then, any file already called that is left alone.
+    pub fn create(
+        &self,
+        device: &dyn BlockDevice,
+        name: ShortName,
+        len: u32,
+    ) -> Result<FileWriter, FatError> {
+        let needed = (len as usize).div_ceil(self.cluster_size()) as u32;
+        let mut fat = FatSector::default();
+        let mut free = 0;
+        for cluster in 2..self.clusters + 2 {
+            if free >= needed {
+                break;
+            }
+            if fat.get(self, device, cluster)? == 0 {
+                free += 1;
+            }
+        }
+        if free < needed {
+            return Err(FatError::Full);
+        }
+        self.forget_free_count(device)?;
+        Ok(FileWriter {
+            name,
+            len,
+            written: 0,
+            first: 0,
+            cluster: 0,
+            sector: self.sectors_per_cluster,
+            buffer: [0; WRITE_SECTORS * SECTOR_SIZE],
+            staged: 0,
+            fat,
+            next_free: 2,
+        })
+    }
+
+    /// Call `visit` with where each entry of the root directory is and what's in it, until it
+    /// returns `false` or the end of the directory is reached. Returns the cluster of the root
+    /// directory that was looked at last.
+    fn scan(
+        &self,
+        device: &dyn BlockDevice,
+        mut visit: impl FnMut((u32, usize), &[u8; ENTRY_LEN]) -> bool,
+    ) -> Result<u32, FatError> {
+        let mut sector_bytes = [0; SECTOR_SIZE];
+        let mut fat = FatSector::default();
+        let mut cluster = self.root;
+        for _ in 0..self.clusters {
+            self.check_cluster(cluster)?;
+            for sector in 0..self.sectors_per_cluster {
+                let lba = self.cluster_lba(cluster) + sector;
+                device.read(lba, &mut sector_bytes)?;
+                for (index, entry) in sector_bytes.chunks_exact(ENTRY_LEN).enumerate() {
+                    if entry[0] == 0 {
+                        return Ok(cluster);
+                    }
+                    if !visit((lba, index), entry.try_into().unwrap()) {
+                        return Ok(cluster);
+                    }
+                }
+            }
+            let next = fat.get(self, device, cluster)?;
+            if next >= END_OF_CHAIN {
+                return Ok(cluster);
+            }
+            cluster = next;
+        }
+        Err(FatError::Corrupt)
+    }
+
+    /// Read the sector with the directory entry at `location`, change the entry, and write it back.
+    fn update_entry(
+        &self,
+        device: &dyn BlockDevice,
+        (lba, index): (u32, usize),
+        change: impl FnOnce(&mut [u8]),
+    ) -> Result<(), FatError> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read(lba, &mut sector)?;
+        change(&mut sector[index * ENTRY_LEN..(index + 1) * ENTRY_LEN]);
+        device.write(lba, &sector)?;
+        Ok(())
+    }
+
+    /// Mark every cluster of the chain starting at `cluster` as free.
+    fn free_chain(
+        &self,
+        device: &dyn BlockDevice,
+        fat: &mut FatSector,
+        mut cluster: u32,
+    ) -> Result<(), FatError> {
+        if cluster == 0 {
+            return Ok(());
+        }
+        for _ in 0..self.clusters {
+            self.check_cluster(cluster)?;
+            let next = fat.get(self, device, cluster)?;
+            fat.set(self, device, cluster, 0)?;
+            if next >= END_OF_CHAIN {
+                return Ok(());
+            }
+            cluster = next;
+        }
+        Err(FatError::Corrupt)
+    }
+
+    /// Tell other systems that the free cluster count in the FS information sector can't be
+    /// trusted any more, rather than keep it up to date.
+    fn forget_free_count(&self, device: &dyn BlockDevice) -> Result<(), FatError> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read(self.fsinfo, &mut sector)?;
+        if sector[0..4] != *b"RRaA" || sector[488..496] == [0xff; 8] {
+            return Ok(());
+        }
+        sector[488..496].fill(0xff);
+        device.write(self.fsinfo, &sector)?;
+        Ok(())
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FatError> {
+        if (2..self.clusters + 2).contains(&cluster) {
+            Ok(())
+        } else {
+            Err(FatError::Corrupt)
+        }
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+}
+
+/// Whether `sector` looks like a FAT boot sector rather than a partition table.
+fn is_boot_sector(sector: &[u8; SECTOR_SIZE]) -> bool {
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let sectors_per_cluster = sector[13];
+    matches!(sector[0], 0xeb | 0xe9)
+        && bytes_per_sector as usize == SECTOR_SIZE
+        && sectors_per_cluster.is_power_of_two()
+        && matches!(sector[16], 1 | 2)
+}
+
+/// The file described by a directory entry, unless it's something else.
+fn parse_entry(location: (u32, usize), entry: &[u8; ENTRY_LEN]) -> Option<DirEntry> {
+    // long name entries have the volume ID bit set, among others
+    if entry[0] == DELETED || entry[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY) != 0 {
+        return None;
+    }
+    let half = |at: usize| u16::from_le_bytes([entry[at], entry[at + 1]]) as u32;
+    Some(DirEntry {
+        name: ShortName(entry[..11].try_into().unwrap()),
+        len: u32::from_le_bytes(entry[28..32].try_into().unwrap()),
+        cluster: (half(20) << 16) | half(26),
+        location,
+    })
+}
+
+fn write_entry(entry: &mut [u8], name: &ShortName, cluster: u32, len: u32) {
+    entry.fill(0);
+    entry[..11].copy_from_slice(&name.0);
+    entry[11] = ATTR_ARCHIVE;
+    for at in [16, 18, 24] {
+        // created, accessed and modified
+        entry[at..at + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&len.to_le_bytes());
+}
+
+/// The sector of the FAT that was looked at last, so that following or building a chain doesn't
+/// read and write the same sector over and over. Changes go to every copy of the FAT once another
+/// sector is needed, or when [flushed](Self::flush).
+struct FatSector {
+    /// Which sector of the FAT this is; `u32::MAX` if none has been read yet.
+    index: u32,
+    bytes: [u8; SECTOR_SIZE],
+    dirty: bool,
+}
+impl Default for FatSector {
+    fn default() -> Self {
+        Self {
+            index: u32::MAX,
+            bytes: [0; SECTOR_SIZE],
+            dirty: false,
+        }
+    }
+}
+impl FatSector {
+    const ENTRIES: u32 = (SECTOR_SIZE / 4) as u32;
+
+    fn load(
+        &mut self,
+        volume: &Volume,
+        device: &dyn BlockDevice,
+        cluster: u32,
+    ) -> Result<usize, FatError> {
+        let index = cluster / Self::ENTRIES;
+        if index >= volume.fat_sectors {
+            return Err(FatError::Corrupt);
+        }
+        if index != self.index {
+            self.flush(volume, device)?;
+            device.read(volume.fat_start + index, &mut self.bytes)?;
+            self.index = index;
+        }
+        Ok((cluster % Self::ENTRIES) as usize * 4)
+    }
+
+    fn get(
+        &mut self,
+        volume: &Volume,
+        device: &dyn BlockDevice,
+        cluster: u32,
+    ) -> Result<u32, FatError> {
+        let at = self.load(volume, device, cluster)?;
+        Ok(u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap()) & FAT_ENTRY_MASK)
+    }
+
+    fn set(
+        &mut self,
+        volume: &Volume,
+        device: &dyn BlockDevice,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), FatError> {
+        let at = self.load(volume, device, cluster)?;
+        let entry = &mut self.bytes[at..at + 4];
+        // the top four bits are reserved, and left as they were
+        let old = u32::from_le_bytes(entry.try_into().unwrap());
+        entry.copy_from_slice(&((old & !FAT_ENTRY_MASK) | value).to_le_bytes());
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self, volume: &Volume, device: &dyn BlockDevice) -> Result<(), FatError> {
+        if self.dirty {
+            for fat in 0..volume.fats {
+                let lba = volume.fat_start + fat * volume.fat_sectors + self.index;
+                device.write(lba, &self.bytes)?;
+            }
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// A file being written, as [created](Volume::create); holds no borrows, so that it can be
+/// written a piece at a time in between other work.
+pub struct FileWriter {
+    name: ShortName,
+    len: u32,
+    written: u32,
+    /// The file's first cluster, and the one being written; zero until there is one.
+    first: u32,
+    cluster: u32,
+    /// Sector of `cluster` that `buffer` starts at.
+    sector: u32,
+    buffer: [u8; WRITE_SECTORS * SECTOR_SIZE],
+    staged: usize,
+    fat: FatSector,
+    /// No cluster below this is free.
+    next_free: u32,
+}
+impl FileWriter {
+    /// Bytes written so far.
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Write the next of the file's bytes, which mustn't run past the length it was created with.
+    pub fn write(
+        &mut self,
+        volume: &Volume,
+        device: &dyn BlockDevice,
+        mut bytes: &[u8],
+    ) -> Result<(), FatError> {
+        assert!(
+            self.written as usize + bytes.len() <= self.len as usize,
+            "writing past the end of {}",
+            self.name
+        );
+        while !bytes.is_empty() {
+            if self.sector == volume.sectors_per_cluster {
+                let previous = self.cluster;
+                self.cluster = self.allocate(volume, device)?;
+                if previous == 0 {
+                    self.first = self.cluster;
+                } else {
+                    self.fat.set(volume, device, previous, self.cluster)?;
+                }
+                self.sector = 0;
+            }
+            let room = self.capacity(volume) - self.staged;
+            let n = room.min(bytes.len());
+            self.buffer[self.staged..self.staged + n].copy_from_slice(&bytes[..n]);
+            self.staged += n;
+            self.written += n as u32;
+            bytes = &bytes[n..];
+            if self.staged == self.capacity(volume) {
+                self.write_staged(volume, device)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write what's left, then replace any file that was already called the same thing with this
+    /// one; it's only freed now, so that it's still there if writing failed.
+    pub fn finish(mut self, volume: &Volume, device: &dyn BlockDevice) -> Result<(), FatError> {
+        assert_eq!(self.written, self.len, "{} isn't finished", self.name);
+        if self.staged > 0 {
+            self.buffer[self.staged..].fill(0);
+            self.staged = self.staged.next_multiple_of(SECTOR_SIZE);
+            self.write_staged(volume, device)?;
+        }
+
+        let mut replaced = None;
+        let mut free = None;
+        let last = volume.scan(device, |location, entry| {
+            if entry[0] == DELETED {
+                free = free.or(Some(location));
+                return true;
+            }
+            match parse_entry(location, entry) {
+                Some(file) if file.name == self.name => {
+                    replaced = Some(file);
+                    false
+                }
+                _ => true,
+            }
+        })?;
+        let location = match (replaced, free) {
+            (Some(file), _) => file.location,
+            (None, Some(location)) => location,
+            (None, None) => self.end_of_directory(volume, device, last)?,
+        };
+        self.fat.flush(volume, device)?;
+        let (name, first, len) = (self.name, self.first, self.len);
+        volume.update_entry(device, location, |entry| {
+            write_entry(entry, &name, first, len)
+        })?;
+        if let Some(file) = replaced {
+            volume.free_chain(device, &mut self.fat, file.cluster)?;
+        }
+        self.fat.flush(volume, device)
+    }
+
+    /// Where the first unused entry of the root directory is, whose last cluster is `last`;
+    /// another cluster is added to the directory if that one is full.
+    fn end_of_directory(
+        &mut self,
+        volume: &Volume,
+        device: &dyn BlockDevice,
+        last: u32,
+    ) -> Result<(u32, usize), FatError> {
+        let mut sector_bytes = [0; SECTOR_SIZE];
+        for sector in 0..volume.sectors_per_cluster {
+            let lba = volume.cluster_lba(last) + sector;
+            device.read(lba, &mut sector_bytes)?;
+            if let Some(index) = sector_bytes.chunks_exact(ENTRY_LEN).position(|e| e[0] == 0) {
+                return Ok((lba, index));
+            }
+        }
+        let cluster = self.allocate(volume, device)?;
+        self.fat.set(volume, device, last, cluster)?;
+        let zero = [0; SECTOR_SIZE];
+        for sector in 0..volume.sectors_per_cluster {
+            device.write(volume.cluster_lba(cluster) + sector, &zero)?;
+        }
+        Ok((volume.cluster_lba(cluster), 0))
+    }
+
+    /// Bytes that can be staged before they have to be written: as many sectors as fit in the
+    /// buffer, without going past the end of the cluster.
+    fn capacity(&self, volume: &Volume) -> usize {
+        (WRITE_SECTORS as u32).min(volume.sectors_per_cluster - self.sector) as usize * SECTOR_SIZE
+    }
+
+    fn write_staged(&mut self, volume: &Volume, device: &dyn BlockDevice) -> Result<(), FatError> {
+        let lba = volume.cluster_lba(self.cluster) + self.sector;
+        device.write(lba, &self.buffer[..self.staged])?;
+        self.sector += (self.staged / SECTOR_SIZE) as u32;
+        self.staged = 0;
+        Ok(())
+    }
+
+    /// Find a free cluster, and mark it as the end of a chain.
+    fn allocate(&mut self, volume: &Volume, device: &dyn BlockDevice) -> Result<u32, FatError> {
+        for cluster in self.next_free..volume.clusters + 2 {
+            if self.fat.get(volume, device, cluster)? == 0 {
+                self.fat.set(volume, device, cluster, FAT_ENTRY_MASK)?;
+                self.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+        }
+        Err(FatError::Full)
+    }
+}
+
+/// Make an empty FAT32 filesystem, with one sector per cluster, on the `sectors` blocks of
+/// `device`, in a partition starting at [`FORMAT_PARTITION_START`] as SD cards come formatted.
+/// For okboot's simulator and tests; real cards are formatted by whoever writes okboot to them.
+pub fn format(device: &dyn BlockDevice, sectors: u32) -> Result<(), BlockError> {
+    const RESERVED: u32 = 32;
+    const FATS: u32 = 2;
+    let start = FORMAT_PARTITION_START;
+    let total = sectors - start;
+    let fat_sectors = ((total - RESERVED) / FatSector::ENTRIES) + 1;
+    let data_start = start + RESERVED + FATS * fat_sectors;
+
+    let mut sector = [0; SECTOR_SIZE];
+    let partition = &mut sector[446..462];
+    partition[4] = PARTITION_TYPES[1];
+    partition[8..12].copy_from_slice(&start.to_le_bytes());
+    partition[12..16].copy_from_slice(&total.to_le_bytes());
+    sector[510..].copy_from_slice(&[0x55, 0xaa]);
+    device.write(0, &sector)?;
+
+    let mut boot = [0; SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"OKBOOT  ");
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+    boot[16] = FATS as u8;
+    boot[21] = 0xf8;
+    boot[28..32].copy_from_slice(&start.to_le_bytes());
+    boot[32..36].copy_from_slice(&total.to_le_bytes());
+    boot[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+    // root directory in cluster 2, FS information sector right after the boot sector
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[71..82].copy_from_slice(b"NO NAME    ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..].copy_from_slice(&[0x55, 0xaa]);
+    device.write(start, &boot)?;
+
+    let mut fsinfo = [0; SECTOR_SIZE];
+    fsinfo[0..4].copy_from_slice(b"RRaA");
+    fsinfo[484..488].copy_from_slice(b"rrAa");
+    fsinfo[488..496].fill(0xff);
+    fsinfo[508..].copy_from_slice(&[0, 0, 0x55, 0xaa]);
+    device.write(start + 1, &fsinfo)?;
+
+    let zero = [0; SECTOR_SIZE];
+    for lba in start + RESERVED..data_start + 1 {
+        device.write(lba, &zero)?;
+    }
+    // media descriptor, then the end-of-chain marker that the root directory's cluster gets
+    let mut fat = [0; SECTOR_SIZE];
+    fat[0..4].copy_from_slice(&0x0fff_fff8u32.to_le_bytes());
+    fat[4..8].copy_from_slice(&FAT_ENTRY_MASK.to_le_bytes());
+    fat[8..12].copy_from_slice(&FAT_ENTRY_MASK.to_le_bytes());
+    for copy in 0..FATS {
+        device.write(start + RESERVED + copy * fat_sectors, &fat)?;
+    }
+    Ok(())
+}
+
+/// Where [`format`] puts the partition: 1MiB in.
+pub const FORMAT_PARTITION_START: u32 = 2048;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_LEN;
+
+    struct RamDisk(RefCell<Vec<u8>>);
+    impl RamDisk {
+        fn formatted(sectors: u32) -> Self {
+            let disk = Self(RefCell::new(vec![0; sectors as usize * SECTOR_SIZE]));
+            format(&disk, sectors).unwrap();
+            disk
+        }
+    }
+    impl BlockDevice for RamDisk {
+        fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), BlockError> {
+            let start = lba as usize * SECTOR_SIZE;
+            let bytes = self.0.borrow();
+            let sectors = bytes.get(start..start + buf.len());
+            buf.copy_from_slice(sectors.ok_or(BlockError::OutOfRange { lba })?);
+            Ok(())
+        }
+        fn write(&self, lba: u32, buf: &[u8]) -> Result<(), BlockError> {
+            let start = lba as usize * SECTOR_SIZE;
+            let mut bytes = self.0.borrow_mut();
+            let sectors = bytes.get_mut(start..start + buf.len());
+            sectors
+                .ok_or(BlockError::OutOfRange { lba })?
+                .copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn write_file(volume: &Volume, disk: &RamDisk, name: ShortName, contents: &[u8]) {
+        let mut writer = volume.create(disk, name, contents.len() as u32).unwrap();
+        // in uneven pieces, as okboot does between other work
+        for piece in contents.chunks(1000) {
+            writer.write(volume, disk, piece).unwrap();
+        }
+        writer.finish(volume, disk).unwrap();
+    }
+
+    fn read_file(volume: &Volume, disk: &RamDisk, name: &ShortName) -> Option<Vec<u8>> {
+        let file = volume.find(disk, name).unwrap()?;
+        let mut contents = vec![];
+        volume
+            .read_file(disk, &file, |bytes| {
+                contents.extend_from_slice(bytes);
+                true
+            })
+            .unwrap();
+        Some(contents)
+    }
+
+    fn free_clusters(volume: &Volume, disk: &RamDisk) -> u32 {
+        let mut fat = FatSector::default();
+        (2..volume.clusters + 2)
+            .filter(|&cluster| fat.get(volume, disk, cluster).unwrap() == 0)
+            .count() as u32
+    }
+
+    /// Test that files read back as written, and that replacing one frees the old one
+    #[test]
+    fn test_write_read_replace() {
+        let disk = RamDisk::formatted(0x4000);
+        let volume = Volume::open(&disk).unwrap();
+        let free = free_clusters(&volume, &disk);
+        let name = ShortName::new(b"DEFAULT", b"OKB");
+        let first: Vec<u8> = (0..20_000u32).map(|i| (i * 7) as u8).collect();
+        write_file(&volume, &disk, name, &first);
+        assert_eq!(read_file(&volume, &disk, &name).unwrap(), first);
+        assert_eq!(free_clusters(&volume, &disk), free - 40);
+
+        let second: Vec<u8> = (0..3000u32).map(|i| (i * 13) as u8).collect();
+        write_file(&volume, &disk, name, &second);
+        assert_eq!(read_file(&volume, &disk, &name).unwrap(), second);
+        assert_eq!(free_clusters(&volume, &disk), free - 6);
+
+        let mut names = vec![];
+        volume
+            .files(&disk, |file| {
+                names.push((file.name, file.len));
+                true
+            })
+            .unwrap();
+        assert_eq!(names, [(name, 3000)]);
+    }
+
+    /// Test that deleting a file frees its clusters, and that its entry gets reused
+    #[test]
+    fn test_delete() {
+        let disk = RamDisk::formatted(0x4000);
+        let volume = Volume::open(&disk).unwrap();
+        let free = free_clusters(&volume, &disk);
+        let (a, b) = (ShortName::new(b"A", b"OKB"), ShortName::new(b"B", b"OKB"));
+        write_file(&volume, &disk, a, &[1; 600]);
+        write_file(&volume, &disk, b, &[2; 100]);
+        assert!(volume.delete(&disk, &a).unwrap());
+        assert!(!volume.delete(&disk, &a).unwrap());
+        assert_eq!(read_file(&volume, &disk, &a), None);
+        assert_eq!(read_file(&volume, &disk, &b).unwrap(), [2; 100]);
+        assert_eq!(free_clusters(&volume, &disk), free - 1);
+
+        let c = ShortName::new(b"C", b"OKB");
+        write_file(&volume, &disk, c, &[]);
+        let file = volume.find(&disk, &c).unwrap().unwrap();
+        assert_eq!(file.location, (volume.cluster_lba(volume.root), 0));
+        assert_eq!(read_file(&volume, &disk, &c).unwrap(), []);
+    }
+
+    /// Test that the root directory grows once its first cluster is full, and that a file that
+    /// doesn't fit is turned down before anything is written
+    #[test]
+    fn test_directory_growth_and_full() {
+        let disk = RamDisk::formatted(0x1000);
+        let volume = Volume::open(&disk).unwrap();
+        for i in 0..ENTRIES_PER_SECTOR as u8 + 1 {
+            write_file(&volume, &disk, ShortName::new(&[b'A' + i], b""), &[i]);
+        }
+        for i in 0..ENTRIES_PER_SECTOR as u8 + 1 {
+            let name = ShortName::new(&[b'A' + i], b"");
+            assert_eq!(read_file(&volume, &disk, &name).unwrap(), [i]);
+        }
+        let free = free_clusters(&volume, &disk);
+        let too_large = (free + 1) * SECTOR_SIZE as u32;
+        assert!(matches!(
+            volume.create(&disk, ShortName::new(b"BIG", b""), too_large),
+            Err(FatError::Full)
+        ));
+    }
+
+    /// Test that partitioned and unpartitioned devices are both found, and that anything else
+    /// isn't taken for FAT32
+    #[test]
+    fn test_open() {
+        let disk = RamDisk::formatted(0x1000);
+        let partitioned = Volume::open(&disk).unwrap();
+        assert_eq!(partitioned.fat_start, FORMAT_PARTITION_START + 32);
+
+        let start = FORMAT_PARTITION_START as usize * SECTOR_SIZE;
+        let bare = RamDisk(RefCell::new(disk.0.borrow()[start..].to_vec()));
+        assert_eq!(Volume::open(&bare).unwrap().fat_start, 32);
+
+        let blank = RamDisk(RefCell::new(vec![0; 0x1000 * SECTOR_SIZE]));
+        assert_eq!(Volume::open(&blank), Err(FatError::NotFat32));
+    }
+
+    #[test]
+    fn test_short_name() {
+        let name = ShortName::new(b"DEFAULT", b"OKB");
+        assert_eq!(&name.0, b"DEFAULT OKB");
+        assert_eq!(std::format!("{name}"), "DEFAULT.OKB");
+        assert_eq!(std::format!("{}", ShortName::new(b"A", b"")), "A");
+    }
+}
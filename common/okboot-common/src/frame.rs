@@ -16,7 +16,9 @@ pub use decode::{CobsError, FrameError, FrameHeader, FrameLayer, FrameOutput, Pr
 
 /// Provides functionality for writing out a stream of COBS-stuffed data.
 mod encode;
-pub use encode::{encode_length, BufferedEncoder, EncodeState, FrameEncoder, SliceBufferedEncoder};
+pub use encode::{
+    encode_length, write_frame, BufferedEncoder, EncodeState, FrameEncoder, SliceBufferedEncoder,
+};
 
 #[cfg(test)]
 mod tests {
@@ -185,4 +187,45 @@ mod tests {
         }
         assert!(did_finish, "did not finish");
     }
+
+    /// Test that an `Exit` frame decodes back to the same message
+    #[test]
+    fn test_exit_frame() {
+        let exit = crate::device::Exit { code: -3 };
+        let mut bytes = vec![];
+        exit.frame(|b| bytes.extend_from_slice(b));
+
+        let mut dec = FrameLayer::new(COBS_XOR);
+        let mut hdr = None;
+        let mut payload = vec![];
+        let mut did_finish = false;
+        for &i in bytes.iter() {
+            match dec.feed(i).expect("error during frame decoding") {
+                FrameOutput::Header(h) => hdr = Some(h),
+                FrameOutput::Payload(b) => payload.push(b),
+                FrameOutput::Finished => did_finish = true,
+                _ => {}
+            }
+        }
+        assert!(did_finish, "did not finish");
+        assert_eq!(hdr.map(|h| h.message_type), Some(MessageType::Exit));
+        assert_eq!(crate::device::Exit::from_payload(&payload), Some(exit));
+    }
+
+    /// Test that a `Reboot` frame is as long as it says, and decodes
+    #[test]
+    fn test_reboot_frame() {
+        let bytes = crate::host::Reboot.frame_bytes();
+        let mut dec = FrameLayer::new(COBS_XOR);
+        let mut hdr = None;
+        for (j, &i) in bytes.iter().enumerate() {
+            match dec.feed(i).expect("error during frame decoding") {
+                FrameOutput::Header(h) => hdr = Some(h),
+                FrameOutput::Finished => assert_eq!(j, bytes.len() - 1, "finished early"),
+                FrameOutput::Payload(_) => panic!("unexpected payload"),
+                _ => {}
+            }
+        }
+        assert_eq!(hdr.map(|h| h.message_type), Some(MessageType::Reboot));
+    }
 }
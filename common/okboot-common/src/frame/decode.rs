@@ -343,14 +343,16 @@ impl FrameLayer {
                 match self.cobs_decoder.poll(byte).map_err(FrameError::Cobs)? {
                     CobsState::Skip => Ok(FrameOutput::Skip),
                     CobsState::Byte(byte) => {
-                        self.decode_state = FrameState::PacketHeader(i + 1, payload_len);
                         self.header_bytes[i] = byte;
                         self.crc.write_u8(byte);
                         if i == 3 {
+                            // don't move past the header until it's known to be valid, or the
+                            // next byte would be written past the end of `header_bytes`
                             let frame_header = self.decode_header_bytes(payload_len)?;
                             self.decode_state = FrameState::PacketBody(i + 1, frame_header);
                             Ok(FrameOutput::Header(frame_header))
                         } else {
+                            self.decode_state = FrameState::PacketHeader(i + 1, payload_len);
                             Ok(FrameOutput::Skip)
                         }
                     }
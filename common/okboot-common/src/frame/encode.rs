@@ -1,4 +1,5 @@
 use crate::frame::COBS_SENTINEL;
+use crate::{MessageType, COBS_XOR, PREAMBLE_BYTES};
 use core::borrow::BorrowMut;
 use core::fmt::Debug;
 
@@ -17,6 +18,29 @@ pub fn encode_length(length: usize) -> Option<[u8; 4]> {
     }
 }
 
+/// Pass a complete frame to `write`, piece by piece, with `payload` as it is rather than
+/// serialized; for the messages that programs send and receive, since they may not have a
+/// serializer.
+pub fn write_frame(message_type: MessageType, payload: &[u8], mut write: impl FnMut(&[u8])) {
+    let header = u32::to_le_bytes(message_type as u32);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&header);
+    crc.update(payload);
+    let crc = u32::to_le_bytes(crc.finalize());
+
+    write(&PREAMBLE_BYTES);
+    write(&encode_length(payload.len()).expect("payload is too long for a frame"));
+    let mut buf = [0; 255];
+    let mut encoder =
+        FrameEncoder::with_buffer_xor(&mut buf, COBS_XOR).expect("buffer is 255 bytes long");
+    for &byte in header.iter().chain(payload).chain(&crc) {
+        if let EncodeState::Buf(b) = encoder.write_u8(byte) {
+            write(b);
+        }
+    }
+    write(encoder.finish());
+}
+
 #[derive(Debug)]
 pub struct BufferedEncoder<T: BorrowMut<[u8]> + Debug> {
     buffer: T,
@@ -0,0 +1,811 @@
+//! The program's side of the GDB remote serial protocol, for debugging programs over the serial
+//! link: okdude listens for GDB, and passes its packets to the program and the answers back in
+//! [`GdbPacket`](crate::MessageType::GdbPacket) frames.
+//!
+//! okdude takes care of everything about getting packets across — the `$...#xx` framing,
+//! checksums, acknowledgements and escapes — so frames carry packets' bodies just as they are.
+//! Whenever the program halts, it sends the reply for why it stopped, then runs its packets through
+//! an [`Agent`] until one of them resumes it.
+use core::fmt::Write;
+
+/// Registers in the order that GDB numbers them: r0-r12, sp, lr and pc, then cpsr.
+pub const REGISTERS: usize = 17;
+const PC: usize = 15;
+
+/// Longest packet body, in either direction; GDB is told not to send longer ones.
+pub const MAX_PACKET: usize = 1024;
+
+/// How many software breakpoints can be inserted at once.
+pub const SOFTWARE_BREAKPOINTS: usize = 32;
+
+/// `bkpt #0`, in ARM state; what software breakpoints are made of.
+pub const BKPT: u32 = 0xe120_0070;
+
+/// Describes the registers to GDB, so that it needn't guess which ARM it's talking to.
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target><architecture>arm</architecture><feature name="org.gnu.gdb.arm.core">"#,
+    r#"<reg name="r0" bitsize="32"/><reg name="r1" bitsize="32"/>"#,
+    r#"<reg name="r2" bitsize="32"/><reg name="r3" bitsize="32"/>"#,
+    r#"<reg name="r4" bitsize="32"/><reg name="r5" bitsize="32"/>"#,
+    r#"<reg name="r6" bitsize="32"/><reg name="r7" bitsize="32"/>"#,
+    r#"<reg name="r8" bitsize="32"/><reg name="r9" bitsize="32"/>"#,
+    r#"<reg name="r10" bitsize="32"/><reg name="r11" bitsize="32"/>"#,
+    r#"<reg name="r12" bitsize="32"/><reg name="sp" bitsize="32" type="data_ptr"/>"#,
+    r#"<reg name="lr" bitsize="32"/><reg name="pc" bitsize="32" type="code_ptr"/>"#,
+    r#"<reg name="cpsr" bitsize="32"/></feature></target>"#,
+);
+
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Error numbers for `E` replies, which GDB only shows to the user.
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+/// Which accesses a watchpoint catches.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+/// Why the program halted.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Stop {
+    /// Executed a `bkpt` instruction (including a software breakpoint), or finished a step.
+    Trap,
+    /// Reached a hardware breakpoint.
+    Breakpoint,
+    /// Accessed `address`, which a watchpoint is on.
+    Watchpoint { kind: WatchKind, address: u32 },
+    /// Faulted loading, storing or fetching an instruction.
+    Fault,
+}
+
+/// What the program should do once [`Agent::handle`] lets it go.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Resume {
+    Continue,
+    /// Run one instruction, then halt again.
+    Step,
+    /// Run on without GDB: take out the hardware breakpoints and watchpoints, and stop debugging.
+    Detach,
+    Kill,
+}
+
+/// The halted program, as seen by the [`Agent`].
+pub trait Target {
+    /// The program's registers, numbered as described at [`REGISTERS`].
+    fn registers(&mut self) -> [u32; REGISTERS];
+    fn set_registers(&mut self, registers: &[u32; REGISTERS]);
+    /// Fill `buf` from memory at `address`; `false` if any of it can't be read.
+    fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool;
+    /// Write `data` to memory at `address`, making sure that it is what gets executed if it's
+    /// code; `false` if any of it can't be written.
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool;
+    /// Set a hardware breakpoint on the instruction at `address`; `false` if there are none left.
+    fn add_breakpoint(&mut self, address: u32) -> bool;
+    /// `false` if there's no hardware breakpoint at `address`.
+    fn remove_breakpoint(&mut self, address: u32) -> bool;
+    /// Set a watchpoint on the `len` bytes at `address`; `false` if there are none left or they
+    /// can't watch that range.
+    fn add_watchpoint(&mut self, kind: WatchKind, address: u32, len: u32) -> bool;
+    /// `false` if there's no such watchpoint.
+    fn remove_watchpoint(&mut self, kind: WatchKind, address: u32, len: u32) -> bool;
+}
+
+/// A software breakpoint, and the instruction that it replaced.
+#[derive(Debug, Copy, Clone)]
+struct SoftwareBreakpoint {
+    address: u32,
+    original: [u8; 4],
+}
+
+/// Answers GDB's packets about a halted [`Target`].
+#[derive(Debug)]
+pub struct Agent {
+    /// Why the program last halted.
+    stop: Stop,
+    /// Whether GDB understands `swbreak` and `hwbreak` stop reasons.
+    stop_reasons: bool,
+    breakpoints: [Option<SoftwareBreakpoint>; SOFTWARE_BREAKPOINTS],
+}
+impl Default for Agent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Agent {
+    pub const fn new() -> Self {
+        Self {
+            stop: Stop::Trap,
+            stop_reasons: false,
+            breakpoints: [None; SOFTWARE_BREAKPOINTS],
+        }
+    }
+
+    /// The program just halted because of `stop`: pass the reply saying so to `send`, which GDB is
+    /// waiting for if it resumed the program, and which okdude waits for before it passes on any
+    /// packets.
+    pub fn stopped(&mut self, target: &mut impl Target, stop: Stop, mut send: impl FnMut(&[u8])) {
+        self.stop = stop;
+        let mut reply = Reply::new();
+        self.stop_reply(target, &mut reply);
+        send(reply.as_bytes());
+    }
+
+    /// Answer `packet`, passing the reply to `send` in one piece, if it has one. Returns how to
+    /// resume the program, if the packet said to; otherwise the program should stay halted and
+    /// wait for the next packet.
+    pub fn handle(
+        &mut self,
+        target: &mut impl Target,
+        packet: &[u8],
+        mut send: impl FnMut(&[u8]),
+    ) -> Option<Resume> {
+        let mut reply = Reply::new();
+        let resume = self.answer(target, packet, &mut reply);
+        // the reply to resuming is the stop reply, once the program halts again
+        if !matches!(resume, Some(Resume::Continue | Resume::Step | Resume::Kill)) {
+            send(reply.as_bytes());
+        }
+        resume
+    }
+
+    fn answer(
+        &mut self,
+        target: &mut impl Target,
+        packet: &[u8],
+        reply: &mut Reply,
+    ) -> Option<Resume> {
+        let (&command, args) = packet.split_first()?;
+        match command {
+            b'?' => self.stop_reply(target, reply),
+            b'g' => {
+                for register in target.registers() {
+                    reply.hex(&register.to_le_bytes());
+                }
+            }
+            b'G' => reply.result(write_registers(target, args)),
+            b'p' => {
+                let result = read_register(target, args, reply);
+                reply.result(result);
+            }
+            b'P' => reply.result(write_register(target, args)),
+            b'm' => {
+                let result = read_memory(target, args, reply);
+                reply.result(result);
+            }
+            b'M' => reply.result(write_memory(target, args, false)),
+            b'X' => reply.result(write_memory(target, args, true)),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let Some(address) = parse_hex(args) else {
+                        reply.error(EINVAL);
+                        return None;
+                    };
+                    let mut registers = target.registers();
+                    registers[PC] = address;
+                    target.set_registers(&registers);
+                } else {
+                    self.skip_bkpt(target);
+                }
+                return Some(if command == b'c' {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            b'Z' | b'z' => {
+                let result = self.breakpoint(target, command == b'Z', args);
+                reply.result(result);
+            }
+            b'q' => self.query(args, reply),
+            b'H' => reply.ok(),
+            b'D' => {
+                for breakpoint in self.breakpoints.iter_mut() {
+                    if let Some(SoftwareBreakpoint { address, original }) = breakpoint.take() {
+                        target.write_memory(address, &original);
+                    }
+                }
+                reply.ok();
+                return Some(Resume::Detach);
+            }
+            b'k' => return Some(Resume::Kill),
+            // anything else isn't supported, which an empty reply says
+            _ => {}
+        }
+        None
+    }
+
+    fn stop_reply(&self, target: &mut impl Target, reply: &mut Reply) {
+        let signal = match self.stop {
+            Stop::Fault => SIGSEGV,
+            _ => SIGTRAP,
+        };
+        let _ = write!(reply, "T{signal:02x}");
+        match self.stop {
+            Stop::Trap if self.stop_reasons && self.breakpoint_at(target.registers()[PC]) => {
+                reply.push(b"swbreak:;")
+            }
+            Stop::Breakpoint if self.stop_reasons => reply.push(b"hwbreak:;"),
+            Stop::Watchpoint { kind, address } => {
+                let reason = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                let _ = write!(reply, "{reason}:{address:x};");
+            }
+            _ => {}
+        }
+    }
+
+    fn breakpoint_at(&self, address: u32) -> bool {
+        self.breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+    }
+
+    /// Move past a `bkpt` that the program halted on, unless it's a software breakpoint, which GDB
+    /// takes out itself before it resumes; otherwise the program would only halt on it again.
+    fn skip_bkpt(&self, target: &mut impl Target) {
+        if self.stop != Stop::Trap {
+            return;
+        }
+        let mut registers = target.registers();
+        let mut instruction = [0; 4];
+        if !self.breakpoint_at(registers[PC])
+            && target.read_memory(registers[PC], &mut instruction)
+            && u32::from_le_bytes(instruction) == BKPT
+        {
+            registers[PC] = registers[PC].wrapping_add(4);
+            target.set_registers(&registers);
+        }
+    }
+
+    /// `Z` and `z` packets: `TYPE,ADDRESS,KIND`.
+    fn breakpoint(&mut self, target: &mut impl Target, add: bool, args: &[u8]) -> Result<(), u8> {
+        let mut fields = args.split(|&b| b == b',');
+        let kind = fields.next().ok_or(EINVAL)?;
+        let address = fields.next().and_then(parse_hex).ok_or(EINVAL)?;
+        let len = fields.next().and_then(parse_hex).ok_or(EINVAL)?;
+        let done = match (kind, add) {
+            // only ARM state, no Thumb
+            (b"0" | b"1", _) if len != 4 => return Err(EINVAL),
+            (b"0", true) => return self.insert(target, address),
+            (b"0", false) => return self.remove(target, address),
+            (b"1", true) => target.add_breakpoint(address),
+            (b"1", false) => target.remove_breakpoint(address),
+            (b"2" | b"3" | b"4", _) => {
+                let kind = match kind {
+                    b"2" => WatchKind::Write,
+                    b"3" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                if add {
+                    target.add_watchpoint(kind, address, len)
+                } else {
+                    target.remove_watchpoint(kind, address, len)
+                }
+            }
+            _ => return Err(EINVAL),
+        };
+        done.then_some(()).ok_or(ENOSPC)
+    }
+
+    fn insert(&mut self, target: &mut impl Target, address: u32) -> Result<(), u8> {
+        if self.breakpoint_at(address) {
+            return Ok(());
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ENOSPC)?;
+        let mut original = [0; 4];
+        if !target.read_memory(address, &mut original)
+            || !target.write_memory(address, &BKPT.to_le_bytes())
+        {
+            return Err(EFAULT);
+        }
+        *slot = Some(SoftwareBreakpoint { address, original });
+        Ok(())
+    }
+
+    fn remove(&mut self, target: &mut impl Target, address: u32) -> Result<(), u8> {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|breakpoint| breakpoint.address == address))
+            .ok_or(EINVAL)?;
+        let breakpoint = slot.take().expect("slot was just found to be full");
+        target
+            .write_memory(address, &breakpoint.original)
+            .then_some(())
+            .ok_or(EFAULT)
+    }
+
+    fn query(&mut self, args: &[u8], reply: &mut Reply) {
+        if let Some(features) = args.strip_prefix(b"Supported") {
+            self.stop_reasons = features
+                .split(|&b| b == b';' || b == b':')
+                .any(|feature| feature == b"swbreak+");
+            let _ = write!(
+                reply,
+                "PacketSize={MAX_PACKET:x};qXfer:features:read+;swbreak+;hwbreak+"
+            );
+        } else if let Some(range) = args.strip_prefix(b"Xfer:features:read:target.xml:") {
+            let mut fields = range.split(|&b| b == b',');
+            let (Some(offset), Some(len)) = (
+                fields.next().and_then(parse_hex),
+                fields.next().and_then(parse_hex),
+            ) else {
+                reply.error(EINVAL);
+                return;
+            };
+            let rest = TARGET_XML
+                .as_bytes()
+                .get(offset as usize..)
+                .unwrap_or_default();
+            let len = (len as usize).min(MAX_PACKET - 1);
+            if rest.len() > len {
+                reply.push(b"m");
+                reply.push(&rest[..len]);
+            } else {
+                reply.push(b"l");
+                reply.push(rest);
+            }
+        } else if args == b"Attached" {
+            // detaching leaves the program running, rather than killing it
+            reply.push(b"1");
+        }
+    }
+}
+
+/// `p` packets: the register's number.
+fn read_register(target: &mut impl Target, args: &[u8], reply: &mut Reply) -> Result<(), u8> {
+    let n = parse_hex(args).ok_or(EINVAL)? as usize;
+    let register = *target.registers().get(n).ok_or(EINVAL)?;
+    reply.hex(&register.to_le_bytes());
+    Ok(())
+}
+
+/// `P` packets: `N=VALUE`.
+fn write_register(target: &mut impl Target, args: &[u8]) -> Result<(), u8> {
+    let (n, value) = split_once(args, b'=').ok_or(EINVAL)?;
+    let n = parse_hex(n).ok_or(EINVAL)? as usize;
+    let value = u32::from_le_bytes(parse_bytes(value).ok_or(EINVAL)?);
+    let mut registers = target.registers();
+    *registers.get_mut(n).ok_or(EINVAL)? = value;
+    target.set_registers(&registers);
+    Ok(())
+}
+
+/// `G` packets: every register, in order.
+fn write_registers(target: &mut impl Target, args: &[u8]) -> Result<(), u8> {
+    if args.len() != REGISTERS * 8 {
+        return Err(EINVAL);
+    }
+    let mut registers = [0; REGISTERS];
+    for (register, hex) in registers.iter_mut().zip(args.chunks_exact(8)) {
+        *register = u32::from_le_bytes(parse_bytes(hex).ok_or(EINVAL)?);
+    }
+    target.set_registers(&registers);
+    Ok(())
+}
+
+/// `m` packets: `ADDRESS,LEN`.
+fn read_memory(target: &mut impl Target, args: &[u8], reply: &mut Reply) -> Result<(), u8> {
+    let (address, len) = split_once(args, b',').ok_or(EINVAL)?;
+    let address = parse_hex(address).ok_or(EINVAL)?;
+    let len = (parse_hex(len).ok_or(EINVAL)? as usize).min(MAX_PACKET / 2);
+    let mut chunk = [0; 64];
+    for offset in (0..len).step_by(chunk.len()) {
+        let chunk = &mut chunk[..(len - offset).min(64)];
+        if !target.read_memory(address.wrapping_add(offset as u32), chunk) {
+            return Err(EFAULT);
+        }
+        reply.hex(chunk);
+    }
+    Ok(())
+}
+
+/// `M` packets, `ADDRESS,LEN:HEX`, or `X` packets, `ADDRESS,LEN:BYTES`.
+fn write_memory(target: &mut impl Target, args: &[u8], binary: bool) -> Result<(), u8> {
+    let (range, data) = split_once(args, b':').ok_or(EINVAL)?;
+    let (address, len) = split_once(range, b',').ok_or(EINVAL)?;
+    let address = parse_hex(address).ok_or(EINVAL)?;
+    let len = parse_hex(len).ok_or(EINVAL)? as usize;
+    if binary {
+        if data.len() != len {
+            return Err(EINVAL);
+        }
+        return target
+            .write_memory(address, data)
+            .then_some(())
+            .ok_or(EFAULT);
+    }
+    if data.len() != len * 2 {
+        return Err(EINVAL);
+    }
+    let mut chunk = [0; 64];
+    for (i, hex) in data.chunks(chunk.len() * 2).enumerate() {
+        let chunk = &mut chunk[..hex.len() / 2];
+        for (byte, hex) in chunk.iter_mut().zip(hex.chunks_exact(2)) {
+            [*byte] = parse_bytes(hex).ok_or(EINVAL)?;
+        }
+        let address = address.wrapping_add((i * 64) as u32);
+        if !target.write_memory(address, chunk) {
+            return Err(EFAULT);
+        }
+    }
+    Ok(())
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// A number written in hex, most significant digit first, as addresses and lengths are.
+fn parse_hex(hex: &[u8]) -> Option<u32> {
+    if hex.is_empty() || hex.len() > 8 {
+        return None;
+    }
+    hex.iter()
+        .try_fold(0, |n, &digit| Some(n << 4 | hex_digit(digit)? as u32))
+}
+
+/// Bytes written as pairs of hex digits, as memory and registers are.
+fn parse_bytes<const N: usize>(hex: &[u8]) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(bytes)
+}
+
+/// A reply being put together; whatever doesn't fit in [`MAX_PACKET`] is dropped, though nothing
+/// that's asked for should be that long.
+struct Reply {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+impl Reply {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PACKET - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn hex(&mut self, bytes: &[u8]) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for &byte in bytes {
+            self.push(&[DIGITS[byte as usize >> 4], DIGITS[byte as usize & 0xf]]);
+        }
+    }
+
+    fn ok(&mut self) {
+        self.push(b"OK");
+    }
+
+    fn error(&mut self, errno: u8) {
+        let _ = write!(self, "E{errno:02x}");
+    }
+
+    fn result(&mut self, result: Result<(), u8>) {
+        match result {
+            // anything that had to be read is already in the reply
+            Ok(()) if self.len > 0 => {}
+            Ok(()) => self.ok(),
+            Err(errno) => {
+                self.len = 0;
+                self.error(errno);
+            }
+        }
+    }
+}
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    const BASE: u32 = 0x8000;
+
+    /// A program halted with 256 bytes of memory at [`BASE`].
+    struct Mock {
+        registers: [u32; REGISTERS],
+        memory: [u8; 256],
+        breakpoints: Vec<u32>,
+        watchpoints: Vec<(WatchKind, u32, u32)>,
+    }
+    impl Mock {
+        fn new() -> Self {
+            let mut registers = [0; REGISTERS];
+            registers[PC] = BASE;
+            Self {
+                registers,
+                memory: core::array::from_fn(|i| i as u8),
+                breakpoints: Vec::new(),
+                watchpoints: Vec::new(),
+            }
+        }
+
+        fn range(&self, address: u32, len: usize) -> Option<core::ops::Range<usize>> {
+            let start = address.checked_sub(BASE)? as usize;
+            (start + len <= self.memory.len()).then_some(start..start + len)
+        }
+    }
+    impl Target for Mock {
+        fn registers(&mut self) -> [u32; REGISTERS] {
+            self.registers
+        }
+
+        fn set_registers(&mut self, registers: &[u32; REGISTERS]) {
+            self.registers = *registers;
+        }
+
+        fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool {
+            let Some(range) = self.range(address, buf.len()) else {
+                return false;
+            };
+            buf.copy_from_slice(&self.memory[range]);
+            true
+        }
+
+        fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+            let Some(range) = self.range(address, data.len()) else {
+                return false;
+            };
+            self.memory[range].copy_from_slice(data);
+            true
+        }
+
+        fn add_breakpoint(&mut self, address: u32) -> bool {
+            self.breakpoints.push(address);
+            self.breakpoints.len() <= 2
+        }
+
+        fn remove_breakpoint(&mut self, address: u32) -> bool {
+            let len = self.breakpoints.len();
+            self.breakpoints.retain(|&b| b != address);
+            self.breakpoints.len() < len
+        }
+
+        fn add_watchpoint(&mut self, kind: WatchKind, address: u32, len: u32) -> bool {
+            self.watchpoints.push((kind, address, len));
+            true
+        }
+
+        fn remove_watchpoint(&mut self, kind: WatchKind, address: u32, len: u32) -> bool {
+            let watchpoint = (kind, address, len);
+            let len = self.watchpoints.len();
+            self.watchpoints.retain(|&w| w != watchpoint);
+            self.watchpoints.len() < len
+        }
+    }
+
+    /// Have `agent` answer `packet`, and return the reply, if any, and how to resume.
+    fn ask(
+        agent: &mut Agent,
+        target: &mut Mock,
+        packet: &str,
+    ) -> (Option<Vec<u8>>, Option<Resume>) {
+        let mut reply = None;
+        let resume = agent.handle(target, packet.as_bytes(), |bytes| {
+            assert!(reply.is_none(), "replied twice");
+            reply = Some(bytes.to_vec());
+        });
+        (reply, resume)
+    }
+
+    fn reply(agent: &mut Agent, target: &mut Mock, packet: &str) -> String {
+        let (reply, resume) = ask(agent, target, packet);
+        assert_eq!(resume, None, "{packet} resumed the program");
+        String::from_utf8(reply.expect("no reply")).unwrap()
+    }
+
+    /// Test that registers are read and written in GDB's order, little-endian
+    #[test]
+    fn test_registers() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        target.registers[1] = 0x1234_5678;
+        let all = reply(&mut agent, &mut target, "g");
+        assert_eq!(all.len(), REGISTERS * 8);
+        assert_eq!(&all[8..16], "78563412");
+        assert_eq!(&all[PC * 8..PC * 8 + 8], "00800000");
+        assert_eq!(reply(&mut agent, &mut target, "pf"), "00800000");
+        assert_eq!(reply(&mut agent, &mut target, "P10=efbeadde"), "OK");
+        assert_eq!(target.registers[16], 0xdead_beef);
+        assert_eq!(reply(&mut agent, &mut target, "p11"), "E16");
+
+        let mut changed = all.clone();
+        changed.replace_range(0..8, "01000000");
+        assert_eq!(
+            reply(&mut agent, &mut target, &std::format!("G{changed}")),
+            "OK"
+        );
+        assert_eq!(target.registers[0], 1);
+        assert_eq!(reply(&mut agent, &mut target, "G00"), "E16");
+    }
+
+    /// Test that memory is read and written as hex or binary, and that accesses outside of it fail
+    #[test]
+    fn test_memory() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        assert_eq!(reply(&mut agent, &mut target, "m8002,3"), "020304");
+        assert_eq!(reply(&mut agent, &mut target, "m80fe,4"), "E0e");
+        let long = reply(&mut agent, &mut target, "m8000,100");
+        assert_eq!(long.len(), 0x200);
+        assert!(long.ends_with("feff"));
+
+        assert_eq!(reply(&mut agent, &mut target, "M8000,2:aabb"), "OK");
+        assert_eq!(target.memory[..3], [0xaa, 0xbb, 2]);
+        assert_eq!(reply(&mut agent, &mut target, "M8000,2:aa"), "E16");
+        assert_eq!(reply(&mut agent, &mut target, "X8010,3:#$}"), "OK");
+        assert_eq!(target.memory[0x10..0x13], *b"#$}");
+        // GDB probes for binary writes with an empty one
+        assert_eq!(reply(&mut agent, &mut target, "X8000,0:"), "OK");
+        assert_eq!(reply(&mut agent, &mut target, "X9000,1:a"), "E0e");
+    }
+
+    /// Test that software breakpoints replace instructions with `bkpt`, are reported as such, and
+    /// put the instructions back
+    #[test]
+    fn test_software_breakpoints() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        reply(
+            &mut agent,
+            &mut target,
+            "qSupported:multiprocess+;swbreak+;hwbreak+",
+        );
+        let original = target.memory[8..12].to_vec();
+        assert_eq!(reply(&mut agent, &mut target, "Z0,8008,4"), "OK");
+        assert_eq!(target.memory[8..12], BKPT.to_le_bytes());
+        assert_eq!(reply(&mut agent, &mut target, "Z0,8008,2"), "E16");
+
+        assert_eq!(
+            ask(&mut agent, &mut target, "c"),
+            (None, Some(Resume::Continue))
+        );
+        target.registers[PC] = 0x8008;
+        let mut stop = std::vec![];
+        agent.stopped(&mut target, Stop::Trap, |bytes| {
+            stop.extend_from_slice(bytes)
+        });
+        assert_eq!(stop, b"T05swbreak:;");
+
+        assert_eq!(reply(&mut agent, &mut target, "z0,8008,4"), "OK");
+        assert_eq!(target.memory[8..12], original);
+        assert_eq!(reply(&mut agent, &mut target, "z0,8008,4"), "E16");
+    }
+
+    /// Test that a `bkpt` compiled into the program is stepped over on resuming, rather than
+    /// halting on it forever
+    #[test]
+    fn test_skips_compiled_bkpt() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        target.memory[..4].copy_from_slice(&BKPT.to_le_bytes());
+        agent.stopped(&mut target, Stop::Trap, |_| {});
+        assert_eq!(reply(&mut agent, &mut target, "?"), "T05");
+        assert_eq!(
+            ask(&mut agent, &mut target, "s"),
+            (None, Some(Resume::Step))
+        );
+        assert_eq!(target.registers[PC], BASE + 4);
+
+        // but not one that GDB put there
+        reply(&mut agent, &mut target, "Z0,8004,4");
+        agent.stopped(&mut target, Stop::Trap, |_| {});
+        ask(&mut agent, &mut target, "c");
+        assert_eq!(target.registers[PC], BASE + 4);
+
+        assert_eq!(
+            ask(&mut agent, &mut target, "c8020"),
+            (None, Some(Resume::Continue))
+        );
+        assert_eq!(target.registers[PC], 0x8020);
+    }
+
+    /// Test that hardware breakpoints and watchpoints go to the target, and are reported
+    #[test]
+    fn test_hardware() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        assert_eq!(reply(&mut agent, &mut target, "Z1,8010,4"), "OK");
+        assert_eq!(reply(&mut agent, &mut target, "Z1,8014,4"), "OK");
+        assert_eq!(reply(&mut agent, &mut target, "Z1,8018,4"), "E1c");
+        assert_eq!(reply(&mut agent, &mut target, "z1,8010,4"), "OK");
+        assert_eq!(reply(&mut agent, &mut target, "Z3,9000,2"), "OK");
+        assert_eq!(target.watchpoints, [(WatchKind::Read, 0x9000, 2)]);
+        assert_eq!(reply(&mut agent, &mut target, "z2,9000,2"), "E1c");
+        assert_eq!(reply(&mut agent, &mut target, "z3,9000,2"), "OK");
+
+        let mut stop = std::vec![];
+        let watch = Stop::Watchpoint {
+            kind: WatchKind::Access,
+            address: 0x9000,
+        };
+        agent.stopped(&mut target, watch, |bytes| stop.extend_from_slice(bytes));
+        assert_eq!(stop, b"T05awatch:9000;");
+        // GDB didn't say that it understands hwbreak
+        agent.stopped(&mut target, Stop::Breakpoint, |_| {});
+        assert_eq!(reply(&mut agent, &mut target, "?"), "T05");
+        agent.stopped(&mut target, Stop::Fault, |_| {});
+        assert_eq!(reply(&mut agent, &mut target, "?"), "T0b");
+    }
+
+    /// Test that the target description is read in pieces, and that unknown packets get empty
+    /// replies
+    #[test]
+    fn test_queries() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        assert_eq!(
+            reply(&mut agent, &mut target, "qSupported"),
+            "PacketSize=400;qXfer:features:read+;swbreak+;hwbreak+"
+        );
+        let mut xml = String::new();
+        loop {
+            let packet = std::format!("qXfer:features:read:target.xml:{:x},40", xml.len());
+            let piece = reply(&mut agent, &mut target, &packet);
+            xml.push_str(&piece[1..]);
+            if piece.starts_with('l') {
+                break;
+            }
+            assert!(piece.starts_with('m'));
+            assert_eq!(piece.len(), 0x41);
+        }
+        assert_eq!(xml, TARGET_XML);
+        assert_eq!(reply(&mut agent, &mut target, "qAttached"), "1");
+        assert_eq!(reply(&mut agent, &mut target, "vMustReplyEmpty"), "");
+        assert_eq!(reply(&mut agent, &mut target, "Hg0"), "OK");
+        assert_eq!(ask(&mut agent, &mut target, ""), (Some(std::vec![]), None));
+    }
+
+    /// Test that detaching takes out software breakpoints and replies, but killing doesn't reply
+    #[test]
+    fn test_detach_and_kill() {
+        let (mut agent, mut target) = (Agent::new(), Mock::new());
+        let original = target.memory[..4].to_vec();
+        reply(&mut agent, &mut target, "Z0,8000,4");
+        assert_eq!(
+            ask(&mut agent, &mut target, "D"),
+            (Some(b"OK".to_vec()), Some(Resume::Detach))
+        );
+        assert_eq!(target.memory[..4], original);
+        assert_eq!(
+            ask(&mut agent, &mut target, "k"),
+            (None, Some(Resume::Kill))
+        );
+    }
+}
@@ -1,6 +1,11 @@
+use crate::compression::Compression;
+use crate::frame::write_frame;
+use crate::persist::Slot;
 use crate::{EncodeMessageType, MessageType};
 use core::fmt::{Display, Formatter};
-use serde::{Deserialize, Serialize};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 /// Indicates to the device that a host has arrived and that it should broadcast an
 /// [`AllowedVersions`](crate::device::AllowedVersions).
@@ -21,6 +26,53 @@ impl EncodeMessageType for UseVersion {
     const TYPE: MessageType = MessageType::UseVersion;
 }
 
+/// Like [`UseVersion`], but additionally proposes a list of baud rates, in order of preference,
+/// that the host is willing to run the protocol at. The device answers with a
+/// [`BaudRateChoice`](crate::device::BaudRateChoice).
+// Same song-and-dance as [`AllowedVersions`](crate::device::AllowedVersions).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ProposeBaudRates<'a> {
+    pub version: u32,
+    baud_rates: &'a [u8],
+}
+impl<'a> ProposeBaudRates<'a> {
+    pub fn new(version: u32, baud_rates: &'a [u32]) -> Self {
+        Self {
+            version,
+            baud_rates: bytemuck::must_cast_slice(baud_rates),
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.baud_rates
+            .chunks_exact(4)
+            .map(|four_bytes| u32::from_le_bytes(four_bytes.try_into().expect("impossible")))
+    }
+}
+impl EncodeMessageType for ProposeBaudRates<'_> {
+    const TYPE: MessageType = MessageType::ProposeBaudRates;
+}
+
+/// Sent at the newly chosen baud rate to check that both sides can hear each other. The device
+/// echoes `nonce` back in a [`BaudProbeAck`](crate::device::BaudProbeAck).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct BaudProbe {
+    pub nonce: u32,
+}
+impl EncodeMessageType for BaudProbe {
+    const TYPE: MessageType = MessageType::BaudProbe;
+}
+
+/// Asks the device which board it is and what it can load, without starting a handshake; answered
+/// with a [`DeviceInfo`](crate::device::DeviceInfo).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Identify {}
+impl EncodeMessageType for Identify {
+    const TYPE: MessageType = MessageType::Identify;
+}
+
 /// How the device should interpret the data it receives from the host.
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
 #[repr(C)]
@@ -30,6 +82,11 @@ pub enum FormatDetails {
         load_address: u64,
     },
     Elf,
+    /// Pieces loaded at different addresses, laid out as [`segments`](crate::segments)
+    /// describes; the program starts at `entry`.
+    Segments {
+        entry: u64,
+    },
 }
 impl Display for FormatDetails {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
@@ -40,10 +97,55 @@ impl Display for FormatDetails {
             FormatDetails::Elf => {
                 write!(f, "ELF")
             }
+            FormatDetails::Segments { entry } => {
+                write!(f, "SEGMENTS(entry={:08x})", entry)
+            }
+        }
+    }
+}
+impl FormatDetails {
+    fn bit(self) -> u32 {
+        match self {
+            FormatDetails::Bin { .. } => Formats::BIN,
+            FormatDetails::Elf => Formats::ELF,
+            FormatDetails::Segments { .. } => Formats::SEGMENTS,
         }
     }
 }
 
+/// Set of program formats that a device is able to load.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Formats(u32);
+impl Formats {
+    const BIN: u32 = 1 << 0;
+    const ELF: u32 = 1 << 1;
+    const SEGMENTS: u32 = 1 << 2;
+
+    /// Every format that [`FormatDetails`] can describe.
+    pub const fn all() -> Self {
+        Self(Self::BIN | Self::ELF | Self::SEGMENTS)
+    }
+
+    pub fn supports(self, format: FormatDetails) -> bool {
+        self.0 & format.bit() != 0
+    }
+}
+impl Display for Formats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let names = [
+            (Self::BIN, "BIN"),
+            (Self::ELF, "ELF"),
+            (Self::SEGMENTS, "SEGMENTS"),
+        ];
+        let mut names = names.iter().filter(|(bit, _)| self.0 & bit != 0);
+        if let Some((_, name)) = names.next() {
+            write!(f, "{name}")?;
+        }
+        names.try_for_each(|(_, name)| write!(f, ", {name}"))
+    }
+}
+
 /// Metadata that the device needs to know about the program being downloaded.
 #[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 #[repr(C)]
@@ -53,11 +155,36 @@ pub struct Metadata {
     pub inflated_crc: u32,
     pub inflated_len: u32,
     pub format_details: FormatDetails,
+    /// How the program is compressed; one of the codecs that the device listed in its
+    /// [`MetadataReq`](crate::device::MetadataReq).
+    pub compression: Compression,
+    /// Slot to [keep](crate::persist) the program in on the SD card once it has been received and
+    /// verified, before it's booted; `None` to only boot it.
+    pub persist: Option<Slot>,
+    /// The program's signature, for devices that only boot signed programs. It's part of the
+    /// metadata so that it's sent again, and echoed back, along with the rest of it.
+    pub signature: Option<ImageSignature>,
 }
 impl EncodeMessageType for Metadata {
     const TYPE: MessageType = MessageType::Metadata;
 }
 
+/// Sent instead of [`Metadata`] to upload the program as a [delta](crate::delta) against the
+/// [`BaseImage`](crate::device::BaseImage) whose hash is `base`.
+///
+/// `deflated_crc` and `deflated_len` describe the compressed delta, while `inflated_crc` and
+/// `inflated_len` still describe the program itself, once reconstructed. The device acknowledges
+/// it with the same [`MetadataAck`](crate::device::MetadataAck) as a [`Metadata`].
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct DeltaMetadata {
+    pub base: [u8; 32],
+    pub metadata: Metadata,
+}
+impl EncodeMessageType for DeltaMetadata {
+    const TYPE: MessageType = MessageType::DeltaMetadata;
+}
+
 /// Signals that the [`MetadataAck`](crate::device::MetadataAck) was received, and additionally
 /// indicates whether the `MetadataAck` was correct.
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,6 +196,70 @@ impl EncodeMessageType for MetadataAckAck {
     const TYPE: MessageType = MessageType::MetadataAckAck;
 }
 
+/// Ed25519 signature of the program being uploaded, as sent in its [`Metadata`].
+///
+/// The signature is Ed25519ph (RFC 8032) with context [`ImageSignature::CONTEXT`], over the
+/// SHA-512 digest of the postcard-serialized [`FormatDetails`] followed by the inflated program.
+/// Devices built with a verifying key refuse to boot programs without a valid signature.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ImageSignature(pub [u8; ImageSignature::SIGNATURE_LEN]);
+impl ImageSignature {
+    pub const CONTEXT: &'static [u8] = b"okboot-image-signature";
+    pub const SIGNATURE_LEN: usize = 64;
+}
+impl core::fmt::Debug for ImageSignature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "ImageSignature(")?;
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))?;
+        write!(f, ")")
+    }
+}
+// serde only knows arrays of up to 32 elements
+impl Serialize for ImageSignature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(Self::SIGNATURE_LEN)?;
+        self.0
+            .iter()
+            .try_for_each(|byte| tuple.serialize_element(byte))?;
+        tuple.end()
+    }
+}
+impl<'de> Deserialize<'de> for ImageSignature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Bytes;
+        impl<'de> Visitor<'de> for Bytes {
+            type Value = ImageSignature;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                write!(f, "{} bytes", ImageSignature::SIGNATURE_LEN)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut signature = [0; ImageSignature::SIGNATURE_LEN];
+                for (i, byte) in signature.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                Ok(ImageSignature(signature))
+            }
+        }
+        deserializer.deserialize_tuple(Self::SIGNATURE_LEN, Bytes)
+    }
+}
+
+/// Answer to [`Resume`](crate::device::Resume): whether the offered transfer is of the program
+/// the host is uploading. If not, the device discards it and sends a
+/// [`MetadataReq`](crate::device::MetadataReq) instead.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ResumeAck {
+    pub is_ok: bool,
+}
+impl EncodeMessageType for ResumeAck {
+    const TYPE: MessageType = MessageType::ResumeAck;
+}
+
 /// A chunk of program data that is being uploaded to the device.
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(C)]
@@ -87,3 +278,105 @@ pub struct BootingAck {}
 impl EncodeMessageType for BootingAck {
     const TYPE: MessageType = MessageType::BootingAck;
 }
+
+/// Asks okboot, while it waits for a handshake, for the `len` bytes at `address`; answered with a
+/// [`MemData`](crate::device::MemData), or with a [`MemRefused`](crate::device::MemRefused) if
+/// the [`MemoryMap`](crate::monitor::MemoryMap) doesn't allow it or it's longer than
+/// [`MAX_CHUNK`](crate::monitor::MAX_CHUNK).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemRead {
+    pub address: u32,
+    pub len: u32,
+}
+impl EncodeMessageType for MemRead {
+    const TYPE: MessageType = MessageType::MemRead;
+}
+
+/// Like [`MemRead`], but writes `bytes` to `address`; answered with the
+/// [`MemCrcResult`](crate::device::MemCrcResult) of what's there afterwards.
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemWrite<'a> {
+    pub address: u32,
+    pub bytes: &'a [u8],
+}
+impl EncodeMessageType for MemWrite<'_> {
+    const TYPE: MessageType = MessageType::MemWrite;
+}
+
+/// Like [`MemRead`], but for the CRC of the `len` bytes at `address`, which can be any length;
+/// answered with a [`MemCrcResult`](crate::device::MemCrcResult).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemCrc {
+    pub address: u32,
+    pub len: u32,
+}
+impl EncodeMessageType for MemCrc {
+    const TYPE: MessageType = MessageType::MemCrc;
+}
+
+/// Has okboot jump to `address`, as if it had booted a program there; answered with a
+/// [`Jumping`](crate::device::Jumping) just before it does, or a
+/// [`MemRefused`](crate::device::MemRefused).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Jump {
+    pub address: u32,
+}
+impl EncodeMessageType for Jump {
+    const TYPE: MessageType = MessageType::Jump;
+}
+
+/// Asks okboot, while it waits for a handshake, which images it keeps on the SD card; answered
+/// with a [`StoredImage`](crate::device::StoredImage) for each, then an
+/// [`ImagesListed`](crate::device::ImagesListed), or a
+/// [`StorageFailed`](crate::device::StorageFailed).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ListImages {}
+impl EncodeMessageType for ListImages {
+    const TYPE: MessageType = MessageType::ListImages;
+}
+
+/// Like [`ListImages`], but deletes the image kept in `slot`; answered with an
+/// [`ImageDeleted`](crate::device::ImageDeleted), or a
+/// [`StorageFailed`](crate::device::StorageFailed).
+#[derive(Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct DeleteImage {
+    pub slot: Slot,
+}
+impl EncodeMessageType for DeleteImage {
+    const TYPE: MessageType = MessageType::DeleteImage;
+}
+
+/// Asks the booted program, rather than okboot, to reboot the device, so that okboot can take
+/// another upload.
+///
+/// There's no payload, so the frame is always the same [`Reboot::FRAME_LEN`] bytes; programs can
+/// look out for them without decoding frames.
+#[derive(Debug, Copy, Clone)]
+pub struct Reboot;
+impl Reboot {
+    /// Length of the frame for this message.
+    pub const FRAME_LEN: usize = 18;
+
+    /// Pass the frame for this message to `write`, piece by piece.
+    pub fn frame(&self, write: impl FnMut(&[u8])) {
+        write_frame(MessageType::Reboot, &[], write);
+    }
+
+    /// The frame for this message.
+    pub fn frame_bytes(&self) -> [u8; Self::FRAME_LEN] {
+        let mut bytes = [0; Self::FRAME_LEN];
+        let mut len = 0;
+        self.frame(|b| {
+            bytes[len..len + b.len()].copy_from_slice(b);
+            len += b.len();
+        });
+        assert_eq!(len, Self::FRAME_LEN);
+        bytes
+    }
+}
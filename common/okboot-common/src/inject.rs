@@ -0,0 +1,144 @@
+//! How okdude lays out what it injects into sections of ELF programs at upload time.
+//!
+//! A program reserves a section for each thing that it wants injected, and okdude fills it in just
+//! before uploading. Everything is little-endian, and a section that okdude left alone is all
+//! zeroes, which reads as an empty list or an empty blob.
+//!
+//! - [`ARGS`] and [`ENV`] are lists: a `u32` count, then each entry as a `u32` length followed by
+//!   its bytes, padded with zeroes to a multiple of 4. Environment entries are `KEY=VALUE`.
+//! - [`BUILD_ID`], [`BOOT_TIME`] and sections that files are injected into are blobs: a `u32`
+//!   length followed by that many bytes. The boot time is a `u64` count of seconds since the Unix
+//!   epoch.
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+/// Command-line arguments.
+pub const ARGS: &str = ".data.args";
+/// Environment variables, as `KEY=VALUE`.
+pub const ENV: &str = ".data.env";
+/// Identifies the build that the program came from, as text.
+pub const BUILD_ID: &str = ".data.build_id";
+/// When the program was uploaded.
+pub const BOOT_TIME: &str = ".data.boot_time";
+
+/// Entries of a list section, until they run out or the section does.
+#[derive(Debug, Clone)]
+pub struct List<'a> {
+    rest: &'a [u8],
+    remaining: u32,
+}
+impl<'a> List<'a> {
+    pub fn new(section: &'a [u8]) -> Self {
+        match read_u32(section) {
+            Some(count) => Self {
+                rest: &section[4..],
+                remaining: count,
+            },
+            None => Self {
+                rest: &[],
+                remaining: 0,
+            },
+        }
+    }
+}
+impl<'a> Iterator for List<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let entry = blob(self.rest);
+        let Some(entry) = entry else {
+            // truncated: the rest of the list is lost
+            self.remaining = 0;
+            return None;
+        };
+        let padded = 4 + entry.len().next_multiple_of(4);
+        self.rest = self.rest.get(padded..).unwrap_or_default();
+        Some(entry)
+    }
+}
+
+/// The contents of a blob section; `None` if the section is too short to hold what it says it
+/// does.
+pub fn blob(section: &[u8]) -> Option<&[u8]> {
+    let len = read_u32(section)? as usize;
+    section.get(4..4usize.checked_add(len)?)
+}
+
+/// The boot time in a [`BOOT_TIME`] section, if okdude filled it in.
+pub fn boot_time(section: &[u8]) -> Option<u64> {
+    let bytes = blob(section)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+/// A list section holding `entries`.
+#[cfg(feature = "alloc")]
+pub fn encode_list<'a>(entries: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut section = alloc::vec![0; 4];
+    let mut count = 0u32;
+    for entry in entries {
+        section.extend_from_slice(&encode_blob(entry));
+        section.resize(section.len().next_multiple_of(4), 0);
+        count += 1;
+    }
+    section[..4].copy_from_slice(&count.to_le_bytes());
+    section
+}
+
+/// A blob section holding `bytes`.
+#[cfg(feature = "alloc")]
+pub fn encode_blob(bytes: &[u8]) -> Vec<u8> {
+    let mut section = Vec::with_capacity(4 + bytes.len());
+    section.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    section.extend_from_slice(bytes);
+    section
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Test that lists read back as they were written, whatever the lengths of their entries.
+    #[test]
+    fn test_list_round_trip() {
+        let entries: [&[u8]; 4] = [b"hello", b"", b"four", b"KEY=VALUE"];
+        let section = encode_list(entries);
+        assert_eq!(section.len() % 4, 0);
+        assert_eq!(
+            &section[..12],
+            [4, 0, 0, 0, 5, 0, 0, 0, b'h', b'e', b'l', b'l']
+        );
+        assert_eq!(List::new(&section).collect::<Vec<_>>(), entries);
+    }
+
+    /// Test that sections okdude left alone read as empty, and truncated ones don't read past
+    /// their end.
+    #[test]
+    fn test_untouched_and_truncated() {
+        assert_eq!(List::new(&[0; 16]).count(), 0);
+        assert_eq!(blob(&[0; 16]), Some(&[][..]));
+        assert_eq!(boot_time(&[0; 16]), None);
+
+        let section = encode_list([&b"hello"[..], b"world"]);
+        let truncated = &section[..section.len() - 4];
+        assert_eq!(List::new(truncated).collect::<Vec<_>>(), vec![b"hello"]);
+        assert_eq!(List::new(&[]).count(), 0);
+        assert_eq!(blob(&encode_blob(b"id")[..5]), None);
+    }
+
+    /// Test that the boot time reads back.
+    #[test]
+    fn test_boot_time() {
+        let section = encode_blob(&1_700_000_000u64.to_le_bytes());
+        assert_eq!(boot_time(&section), Some(1_700_000_000));
+    }
+}
@@ -2,6 +2,8 @@
 #![feature(core_intrinsics)]
 #![cfg_attr(not(feature = "std"), no_std)]
 //! Common structures shared by the `okboot` and `okdude` crates.
+#[cfg(feature = "alloc")]
+extern crate alloc;
 #[cfg(test)]
 extern crate std;
 
@@ -16,12 +18,33 @@ pub const COBS_XOR: u8 = 0x55;
 /// Message preamble, shortened from Ethernet.
 pub const PREAMBLE_BYTES: [u8; 4] = [0x55, 0x55, 0x55, 0x5e];
 
+/// Codecs that programs can be compressed with for upload.
+pub mod compression;
+/// Binary deltas between program images, for uploading only what changed.
+pub mod delta;
 /// Message structures sent from the device.
 pub mod device;
+/// Checks that ELF files are ones that okboot can load, shared by both sides.
+pub mod executable;
+/// Minimal FAT32, for keeping images on the SD card's boot partition.
+pub mod fat;
 /// Frame encoding and decoding, for both sides.
 pub mod frame;
+/// Debugging programs with GDB over the serial link.
+pub mod gdb;
 /// Message structure sent from the host.
 pub mod host;
+/// Layout of the sections that okdude fills in when it uploads a program.
+pub mod inject;
+/// `log` crate backend that queues records to be sent as [`Log`](device::Log) messages.
+#[cfg(feature = "log")]
+pub mod logger;
+/// Reading and writing the device's memory before a program is uploaded.
+pub mod monitor;
+/// Images kept on the SD card, to be booted again after a power cycle.
+pub mod persist;
+/// Layout of programs sent as separate segments, each loaded at its own address.
+pub mod segments;
 
 pub trait EncodeMessageType {
     const TYPE: MessageType;
@@ -33,12 +56,30 @@ pub trait EncodeMessageType {
 pub enum MessageType {
     /// Corresponds to an unserialized UTF-8 string type.
     PrintString = 101,
+    /// Corresponds to [`Log`](device::Log)
+    Log = 102,
+    /// Corresponds to [`Exit`](device::Exit)
+    Exit = 103,
+    /// Corresponds to [`Reboot`](host::Reboot)
+    Reboot = 104,
     /// Corresponds to [`Probe`](host::Probe)
     Probe = 201,
     /// Corresponds to [`AllowedVersions`](device::AllowedVersions).
     AllowedVersions = 202,
     /// Corresponds to [`UseVersion`](host::UseVersion)
     UseVersion = 203,
+    /// Corresponds to [`ProposeBaudRates`](host::ProposeBaudRates)
+    ProposeBaudRates = 204,
+    /// Corresponds to [`BaudRateChoice`](device::BaudRateChoice)
+    BaudRateChoice = 205,
+    /// Corresponds to [`BaudProbe`](host::BaudProbe)
+    BaudProbe = 206,
+    /// Corresponds to [`BaudProbeAck`](device::BaudProbeAck)
+    BaudProbeAck = 207,
+    /// Corresponds to [`Identify`](host::Identify)
+    Identify = 208,
+    /// Corresponds to [`DeviceInfo`](device::DeviceInfo)
+    DeviceInfo = 209,
     /// Corresponds to [`MetadataReq`](device::MetadataReq)
     MetadataReq = 301,
     /// Corresponds to [`Metadata`](host::Metadata)
@@ -47,14 +88,63 @@ pub enum MessageType {
     MetadataAck = 303,
     /// Corresponds to [`MetadataAckAck`](host::MetadataAckAck)
     MetadataAckAck = 304,
+    /// Corresponds to [`PipelinedMetadataAck`](device::PipelinedMetadataAck)
+    PipelinedMetadataAck = 305,
+    // 306 was ImageSignature, which is now sent in the Metadata
+    /// Corresponds to [`Resume`](device::Resume)
+    Resume = 307,
+    /// Corresponds to [`ResumeAck`](host::ResumeAck)
+    ResumeAck = 308,
+    /// Corresponds to [`BaseImage`](device::BaseImage)
+    BaseImage = 309,
+    /// Corresponds to [`DeltaMetadata`](host::DeltaMetadata)
+    DeltaMetadata = 310,
     /// Corresponds to [`ChunkReq`](device::ChunkReq`)
     ChunkReq = 401,
     /// Corresponds to [`Chunk`](host::Chunk)
     Chunk = 402,
+    /// Corresponds to [`ChunkAck`](device::ChunkAck)
+    ChunkAck = 403,
     /// Corresponds to [`Booting`](device::Booting)
     Booting = 501,
     /// Corresponds to [`BootingAck`](host::BootingAck)
     BootingAck = 502,
+    /// Corresponds to [`BootRefused`](device::BootRefused)
+    BootRefused = 503,
+    /// A GDB remote protocol packet's body, to or from a program's [`Agent`](gdb::Agent).
+    GdbPacket = 601,
+    /// Corresponds to [`MemRead`](host::MemRead)
+    MemRead = 701,
+    /// Corresponds to [`MemData`](device::MemData)
+    MemData = 702,
+    /// Corresponds to [`MemWrite`](host::MemWrite)
+    MemWrite = 703,
+    /// Corresponds to [`MemCrc`](host::MemCrc)
+    MemCrc = 704,
+    /// Corresponds to [`MemCrcResult`](device::MemCrcResult)
+    MemCrcResult = 705,
+    /// Corresponds to [`Jump`](host::Jump)
+    Jump = 706,
+    /// Corresponds to [`Jumping`](device::Jumping)
+    Jumping = 707,
+    /// Corresponds to [`MemRefused`](device::MemRefused)
+    MemRefused = 708,
+    /// Corresponds to [`ListImages`](host::ListImages)
+    ListImages = 801,
+    /// Corresponds to [`StoredImage`](device::StoredImage)
+    StoredImage = 802,
+    /// Corresponds to [`ImagesListed`](device::ImagesListed)
+    ImagesListed = 803,
+    /// Corresponds to [`DeleteImage`](host::DeleteImage)
+    DeleteImage = 804,
+    /// Corresponds to [`ImageDeleted`](device::ImageDeleted)
+    ImageDeleted = 805,
+    /// Corresponds to [`StorageFailed`](device::StorageFailed)
+    StorageFailed = 806,
+    /// Corresponds to [`Persisting`](device::Persisting)
+    Persisting = 807,
+    /// Corresponds to [`Persisted`](device::Persisted)
+    Persisted = 808,
 }
 impl From<MessageType> for u32 {
     fn from(val: MessageType) -> u32 {
@@ -67,17 +157,50 @@ impl TryFrom<u32> for MessageType {
     fn try_from(value: u32) -> Result<Self, Self::Error> {
         Ok(match value {
             101 => Self::PrintString,
+            102 => Self::Log,
+            103 => Self::Exit,
+            104 => Self::Reboot,
             201 => Self::Probe,
             202 => Self::AllowedVersions,
             203 => Self::UseVersion,
+            204 => Self::ProposeBaudRates,
+            205 => Self::BaudRateChoice,
+            206 => Self::BaudProbe,
+            207 => Self::BaudProbeAck,
+            208 => Self::Identify,
+            209 => Self::DeviceInfo,
             301 => Self::MetadataReq,
             302 => Self::Metadata,
             303 => Self::MetadataAck,
             304 => Self::MetadataAckAck,
+            305 => Self::PipelinedMetadataAck,
+            307 => Self::Resume,
+            308 => Self::ResumeAck,
+            309 => Self::BaseImage,
+            310 => Self::DeltaMetadata,
             401 => Self::ChunkReq,
             402 => Self::Chunk,
+            403 => Self::ChunkAck,
             501 => Self::Booting,
             502 => Self::BootingAck,
+            503 => Self::BootRefused,
+            601 => Self::GdbPacket,
+            701 => Self::MemRead,
+            702 => Self::MemData,
+            703 => Self::MemWrite,
+            704 => Self::MemCrc,
+            705 => Self::MemCrcResult,
+            706 => Self::Jump,
+            707 => Self::Jumping,
+            708 => Self::MemRefused,
+            801 => Self::ListImages,
+            802 => Self::StoredImage,
+            803 => Self::ImagesListed,
+            804 => Self::DeleteImage,
+            805 => Self::ImageDeleted,
+            806 => Self::StorageFailed,
+            807 => Self::Persisting,
+            808 => Self::Persisted,
             _ => return Err(()),
         })
     }
@@ -109,6 +232,9 @@ pub mod su_boot {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum SupportedProtocol {
     V2 = 2,
+    /// Like [`V2`](SupportedProtocol::V2), but the host streams a window of chunks ahead of the
+    /// device's acknowledgements instead of waiting for a [`ChunkReq`](device::ChunkReq) per chunk.
+    V3 = 3,
 }
 impl TryFrom<u32> for SupportedProtocol {
     type Error = u32;
@@ -116,14 +242,17 @@ impl TryFrom<u32> for SupportedProtocol {
     fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
             2 => Ok(Self::V2),
+            3 => Ok(Self::V3),
             _ => Err(value),
         }
     }
 }
 impl SupportedProtocol {
+    /// Baud rate used when the host selects the protocol with [`UseVersion`](host::UseVersion)
+    /// instead of negotiating one with [`ProposeBaudRates`](host::ProposeBaudRates).
     pub fn baud_rate(self) -> u32 {
         match self {
-            SupportedProtocol::V2 => 1_500_000,
+            SupportedProtocol::V2 | SupportedProtocol::V3 => 1_500_000,
         }
     }
 }
@@ -0,0 +1,236 @@
+use crate::device::{Log, LogLevel};
+use alloc::string::String;
+use core::cell::RefCell;
+use critical_section::Mutex;
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Self::Error,
+            log::Level::Warn => Self::Warn,
+            log::Level::Info => Self::Info,
+            log::Level::Debug => Self::Debug,
+            log::Level::Trace => Self::Trace,
+        }
+    }
+}
+
+struct Record {
+    level: LogLevel,
+    timestamp_us: u64,
+    target: String,
+    message: String,
+}
+
+// NOTE: the critical section may well be the same lock that the global allocator uses, so nothing
+//       may be allocated or freed while it is held; records are only ever moved in and out of
+//       `slots`, and dropped outside of it.
+struct Inner<const N: usize> {
+    slots: [Option<Record>; N],
+    /// Index of the oldest record.
+    head: usize,
+    len: usize,
+    /// Number of records discarded because the queue was full.
+    dropped: usize,
+    clock: fn() -> u64,
+}
+
+impl<const N: usize> Inner<N> {
+    fn push_back(&mut self, record: Record) -> Option<Record> {
+        let evicted = if self.len == N {
+            self.dropped += 1;
+            self.pop_front()
+        } else {
+            None
+        };
+        let tail = (self.head + self.len) % N;
+        self.slots[tail] = Some(record);
+        self.len += 1;
+        evicted
+    }
+
+    fn pop_front(&mut self) -> Option<Record> {
+        if self.len == 0 {
+            return None;
+        }
+        let record = self.slots[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        record
+    }
+
+    /// Put back a record that was just popped; it's dropped instead if the queue filled up since.
+    fn push_front(&mut self, record: Record) -> Option<Record> {
+        if self.len == N {
+            self.dropped += 1;
+            return Some(record);
+        }
+        self.head = (self.head + N - 1) % N;
+        self.slots[self.head] = Some(record);
+        self.len += 1;
+        None
+    }
+}
+
+/// [`log::Log`] implementation that keeps up to `N` records in memory until whoever owns the
+/// transmit path gets around to sending them with [`drain`](LogQueue::drain). If the queue fills
+/// up, the oldest records are discarded.
+///
+/// Intended to be used as a `static`, and registered with [`log::set_logger`].
+pub struct LogQueue<const N: usize> {
+    inner: Mutex<RefCell<Inner<N>>>,
+}
+
+impl<const N: usize> LogQueue<N> {
+    pub const fn new() -> Self {
+        assert!(N > 0, "LogQueue must have room for at least one record");
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                slots: [const { None }; N],
+                head: 0,
+                len: 0,
+                dropped: 0,
+                clock: || 0,
+            })),
+        }
+    }
+
+    /// Set the source of record timestamps, in microseconds.
+    pub fn set_clock(&self, clock: fn() -> u64) {
+        critical_section::with(|cs| self.inner.borrow_ref_mut(cs).clock = clock);
+    }
+
+    /// Pass queued records to `send`, oldest first, until either the queue is empty or `send`
+    /// returns `false`; in the latter case, the rejected record stays at the front of the queue.
+    pub fn drain(&self, mut send: impl FnMut(&Log) -> bool) {
+        loop {
+            let (dropped, record, now) = critical_section::with(|cs| {
+                let mut inner = self.inner.borrow_ref_mut(cs);
+                let dropped = core::mem::take(&mut inner.dropped);
+                let record = inner.pop_front();
+                (dropped, record, (inner.clock)())
+            });
+            if dropped > 0 {
+                let message = alloc::format!("log queue full, dropped {dropped} records");
+                let sent = send(&Log {
+                    level: LogLevel::Warn,
+                    timestamp_us: now,
+                    target: module_path!(),
+                    message: &message,
+                });
+                if !sent {
+                    let _discarded = critical_section::with(|cs| {
+                        let mut inner = self.inner.borrow_ref_mut(cs);
+                        inner.dropped += dropped;
+                        record.and_then(|record| inner.push_front(record))
+                    });
+                    return;
+                }
+            }
+            let Some(record) = record else {
+                return;
+            };
+            let sent = send(&Log {
+                level: record.level,
+                timestamp_us: record.timestamp_us,
+                target: &record.target,
+                message: &record.message,
+            });
+            if !sent {
+                let _discarded =
+                    critical_section::with(|cs| self.inner.borrow_ref_mut(cs).push_front(record));
+                return;
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for LogQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> log::Log for LogQueue<N> {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        // filtering is up to `log::set_max_level` and the host
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = alloc::format!("{}", record.args());
+        let target = String::from(record.target());
+        let _evicted = critical_section::with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            let timestamp_us = (inner.clock)();
+            inner.push_back(Record {
+                level: record.level().into(),
+                timestamp_us,
+                target,
+                message,
+            })
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use std::vec;
+
+    fn log(queue: &impl log::Log, message: &str) {
+        queue.log(
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .target("test")
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    fn drain_all<const N: usize>(queue: &LogQueue<N>) -> Vec<String> {
+        let mut out = vec![];
+        queue.drain(|log| {
+            out.push(log.message.to_string());
+            true
+        });
+        out
+    }
+
+    /// Test that the oldest records are dropped (and reported) when the queue overflows
+    #[test]
+    fn test_overflow() {
+        let queue = LogQueue::<3>::new();
+        for i in 0..5 {
+            log(&queue, &i.to_string());
+        }
+        assert_eq!(
+            drain_all(&queue),
+            ["log queue full, dropped 2 records", "2", "3", "4"]
+        );
+        assert!(drain_all(&queue).is_empty());
+    }
+
+    /// Test that a record rejected by `send` is kept, in order, for the next drain
+    #[test]
+    fn test_drain_retry() {
+        let queue = LogQueue::<4>::new();
+        for i in 0..3 {
+            log(&queue, &i.to_string());
+        }
+        let mut sent = vec![];
+        queue.drain(|log| {
+            if sent.len() == 1 {
+                return false;
+            }
+            sent.push(log.message.to_string());
+            true
+        });
+        assert_eq!(sent, ["0"]);
+        log(&queue, "3");
+        assert_eq!(drain_all(&queue), ["1", "2", "3"]);
+    }
+}
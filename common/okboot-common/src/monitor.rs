@@ -0,0 +1,250 @@
+//! Reading and writing the device's memory while okboot waits for a handshake, and jumping into
+//! it: okdude sends a [`MemRead`](crate::host::MemRead), [`MemWrite`](crate::host::MemWrite),
+//! [`MemCrc`](crate::host::MemCrc) or [`Jump`](crate::host::Jump), and okboot checks it against
+//! the [`MemoryMap`] in its [`DeviceInfo`](crate::device::DeviceInfo) before it does anything.
+use core::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Most bytes that a single [`MemRead`](crate::host::MemRead) or
+/// [`MemWrite`](crate::host::MemWrite) can cover; longer ranges are split up by okdude.
+pub const MAX_CHUNK: u32 = 0x1000;
+
+/// Addresses from `start` up to (but not including) `end`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Region {
+    pub start: u32,
+    pub end: u32,
+}
+impl Region {
+    /// No addresses at all, for what a device doesn't have.
+    pub const EMPTY: Self = Self::new(0, 0);
+
+    pub const fn new(start: u32, end: u32) -> Self {
+        Self { start, end }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Whether all of `start..end` is in this region.
+    fn contains(&self, start: u64, end: u64) -> bool {
+        self.start as u64 <= start && end <= self.end as u64
+    }
+
+    /// Whether any of `start..end` is in this region.
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end as u64 && (self.start as u64) < end
+    }
+}
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#010x}..{:#010x}", self.start, self.end)
+    }
+}
+
+/// The device's physical address space, as far as the monitor is concerned.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MemoryMap {
+    /// RAM that the ARM core gets.
+    pub ram: Region,
+    /// Peripherals' registers, which are read and written a word at a time.
+    pub peripherals: Region,
+    /// okboot's own image, stack included.
+    pub okboot: Region,
+    /// okboot's heap.
+    pub heap: Region,
+    /// Registers of the UART that okboot talks to okdude through; even reading them gets in the
+    /// way, since reading the data register takes a byte out of the FIFO.
+    pub uart: Region,
+}
+impl MemoryMap {
+    /// Check that the `len` bytes at `address` can be accessed as `access` says, returning which
+    /// kind of memory they are. They have to be all RAM, or all peripheral registers and whole
+    /// words of them, and clear of the [`uart`](Self::uart); unless they're only being read, they
+    /// have to be clear of the rest of okboot too. A jump is checked as the (aligned) word at
+    /// `address`, which has to be RAM.
+    pub fn check(&self, access: Access, address: u32, len: u32) -> Result<Space, Refusal> {
+        let len = match access {
+            Access::Jump => 4,
+            Access::Read | Access::Write => len,
+        };
+        let (start, end) = (address as u64, address as u64 + len as u64);
+        let space = if self.ram.contains(start, end) {
+            Space::Ram
+        } else if self.peripherals.contains(start, end) && access != Access::Jump {
+            Space::Peripherals
+        } else {
+            return Err(Refusal::Unmapped { address, len });
+        };
+        if (space == Space::Peripherals || access == Access::Jump) && (address | len) & 3 != 0 {
+            return Err(Refusal::Unaligned { address, len });
+        }
+        let mut in_use = [
+            (InUse::Uart, self.uart),
+            (InUse::Okboot, self.okboot),
+            (InUse::Heap, self.heap),
+        ]
+        .into_iter()
+        .filter(|(_, region)| region.overlaps(start, end))
+        .map(|(region, _)| region);
+        match in_use.next() {
+            Some(InUse::Uart) => Err(Refusal::InUse {
+                address,
+                len,
+                region: InUse::Uart,
+            }),
+            Some(region) if access != Access::Read => Err(Refusal::InUse {
+                address,
+                len,
+                region,
+            }),
+            _ => Ok(space),
+        }
+    }
+}
+
+/// What the monitor is asked to do with some memory.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Jump,
+}
+
+/// Which kind of memory an access is to, as [`MemoryMap::check`] found.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Space {
+    Ram,
+    Peripherals,
+}
+
+/// Part of the [`MemoryMap`] that okboot needs for itself.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub enum InUse {
+    Okboot,
+    Heap,
+    Uart,
+}
+impl Display for InUse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            InUse::Okboot => "okboot's image",
+            InUse::Heap => "okboot's heap",
+            InUse::Uart => "the UART that okboot talks to okdude through",
+        })
+    }
+}
+
+/// Why the monitor won't touch some memory, as sent in a
+/// [`MemRefused`](crate::device::MemRefused).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error, Serialize, Deserialize)]
+#[repr(C)]
+pub enum Refusal {
+    #[error("{len} bytes at {address:#x} aren't all RAM, or all peripheral registers")]
+    Unmapped { address: u32, len: u32 },
+    #[error("{len} bytes at {address:#x} aren't whole, aligned words")]
+    Unaligned { address: u32, len: u32 },
+    #[error("{len} bytes at {address:#x} overlap {region}")]
+    InUse {
+        address: u32,
+        len: u32,
+        region: InUse,
+    },
+    #[error("{len} bytes is more than the {max} that fit in one message", max = MAX_CHUNK)]
+    TooLong { len: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> MemoryMap {
+        MemoryMap {
+            ram: Region::new(0, 0x1c00_0000),
+            peripherals: Region::new(0x2000_0000, 0x2100_0000),
+            okboot: Region::new(0x8000, 0x10_0000),
+            heap: Region::new(0x1000_0000, 0x1800_0000),
+            uart: Region::new(0x2021_5000, 0x2021_5100),
+        }
+    }
+
+    /// Test that anything but the UART can be read, as long as it exists
+    #[test]
+    fn test_check_read() {
+        let map = map();
+        assert_eq!(map.check(Access::Read, 0x8000, 0x100), Ok(Space::Ram));
+        assert_eq!(map.check(Access::Read, 0x1000_0000, 4), Ok(Space::Ram));
+        assert_eq!(
+            map.check(Access::Read, 0x2020_0000, 0x40),
+            Ok(Space::Peripherals)
+        );
+        assert_eq!(
+            map.check(Access::Read, 0x2020_0002, 4),
+            Err(Refusal::Unaligned {
+                address: 0x2020_0002,
+                len: 4
+            })
+        );
+        assert_eq!(
+            map.check(Access::Read, 0x1bff_fff0, 0x20),
+            Err(Refusal::Unmapped {
+                address: 0x1bff_fff0,
+                len: 0x20
+            })
+        );
+        assert_eq!(
+            map.check(Access::Read, 0xffff_fffc, 8),
+            Err(Refusal::Unmapped {
+                address: 0xffff_fffc,
+                len: 8
+            })
+        );
+        assert_eq!(
+            map.check(Access::Read, 0x2021_5040, 4),
+            Err(Refusal::InUse {
+                address: 0x2021_5040,
+                len: 4,
+                region: InUse::Uart
+            })
+        );
+    }
+
+    /// Test that okboot's image and heap can't be written, or jumped into
+    #[test]
+    fn test_check_write_and_jump() {
+        let map = map();
+        assert_eq!(map.check(Access::Write, 0x10_0000, 0x100), Ok(Space::Ram));
+        assert_eq!(map.check(Access::Write, 0x4000, 3), Ok(Space::Ram));
+        assert_eq!(
+            map.check(Access::Write, 0x7ff0, 0x20),
+            Err(Refusal::InUse {
+                address: 0x7ff0,
+                len: 0x20,
+                region: InUse::Okboot
+            })
+        );
+        assert_eq!(
+            map.check(Access::Write, 0x17ff_fffc, 8),
+            Err(Refusal::InUse {
+                address: 0x17ff_fffc,
+                len: 8,
+                region: InUse::Heap
+            })
+        );
+        assert_eq!(map.check(Access::Jump, 0x20_0000, 0), Ok(Space::Ram));
+        assert_eq!(
+            map.check(Access::Jump, 0x20_0002, 0),
+            Err(Refusal::Unaligned {
+                address: 0x20_0002,
+                len: 4
+            })
+        );
+        assert!(map.check(Access::Jump, 0x8000, 0).is_err());
+        assert!(map.check(Access::Jump, 0x2020_0000, 0).is_err());
+    }
+}
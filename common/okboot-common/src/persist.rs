@@ -0,0 +1,213 @@
+//! Images that okboot keeps on the SD card, so that a board comes back with its program after a
+//! power cycle: okdude asks for one to be kept with [`Metadata::persist`](crate::host::Metadata),
+//! and okboot writes it to the root directory of the [FAT32](crate::fat) boot partition once it
+//! has been received and verified.
+//!
+//! Each image is kept in a file named after its [`Slot`], as an [`ImageHeader`] followed by the
+//! inflated program. `kernel.img` is left alone: on most cards that's okboot itself.
+use crate::fat::ShortName;
+use crate::host::FormatDetails;
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Extension of the files that images are kept in.
+const EXTENSION: &[u8] = b"OKB";
+
+/// Name of a kept image: one to eight letters, digits, `-` or `_`, in upper case, since that's
+/// all a FAT short name can hold.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Slot([u8; 8]);
+impl Slot {
+    /// Where images go when no slot is named, and what okboot boots from the card.
+    pub const DEFAULT: Self = Self(*b"DEFAULT ");
+
+    /// The slot called `name`, which is upper-cased.
+    pub fn new(name: &str) -> Result<Self, BadSlot> {
+        if name.is_empty() || name.len() > 8 {
+            return Err(BadSlot::Length);
+        }
+        let mut bytes = [b' '; 8];
+        for (byte, c) in bytes.iter_mut().zip(name.bytes()) {
+            if !(c.is_ascii_alphanumeric() || c == b'-' || c == b'_') {
+                return Err(BadSlot::Character(c as char));
+            }
+            *byte = c.to_ascii_uppercase();
+        }
+        Ok(Self(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == b' ').unwrap_or(8);
+        core::str::from_utf8(&self.0[..len]).unwrap_or("?")
+    }
+
+    /// Name of the file that the slot's image is kept in.
+    pub fn file_name(&self) -> ShortName {
+        ShortName::new(self.as_str().as_bytes(), EXTENSION)
+    }
+
+    /// The slot kept in the file called `name`, if that's one of okboot's.
+    pub fn from_file_name(name: &ShortName) -> Option<Self> {
+        if name.extension() != EXTENSION {
+            return None;
+        }
+        Self::new(core::str::from_utf8(name.name()).ok()?).ok()
+    }
+}
+impl Display for Slot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+impl FromStr for Slot {
+    type Err = BadSlot;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+/// Why a string isn't the name of a [`Slot`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum BadSlot {
+    #[error("slot names are one to eight characters long")]
+    Length,
+    #[error("slot names can't contain {0:?}; only letters, digits, '-' and '_'")]
+    Character(char),
+}
+
+/// What okboot needs to know to boot a kept image again: how to load it, and how to check that
+/// it's still what was uploaded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ImageHeader {
+    /// Length of the inflated image that follows the header.
+    pub len: u32,
+    /// CRC-32 of the image.
+    pub crc: u32,
+    pub format_details: FormatDetails,
+    /// The image's [`ImageSignature`](crate::host::ImageSignature), if it was signed, so that
+    /// okboot can check it again before booting the image.
+    pub signature: Option<[u8; 64]>,
+}
+impl ImageHeader {
+    /// Bytes taken up by the header; the image starts a sector in.
+    pub const LEN: usize = crate::fat::SECTOR_SIZE;
+    const MAGIC: [u8; 4] = *b"okpi";
+    const VERSION: u32 = 1;
+
+    /// Lay the header out as it's kept on the card:
+    ///
+    /// | offset | contents                                                   |
+    /// |--------|------------------------------------------------------------|
+    /// | 0      | `okpi`                                                     |
+    /// | 4      | version, 1                                                 |
+    /// | 8      | length of the image                                        |
+    /// | 12     | CRC-32 of the image                                        |
+    /// | 16     | format: 0 for binaries, 1 for ELF files, 2 for segments    |
+    /// | 20     | binaries' load address, or segments' entry point           |
+    /// | 28     | 1 if a signature follows, or else 0                        |
+    /// | 32     | the signature                                              |
+    ///
+    /// with every number little-endian, and the rest zero.
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        let (format, address) = match self.format_details {
+            FormatDetails::Bin { load_address } => (0u32, load_address),
+            FormatDetails::Elf => (1, 0),
+            FormatDetails::Segments { entry } => (2, entry),
+        };
+        bytes[0..4].copy_from_slice(&Self::MAGIC);
+        bytes[4..8].copy_from_slice(&Self::VERSION.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.len.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        bytes[16..20].copy_from_slice(&format.to_le_bytes());
+        bytes[20..28].copy_from_slice(&address.to_le_bytes());
+        if let Some(signature) = &self.signature {
+            bytes[28] = 1;
+            bytes[32..96].copy_from_slice(signature);
+        }
+        bytes
+    }
+
+    /// Read back a header laid out by [`encode`](Self::encode); `None` if it isn't one.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        if bytes.len() < Self::LEN || bytes[0..4] != Self::MAGIC || word(4) != Self::VERSION {
+            return None;
+        }
+        let address = u64::from_le_bytes(bytes[20..28].try_into().unwrap());
+        let format_details = match word(16) {
+            0 => FormatDetails::Bin {
+                load_address: address,
+            },
+            1 => FormatDetails::Elf,
+            2 => FormatDetails::Segments { entry: address },
+            _ => return None,
+        };
+        let signature = match bytes[28] {
+            0 => None,
+            1 => Some(bytes[32..96].try_into().unwrap()),
+            _ => return None,
+        };
+        Some(Self {
+            len: word(8),
+            crc: word(12),
+            format_details,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that slot names are checked, and map to and from file names
+    #[test]
+    fn test_slot() {
+        let slot = Slot::new("test-1").unwrap();
+        assert_eq!(slot.as_str(), "TEST-1");
+        assert_eq!(&slot.file_name().0, b"TEST-1  OKB");
+        assert_eq!(Slot::from_file_name(&slot.file_name()), Some(slot));
+        assert_eq!(Slot::DEFAULT.as_str(), "DEFAULT");
+        assert_eq!(Slot::new(""), Err(BadSlot::Length));
+        assert_eq!(Slot::new("ninechars"), Err(BadSlot::Length));
+        assert_eq!(Slot::new("a.b"), Err(BadSlot::Character('.')));
+        assert_eq!(
+            Slot::from_file_name(&ShortName::new(b"KERNEL", b"IMG")),
+            None
+        );
+    }
+
+    /// Test that headers read back as they were written, and that anything else isn't taken for
+    /// one
+    #[test]
+    fn test_header() {
+        let headers = [
+            ImageHeader {
+                len: 0x1234,
+                crc: 0xdead_beef,
+                format_details: FormatDetails::Bin {
+                    load_address: 0x8000,
+                },
+                signature: None,
+            },
+            ImageHeader {
+                len: 1,
+                crc: 2,
+                format_details: FormatDetails::Segments { entry: 0x20_0000 },
+                signature: Some([7; 64]),
+            },
+        ];
+        for header in headers {
+            assert_eq!(ImageHeader::decode(&header.encode()), Some(header));
+        }
+        assert_eq!(ImageHeader::decode(&[0; ImageHeader::LEN]), None);
+        let mut unknown_format = headers[0].encode();
+        unknown_format[16] = 3;
+        assert_eq!(ImageHeader::decode(&unknown_format), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! How [`FormatDetails::Segments`](crate::host::FormatDetails::Segments) programs are laid out.
+//!
+//! The inflated program starts with a segment table: a `u32` count, then each segment as its
+//! `u32` address, `u32` length and the `u32` CRC32 of its bytes, all little-endian. Segments are
+//! listed in ascending order of address, don't overlap and aren't empty. Their bytes follow the
+//! table, one segment after another in the table's order, so the device knows where every byte
+//! goes as soon as the table has arrived. Memory between segments is left undefined.
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use thiserror::Error;
+
+/// Length of the count at the start of the table.
+pub const HEADER_LEN: usize = 4;
+/// Length of each segment's entry in the table.
+pub const ENTRY_LEN: usize = 12;
+/// Most segments that a table may list.
+pub const MAX_SEGMENTS: u32 = 256;
+
+/// One entry of the segment table.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub len: u32,
+    pub crc: u32,
+}
+impl Segment {
+    /// First address past the segment.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.len as u64
+    }
+}
+
+/// Why a segment table can't be loaded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum SegmentError {
+    #[error("the table lists no segments")]
+    Empty,
+    #[error("the table lists {0} segments, but at most {MAX_SEGMENTS} are allowed")]
+    TooMany(u32),
+    #[error("segment at {address:#x} is empty or not above the one before it")]
+    Misplaced { address: u32 },
+    #[error("the table describes {described} bytes, but the program is {len}")]
+    Length { described: u64, len: u32 },
+}
+
+/// Length of the whole table, from its first [`HEADER_LEN`] bytes.
+pub fn table_len(header: [u8; HEADER_LEN]) -> Result<usize, SegmentError> {
+    match u32::from_le_bytes(header) {
+        0 => Err(SegmentError::Empty),
+        count if count > MAX_SEGMENTS => Err(SegmentError::TooMany(count)),
+        count => Ok(HEADER_LEN + count as usize * ENTRY_LEN),
+    }
+}
+
+/// The segments listed in `table`, as far as it goes.
+pub fn entries(table: &[u8]) -> impl Iterator<Item = Segment> + Clone + '_ {
+    let word = |entry: &[u8], i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+    table
+        .get(HEADER_LEN..)
+        .unwrap_or_default()
+        .chunks_exact(ENTRY_LEN)
+        .map(move |entry| Segment {
+            address: word(entry, 0),
+            len: word(entry, 4),
+            crc: word(entry, 8),
+        })
+}
+
+/// Check that the complete `table` at the start of a program of `len` (inflated) bytes is one
+/// that can be loaded.
+pub fn check(table: &[u8], len: u32) -> Result<(), SegmentError> {
+    let mut described = table.len() as u64;
+    let mut end = 0;
+    for segment in entries(table) {
+        if segment.len == 0 || (segment.address as u64) < end {
+            return Err(SegmentError::Misplaced {
+                address: segment.address,
+            });
+        }
+        end = segment.end();
+        described += segment.len as u64;
+    }
+    if described != len as u64 {
+        return Err(SegmentError::Length { described, len });
+    }
+    Ok(())
+}
+
+/// The program made of `segments`, as `(address, bytes)`, table and all. They have to be in
+/// ascending order of address and not overlap.
+#[cfg(feature = "alloc")]
+pub fn encode<'a>(segments: impl ExactSizeIterator<Item = (u32, &'a [u8])> + Clone) -> Vec<u8> {
+    let mut program = Vec::new();
+    program.extend_from_slice(&(segments.len() as u32).to_le_bytes());
+    for (address, bytes) in segments.clone() {
+        program.extend_from_slice(&address.to_le_bytes());
+        program.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        program.extend_from_slice(&crc32fast::hash(bytes).to_le_bytes());
+    }
+    for (_, bytes) in segments {
+        program.extend_from_slice(bytes);
+    }
+    program
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Test that an encoded program reads back as the segments it was made of
+    #[test]
+    fn test_roundtrip() {
+        let program = encode([(0x8000, &[1u8, 2, 3][..]), (0x10_0000, &[4])].into_iter());
+        let table_len = table_len(program[..4].try_into().unwrap()).unwrap();
+        assert_eq!(table_len, 4 + 2 * 12);
+        let table = &program[..table_len];
+        assert_eq!(
+            entries(table).collect::<Vec<_>>(),
+            [
+                Segment {
+                    address: 0x8000,
+                    len: 3,
+                    crc: crc32fast::hash(&[1, 2, 3])
+                },
+                Segment {
+                    address: 0x10_0000,
+                    len: 1,
+                    crc: crc32fast::hash(&[4])
+                },
+            ]
+        );
+        assert_eq!(check(table, program.len() as u32), Ok(()));
+        assert_eq!(&program[table_len..], [1, 2, 3, 4]);
+    }
+
+    /// Test that tables that can't be loaded are refused
+    #[test]
+    fn test_check() {
+        assert_eq!(table_len([0; 4]), Err(SegmentError::Empty));
+        assert_eq!(
+            table_len(257u32.to_le_bytes()),
+            Err(SegmentError::TooMany(257))
+        );
+        let overlapping = encode([(0x8000, &[1u8, 2][..]), (0x8001, &[3])].into_iter());
+        assert_eq!(
+            check(&overlapping[..28], overlapping.len() as u32),
+            Err(SegmentError::Misplaced { address: 0x8001 })
+        );
+        let empty = encode([(0x8000, &[][..])].into_iter());
+        assert_eq!(
+            check(&empty[..16], empty.len() as u32),
+            Err(SegmentError::Misplaced { address: 0x8000 })
+        );
+        let program = encode([(0x8000, &[1u8, 2][..])].into_iter());
+        assert_eq!(
+            check(&program[..16], program.len() as u32 - 1),
+            Err(SegmentError::Length {
+                described: 18,
+                len: 17
+            })
+        );
+    }
+}
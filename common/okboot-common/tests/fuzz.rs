@@ -0,0 +1,542 @@
+//! Property-based fuzzing of everything in `okboot-common` that parses untrusted serial bytes: the
+//! [`FrameLayer`] decode pipeline (including the legacy SU-BOOT sniffing states) and the postcard
+//! deserializers of every [`device`] and [`host`] message, as well as the [`delta`] patcher, the
+//! [`compression`] decoders, the [`executable`] checks and the [`gdb`] agent.
+//!
+//! Inputs that have caused failures are kept as raw byte streams in `tests/corpus/` and replayed
+//! by [`regression_corpus`]; when proptest finds a new one, save the minimized stream there.
+//! Proptest also records the seeds of failing cases in `fuzz.proptest-regressions`, which should be
+//! checked in as well.
+#[cfg(feature = "alloc")]
+use okboot_common::compression::{self, Compression};
+use okboot_common::frame::{BufferedEncoder, EncodeState, FrameLayer, FrameOutput};
+use okboot_common::{
+    delta, device, executable, frame, gdb, host, monitor, MessageType, COBS_XOR, PREAMBLE_BYTES,
+};
+use proptest::prelude::*;
+use proptest::test_runner::FileFailurePersistence;
+
+/// Every message type, to pick from when generating frames.
+const MESSAGE_TYPES: &[MessageType] = &[
+    MessageType::PrintString,
+    MessageType::Log,
+    MessageType::Exit,
+    MessageType::Reboot,
+    MessageType::Probe,
+    MessageType::AllowedVersions,
+    MessageType::UseVersion,
+    MessageType::ProposeBaudRates,
+    MessageType::BaudRateChoice,
+    MessageType::BaudProbe,
+    MessageType::BaudProbeAck,
+    MessageType::Identify,
+    MessageType::DeviceInfo,
+    MessageType::MetadataReq,
+    MessageType::Metadata,
+    MessageType::MetadataAck,
+    MessageType::MetadataAckAck,
+    MessageType::PipelinedMetadataAck,
+    MessageType::Resume,
+    MessageType::ResumeAck,
+    MessageType::BaseImage,
+    MessageType::DeltaMetadata,
+    MessageType::ChunkReq,
+    MessageType::Chunk,
+    MessageType::ChunkAck,
+    MessageType::Booting,
+    MessageType::BootingAck,
+    MessageType::BootRefused,
+    MessageType::GdbPacket,
+    MessageType::MemRead,
+    MessageType::MemData,
+    MessageType::MemWrite,
+    MessageType::MemCrc,
+    MessageType::MemCrcResult,
+    MessageType::Jump,
+    MessageType::Jumping,
+    MessageType::MemRefused,
+    MessageType::ListImages,
+    MessageType::StoredImage,
+    MessageType::ImagesListed,
+    MessageType::DeleteImage,
+    MessageType::ImageDeleted,
+    MessageType::StorageFailed,
+    MessageType::Persisting,
+    MessageType::Persisted,
+];
+
+/// Build the wire representation of a frame, the same way `okdude` does.
+fn encode_frame(message_type: MessageType, payload: &[u8]) -> Vec<u8> {
+    let mut message_bytes = vec![];
+    message_bytes.extend_from_slice(&u32::from(message_type).to_le_bytes());
+    message_bytes.extend_from_slice(payload);
+    let crc32 = crc32fast::hash(&message_bytes);
+    message_bytes.extend_from_slice(&crc32.to_le_bytes());
+
+    let mut wire_bytes = vec![];
+    wire_bytes.extend_from_slice(&PREAMBLE_BYTES);
+    wire_bytes.extend_from_slice(
+        &frame::encode_length(payload.len()).expect("payload should be shorter than 16MiB"),
+    );
+    let mut buf = [0; 255];
+    let mut buf_enc = BufferedEncoder::with_buffer_xor(&mut buf[..], COBS_XOR);
+    let mut frame_encoder = buf_enc.frame().expect("failed to build frame encoder");
+    for byte in message_bytes {
+        if let EncodeState::Buf(b) = frame_encoder.write_u8(byte) {
+            wire_bytes.extend_from_slice(b);
+        }
+    }
+    wire_bytes.extend_from_slice(frame_encoder.finish());
+    wire_bytes
+}
+
+/// Deserialize `payload` as the message corresponding to `message_type`, and poke at the result.
+/// Only checks that nothing panics; garbage is expected to fail to deserialize.
+fn deserialize_message(message_type: MessageType, payload: &[u8]) {
+    fn de<'a, T: serde::Deserialize<'a>>(payload: &'a [u8]) -> Option<T> {
+        postcard::from_bytes(payload).ok()
+    }
+    match message_type {
+        MessageType::PrintString => {
+            let _ = String::from_utf8_lossy(payload);
+        }
+        MessageType::Log => {
+            let _ = de::<device::Log>(payload);
+        }
+        MessageType::Exit => {
+            let _ = device::Exit::from_payload(payload);
+        }
+        // no payload to deserialize
+        MessageType::Reboot => {}
+        MessageType::Probe => {
+            let _ = de::<host::Probe>(payload);
+        }
+        MessageType::AllowedVersions => {
+            if let Some(allowed_versions) = de::<device::AllowedVersions>(payload) {
+                allowed_versions.iter().for_each(drop);
+            }
+        }
+        MessageType::UseVersion => {
+            let _ = de::<host::UseVersion>(payload);
+        }
+        MessageType::ProposeBaudRates => {
+            if let Some(propose_baud_rates) = de::<host::ProposeBaudRates>(payload) {
+                propose_baud_rates.iter().for_each(drop);
+            }
+        }
+        MessageType::BaudRateChoice => {
+            let _ = de::<device::BaudRateChoice>(payload);
+        }
+        MessageType::BaudProbe => {
+            let _ = de::<host::BaudProbe>(payload);
+        }
+        MessageType::BaudProbeAck => {
+            let _ = de::<device::BaudProbeAck>(payload);
+        }
+        MessageType::Identify => {
+            let _ = de::<host::Identify>(payload);
+        }
+        MessageType::DeviceInfo => {
+            if let Some(device_info) = de::<device::DeviceInfo>(payload) {
+                let map = device_info.memory_map;
+                let _ = map.check(monitor::Access::Write, 0x8000, 0x1000);
+            }
+        }
+        MessageType::MetadataReq => {
+            let _ = de::<device::MetadataReq>(payload);
+        }
+        MessageType::Metadata => {
+            if let Some(metadata) = de::<host::Metadata>(payload) {
+                let _ = metadata.format_details.to_string();
+                let _ = metadata.compression.to_string();
+            }
+        }
+        MessageType::MetadataAck => {
+            let _ = de::<device::MetadataAck>(payload);
+        }
+        MessageType::MetadataAckAck => {
+            let _ = de::<host::MetadataAckAck>(payload);
+        }
+        MessageType::PipelinedMetadataAck => {
+            let _ = de::<device::PipelinedMetadataAck>(payload);
+        }
+        MessageType::Resume => {
+            let _ = de::<device::Resume>(payload);
+        }
+        MessageType::ResumeAck => {
+            let _ = de::<host::ResumeAck>(payload);
+        }
+        MessageType::BaseImage => {
+            let _ = de::<device::BaseImage>(payload);
+        }
+        MessageType::DeltaMetadata => {
+            if let Some(delta_metadata) = de::<host::DeltaMetadata>(payload) {
+                let _ = delta_metadata.metadata.format_details.to_string();
+            }
+        }
+        MessageType::ChunkReq => {
+            let _ = de::<device::ChunkReq>(payload);
+        }
+        MessageType::Chunk => {
+            let _ = de::<host::Chunk>(payload);
+        }
+        MessageType::ChunkAck => {
+            let _ = de::<device::ChunkAck>(payload);
+        }
+        MessageType::Booting => {
+            let _ = de::<device::Booting>(payload);
+        }
+        MessageType::BootingAck => {
+            let _ = de::<host::BootingAck>(payload);
+        }
+        MessageType::BootRefused => {
+            if let Some(boot_refused) = de::<device::BootRefused>(payload) {
+                let _ = boot_refused.error.to_string();
+            }
+        }
+        // not serialized; the program's agent parses the packet itself
+        MessageType::GdbPacket => {
+            let mut target = Registers([0; gdb::REGISTERS]);
+            gdb::Agent::new().handle(&mut target, payload, |_| {});
+        }
+        MessageType::MemRead => {
+            let _ = de::<host::MemRead>(payload);
+        }
+        MessageType::MemData => {
+            let _ = de::<device::MemData>(payload);
+        }
+        MessageType::MemWrite => {
+            let _ = de::<host::MemWrite>(payload);
+        }
+        MessageType::MemCrc => {
+            let _ = de::<host::MemCrc>(payload);
+        }
+        MessageType::MemCrcResult => {
+            let _ = de::<device::MemCrcResult>(payload);
+        }
+        MessageType::Jump => {
+            let _ = de::<host::Jump>(payload);
+        }
+        MessageType::Jumping => {
+            let _ = de::<device::Jumping>(payload);
+        }
+        MessageType::MemRefused => {
+            if let Some(mem_refused) = de::<device::MemRefused>(payload) {
+                let _ = mem_refused.refusal.to_string();
+            }
+        }
+        MessageType::ListImages => {
+            let _ = de::<host::ListImages>(payload);
+        }
+        MessageType::StoredImage => {
+            if let Some(stored_image) = de::<device::StoredImage>(payload) {
+                let _ = stored_image.slot.to_string();
+                let _ = stored_image.format_details.to_string();
+            }
+        }
+        MessageType::ImagesListed => {
+            let _ = de::<device::ImagesListed>(payload);
+        }
+        MessageType::DeleteImage => {
+            let _ = de::<host::DeleteImage>(payload);
+        }
+        MessageType::ImageDeleted => {
+            let _ = de::<device::ImageDeleted>(payload);
+        }
+        MessageType::StorageFailed => {
+            if let Some(storage_failed) = de::<device::StorageFailed>(payload) {
+                let _ = storage_failed.error.to_string();
+            }
+        }
+        MessageType::Persisting => {
+            let _ = de::<device::Persisting>(payload);
+        }
+        MessageType::Persisted => {
+            let _ = de::<device::Persisted>(payload);
+        }
+    }
+}
+
+/// A halted program without any memory, breakpoints or watchpoints, for the GDB agent to answer
+/// packets about.
+struct Registers([u32; gdb::REGISTERS]);
+impl gdb::Target for Registers {
+    fn registers(&mut self) -> [u32; gdb::REGISTERS] {
+        self.0
+    }
+
+    fn set_registers(&mut self, registers: &[u32; gdb::REGISTERS]) {
+        self.0 = *registers;
+    }
+
+    fn read_memory(&mut self, _: u32, _: &mut [u8]) -> bool {
+        false
+    }
+
+    fn write_memory(&mut self, _: u32, _: &[u8]) -> bool {
+        false
+    }
+
+    fn add_breakpoint(&mut self, _: u32) -> bool {
+        false
+    }
+
+    fn remove_breakpoint(&mut self, _: u32) -> bool {
+        false
+    }
+
+    fn add_watchpoint(&mut self, _: gdb::WatchKind, _: u32, _: u32) -> bool {
+        false
+    }
+
+    fn remove_watchpoint(&mut self, _: gdb::WatchKind, _: u32, _: u32) -> bool {
+        false
+    }
+}
+
+/// Run `bytes` through a [`FrameLayer`] the way the host and device do: reset on errors, hand
+/// finished frames to the deserializers, and give up on the stream once it turns into legacy
+/// SU-BOOT traffic. Returns the frames that were successfully decoded.
+fn decode_stream(bytes: &[u8]) -> Vec<(MessageType, Vec<u8>)> {
+    let mut frame_layer = FrameLayer::new(COBS_XOR);
+    let mut frames = vec![];
+    let mut current: Option<(MessageType, Vec<u8>)> = None;
+    for &byte in bytes {
+        match frame_layer.feed(byte) {
+            Ok(FrameOutput::Skip) | Ok(FrameOutput::LegacyPrintStringByte(_, _)) => {}
+            Ok(FrameOutput::Header(header)) => {
+                assert!(
+                    current.is_none(),
+                    "second header {header:?} within one frame"
+                );
+                current = Some((header.message_type, Vec::with_capacity(header.payload_len)));
+            }
+            Ok(FrameOutput::Payload(b)) => {
+                let (_, payload) = current.as_mut().expect("payload byte before header");
+                payload.push(b);
+            }
+            Ok(FrameOutput::Finished) => {
+                let (message_type, payload) = current.take().expect("finished before header");
+                deserialize_message(message_type, &payload);
+                frames.push((message_type, payload));
+            }
+            Ok(FrameOutput::Legacy) => break,
+            Err(_) => {
+                frame_layer.reset();
+                current = None;
+            }
+        }
+    }
+    frames
+}
+
+/// Like [`decode_stream`], but keep feeding bytes after errors and legacy traffic instead of
+/// resetting; the [`FrameLayer`] has to put up with careless callers too.
+fn feed_stream(bytes: &[u8]) {
+    let mut frame_layer = FrameLayer::new(COBS_XOR);
+    for &byte in bytes {
+        let _ = frame_layer.feed(byte);
+    }
+}
+
+fn message_type() -> impl Strategy<Value = MessageType> {
+    proptest::sample::select(MESSAGE_TYPES)
+}
+
+/// Bytes that are more likely than uniformly random ones to get deep into the decoder: preamble
+/// and legacy magic bytes, COBS-encoded zeros, and length bytes.
+fn interesting_byte() -> impl Strategy<Value = u8> {
+    prop_oneof![
+        any::<u8>(),
+        Just(0x55),
+        Just(0x5e),
+        Just(0x44),
+        Just(0x33),
+        Just(0xee),
+        Just(0xdd),
+        Just(COBS_XOR),
+        0xc0u8..=0xff,
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        // the default looks for `lib.rs`, which integration tests don't have
+        failure_persistence: Some(Box::new(FileFailurePersistence::WithSource(
+            "proptest-regressions",
+        ))),
+        ..ProptestConfig::default()
+    })]
+
+    /// The decoder must never panic, no matter what arrives on the wire.
+    #[test]
+    fn decode_arbitrary_bytes(bytes in proptest::collection::vec(interesting_byte(), 0..1024)) {
+        decode_stream(&bytes);
+        feed_stream(&bytes);
+    }
+
+    /// The decoder must never panic on a frame that has been corrupted after encoding.
+    #[test]
+    fn decode_corrupted_frame(
+        message_type in message_type(),
+        payload in proptest::collection::vec(any::<u8>(), 0..512),
+        corruptions in proptest::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4),
+    ) {
+        let mut bytes = encode_frame(message_type, &payload);
+        for (index, byte) in corruptions {
+            *index.get_mut(&mut bytes) = byte;
+        }
+        decode_stream(&bytes);
+        feed_stream(&bytes);
+    }
+
+    /// Whatever came before, a [`FrameLayer`] that has been reset decodes a well-formed frame.
+    #[test]
+    fn encode_decode_round_trip(
+        noise in proptest::collection::vec(interesting_byte(), 0..256),
+        frames in proptest::collection::vec(
+            (message_type(), proptest::collection::vec(any::<u8>(), 0..1024)),
+            1..4,
+        ),
+    ) {
+        let mut frame_layer = FrameLayer::new(COBS_XOR);
+        for &byte in &noise {
+            let _ = frame_layer.feed(byte);
+        }
+        frame_layer.reset();
+
+        let mut bytes = vec![];
+        for (message_type, payload) in &frames {
+            bytes.extend(encode_frame(*message_type, payload));
+        }
+        let mut decoded = vec![];
+        let mut payload = vec![];
+        let mut header = None;
+        for byte in bytes {
+            match frame_layer.feed(byte) {
+                Ok(FrameOutput::Skip) => {}
+                Ok(FrameOutput::Header(h)) => {
+                    prop_assert!(header.replace(h).is_none(), "second header {:?}", h);
+                }
+                Ok(FrameOutput::Payload(b)) => payload.push(b),
+                Ok(FrameOutput::Finished) => {
+                    let h = header.take().expect("finished before header");
+                    prop_assert_eq!(h.payload_len, payload.len());
+                    decoded.push((h.message_type, core::mem::take(&mut payload)));
+                }
+                Ok(output) => prop_assert!(false, "unexpected {:?}", output),
+                Err(e) => prop_assert!(false, "error during frame decoding: {}", e),
+            }
+        }
+        prop_assert_eq!(decoded, frames);
+    }
+
+    /// Message deserializers must never panic on garbage, whether or not it arrived in a frame.
+    #[test]
+    fn deserialize_arbitrary_payload(
+        message_type in message_type(),
+        payload in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        deserialize_message(message_type, &payload);
+        let bytes = encode_frame(message_type, &payload);
+        let frames = decode_stream(&bytes);
+        prop_assert_eq!(frames, vec![(message_type, payload)]);
+    }
+
+    /// Applying a garbage delta must fail cleanly, however it's split up.
+    #[test]
+    fn patch_arbitrary_delta(
+        base in proptest::collection::vec(any::<u8>(), 0..256),
+        delta in proptest::collection::vec(prop_oneof![any::<u8>(), 0u8..=2], 0..512),
+        piece in 1usize..64,
+        budget in 0usize..64,
+    ) {
+        let mut patcher = delta::Patcher::new();
+        let mut input = &delta[..];
+        while !input.is_empty() || patcher.has_output() {
+            let end = piece.min(input.len());
+            match patcher.feed(&input[..end], &base, budget.max(1), |_| {}) {
+                Ok(consumed) => input = &input[consumed..],
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// ELF validation must turn down garbage cleanly, even behind a plausible identification.
+    #[test]
+    fn validate_arbitrary_elf(
+        body in proptest::collection::vec(any::<u8>(), 0..512),
+        load_end in any::<u32>(),
+    ) {
+        let mut bytes = b"\x7fELF\x01\x01\x01\x00".to_vec();
+        bytes.resize(16, 0);
+        bytes.extend(body);
+        let _ = executable::validate(&bytes, load_end.into(), |index, segment| {
+            executable::hazards(index, &segment, load_end.into()).for_each(drop);
+        });
+    }
+}
+
+/// Codecs to decode garbage with; the ones that aren't built in are skipped.
+#[cfg(feature = "alloc")]
+const COMPRESSIONS: &[Compression] = &[
+    Compression::None,
+    Compression::Deflate { level: 5 },
+    Compression::Lz4,
+    Compression::Heatshrink {
+        window: 8,
+        lookahead: 4,
+    },
+    Compression::Heatshrink {
+        window: 15,
+        lookahead: 14,
+    },
+];
+
+#[cfg(feature = "alloc")]
+proptest! {
+    /// Decoding a garbage stream must fail cleanly or produce garbage, however it's split up.
+    #[test]
+    fn decode_arbitrary_stream(
+        compression in proptest::sample::select(COMPRESSIONS),
+        stream in proptest::collection::vec(any::<u8>(), 0..2048),
+        piece in 1usize..512,
+        output_len in 1usize..1024,
+    ) {
+        let Ok(mut decoder) = compression::Decoder::new(compression) else {
+            return Ok(());
+        };
+        let mut output = vec![0; output_len];
+        let mut input = &stream[..];
+        loop {
+            let end = piece.min(input.len());
+            match decoder.decode(&input[..end], &mut output) {
+                Ok(decoded) => {
+                    prop_assert!(decoded.consumed <= end && decoded.written <= output_len);
+                    input = &input[decoded.consumed..];
+                    if decoded.finished || (decoded.consumed == 0 && decoded.written == 0) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Replay every input in `tests/corpus/`.
+#[test]
+fn regression_corpus() {
+    let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    for entry in std::fs::read_dir(&corpus).expect("failed to read regression corpus") {
+        let path = entry
+            .expect("failed to read regression corpus entry")
+            .path();
+        let bytes = std::fs::read(&path).expect("failed to read regression corpus input");
+        let replayed = std::panic::catch_unwind(|| {
+            decode_stream(&bytes);
+            feed_stream(&bytes);
+        });
+        assert!(replayed.is_ok(), "{} failed to replay", path.display());
+    }
+}
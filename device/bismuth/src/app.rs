@@ -1,2 +1,4 @@
 pub mod cpuid;
 pub mod debug;
+pub mod gdb;
+pub mod idle;
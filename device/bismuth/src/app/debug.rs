@@ -163,7 +163,7 @@ proc_bitfield::bitfield! {
         read_or_write: bool @ 11,
         status_bank : bool @ 10,
         domain : u8 @ 4..=7,
-        status : u8 @ 0..=3,
+        pub status : u8 @ 0..=3,
     }
 }
 proc_bitfield::bitfield! {
@@ -180,16 +180,16 @@ define_coprocessor_registers! {
     watchpoint_fault_address : Wfar => p14 0 c0 c6 0;
 
     breakpoint_value_register_0 : Bvr => p14 0 c0 c0 4;
-    // breakpoint_value_register_1 : BVR => p14 0 c0 c1 4;
-    // breakpoint_value_register_2 : BVR => p14 0 c0 c2 4;
-    // breakpoint_value_register_3 : BVR => p14 0 c0 c3 4;
+    breakpoint_value_register_1 : Bvr => p14 0 c0 c1 4;
+    breakpoint_value_register_2 : Bvr => p14 0 c0 c2 4;
+    breakpoint_value_register_3 : Bvr => p14 0 c0 c3 4;
     breakpoint_value_register_4 : Bvr => p14 0 c0 c4 4; /* context id */
     breakpoint_value_register_5 : Bvr => p14 0 c0 c5 4; /* context id */
 
     breakpoint_control_register_0 : Bcr => p14 0 c0 c0 5;
-    // breakpoint_control_register_1 : BCR => p14 0 c0 c1 5;
-    // breakpoint_control_register_2 : BCR => p14 0 c0 c2 5;
-    // breakpoint_control_register_3 : BCR => p14 0 c0 c3 5;
+    breakpoint_control_register_1 : Bcr => p14 0 c0 c1 5;
+    breakpoint_control_register_2 : Bcr => p14 0 c0 c2 5;
+    breakpoint_control_register_3 : Bcr => p14 0 c0 c3 5;
     breakpoint_control_register_4 : Bcr => p14 0 c0 c4 5;
     breakpoint_control_register_5 : Bcr => p14 0 c0 c5 5;
 
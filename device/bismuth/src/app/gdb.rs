@@ -0,0 +1,366 @@
+//! Debugging with GDB through `okdude --gdb`. With `gdb` as its first argument, the program halts
+//! before it runs the command in the next one, so that GDB can set breakpoints; after that, it
+//! halts whenever it reaches one, touches a watched address or faults, and answers GDB's packets
+//! from the abort handler until GDB resumes it.
+//!
+//! BRP0 single-steps, as in [`interleave_checker`](super::debug::interleave_checker), which leaves
+//! BRPs 1-5 for GDB's hardware breakpoints, and WRPs 0-1 for its watchpoints.
+use super::debug::{
+    Bcr, Bvr, BvrMeaning, DebugEntryMethod, Wcr, breakpoint_control_register_0,
+    breakpoint_control_register_1, breakpoint_control_register_2, breakpoint_control_register_3,
+    breakpoint_control_register_4, breakpoint_control_register_5, breakpoint_value_register_0,
+    breakpoint_value_register_1, breakpoint_value_register_2, breakpoint_value_register_3,
+    breakpoint_value_register_4, breakpoint_value_register_5, data_fault_status,
+    debug_status_control, instruction_fault_status, watchpoint_control_register_0,
+    watchpoint_control_register_1, watchpoint_value_register_0, watchpoint_value_register_1,
+};
+use crate::exceptions::VectorBuilder;
+use crate::fmt::Uart1WriteProxy;
+use crate::int::{self, OperatingMode};
+use crate::{define_halt_trampoline, steal_println};
+use bcm2835_lpa::{Peripherals, UART1};
+use core::alloc::Layout;
+use core::cell::SyncUnsafeCell;
+use okboot_common::frame::{FrameLayer, FrameOutput, write_frame};
+use okboot_common::gdb::{Agent, MAX_PACKET, REGISTERS, Resume, Stop, Target, WatchKind};
+use okboot_common::{COBS_XOR, MessageType};
+use quartz::arch::arm1176::{dsb, prefetch_flush};
+use quartz::device::bcm2835::watchdog;
+
+/// IFSR and DFSR status for a debug event, rather than an actual abort.
+const DEBUG_EVENT: u8 = 0b0010;
+
+/// Exit code for when GDB kills the program, as if it had been sent SIGKILL.
+const KILLED: i32 = 128 + 9;
+
+const PC: usize = 15;
+
+define_halt_trampoline!(_gdb_prefetch_abort_trampoline, prefetch_abort, 4);
+define_halt_trampoline!(_gdb_data_abort_trampoline, data_abort, 8);
+
+/// What the debugger keeps between halts.
+struct Debugger {
+    agent: Agent,
+    /// Whether the program was resumed to run a single instruction.
+    stepping: bool,
+    /// What each WRP is watching.
+    watchpoints: [Option<(WatchKind, u32, u32)>; 2],
+}
+
+static DEBUGGER: SyncUnsafeCell<Debugger> = SyncUnsafeCell::new(Debugger {
+    agent: Agent::new(),
+    stepping: false,
+    watchpoints: [None; 2],
+});
+
+/// Set up the abort handlers and turn on monitor debug-mode, then halt until GDB resumes the
+/// program.
+pub fn attach() {
+    let layout = Layout::array::<u32>(8).unwrap();
+    let vdi_ptr: *mut u32 = core::ptr::with_exposed_provenance_mut(0);
+    let vector_dst = core::ptr::slice_from_raw_parts_mut(vdi_ptr, layout.size());
+
+    dsb();
+    unsafe {
+        VectorBuilder::new()
+            .set_data_abort_handler((&raw const _gdb_data_abort_trampoline).addr())
+            .set_prefetch_abort_handler((&raw const _gdb_prefetch_abort_trampoline).addr())
+            .install(vector_dst)
+            .expect("#failed to install exception vector");
+        int::init_stack_for_mode(OperatingMode::Abort, 0x0f20_0000);
+        debug_status_control::modify(|dscr| dscr.with_monitor_debug_enable(true));
+    }
+    prefetch_flush();
+    dsb();
+
+    steal_println!("Waiting for GDB.");
+    // the agent steps over it when GDB resumes the program
+    unsafe { core::arch::asm!("bkpt #0") };
+}
+
+extern "C" fn prefetch_abort(frame: *mut [u32; REGISTERS]) {
+    let frame = unsafe { &mut *frame };
+    let ifsr = unsafe { instruction_fault_status::read() };
+    let dscr = unsafe { debug_status_control::read() };
+    let stepping = unsafe { DEBUGGER.get().as_ref_unchecked() }.stepping;
+    let stop = if ifsr.status() != DEBUG_EVENT {
+        Stop::Fault
+    } else {
+        match DebugEntryMethod::try_from(dscr.debug_entry()) {
+            Ok(DebugEntryMethod::Breakpoint) if !stepping => Stop::Breakpoint,
+            _ => Stop::Trap,
+        }
+    };
+    halt(frame, stop);
+}
+
+extern "C" fn data_abort(frame: *mut [u32; REGISTERS]) {
+    let frame = unsafe { &mut *frame };
+    let dfsr = unsafe { data_fault_status::read() };
+    let debugger = unsafe { DEBUGGER.get().as_ref_unchecked() };
+    // the WRPs don't say which of them it was, so go with the first
+    let stop = match debugger.watchpoints.iter().flatten().next() {
+        Some(&(kind, address, _)) if dfsr.status() == DEBUG_EVENT => {
+            Stop::Watchpoint { kind, address }
+        }
+        _ => Stop::Fault,
+    };
+    halt(frame, stop);
+}
+
+/// Tell GDB why the program stopped, then answer its packets until it resumes the program.
+fn halt(frame: &mut [u32; REGISTERS], stop: Stop) {
+    let peripherals = unsafe { Peripherals::steal() };
+    let debugger = unsafe { DEBUGGER.get().as_mut_unchecked() };
+    if core::mem::take(&mut debugger.stepping) {
+        unsafe { breakpoint_control_register_0::write(Bcr(0)) };
+        dsb();
+    }
+    let send = |reply: &[u8]| {
+        let mut proxy = Uart1WriteProxy::new(&peripherals.UART1);
+        write_frame(MessageType::GdbPacket, reply, |bytes| {
+            proxy.write_bytes(bytes)
+        });
+        proxy.flush();
+    };
+
+    let mut target = Halted {
+        frame,
+        watchpoints: &mut debugger.watchpoints,
+    };
+    debugger.agent.stopped(&mut target, stop, send);
+
+    let mut decoder = FrameLayer::new(COBS_XOR);
+    let mut message_type = None;
+    let mut packet = [0; MAX_PACKET];
+    let mut len = 0;
+    let resume = loop {
+        match decoder.feed(read_byte(&peripherals.UART1)) {
+            Ok(FrameOutput::Header(header)) => {
+                message_type = Some(header.message_type);
+                len = 0;
+            }
+            Ok(FrameOutput::Payload(byte)) => {
+                // GDB is told not to send longer packets
+                if len < MAX_PACKET {
+                    packet[len] = byte;
+                    len += 1;
+                }
+            }
+            Ok(FrameOutput::Finished) => match message_type.take() {
+                Some(MessageType::GdbPacket) => {
+                    if let Some(resume) = debugger.agent.handle(&mut target, &packet[..len], send) {
+                        break resume;
+                    }
+                }
+                Some(MessageType::Reboot) => watchdog::restart(&peripherals.PM),
+                _ => {}
+            },
+            Ok(FrameOutput::Legacy) | Err(_) => decoder.reset(),
+            Ok(_) => {}
+        }
+    };
+
+    match resume {
+        Resume::Continue => {}
+        Resume::Step => unsafe {
+            // halt at the next instruction that isn't this one
+            breakpoint_value_register_0::write(Bvr(target.frame[PC]));
+            breakpoint_control_register_0::write(
+                Bcr(0)
+                    .with_meaning(BvrMeaning::IMVAMismatch as u8)
+                    .with_supervisor_access(3)
+                    .with_byte_address_select(0xf)
+                    .with_enable(true),
+            );
+            debugger.stepping = true;
+        },
+        Resume::Detach => unsafe {
+            for n in 1..=5 {
+                set_breakpoint(n, Bvr(0), Bcr(0));
+            }
+            watchpoint_control_register_0::write(Wcr(0));
+            watchpoint_control_register_1::write(Wcr(0));
+            debugger.watchpoints = [None; 2];
+            debug_status_control::modify(|dscr| dscr.with_monitor_debug_enable(false));
+        },
+        Resume::Kill => crate::exit(KILLED),
+    }
+    dsb();
+    prefetch_flush();
+}
+
+fn read_byte(uart: &UART1) -> u8 {
+    while !uart.stat().read().data_ready().bit_is_set() {}
+    uart.io().read().data().bits()
+}
+
+/// Whether the `len` bytes at `address` are RAM or peripherals.
+fn accessible(address: u32, len: usize) -> bool {
+    let end = address as u64 + len as u64;
+    end <= 0x2100_0000
+}
+
+unsafe fn breakpoint_control(n: usize) -> Bcr {
+    unsafe {
+        match n {
+            1 => breakpoint_control_register_1::read(),
+            2 => breakpoint_control_register_2::read(),
+            3 => breakpoint_control_register_3::read(),
+            4 => breakpoint_control_register_4::read(),
+            5 => breakpoint_control_register_5::read(),
+            _ => unreachable!("BRP{n} isn't for GDB"),
+        }
+    }
+}
+
+unsafe fn breakpoint_value(n: usize) -> Bvr {
+    unsafe {
+        match n {
+            1 => breakpoint_value_register_1::read(),
+            2 => breakpoint_value_register_2::read(),
+            3 => breakpoint_value_register_3::read(),
+            4 => breakpoint_value_register_4::read(),
+            5 => breakpoint_value_register_5::read(),
+            _ => unreachable!("BRP{n} isn't for GDB"),
+        }
+    }
+}
+
+unsafe fn set_breakpoint(n: usize, bvr: Bvr, bcr: Bcr) {
+    unsafe {
+        match n {
+            1 => {
+                breakpoint_value_register_1::write(bvr);
+                breakpoint_control_register_1::write(bcr);
+            }
+            2 => {
+                breakpoint_value_register_2::write(bvr);
+                breakpoint_control_register_2::write(bcr);
+            }
+            3 => {
+                breakpoint_value_register_3::write(bvr);
+                breakpoint_control_register_3::write(bcr);
+            }
+            4 => {
+                breakpoint_value_register_4::write(bvr);
+                breakpoint_control_register_4::write(bcr);
+            }
+            5 => {
+                breakpoint_value_register_5::write(bvr);
+                breakpoint_control_register_5::write(bcr);
+            }
+            _ => unreachable!("BRP{n} isn't for GDB"),
+        }
+    }
+}
+
+unsafe fn set_watchpoint(n: usize, wvr: u32, wcr: Wcr) {
+    unsafe {
+        match n {
+            0 => {
+                watchpoint_value_register_0::write_raw(wvr);
+                watchpoint_control_register_0::write(wcr);
+            }
+            _ => {
+                watchpoint_value_register_1::write_raw(wvr);
+                watchpoint_control_register_1::write(wcr);
+            }
+        }
+    }
+}
+
+/// The program, halted in [`halt`].
+struct Halted<'a> {
+    /// Its registers, as the trampoline saved them.
+    frame: &'a mut [u32; REGISTERS],
+    watchpoints: &'a mut [Option<(WatchKind, u32, u32)>; 2],
+}
+impl Target for Halted<'_> {
+    fn registers(&mut self) -> [u32; REGISTERS] {
+        *self.frame
+    }
+
+    fn set_registers(&mut self, registers: &[u32; REGISTERS]) {
+        *self.frame = *registers;
+    }
+
+    fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool {
+        if !accessible(address, buf.len()) {
+            return false;
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let from = core::ptr::with_exposed_provenance::<u8>(address as usize + i);
+            *byte = unsafe { from.read_volatile() };
+        }
+        true
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+        if !accessible(address, data.len()) {
+            return false;
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            let to = core::ptr::with_exposed_provenance_mut::<u8>(address as usize + i);
+            unsafe { to.write_volatile(byte) };
+        }
+        dsb();
+        // it may have been code, e.g. a software breakpoint
+        unsafe { core::arch::asm!("mcr p15, 0, {t}, c7, c5, 0", t = in(reg) 0) };
+        prefetch_flush();
+        true
+    }
+
+    fn add_breakpoint(&mut self, address: u32) -> bool {
+        let Some(n) = (1..=5).find(|&n| !unsafe { breakpoint_control(n) }.enable()) else {
+            return false;
+        };
+        let bcr = Bcr(0)
+            .with_meaning(BvrMeaning::IMVAMatch as u8)
+            .with_supervisor_access(3)
+            .with_byte_address_select(0xf)
+            .with_enable(true);
+        unsafe { set_breakpoint(n, Bvr(address), bcr) };
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u32) -> bool {
+        let Some(n) = (1..=5).find(|&n| unsafe {
+            breakpoint_control(n).enable() && breakpoint_value(n) == Bvr(address)
+        }) else {
+            return false;
+        };
+        unsafe { set_breakpoint(n, Bvr(0), Bcr(0)) };
+        true
+    }
+
+    fn add_watchpoint(&mut self, kind: WatchKind, address: u32, len: u32) -> bool {
+        // a WRP watches bytes of one word
+        let offset = address & 3;
+        if !(1..=4).contains(&len) || offset + len > 4 {
+            return false;
+        }
+        let Some(n) = self.watchpoints.iter().position(Option::is_none) else {
+            return false;
+        };
+        let wcr = Wcr(0)
+            .with_byte_address_select((((1 << len) - 1) << offset) as u8)
+            .with_on_store(kind != WatchKind::Read)
+            .with_on_load(kind != WatchKind::Write)
+            .with_supervisor_access(3)
+            .with_enable(true);
+        unsafe { set_watchpoint(n, address & !3, wcr) };
+        self.watchpoints[n] = Some((kind, address, len));
+        true
+    }
+
+    fn remove_watchpoint(&mut self, kind: WatchKind, address: u32, len: u32) -> bool {
+        let watchpoint = Some((kind, address, len));
+        let Some(n) = self.watchpoints.iter().position(|&w| w == watchpoint) else {
+            return false;
+        };
+        unsafe { set_watchpoint(n, 0, Wcr(0)) };
+        self.watchpoints[n] = None;
+        true
+    }
+}
@@ -0,0 +1,13 @@
+use crate::steal_println;
+use bcm2835_lpa::Peripherals;
+use quartz::device::bcm2835::watchdog::RebootListener;
+
+/// Stay running until okdude asks for the device back, e.g. to upload the next program.
+pub fn wait_for_reboot() -> ! {
+    let peripherals = unsafe { Peripherals::steal() };
+    steal_println!("Waiting for okdude to reboot the device.");
+    let mut listener = RebootListener::new();
+    loop {
+        listener.poll(&peripherals.UART1, &peripherals.PM);
+    }
+}
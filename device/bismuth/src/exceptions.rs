@@ -77,6 +77,70 @@ macro_rules! define_dabt_trampoline {
     };
 }
 
+/// For debuggers: `$target` expects `fn(frame: *mut [u32; 17])`, where `frame` holds r0-r12, then
+/// the sp and lr of the mode that had the abort, then the address of the instruction that had it
+/// (`lr - $offset`: 4 for prefetch aborts, 8 for data aborts), then its cpsr; the registers and the
+/// address to resume at are restored from it once `$target` returns.
+#[macro_export]
+macro_rules! define_halt_trampoline {
+    ($name:ident, $target:ident, $offset:literal) => {
+        unsafe extern "C" { pub static $name: [u32; 0]; }
+        ::core::arch::global_asm!(
+        r#"
+            .globl {EXPORT_SYM}
+            .extern {TARGET_SYM}
+            {EXPORT_SYM}:
+                sub lr, lr, #{OFFSET}
+                srsdb sp!, #{ABORT_MODE}
+                sub sp, sp, #8
+                push {{r0-r12}}
+                @ keep the stack 8-byte aligned for the call
+                sub sp, sp, #4
+                @ user mode's sp and lr are system mode's
+                mrs r0, spsr
+                and r0, r0, #0x1f
+                cmp r0, #{USER_MODE}
+                moveq r0, #{SYSTEM_MODE}
+                mrs r1, cpsr
+                bic r2, r1, #0x1f
+                orr r2, r2, r0
+                msr cpsr_c, r2
+                mov r3, sp
+                mov r4, lr
+                msr cpsr_c, r1
+                add r0, sp, #56
+                stmia r0, {{r3, r4}}
+                add r0, sp, #4
+                bl {TARGET_SYM}
+                @ the target may have changed any of them, the mode included
+                add r0, sp, #56
+                ldmia r0, {{r3, r4}}
+                ldr r0, [sp, #68]
+                and r0, r0, #0x1f
+                cmp r0, #{USER_MODE}
+                moveq r0, #{SYSTEM_MODE}
+                mrs r1, cpsr
+                bic r2, r1, #0x1f
+                orr r2, r2, r0
+                msr cpsr_c, r2
+                mov sp, r3
+                mov lr, r4
+                msr cpsr_c, r1
+                add sp, sp, #4
+                pop {{r0-r12}}
+                add sp, sp, #8
+                rfeia sp!
+        "#,
+            EXPORT_SYM = sym $name,
+            TARGET_SYM = sym $target,
+            OFFSET = const $offset,
+            ABORT_MODE = const 0b10111,
+            USER_MODE = const 0b10000,
+            SYSTEM_MODE = const 0b11111,
+        );
+    }
+}
+
 /*
 This one looks a bit different; for a start, we can rely on register saving to work in our
 favor here.
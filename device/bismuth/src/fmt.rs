@@ -13,16 +13,19 @@ impl<'a> Uart1WriteProxy<'a> {
     pub fn flush(&mut self) {
         quartz::device::bcm2835::mini_uart::mini_uart1_flush_tx(self.inner);
     }
-}
-
-impl<'a> core::fmt::Write for Uart1WriteProxy<'a> {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
         dsb();
-        for &b in s.as_bytes() {
+        for &b in bytes {
             while !self.inner.stat().read().tx_ready().bit_is_set() {}
             self.inner.io().write(|w| unsafe { w.data().bits(b) });
         }
         dsb();
+    }
+}
+
+impl<'a> core::fmt::Write for Uart1WriteProxy<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
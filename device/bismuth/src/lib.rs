@@ -27,7 +27,8 @@ unsafe extern "C" {
     static __symbol_bss_end__: [u32; 0];
     static __symbol_stack_init__: [u32; 0];
 
-    static __symbol_args_begin__: [u8; 0x100];
+    static __symbol_args_begin__: [u8; 0];
+    static __symbol_args_end__: [u8; 0];
 }
 
 /*
@@ -85,26 +86,12 @@ static HEAP: embedded_alloc::TlsfHeap = embedded_alloc::TlsfHeap::empty();
 
 const DEFAULT_CLOCK_DIVIDER: u16 = baud_to_clock_divider(115200);
 
-fn arg_count() -> usize {
-    let arg_byte_slice = unsafe { &__symbol_args_begin__ };
-    let slice_count = u32::from_le_bytes(arg_byte_slice[0..4].try_into().unwrap()) as usize;
-    slice_count
-}
-
-fn get_nth_arg(idx: usize) -> Option<&'static str> {
-    let arg_byte_slice = unsafe { &__symbol_args_begin__ };
-    let slice_count = arg_count();
-    if idx >= slice_count {
-        return None;
-    }
-    let mut j = 4usize;
-    for _ in 0..idx {
-        let slice_len = u32::from_le_bytes(arg_byte_slice[j..j + 4].try_into().unwrap()) as usize;
-        j += 4 + ((slice_len + 3) & !3);
-    }
-    let len = u32::from_le_bytes(arg_byte_slice[j..j + 4].try_into().unwrap()) as usize;
-    let slice = &arg_byte_slice[j + 4..j + 4 + len];
-    Some(core::str::from_utf8(slice).expect("Invalid UTF-8 in argument"))
+/// The arguments that okdude put in `.data.args`.
+fn args() -> impl Iterator<Item = &'static str> {
+    let section = unsafe {
+        quartz::inject::section(__symbol_args_begin__.as_ptr(), __symbol_args_end__.as_ptr())
+    };
+    quartz::inject::args(section)
 }
 
 #[unsafe(no_mangle)]
@@ -131,18 +118,34 @@ pub extern "C" fn __kernel_start() -> ! {
 
     unsafe { HEAP.init(0x1000_0000, 0x1000_0000) };
 
-    if arg_count() > 0 {
-        let arg0 = get_nth_arg(0).expect("Failed to get nth argument");
+    let mut args = args();
+    let mut arg0 = args.next();
+    if arg0 == Some("gdb") {
+        // halt for GDB (`okdude --gdb`) before running the command
+        app::gdb::attach();
+        arg0 = args.next();
+    }
+    if let Some(arg0) = arg0 {
         match arg0 {
             "cpuid" => app::cpuid::dump_cpu_info(),
             "debug" => app::debug::interleave_checker(),
+            "idle" => app::idle::wait_for_reboot(),
             _ => {
                 steal_println!("unknown command {arg0}");
             }
         }
     }
 
-    quartz::device::bcm2835::mini_uart::mini_uart1_flush_tx(&peripherals.UART1);
+    exit(0);
+}
+
+/// Tell the host that the program has finished (`okdude --run-until` exits with `code`), then
+/// reboot.
+pub fn exit(code: i32) -> ! {
+    let peripherals = unsafe { Peripherals::steal() };
+    let mut proxy = Uart1WriteProxy::new(&peripherals.UART1);
+    okboot_common::device::Exit { code }.frame(|bytes| proxy.write_bytes(bytes));
+    proxy.flush();
 
     __kernel_restart();
 }
@@ -197,7 +200,8 @@ fn __kernel_panic_handler(info: &core::panic::PanicInfo) -> ! {
     let mut proxy = Uart1WriteProxy::new(&peripherals.UART1);
     let _ = core::fmt::write(&mut proxy, format_args!("{}\n", msg));
 
-    __kernel_restart();
+    // like a panicking test on the host
+    exit(101);
 }
 
 /// We don't have built-in support from `critical_section` crate, so we need to implement our own
@@ -23,6 +23,12 @@ impl<'a> ReceiveBuffer<'a> {
         self.cursor = 0;
         self.underlying_storage.fill(0);
     }
+    /// Forget the bytes of a partially received frame, without paying for [`clear`].
+    ///
+    /// [`clear`]: ReceiveBuffer::clear
+    pub fn discard(&mut self) {
+        self.cursor = 0;
+    }
     pub fn push_u8(&mut self, b: u8) -> Result<(), ReceiveError> {
         if self.cursor >= self.underlying_storage.len() {
             Err(ReceiveError::BufferOverflow)
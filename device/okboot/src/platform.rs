@@ -0,0 +1,135 @@
+//! Everything the protocol state machine needs from the machine it runs on.
+//!
+//! [`protocol::run`](crate::protocol::run) only talks to the outside world through a
+//! [`Platform`]: on the Raspberry Pi that's the Mini UART, the system timer, physical memory and
+//! the firmware's mailbox (`Bcm2835`); with the `sim` feature, [`sim`] provides a host implementation so that the state
+//! machine can be driven by `okdude` without any hardware.
+use core::ops::Range;
+use core::time::Duration;
+use okboot_common::fat::BlockDevice;
+use okboot_common::monitor::MemoryMap;
+
+#[cfg(not(feature = "sim"))]
+mod bcm2835;
+#[cfg(feature = "sim")]
+pub mod sim;
+
+#[cfg(not(feature = "sim"))]
+pub use bcm2835::Bcm2835;
+
+/// Snapshot of the serial line, read once per iteration of the protocol loop.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LineStatus {
+    /// A byte can be read with [`Transport::read_byte`].
+    pub data_ready: bool,
+    /// A byte can be written with [`Transport::write_byte`].
+    pub tx_ready: bool,
+    /// Received bytes were dropped since the last time the line status was read.
+    pub overrun: bool,
+}
+
+/// Byte-oriented serial line to the host.
+pub trait Transport {
+    fn line_status(&self) -> LineStatus;
+    /// Only valid if the last [`LineStatus`] had `data_ready` set.
+    fn read_byte(&self) -> u8;
+    /// Only valid if the last [`LineStatus`] had `tx_ready` set.
+    fn write_byte(&self, byte: u8);
+    /// Block until everything written so far has actually been sent.
+    fn flush(&self);
+    /// The baud rate the line would actually run at if asked for `baud_rate`, if it can at all.
+    fn closest_baud_rate(&self, baud_rate: u32) -> Option<u32>;
+    /// Switch to (the closest approximation of) `baud_rate`, dropping anything not yet received.
+    fn set_baud_rate(&self, baud_rate: u32) -> bool;
+}
+
+/// Programs have to be loaded below this address; okboot's heap starts here (see `rt`).
+pub const LOAD_END: usize = 0x1000_0000;
+/// Size of okboot's heap, which runs up to where [`Memory::retained`] starts on the Raspberry Pi.
+pub const HEAP_SIZE: usize = 0x0800_0000;
+/// Largest ELF file that okboot takes: the whole file is kept on the heap while its segments are
+/// loaded, and the rest of the heap is left for okboot itself.
+pub const MAX_ELF_LEN: usize = 0x0700_0000;
+
+/// Free-running microsecond clock.
+pub trait Clock {
+    fn micros(&self) -> u64;
+
+    /// Blocking wait for (at least) `duration`.
+    fn delay(&self, duration: Duration) {
+        let start = Instant::now(self);
+        while start.elapsed(self) < duration {}
+    }
+}
+
+/// Memory that programs are loaded into.
+pub trait Memory {
+    /// First address past okboot's own image; programs loaded below it have to be relocated.
+    fn image_end(&self) -> usize;
+
+    /// Memory that survives a soft reboot and is left alone by okboot itself, where the last
+    /// uploaded image is kept as the base for delta uploads. May be empty.
+    fn retained(&self) -> Range<usize>;
+
+    /// What okdude's `mem` commands may touch: what exists, and what okboot is using itself.
+    fn memory_map(&self) -> MemoryMap;
+
+    /// # Safety
+    /// `address..address + bytes.len()` must not overlap with anything okboot is still using.
+    unsafe fn write(&self, address: usize, bytes: &[u8]);
+
+    /// # Safety
+    /// `address..address + len` must not be written to while the returned slice is alive.
+    unsafe fn read(&self, address: usize, len: usize) -> &[u8];
+
+    /// # Safety
+    /// `address` must be that of a peripheral register, in the
+    /// [`memory_map`](Self::memory_map)'s `peripherals`.
+    unsafe fn read_register(&self, address: usize) -> u32;
+
+    /// # Safety
+    /// As for [`read_register`](Self::read_register).
+    unsafe fn write_register(&self, address: usize, value: u32);
+}
+
+/// The board itself, as opposed to any one peripheral.
+pub trait Board {
+    /// Tells this board apart from others; zero if it can't be read.
+    fn serial_number(&self) -> u64;
+    /// Which model the board is, as the firmware encodes it; zero if it can't be read.
+    fn board_revision(&self) -> u32;
+    /// The CPU's Main ID register.
+    fn cpu_id(&self) -> u32;
+    /// Ed25519 public key that programs must be signed with, if any.
+    fn verifying_key(&self) -> Option<[u8; 32]> {
+        crate::protocol::VERIFYING_KEY
+    }
+}
+
+/// The SD card, where programs are [kept](okboot_common::persist) across power cycles.
+pub trait Storage {
+    /// The card, if there is one and it could be brought up.
+    fn card(&self) -> Option<&dyn BlockDevice>;
+    /// How long to wait for a host before booting the program kept on the card, if ever.
+    fn sd_boot_timeout(&self) -> Option<Duration> {
+        crate::timeouts::SD_BOOT
+    }
+}
+
+pub trait Platform: Transport + Clock + Memory + Board + Storage {}
+impl<T: Transport + Clock + Memory + Board + Storage> Platform for T {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Instant {
+    micros: u64,
+}
+impl Instant {
+    pub fn now(clock: &(impl Clock + ?Sized)) -> Self {
+        Self {
+            micros: clock.micros(),
+        }
+    }
+    pub fn elapsed(&self, clock: &(impl Clock + ?Sized)) -> Duration {
+        Duration::from_micros(clock.micros().wrapping_sub(self.micros))
+    }
+}
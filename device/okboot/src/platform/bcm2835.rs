@@ -0,0 +1,146 @@
+use crate::platform::{Board, Clock, HEAP_SIZE, LOAD_END, LineStatus, Memory, Storage, Transport};
+use bcm2835_lpa::Peripherals;
+use core::ops::Range;
+use core::time::Duration;
+use okboot_common::fat::BlockDevice;
+use okboot_common::monitor::{MemoryMap, Region};
+use quartz::arch::arm1176::{cpuid, dsb};
+use quartz::device::bcm2835::emmc::Card;
+use quartz::device::bcm2835::mailbox;
+use quartz::device::bcm2835::mini_uart::{
+    checked_baud_to_clock_divider, clock_divider_to_baud, mini_uart1_flush_tx, mini_uart1_set_clock,
+};
+use quartz::device::bcm2835::timing::{__floating_time, delay_micros};
+
+/// Where the peripherals' registers are, as the ARM core sees them.
+const PERIPHERALS: Region = Region::new(0x2000_0000, 0x2100_0000);
+/// The auxiliary peripherals' registers, the Mini UART's among them.
+const AUX: Region = Region::new(0x2021_5000, 0x2021_5100);
+
+/// The Raspberry Pi: Mini UART, system timer, physical memory, the firmware's mailbox, and the SD
+/// card if it could be brought up.
+pub struct Bcm2835<'a> {
+    peripherals: &'a Peripherals,
+    card: Option<Card<'a>>,
+}
+impl<'a> Bcm2835<'a> {
+    pub fn new(peripherals: &'a Peripherals, card: Option<Card<'a>>) -> Self {
+        Self { peripherals, card }
+    }
+}
+
+impl Transport for Bcm2835<'_> {
+    fn line_status(&self) -> LineStatus {
+        dsb();
+        let lsr = self.peripherals.UART1.lsr().read();
+        LineStatus {
+            data_ready: lsr.data_ready().bit_is_set(),
+            tx_ready: lsr.tx_empty().bit_is_set(),
+            overrun: lsr.rx_overrun().bit_is_set(),
+        }
+    }
+
+    fn read_byte(&self) -> u8 {
+        dsb();
+        let byte = self.peripherals.UART1.io().read().data().bits();
+        dsb();
+        byte
+    }
+
+    fn write_byte(&self, byte: u8) {
+        self.peripherals
+            .UART1
+            .io()
+            .write(|w| unsafe { w.data().bits(byte) });
+        dsb();
+    }
+
+    fn flush(&self) {
+        mini_uart1_flush_tx(&self.peripherals.UART1);
+    }
+
+    fn closest_baud_rate(&self, baud_rate: u32) -> Option<u32> {
+        checked_baud_to_clock_divider(baud_rate).map(clock_divider_to_baud)
+    }
+
+    fn set_baud_rate(&self, baud_rate: u32) -> bool {
+        checked_baud_to_clock_divider(baud_rate)
+            .is_some_and(|divider| mini_uart1_set_clock(&self.peripherals.UART1, divider))
+    }
+}
+
+impl Clock for Bcm2835<'_> {
+    fn micros(&self) -> u64 {
+        __floating_time(&self.peripherals.SYSTMR)
+    }
+
+    fn delay(&self, duration: Duration) {
+        delay_micros(&self.peripherals.SYSTMR, duration.as_micros() as u64);
+    }
+}
+
+impl Memory for Bcm2835<'_> {
+    fn image_end(&self) -> usize {
+        unsafe { crate::stub::locate_end() }.addr()
+    }
+
+    fn retained(&self) -> Range<usize> {
+        // just past the heap (see `rt`), and below the GPU's share of memory
+        0x1800_0000..0x1c00_0000
+    }
+
+    fn memory_map(&self) -> MemoryMap {
+        let start = unsafe { crate::stub::locate_start() }.addr();
+        MemoryMap {
+            // leaving out the first page, so that nothing goes through a null pointer
+            ram: Region::new(0x1000, self.retained().end as u32),
+            peripherals: PERIPHERALS,
+            okboot: Region::new(start as u32, self.image_end() as u32),
+            heap: Region::new(LOAD_END as u32, (LOAD_END + HEAP_SIZE) as u32),
+            uart: AUX,
+        }
+    }
+
+    unsafe fn write(&self, address: usize, bytes: &[u8]) {
+        let dst = core::ptr::with_exposed_provenance_mut::<u8>(address);
+        unsafe { core::ptr::copy(bytes.as_ptr(), dst, bytes.len()) };
+    }
+
+    unsafe fn read(&self, address: usize, len: usize) -> &[u8] {
+        let src = core::ptr::with_exposed_provenance::<u8>(address);
+        unsafe { core::slice::from_raw_parts(src, len) }
+    }
+
+    unsafe fn read_register(&self, address: usize) -> u32 {
+        dsb();
+        let value = unsafe { core::ptr::with_exposed_provenance::<u32>(address).read_volatile() };
+        dsb();
+        value
+    }
+
+    unsafe fn write_register(&self, address: usize, value: u32) {
+        dsb();
+        unsafe { core::ptr::with_exposed_provenance_mut::<u32>(address).write_volatile(value) };
+        dsb();
+    }
+}
+
+impl Board for Bcm2835<'_> {
+    fn serial_number(&self) -> u64 {
+        mailbox::board_serial(&self.peripherals.VCMAILBOX).unwrap_or(0)
+    }
+
+    fn board_revision(&self) -> u32 {
+        mailbox::board_revision(&self.peripherals.VCMAILBOX).unwrap_or(0)
+    }
+
+    fn cpu_id(&self) -> u32 {
+        cpuid::main_id::read_raw()
+    }
+}
+
+impl Storage for Bcm2835<'_> {
+    fn card(&self) -> Option<&dyn BlockDevice> {
+        self.card.as_ref().map(|card| card as &dyn BlockDevice)
+    }
+}
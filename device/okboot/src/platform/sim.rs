@@ -0,0 +1,338 @@
+//! Host simulation of okboot, for testing `okdude` end-to-end without a Raspberry Pi.
+//!
+//! A [`Simulator`] runs the same protocol state machine as the device, over any [`Transport`] (in
+//! practice, one end of a pseudoterminal that `okdude` opens as its TTY), with the host's clock
+//! and a block of simulated memory to load programs into. Instead of jumping to the program, it
+//! returns what would have been booted.
+use crate::platform::{Board, Clock, LOAD_END, LineStatus, Memory, Storage, Transport};
+use crate::protocol::{self, AllocatedBuffers, Booter, Exit};
+use okboot_common::fat::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use okboot_common::monitor::{MemoryMap, Region};
+use std::cell::{RefCell, UnsafeCell};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Size of the simulated memory; programs have to be loaded below [`LOAD_END`] anyway, and
+/// [`RETAINED`] comes after that.
+pub const MEMORY_SIZE: usize = 0x1100_0000;
+/// Simulated memory that survives a soft reboot, see [`Simulator::with_memory`].
+pub const RETAINED: Range<usize> = LOAD_END..MEMORY_SIZE;
+/// Where the simulated okboot image starts, as the real one does.
+pub const IMAGE_START: usize = 0x8000;
+/// Where the simulated okboot image ends; roughly where the real one does.
+pub const IMAGE_END: usize = 0x10_0000;
+/// How many times slower than real time the simulated clock runs. okboot's timeouts are derived
+/// from the baud rate, but a pseudoterminal doesn't pace bytes like a UART does, and neither end is
+/// scheduled anywhere near as promptly as the device's busy loop.
+pub const TIME_SCALE: u64 = 10;
+/// Serial number that the simulated board reports, unless told otherwise with
+/// [`Simulator::with_serial_number`].
+pub const SERIAL_NUMBER: u64 = 0x0000_0000_0c0f_fee5;
+/// Revision code that the simulated board reports: a Raspberry Pi Zero with 512MB of memory.
+pub const BOARD_REVISION: u32 = 0x0090_0093;
+/// Main ID register of the simulated CPU: an ARM1176JZF-S, as on the real board.
+pub const CPU_ID: u32 = 0x410f_b767;
+
+/// What the relocation stubs would have left in memory, and where they would have jumped to.
+pub struct Booted {
+    pub entry: usize,
+    pub memory: Box<[u8]>,
+    /// The SD card, as okboot left it, if the board had one.
+    pub card: Option<RamDisk>,
+}
+
+pub struct Simulator<T> {
+    transport: T,
+    started: std::time::Instant,
+    memory: UnsafeCell<Box<[u8]>>,
+    serial_number: u64,
+    card: Option<RamDisk>,
+    sd_boot_timeout: Option<Duration>,
+    verifying_key: Option<[u8; 32]>,
+}
+impl<T: Transport> Simulator<T> {
+    pub fn new(transport: T) -> Self {
+        Self::with_memory(transport, vec![0; MEMORY_SIZE].into_boxed_slice())
+    }
+
+    /// Start with the `memory` that a previous run [`Booted`] with, as if the device had been
+    /// soft-rebooted.
+    pub fn with_memory(transport: T, memory: Box<[u8]>) -> Self {
+        assert_eq!(memory.len(), MEMORY_SIZE);
+        Self {
+            transport,
+            started: std::time::Instant::now(),
+            memory: UnsafeCell::new(memory),
+            serial_number: SERIAL_NUMBER,
+            card: None,
+            sd_boot_timeout: None,
+            verifying_key: None,
+        }
+    }
+
+    /// Report `serial_number` as the board's, to tell simulated boards apart.
+    pub fn with_serial_number(mut self, serial_number: u64) -> Self {
+        self.serial_number = serial_number;
+        self
+    }
+
+    /// Give the board an SD card.
+    pub fn with_card(mut self, card: RamDisk) -> Self {
+        self.card = Some(card);
+        self
+    }
+
+    /// Boot the program kept on the card if no host turns up within `timeout`, as measured by the
+    /// simulated clock; unlike the real okboot, the simulator waits forever unless told to.
+    pub fn with_sd_boot_timeout(mut self, timeout: Duration) -> Self {
+        self.sd_boot_timeout = Some(timeout);
+        self
+    }
+
+    /// Only boot programs signed with the Ed25519 key that `key` is the public half of; unlike
+    /// the real okboot, the simulator ignores `OKBOOT_VERIFYING_KEY`.
+    pub fn with_verifying_key(mut self, key: [u8; 32]) -> Self {
+        self.verifying_key = Some(key);
+        self
+    }
+
+    /// Run the protocol until the host has uploaded a program and acknowledged that it's being
+    /// booted. Returns `None` if the host falls back to SU-BOOT, which isn't simulated.
+    pub fn run(self) -> Option<Booted> {
+        let mut receive_buffer = vec![0; protocol::RECEIVE_BUFFER_SIZE];
+        let mut transmit_buffer = vec![0; protocol::TRANSMIT_BUFFER_SIZE];
+        let mut staging_buffer = vec![0; protocol::STAGING_BUFFER_SIZE];
+        let mut cobs_encode_buffer = vec![0; protocol::COBS_ENCODE_BUFFER_SIZE];
+        let mut inflate_buffer = vec![0; protocol::INFLATE_BUFFER_SIZE];
+        let buffers = AllocatedBuffers {
+            receive_buffer: &mut receive_buffer,
+            transmit_buffer: &mut transmit_buffer,
+            staging_buffer: &mut staging_buffer,
+            cobs_encode_buffer: &mut cobs_encode_buffer,
+            inflate_buffer: &mut inflate_buffer,
+        };
+        let booter = match protocol::run(&self, buffers) {
+            Exit::Boot(booter) => booter,
+            Exit::Legacy => return None,
+        };
+
+        let mut memory = self.memory.into_inner();
+        let entry = match booter {
+            Booter::Relocation { relocation } => {
+                // what the relocation stub does
+                let side_buffer = relocation.side_buffer;
+                let len = relocation.relocate_first_n_bytes;
+                memory.copy_within(side_buffer..side_buffer + len, relocation.base_address);
+                relocation.entry
+            }
+            Booter::Segments {
+                relocation,
+                segments,
+            } => {
+                // what the segment relocation stub does; it hangs rather than jump if a segment
+                // doesn't match its CRC
+                let side_buffer = relocation.side_buffer;
+                let len = relocation.relocate_first_n_bytes;
+                memory.copy_within(side_buffer..side_buffer + len, relocation.base_address);
+                for segment in segments {
+                    let (address, len) = (segment.address as usize, segment.len as usize);
+                    let crc = crc32fast::hash(&memory[address..address + len]);
+                    assert_eq!(
+                        crc, segment.crc,
+                        "relocation stub hung: CRC mismatch in segment at {address:#x}"
+                    );
+                }
+                relocation.entry
+            }
+            Booter::Elf {
+                program_headers,
+                elf,
+                entry,
+            } => {
+                // what the ELF stub does
+                for phdr in program_headers {
+                    let (offset, vaddr) = (phdr.p_offset as usize, phdr.p_vaddr as usize);
+                    let (filesz, memsz) = (phdr.p_filesz as usize, phdr.p_memsz as usize);
+                    memory[vaddr..vaddr + filesz].copy_from_slice(&elf[offset..offset + filesz]);
+                    memory[vaddr + filesz..vaddr + memsz].fill(0);
+                }
+                entry
+            }
+            Booter::Jump { entry } => entry,
+        };
+        Some(Booted {
+            entry,
+            memory,
+            card: self.card,
+        })
+    }
+}
+
+/// A simulated SD card, held in memory.
+pub struct RamDisk(RefCell<Box<[u8]>>);
+impl RamDisk {
+    /// A card of `sectors` blocks, with nothing on it.
+    pub fn new(sectors: u32) -> Self {
+        Self(RefCell::new(
+            vec![0; sectors as usize * SECTOR_SIZE].into_boxed_slice(),
+        ))
+    }
+
+    /// A card of `sectors` blocks, with an empty boot partition.
+    pub fn formatted(sectors: u32) -> Self {
+        let card = Self::new(sectors);
+        fat::format(&card, sectors).expect("failed to format simulated card");
+        card
+    }
+}
+impl BlockDevice for RamDisk {
+    fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = lba as usize * SECTOR_SIZE;
+        let bytes = self.0.borrow();
+        let sectors = bytes.get(start..start + buf.len());
+        buf.copy_from_slice(sectors.ok_or(BlockError::OutOfRange { lba })?);
+        Ok(())
+    }
+
+    fn write(&self, lba: u32, buf: &[u8]) -> Result<(), BlockError> {
+        let start = lba as usize * SECTOR_SIZE;
+        let mut bytes = self.0.borrow_mut();
+        let sectors = bytes.get_mut(start..start + buf.len());
+        sectors
+            .ok_or(BlockError::OutOfRange { lba })?
+            .copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for Simulator<T> {
+    fn line_status(&self) -> LineStatus {
+        self.transport.line_status()
+    }
+
+    fn read_byte(&self) -> u8 {
+        self.transport.read_byte()
+    }
+
+    fn write_byte(&self, byte: u8) {
+        self.transport.write_byte(byte)
+    }
+
+    fn flush(&self) {
+        self.transport.flush()
+    }
+
+    fn closest_baud_rate(&self, baud_rate: u32) -> Option<u32> {
+        self.transport.closest_baud_rate(baud_rate)
+    }
+
+    fn set_baud_rate(&self, baud_rate: u32) -> bool {
+        self.transport.set_baud_rate(baud_rate)
+    }
+}
+
+impl<T> Clock for Simulator<T> {
+    fn micros(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64 / TIME_SCALE
+    }
+
+    fn delay(&self, duration: Duration) {
+        // nothing is lost while waiting, so there's no need to stretch delays
+        std::thread::sleep(duration);
+    }
+}
+
+impl<T> Memory for Simulator<T> {
+    fn image_end(&self) -> usize {
+        IMAGE_END
+    }
+
+    fn retained(&self) -> Range<usize> {
+        RETAINED
+    }
+
+    fn memory_map(&self) -> MemoryMap {
+        // okboot's heap is the host's, and there are no peripherals
+        MemoryMap {
+            ram: Region::new(0, MEMORY_SIZE as u32),
+            peripherals: Region::EMPTY,
+            okboot: Region::new(IMAGE_START as u32, IMAGE_END as u32),
+            heap: Region::EMPTY,
+            uart: Region::EMPTY,
+        }
+    }
+
+    unsafe fn write(&self, address: usize, bytes: &[u8]) {
+        let memory = unsafe { &mut *self.memory.get() };
+        memory
+            .get_mut(address..address + bytes.len())
+            .expect("write outside of simulated memory")
+            .copy_from_slice(bytes);
+    }
+
+    unsafe fn read(&self, address: usize, len: usize) -> &[u8] {
+        let memory = unsafe { &*self.memory.get() };
+        memory
+            .get(address..address + len)
+            .expect("read outside of simulated memory")
+    }
+
+    unsafe fn read_register(&self, _address: usize) -> u32 {
+        unreachable!("the simulator has no peripherals")
+    }
+
+    unsafe fn write_register(&self, _address: usize, _value: u32) {
+        unreachable!("the simulator has no peripherals")
+    }
+}
+
+impl<T> Storage for Simulator<T> {
+    fn card(&self) -> Option<&dyn BlockDevice> {
+        self.card.as_ref().map(|card| card as &dyn BlockDevice)
+    }
+
+    fn sd_boot_timeout(&self) -> Option<Duration> {
+        self.sd_boot_timeout
+    }
+}
+
+impl<T> Board for Simulator<T> {
+    fn serial_number(&self) -> u64 {
+        self.serial_number
+    }
+
+    fn board_revision(&self) -> u32 {
+        BOARD_REVISION
+    }
+
+    fn cpu_id(&self) -> u32 {
+        CPU_ID
+    }
+
+    fn verifying_key(&self) -> Option<[u8; 32]> {
+        self.verifying_key
+    }
+}
+
+/// Stands in for the ticket lock that guards the log queue on the device.
+struct SimCriticalSection;
+critical_section::set_impl!(SimCriticalSection);
+
+static CRITICAL_SECTION_LOCK: AtomicBool = AtomicBool::new(false);
+
+unsafe impl critical_section::Impl for SimCriticalSection {
+    unsafe fn acquire() -> critical_section::RawRestoreState {
+        while CRITICAL_SECTION_LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        0
+    }
+
+    unsafe fn release(_token: critical_section::RawRestoreState) {
+        CRITICAL_SECTION_LOCK.store(false, Ordering::Release);
+    }
+}
@@ -1,23 +1,33 @@
 mod handshake;
+mod monitor;
+mod persist;
 mod v2;
 
 use crate::buf::{FrameSink, ReceiveBuffer, TransmitBuffer};
-use crate::{legacy_print_string, legacy_print_string_blocking, timeouts};
-use bcm2835_lpa::{Peripherals, SYSTMR, UART1};
-use core::arch::asm;
+use crate::platform::{Instant, LOAD_END, MAX_ELF_LEN, Platform};
+use crate::{legacy_print_string, timeouts};
+#[cfg(not(feature = "sim"))]
 use core::cell::UnsafeCell;
 use core::time::Duration;
+use okboot_common::compression::Codecs;
+use okboot_common::device::DeviceInfo;
 use okboot_common::frame::{BufferedEncoder, FrameError, FrameHeader, FrameLayer, FrameOutput};
+use okboot_common::host::Formats;
+use okboot_common::persist::Slot;
 use okboot_common::{COBS_XOR, INITIAL_BAUD_RATE};
-use quartz::arch::arm1176::dsb;
-use quartz::device::bcm2835::mini_uart::mini_uart1_flush_tx;
-use quartz::device::bcm2835::timing::{self, Instant};
 use thiserror::Error;
 
 use handshake::Handshake;
 use v2::V2;
 
-const COBS_ENCODE_BUFFER_SIZE: usize = 255;
+pub use v2::Booter;
+pub(crate) use v2::VERIFYING_KEY;
+
+pub(crate) const TRANSMIT_BUFFER_SIZE: usize = 0x10000;
+pub(crate) const RECEIVE_BUFFER_SIZE: usize = 0x10000;
+pub(crate) const STAGING_BUFFER_SIZE: usize = 0x10000;
+pub(crate) const COBS_ENCODE_BUFFER_SIZE: usize = 255;
+pub(crate) const INFLATE_BUFFER_SIZE: usize = 0x20000;
 
 #[enum_dispatch::enum_dispatch]
 #[derive(Debug)]
@@ -39,7 +49,7 @@ pub trait Protocol {
         payload: &[u8],
         frame_sink: &mut FrameSink,
         timeouts: &mut Timeouts,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
         inflate_buffer: &mut [u8],
     ) -> ProtocolStatus;
 
@@ -47,35 +57,33 @@ pub trait Protocol {
         &mut self,
         frame_sink: &mut FrameSink,
         timeouts: &mut Timeouts,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
     ) -> ProtocolStatus;
 }
 
-pub fn flush_to_fifo(sink: &mut FrameSink, uart: &UART1) {
-    dsb();
+pub fn flush_to_fifo(sink: &mut FrameSink, platform: &dyn Platform) {
     while let Some(b) = sink.buffer_mut().shift_byte() {
-        while uart.stat().read().tx_ready().bit_is_clear() {}
-        uart.io().write(|w| unsafe { w.data().bits(b) })
+        while !platform.line_status().tx_ready {}
+        platform.write_byte(b);
     }
-    dsb();
 }
 
 struct GetProgInfoSender {
     last_sent_at: Instant,
 }
 impl GetProgInfoSender {
-    pub fn new(st: &SYSTMR) -> Self {
+    pub fn new(clock: &dyn Platform) -> Self {
         Self {
-            last_sent_at: Instant::now(st),
+            last_sent_at: Instant::now(clock),
         }
     }
-    pub(crate) fn tick(&mut self, st: &SYSTMR, fs: &mut FrameSink) -> bool {
-        if self.last_sent_at.elapsed(st) >= timeouts::GET_PROG_INFO_INTERVAL
+    pub(crate) fn tick(&mut self, clock: &dyn Platform, fs: &mut FrameSink) -> bool {
+        if self.last_sent_at.elapsed(clock) >= timeouts::GET_PROG_INFO_INTERVAL
             && fs.buffer().is_empty()
         {
             static GET_PROG_INFO: &[u8] = &[0x22, 0x22, 0x11, 0x11];
             fs.buffer_mut().extend_from_slice(GET_PROG_INFO);
-            self.last_sent_at = timing::Instant::now(st);
+            self.last_sent_at = Instant::now(clock);
             true
         } else {
             false
@@ -83,25 +91,45 @@ impl GetProgInfoSender {
     }
 }
 
-pub fn run(peripherals: &Peripherals) {
-    let mut sp: u32;
-    unsafe {
-        asm!(
-        "mov {t}, sp",
-        "wfe",
-        t = out(reg) sp
-        );
+/// Why [`run`] returned.
+#[derive(Debug)]
+pub enum Exit {
+    /// The host acknowledged that the program is about to be booted.
+    Boot(Booter),
+    /// The host only speaks SU-BOOT, and has just sent `PUT_PROG_INFO`.
+    Legacy,
+}
+
+/// The [`DeviceInfo`] that answers an `Identify`, and that program metadata is checked against.
+pub(crate) fn device_info(platform: &dyn Platform) -> DeviceInfo<'static> {
+    DeviceInfo {
+        serial: platform.serial_number(),
+        board_revision: platform.board_revision(),
+        cpu_id: platform.cpu_id(),
+        version: env!("CARGO_PKG_VERSION"),
+        // e.g. `OKBOOT_GIT_HASH=$(git rev-parse --short HEAD) cargo build`
+        git_hash: option_env!("OKBOOT_GIT_HASH").unwrap_or_default(),
+        image_end: platform.image_end() as u32,
+        load_end: LOAD_END as u32,
+        max_elf_len: MAX_ELF_LEN as u32,
+        formats: Formats::all(),
+        codecs: Codecs::built_in(),
+        memory_map: platform.memory_map(),
+        max_persist_len: match platform.card() {
+            Some(_) => v2::base_image_capacity(platform) as u32,
+            None => 0,
+        },
     }
-    legacy_print_string_blocking!(&peripherals.UART1, "<SP={sp:08x}>");
-    let uart = &peripherals.UART1;
+}
 
+pub fn run(platform: &dyn Platform, buffers: AllocatedBuffers) -> Exit {
     let AllocatedBuffers {
         receive_buffer,
         transmit_buffer,
         staging_buffer,
         cobs_encode_buffer,
         inflate_buffer,
-    } = unsafe { STATIC_BUFFERS.get() };
+    } = buffers;
 
     let mut frame_sink = {
         let tx_buffer = TransmitBuffer::new(transmit_buffer);
@@ -110,9 +138,11 @@ pub fn run(peripherals: &Peripherals) {
         FrameSink::new(tx_buffer, cobs_encoder, px_buffer)
     };
 
+    v2::validate_base_image(platform);
+
     legacy_print_string!(&mut frame_sink, "[device]: starting state machine\n");
-    flush_to_fifo(&mut frame_sink, uart);
-    mini_uart1_flush_tx(uart);
+    flush_to_fifo(&mut frame_sink, platform);
+    platform.flush();
 
     enum ReceiveState {
         Waiting {
@@ -124,9 +154,9 @@ pub fn run(peripherals: &Peripherals) {
         },
     }
     impl ReceiveState {
-        pub fn error(systmr: &SYSTMR, error: ReceiveError) -> Self {
+        pub fn error(clock: &dyn Platform, error: ReceiveError) -> Self {
             Self::Error {
-                at_instant: Instant::now(systmr),
+                at_instant: Instant::now(clock),
                 receive_error: Some(error),
             }
         }
@@ -136,12 +166,15 @@ pub fn run(peripherals: &Peripherals) {
     let mut decoder = FrameLayer::new(COBS_XOR);
 
     let mut timeouts = Timeouts::new_8n1(INITIAL_BAUD_RATE);
-    let mut last_byte_received = Instant::now(&peripherals.SYSTMR);
-    let mut last_packet_received = Instant::now(&peripherals.SYSTMR);
+    let mut last_byte_received = Instant::now(platform);
+    let mut last_packet_received = Instant::now(platform);
     let mut recv_state = ReceiveState::Waiting { initial: true };
-    let mut gpi_sender = GetProgInfoSender::new(&peripherals.SYSTMR);
+    let mut gpi_sender = GetProgInfoSender::new(platform);
     let mut protocol = ProtocolEnum::Handshake(Handshake::default());
     let mut frame_header = None;
+    // until a host turns up, at which point it's up to the host
+    let mut sd_boot_timeout = platform.card().and(platform.sd_boot_timeout());
+    let waiting_since = Instant::now(platform);
 
     legacy_print_string!(
         &mut frame_sink,
@@ -153,39 +186,58 @@ pub fn run(peripherals: &Peripherals) {
         // let tx_did_send = false;
         // -- end debug --
 
-        dsb();
-        let lsr = uart.lsr().read();
-
-        let data_available = lsr.data_ready().bit_is_set();
-        let can_write = lsr.tx_empty().bit_is_set();
-        let is_overrun = lsr.rx_overrun().bit_is_set();
+        let line_status = platform.line_status();
 
-        if can_write {
+        if line_status.tx_ready {
             if let Some(b) = frame_sink.buffer_mut().shift_byte() {
-                uart.io().write(|w| unsafe { w.data().bits(b) });
+                platform.write_byte(b);
                 // tx_did_send = true;
             }
         }
-        dsb();
 
-        if is_overrun {
-            recv_state = ReceiveState::error(&peripherals.SYSTMR, ReceiveError::FifoOverrun);
+        if line_status.overrun {
+            recv_state = ReceiveState::error(platform, ReceiveError::FifoOverrun);
         }
-        let byte = if data_available {
-            dsb();
-            let byte = uart.io().read().data().bits();
-            dsb();
-
-            Some(byte)
+        let byte = if line_status.data_ready {
+            Some(platform.read_byte())
         } else {
             None
         };
 
         if matches!(recv_state, ReceiveState::Waiting { initial: true }) {
-            gpi_sender.tick(&peripherals.SYSTMR, &mut frame_sink);
+            gpi_sender.tick(platform, &mut frame_sink);
+        }
+
+        let sd_boot_due =
+            sd_boot_timeout.filter(|&timeout| waiting_since.elapsed(platform) >= timeout);
+        if let Some(timeout) = sd_boot_due {
+            sd_boot_timeout = None;
+            legacy_print_string!(
+                &mut frame_sink,
+                "[device]: no host after {timeout:?}, booting slot {} from the SD card",
+                Slot::DEFAULT
+            );
+            flush_to_fifo(&mut frame_sink, platform);
+            if let Some(booter) = v2::boot_from_card(&mut frame_sink, platform) {
+                legacy_print_string!(&mut frame_sink, "[device]: booting from the SD card");
+                flush_to_fifo(&mut frame_sink, platform);
+                platform.flush();
+                return Exit::Boot(booter);
+            }
+            legacy_print_string!(&mut frame_sink, "[device]: waiting for a host instead");
+        }
+
+        if let ProtocolStatus::Abend = protocol.heartbeat(&mut frame_sink, &mut timeouts, platform)
+        {
+            // as for a packet, e.g. when a streamed program can't be booted after all
+            protocol = ProtocolEnum::Handshake(Handshake::default());
+            recv_state = ReceiveState::error(platform, ReceiveError::Protocol);
         }
 
-        protocol.heartbeat(&mut frame_sink, &mut timeouts, peripherals);
+        // only V2 hosts know what to do with log messages; until then, they stay queued
+        if matches!(protocol, ProtocolEnum::V2(_)) && frame_sink.buffer().is_empty() {
+            crate::LOGGER.drain(|log| frame_sink.send(log).is_ok());
+        }
 
         recv_state = match (byte, recv_state) {
             (Some(b), ReceiveState::Waiting { initial: _ }) => {
@@ -194,12 +246,14 @@ pub fn run(peripherals: &Peripherals) {
                         match o {
                             FrameOutput::Skip => ReceiveState::Waiting { initial: false },
                             FrameOutput::Header(hdr) => {
+                                // a previous frame may have been cut short by a receive error
+                                rx_buffer.discard();
                                 frame_header = Some(hdr);
                                 ReceiveState::Waiting { initial: false }
                             }
                             FrameOutput::Payload(p) => match rx_buffer.push_u8(p) {
                                 Ok(_) => ReceiveState::Waiting { initial: false },
-                                Err(e) => ReceiveState::error(&peripherals.SYSTMR, e),
+                                Err(e) => ReceiveState::error(platform, e),
                             },
                             FrameOutput::Finished => {
                                 let frame_header = frame_header.take().unwrap();
@@ -211,45 +265,44 @@ pub fn run(peripherals: &Peripherals) {
                                     payload,
                                     &mut frame_sink,
                                     &mut timeouts,
-                                    peripherals,
+                                    platform,
                                     inflate_buffer,
                                 ) {
                                     ProtocolStatus::Continue => None,
                                     ProtocolStatus::Abcon => {
                                         // TODO
-                                        Some(ReceiveState::error(
-                                            &peripherals.SYSTMR,
-                                            ReceiveError::Protocol,
-                                        ))
+                                        Some(ReceiveState::error(platform, ReceiveError::Protocol))
                                     }
                                     ProtocolStatus::Abend => {
                                         protocol = ProtocolEnum::Handshake(Handshake::default());
                                         // TODO
-                                        Some(ReceiveState::error(
-                                            &peripherals.SYSTMR,
-                                            ReceiveError::Protocol,
-                                        ))
+                                        Some(ReceiveState::error(platform, ReceiveError::Protocol))
                                     }
                                     ProtocolStatus::Switch(pe) => {
                                         protocol = pe;
                                         None
                                     }
+                                    ProtocolStatus::Boot(booter) => {
+                                        flush_to_fifo(&mut frame_sink, platform);
+                                        platform.flush();
+                                        return Exit::Boot(booter);
+                                    }
                                 };
                                 rx_buffer.clear();
 
-                                last_packet_received = Instant::now(&peripherals.SYSTMR);
+                                if sd_boot_timeout.take().is_some() {
+                                    legacy_print_string!(
+                                        &mut frame_sink,
+                                        "[device]: host found, not booting from the SD card"
+                                    );
+                                }
+                                last_packet_received = Instant::now(platform);
                                 res.unwrap_or(ReceiveState::Waiting { initial: false })
                             }
                             FrameOutput::Legacy => {
                                 decoder.reset();
-                                // received PUT_PROG_INFO
-                                // handle legacy download
-
-                                crate::legacy::perform_download(&peripherals.UART1);
-
-                                // if legacy::perform_download actually returns, then assume program
-                                // state is hopelessly corrupted and return so we can reinit.
-                                return;
+                                // received PUT_PROG_INFO; the caller handles the legacy download
+                                return Exit::Legacy;
                             }
                             FrameOutput::LegacyPrintStringByte(_, _) => {
                                 decoder.reset();
@@ -257,13 +310,13 @@ pub fn run(peripherals: &Peripherals) {
                                     &mut frame_sink,
                                     "[device] received legacy PRINT_STRING from"
                                 );
-                                ReceiveState::error(&peripherals.SYSTMR, ReceiveError::Protocol)
+                                ReceiveState::error(platform, ReceiveError::Protocol)
                             }
                         }
                     }
                     Err(e) => {
                         decoder.reset();
-                        ReceiveState::error(&peripherals.SYSTMR, ReceiveError::Decode(e))
+                        ReceiveState::error(platform, ReceiveError::Decode(e))
                     }
                 };
                 r
@@ -284,7 +337,7 @@ pub fn run(peripherals: &Peripherals) {
                         "[device]: receive error: {receive_error}"
                     );
                 }
-                if at_instant.elapsed(&peripherals.SYSTMR) < timeouts.error_recovery {
+                if at_instant.elapsed(platform) < timeouts.error_recovery {
                     ReceiveState::Error {
                         at_instant,
                         receive_error: None,
@@ -298,8 +351,8 @@ pub fn run(peripherals: &Peripherals) {
             //       either did not receive a byte, OR we're in the initial preamble state and the
             //       wrong byte was received.
             (_, state) => {
-                let packet_elapsed = last_packet_received.elapsed(&peripherals.SYSTMR);
-                let byte_elapsed = last_byte_received.elapsed(&peripherals.SYSTMR);
+                let packet_elapsed = last_packet_received.elapsed(platform);
+                let byte_elapsed = last_byte_received.elapsed(platform);
 
                 let session_timeout = timeouts
                     .override_session_timeout
@@ -310,22 +363,29 @@ pub fn run(peripherals: &Peripherals) {
                     && !matches!(state, ReceiveState::Waiting { initial: true })
                     && byte_elapsed >= timeouts.byte_read
                 {
-                    last_packet_received = timing::Instant::now(&peripherals.SYSTMR);
+                    last_packet_received = Instant::now(platform);
                     legacy_print_string!(
                         &mut frame_sink,
                         "[device]: session expired after {packet_elapsed:?}, dumping."
                     );
-                    flush_to_fifo(&mut frame_sink, uart);
-                    mini_uart1_flush_tx(uart);
+                    flush_to_fifo(&mut frame_sink, platform);
+                    platform.flush();
                     timeouts = Timeouts::new_8n1(INITIAL_BAUD_RATE);
+                    // a reconnecting host starts over at the initial baud rate
+                    platform.set_baud_rate(INITIAL_BAUD_RATE);
 
-                    protocol = ProtocolEnum::Handshake(Handshake::default());
+                    protocol = ProtocolEnum::Handshake(match core::mem::take(&mut protocol) {
+                        ProtocolEnum::V2(v2) => {
+                            Handshake::suspending(v2, &mut frame_sink, platform)
+                        }
+                        ProtocolEnum::Handshake(handshake) => handshake.restart(),
+                    });
 
                     ReceiveState::Waiting { initial: true }
                 } else if byte_elapsed >= timeouts.byte_read
                     && !matches!(state, ReceiveState::Waiting { initial: true })
                 {
-                    last_byte_received = Instant::now(&peripherals.SYSTMR);
+                    last_byte_received = Instant::now(platform);
                     ReceiveState::Waiting { initial: false }
                 } else {
                     state
@@ -334,11 +394,12 @@ pub fn run(peripherals: &Peripherals) {
         };
 
         if byte.is_some() {
-            last_byte_received = Instant::now(&peripherals.SYSTMR);
+            last_byte_received = Instant::now(platform);
         }
     }
 }
 
+#[cfg(not(feature = "sim"))]
 pub struct StaticBuffers<const TX: usize, const RX: usize, const PX: usize, const IX: usize> {
     transmit: UnsafeCell<[u8; TX]>,
     receive: UnsafeCell<[u8; RX]>,
@@ -346,6 +407,7 @@ pub struct StaticBuffers<const TX: usize, const RX: usize, const PX: usize, cons
     cobs: UnsafeCell<[u8; COBS_ENCODE_BUFFER_SIZE]>,
     inflate: UnsafeCell<[u8; IX]>,
 }
+#[cfg(not(feature = "sim"))]
 impl<const TX: usize, const RX: usize, const PX: usize, const IX: usize>
     StaticBuffers<TX, RX, PX, IX>
 {
@@ -358,7 +420,7 @@ impl<const TX: usize, const RX: usize, const PX: usize, const IX: usize>
             inflate: UnsafeCell::new([0u8; IX]),
         }
     }
-    unsafe fn get(&self) -> AllocatedBuffers {
+    pub unsafe fn get(&self) -> AllocatedBuffers<'_> {
         // SAFETY:
         unsafe fn materialize<const N: usize>(b: &UnsafeCell<[u8; N]>) -> &'static mut [u8] {
             unsafe { (*b.get()).as_mut_slice() }
@@ -372,12 +434,19 @@ impl<const TX: usize, const RX: usize, const PX: usize, const IX: usize>
         }
     }
 }
+#[cfg(not(feature = "sim"))]
 unsafe impl<const TX: usize, const RX: usize, const PX: usize, const IX: usize> Sync
     for StaticBuffers<TX, RX, PX, IX>
 {
 }
-static STATIC_BUFFERS: StaticBuffers<0x10000, 0x10000, 0x10000, 0x20000> = StaticBuffers::new();
-struct AllocatedBuffers<'a> {
+#[cfg(not(feature = "sim"))]
+pub static STATIC_BUFFERS: StaticBuffers<
+    TRANSMIT_BUFFER_SIZE,
+    RECEIVE_BUFFER_SIZE,
+    STAGING_BUFFER_SIZE,
+    INFLATE_BUFFER_SIZE,
+> = StaticBuffers::new();
+pub struct AllocatedBuffers<'a> {
     pub receive_buffer: &'a mut [u8],
     pub transmit_buffer: &'a mut [u8],
     pub staging_buffer: &'a mut [u8],
@@ -425,4 +494,6 @@ pub enum ProtocolStatus {
     // Abnormal end, abort all processing and return to initial state.
     Abend,
     Switch(ProtocolEnum),
+    // The host has acknowledged the boot, return from the protocol loop.
+    Boot(Booter),
 }
@@ -1,29 +1,93 @@
 use crate::buf::FrameSink;
-use crate::legacy_print_string;
+use crate::platform::{Instant, Platform};
+use crate::protocol::v2::V2;
 use crate::protocol::{Protocol, ProtocolEnum, ProtocolStatus, Timeouts};
-use bcm2835_lpa::Peripherals;
-use okboot_common::device::AllowedVersions;
+use crate::{legacy_print_string, timeouts};
+use core::time::Duration;
+use okboot_common::device::{AllowedVersions, BaudProbeAck, BaudRateChoice};
 use okboot_common::frame::FrameHeader;
-use okboot_common::host::UseVersion;
-use okboot_common::{MessageType, SupportedProtocol};
-use quartz::device::bcm2835::mini_uart::baud_to_clock_divider;
+use okboot_common::host::{BaudProbe, ProposeBaudRates, UseVersion};
+use okboot_common::{INITIAL_BAUD_RATE, MessageType, SupportedProtocol};
 
-const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[okboot_common::SupportedProtocol::V2 as u32];
+const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[
+    okboot_common::SupportedProtocol::V2 as u32,
+    okboot_common::SupportedProtocol::V3 as u32,
+];
+
+/// How far (in percent) the actual baud rate may be from the requested one.
+// 1.5Mbaud, which V2 has always used, comes out at 1.5625Mbaud (~4.2% fast).
+const MAX_BAUD_ERROR_PERCENT: u64 = 5;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum Expecting {
     Probe,
     Version,
+    BaudProbe {
+        protocol_version: SupportedProtocol,
+        baud_rate: u32,
+        since: Instant,
+    },
+}
+
+/// Pick the first of `candidates` that the transport can run at closely enough, along with the
+/// baud rate it will actually run at.
+fn choose_baud_rate(
+    platform: &dyn Platform,
+    candidates: impl Iterator<Item = u32>,
+) -> Option<(u32, u32)> {
+    candidates
+        .filter_map(|baud_rate| {
+            let actual = platform.closest_baud_rate(baud_rate)?;
+            let error = (actual as u64).abs_diff(baud_rate as u64);
+            (error * 100 <= baud_rate as u64 * MAX_BAUD_ERROR_PERCENT)
+                .then_some((baud_rate, actual))
+        })
+        .next()
+}
+
+/// Drain the transmit buffer, then switch the transport to `baud_rate`.
+fn switch_baud_rate(frame_sink: &mut FrameSink, platform: &dyn Platform, baud_rate: u32) -> bool {
+    super::flush_to_fifo(frame_sink, platform);
+    platform.set_baud_rate(baud_rate)
 }
 
 #[derive(Debug)]
 pub struct Handshake {
     expecting: Expecting,
+    /// A transfer that was cut off when the previous session expired, and when that happened.
+    suspended: Option<(V2, Instant)>,
 }
 impl Default for Handshake {
     fn default() -> Self {
         Self {
             expecting: Expecting::Probe,
+            suspended: None,
+        }
+    }
+}
+impl Handshake {
+    /// Start over after `session` ended, keeping its transfer (if any) to be resumed by the next
+    /// session.
+    pub fn suspending(session: V2, frame_sink: &mut FrameSink, platform: &dyn Platform) -> Self {
+        if !session.is_resumable() {
+            return Self::default();
+        }
+        legacy_print_string!(
+            frame_sink,
+            "[device]: suspending transfer, can be resumed for {:?}",
+            timeouts::SESSION_RESUMABLE.at_baud_8n1(INITIAL_BAUD_RATE)
+        );
+        Self {
+            suspended: Some((session, Instant::now(platform))),
+            ..Self::default()
+        }
+    }
+
+    /// Start over, keeping any suspended transfer.
+    pub fn restart(self) -> Self {
+        Self {
+            suspended: self.suspended,
+            ..Self::default()
         }
     }
 }
@@ -34,7 +98,7 @@ impl Protocol for Handshake {
         payload: &[u8],
         frame_sink: &mut FrameSink,
         _timeouts: &mut Timeouts,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
         _inflate_buffer: &mut [u8],
     ) -> ProtocolStatus {
         match frame_header.message_type {
@@ -42,7 +106,8 @@ impl Protocol for Handshake {
                 if !matches!(self.expecting, Expecting::Probe) {
                     legacy_print_string!(
                         frame_sink,
-                        "[device]: received Handshake/Probe, expected Handshake/UseVersion"
+                        "[device]: received Handshake/Probe, expected {:?}",
+                        self.expecting
                     );
                     ProtocolStatus::Abcon
                 } else {
@@ -70,6 +135,41 @@ impl Protocol for Handshake {
                     }
                 }
             }
+            MessageType::Identify => {
+                // not part of the handshake as such, so it doesn't move it along either
+                match crate::buf::send(frame_sink, &super::device_info(platform)) {
+                    Err(e) => {
+                        legacy_print_string!(
+                            frame_sink,
+                            "[device]: failed to send Handshake/DeviceInfo: {}",
+                            e
+                        );
+                        ProtocolStatus::Abcon
+                    }
+                    Ok(()) => ProtocolStatus::Continue,
+                }
+            }
+            MessageType::MemRead
+            | MessageType::MemWrite
+            | MessageType::MemCrc
+            | MessageType::Jump => {
+                // okdude's `mem` commands; like Identify, they don't move the handshake along
+                super::monitor::handle_packet(
+                    frame_header.message_type,
+                    payload,
+                    frame_sink,
+                    platform,
+                )
+            }
+            MessageType::ListImages | MessageType::DeleteImage => {
+                // okdude's `images` commands, which don't move the handshake along either
+                super::persist::handle_packet(
+                    frame_header.message_type,
+                    payload,
+                    frame_sink,
+                    platform,
+                )
+            }
             MessageType::UseVersion => {
                 if !matches!(self.expecting, Expecting::Version) {
                     legacy_print_string!(
@@ -79,10 +179,15 @@ impl Protocol for Handshake {
                     return ProtocolStatus::Abend;
                 }
                 legacy_print_string!(frame_sink, "[device]: received Handshake/UseVersion");
+                // older hosts don't negotiate the baud rate
                 let use_version: UseVersion = match postcard::from_bytes(payload) {
                     Ok(x) => x,
                     Err(e) => {
-                        legacy_print_string!(frame_sink, "[device]: failed to receive Handshake/UseVersion: deserialization error: {}", e);
+                        legacy_print_string!(
+                            frame_sink,
+                            "[device]: failed to receive Handshake/UseVersion: deserialization error: {}",
+                            e
+                        );
                         return ProtocolStatus::Abend;
                     }
                 };
@@ -99,16 +204,13 @@ impl Protocol for Handshake {
                 };
 
                 let new_baud_rate = protocol_version.baud_rate();
-                let new_clock_divider = baud_to_clock_divider(new_baud_rate);
                 legacy_print_string!(
                     frame_sink,
-                    "[device]: setting baud rate to: {}Bd, divider={}",
-                    new_baud_rate,
-                    new_clock_divider
+                    "[device]: setting baud rate to: {}Bd",
+                    new_baud_rate
                 );
 
-                super::flush_to_fifo(frame_sink, &peripherals.UART1);
-                if !crate::mini_uart::mini_uart1_set_clock(&peripherals.UART1, new_clock_divider) {
+                if !switch_baud_rate(frame_sink, platform, new_baud_rate) {
                     legacy_print_string!(
                         frame_sink,
                         "[device]: setting baud rate failed (divider readback failed)"
@@ -118,23 +220,138 @@ impl Protocol for Handshake {
 
                 legacy_print_string!(
                     frame_sink,
-                    "[device:v{}]: set baud rate to: {}Bd, divider={}",
+                    "[device:v{}]: set baud rate to: {}Bd",
                     use_version.version,
-                    new_baud_rate,
-                    new_clock_divider
+                    new_baud_rate
                 );
 
-                quartz::device::bcm2835::timing::delay_millis(&peripherals.SYSTMR, 50);
+                platform.delay(Duration::from_millis(50));
                 crate::rpc_println!(
                     frame_sink,
                     "[device:v{}]: transitioned baud rate!",
                     use_version.version
                 );
 
-                ProtocolStatus::Switch(ProtocolEnum::V2(super::v2::V2::new(
-                    peripherals,
+                ProtocolStatus::Switch(switch_protocol(
+                    protocol_version,
+                    platform,
                     new_baud_rate,
-                )))
+                    self.suspended.take(),
+                ))
+            }
+            MessageType::ProposeBaudRates => {
+                if !matches!(self.expecting, Expecting::Version) {
+                    legacy_print_string!(
+                        frame_sink,
+                        "[device]: received Handshake/ProposeBaudRates, expected {:?}",
+                        self.expecting
+                    );
+                    return ProtocolStatus::Abend;
+                }
+                let proposal: ProposeBaudRates = match postcard::from_bytes(payload) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        legacy_print_string!(
+                            frame_sink,
+                            "[device]: failed to receive Handshake/ProposeBaudRates: deserialization error: {}",
+                            e
+                        );
+                        return ProtocolStatus::Abend;
+                    }
+                };
+                let protocol_version = match SupportedProtocol::try_from(proposal.version) {
+                    Ok(sp) => sp,
+                    Err(_) => {
+                        legacy_print_string!(
+                            frame_sink,
+                            "[device]: Handshake/ProposeBaudRates: unsupported version number: {}",
+                            proposal.version
+                        );
+                        return ProtocolStatus::Abend;
+                    }
+                };
+                let (baud_rate, actual_baud_rate) = choose_baud_rate(platform, proposal.iter())
+                    .unwrap_or_else(|| {
+                        legacy_print_string!(
+                            frame_sink,
+                            "[device]: none of the proposed baud rates are usable, staying at {}Bd",
+                            INITIAL_BAUD_RATE
+                        );
+                        (INITIAL_BAUD_RATE, INITIAL_BAUD_RATE)
+                    });
+                legacy_print_string!(
+                    frame_sink,
+                    "[device]: chose baud rate {}Bd (actual {}Bd)",
+                    baud_rate,
+                    actual_baud_rate
+                );
+                if let Err(e) = crate::buf::send(frame_sink, &BaudRateChoice { baud_rate }) {
+                    legacy_print_string!(
+                        frame_sink,
+                        "[device]: failed to send Handshake/BaudRateChoice: {}",
+                        e
+                    );
+                    return ProtocolStatus::Abend;
+                }
+                if !switch_baud_rate(frame_sink, platform, baud_rate) {
+                    legacy_print_string!(
+                        frame_sink,
+                        "[device]: setting baud rate failed (divider readback failed)"
+                    );
+                    return ProtocolStatus::Abend;
+                }
+                self.expecting = Expecting::BaudProbe {
+                    protocol_version,
+                    baud_rate,
+                    since: Instant::now(platform),
+                };
+                ProtocolStatus::Continue
+            }
+            MessageType::BaudProbe => {
+                let Expecting::BaudProbe {
+                    protocol_version,
+                    baud_rate,
+                    since: _,
+                } = self.expecting
+                else {
+                    legacy_print_string!(
+                        frame_sink,
+                        "[device]: received Handshake/BaudProbe, expected {:?}",
+                        self.expecting
+                    );
+                    return ProtocolStatus::Abend;
+                };
+                let probe: BaudProbe = match postcard::from_bytes(payload) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        legacy_print_string!(
+                            frame_sink,
+                            "[device]: failed to receive Handshake/BaudProbe: deserialization error: {}",
+                            e
+                        );
+                        return ProtocolStatus::Abcon;
+                    }
+                };
+                if let Err(e) = crate::buf::send(frame_sink, &BaudProbeAck { nonce: probe.nonce }) {
+                    legacy_print_string!(
+                        frame_sink,
+                        "[device]: failed to send Handshake/BaudProbeAck: {}",
+                        e
+                    );
+                    return ProtocolStatus::Abcon;
+                }
+                crate::rpc_println!(
+                    frame_sink,
+                    "[device:v{}]: verified baud rate {}Bd",
+                    protocol_version as u32,
+                    baud_rate
+                );
+                ProtocolStatus::Switch(switch_protocol(
+                    protocol_version,
+                    platform,
+                    baud_rate,
+                    self.suspended.take(),
+                ))
             }
             w => {
                 legacy_print_string!(
@@ -149,10 +366,51 @@ impl Protocol for Handshake {
 
     fn heartbeat(
         &mut self,
-        _frame_sink: &mut FrameSink,
+        frame_sink: &mut FrameSink,
         _timeouts: &mut Timeouts,
-        _peripherals: &Peripherals,
+        platform: &dyn Platform,
     ) -> ProtocolStatus {
+        if let Some((_, since)) = self.suspended
+            && since.elapsed(platform) >= timeouts::SESSION_RESUMABLE.at_baud_8n1(INITIAL_BAUD_RATE)
+        {
+            self.suspended = None;
+            legacy_print_string!(frame_sink, "[device]: suspended transfer expired");
+        }
+        if let Expecting::BaudProbe {
+            baud_rate, since, ..
+        } = self.expecting
+            && since.elapsed(platform) >= timeouts::BAUD_PROBE
+        {
+            // host couldn't hear us (or we couldn't hear it); go back to the initial baud rate
+            // and wait for it to retry with a slower one
+            self.expecting = Expecting::Probe;
+            if !switch_baud_rate(frame_sink, platform, INITIAL_BAUD_RATE) {
+                return ProtocolStatus::Abend;
+            }
+            legacy_print_string!(
+                frame_sink,
+                "[device]: no Handshake/BaudProbe at {}Bd, reverted to {}Bd",
+                baud_rate,
+                INITIAL_BAUD_RATE
+            );
+        }
         ProtocolStatus::Continue
     }
 }
+
+fn switch_protocol(
+    protocol_version: SupportedProtocol,
+    platform: &dyn Platform,
+    baud_rate: u32,
+    suspended: Option<(V2, Instant)>,
+) -> ProtocolEnum {
+    let pipelined = protocol_version == SupportedProtocol::V3;
+    let protocol = match suspended {
+        Some((transfer, _)) if transfer.is_pipelined() == pipelined => {
+            transfer.resume(platform, baud_rate)
+        }
+        _ if pipelined => V2::new_pipelined(platform, baud_rate),
+        _ => V2::new(platform, baud_rate),
+    };
+    ProtocolEnum::V2(protocol)
+}
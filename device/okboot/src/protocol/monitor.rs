@@ -0,0 +1,178 @@
+//! okboot's side of the [monitor](okboot_common::monitor): okdude's `mem` commands, which are
+//! answered while okboot waits for a handshake.
+use crate::buf::FrameSink;
+use crate::legacy_print_string;
+use crate::platform::Platform;
+use crate::protocol::ProtocolStatus;
+use crate::protocol::v2::Booter;
+use okboot_common::MessageType;
+use okboot_common::device::{Jumping, MemCrcResult, MemData, MemRefused};
+use okboot_common::host::{Jump, MemCrc, MemRead, MemWrite};
+use okboot_common::monitor::{Access, MAX_CHUNK, Refusal, Space};
+
+/// Carry out a [`MemRead`], [`MemWrite`], [`MemCrc`] or [`Jump`], if the platform's
+/// [`MemoryMap`](okboot_common::monitor::MemoryMap) allows it, and answer it.
+pub fn handle_packet(
+    message_type: MessageType,
+    payload: &[u8],
+    frame_sink: &mut FrameSink,
+    platform: &dyn Platform,
+) -> ProtocolStatus {
+    macro_rules! receive {
+        ($message:ty) => {
+            match postcard::from_bytes::<$message>(payload) {
+                Ok(message) => message,
+                Err(e) => {
+                    legacy_print_string!(
+                        frame_sink,
+                        "[device]: failed to receive Monitor/{:?}: deserialization error: {}",
+                        message_type,
+                        e
+                    );
+                    return ProtocolStatus::Abcon;
+                }
+            }
+        };
+    }
+
+    let answered = match message_type {
+        MessageType::MemRead => {
+            let MemRead { address, len } = receive!(MemRead);
+            read(frame_sink, platform, address, len)
+        }
+        MessageType::MemWrite => {
+            let MemWrite { address, bytes } = receive!(MemWrite);
+            write(frame_sink, platform, address, bytes)
+        }
+        MessageType::MemCrc => {
+            let MemCrc { address, len } = receive!(MemCrc);
+            platform
+                .memory_map()
+                .check(Access::Read, address, len)
+                .map(|space| send_crc(frame_sink, platform, space, address, len))
+        }
+        MessageType::Jump => {
+            let Jump { address } = receive!(Jump);
+            match platform.memory_map().check(Access::Jump, address, 0) {
+                Ok(_) => return jump(frame_sink, address),
+                Err(refusal) => Err(refusal),
+            }
+        }
+        _ => unreachable!("{message_type:?} isn't for the monitor"),
+    };
+
+    let sent = match answered {
+        Ok(sent) => sent,
+        Err(refusal) => {
+            legacy_print_string!(
+                frame_sink,
+                "[device]: refused Monitor/{:?}: {}",
+                message_type,
+                refusal
+            );
+            crate::buf::send(frame_sink, &MemRefused { refusal })
+        }
+    };
+    match sent {
+        Ok(()) => ProtocolStatus::Continue,
+        Err(e) => {
+            legacy_print_string!(
+                frame_sink,
+                "[device]: failed to answer Monitor/{:?}: {}",
+                message_type,
+                e
+            );
+            ProtocolStatus::Abcon
+        }
+    }
+}
+
+type Sent = Result<(), crate::buf::SendError>;
+
+fn read(
+    frame_sink: &mut FrameSink,
+    platform: &dyn Platform,
+    address: u32,
+    len: u32,
+) -> Result<Sent, Refusal> {
+    if len > MAX_CHUNK {
+        return Err(Refusal::TooLong { len });
+    }
+    let sent = match platform.memory_map().check(Access::Read, address, len)? {
+        Space::Ram => {
+            let bytes = unsafe { platform.read(address as usize, len as usize) };
+            crate::buf::send(frame_sink, &MemData { address, bytes })
+        }
+        Space::Peripherals => {
+            let mut buf = [0; MAX_CHUNK as usize];
+            let bytes = &mut buf[..len as usize];
+            for (offset, word) in (0..).step_by(4).zip(bytes.chunks_exact_mut(4)) {
+                let value = unsafe { platform.read_register((address + offset) as usize) };
+                word.copy_from_slice(&value.to_le_bytes());
+            }
+            crate::buf::send(frame_sink, &MemData { address, bytes })
+        }
+    };
+    Ok(sent)
+}
+
+fn write(
+    frame_sink: &mut FrameSink,
+    platform: &dyn Platform,
+    address: u32,
+    bytes: &[u8],
+) -> Result<Sent, Refusal> {
+    let len = bytes.len() as u32;
+    if len > MAX_CHUNK {
+        return Err(Refusal::TooLong { len });
+    }
+    let space = platform.memory_map().check(Access::Write, address, len)?;
+    match space {
+        Space::Ram => unsafe { platform.write(address as usize, bytes) },
+        Space::Peripherals => {
+            for (offset, word) in (0..).step_by(4).zip(bytes.chunks_exact(4)) {
+                let value = u32::from_le_bytes(word.try_into().unwrap());
+                unsafe { platform.write_register((address + offset) as usize, value) };
+            }
+        }
+    }
+    // for registers, this is what reads back, which isn't necessarily what was written
+    Ok(send_crc(frame_sink, platform, space, address, len))
+}
+
+fn jump(frame_sink: &mut FrameSink, address: u32) -> ProtocolStatus {
+    match crate::buf::send(frame_sink, &Jumping { address }) {
+        Ok(()) => ProtocolStatus::Boot(Booter::Jump {
+            entry: address as usize,
+        }),
+        Err(e) => {
+            legacy_print_string!(
+                frame_sink,
+                "[device]: failed to send Monitor/Jumping: {}",
+                e
+            );
+            ProtocolStatus::Abcon
+        }
+    }
+}
+
+fn send_crc(
+    frame_sink: &mut FrameSink,
+    platform: &dyn Platform,
+    space: Space,
+    address: u32,
+    len: u32,
+) -> Sent {
+    let mut hasher = crc32fast::Hasher::new();
+    match space {
+        Space::Ram => hasher.update(unsafe { platform.read(address as usize, len as usize) }),
+        Space::Peripherals => {
+            for offset in (0..len).step_by(4) {
+                let value = unsafe { platform.read_register((address + offset) as usize) };
+                hasher.update(&value.to_le_bytes());
+            }
+        }
+    }
+    let crc = hasher.finalize();
+    crate::buf::send(frame_sink, &MemCrcResult { address, len, crc })
+}
@@ -0,0 +1,236 @@
+//! Keeping images on the SD card: okdude's `images` commands, which are answered while okboot
+//! waits for a handshake, and the [`Job`] that writes an uploaded program to the card before it's
+//! booted, when the host asked for that in its [`Metadata`](okboot_common::host::Metadata); and
+//! [`find`]ing a kept image again, to boot it when no host turns up.
+use crate::buf::{FrameSink, SendError};
+use crate::legacy_print_string;
+use crate::platform::Platform;
+use crate::protocol::ProtocolStatus;
+use okboot_common::MessageType;
+use okboot_common::device::{ImageDeleted, ImagesListed, StorageFailed, StoredImage};
+use okboot_common::fat::{BlockDevice, BlockError, DirEntry, FatError, FileWriter, Volume};
+use okboot_common::host::DeleteImage;
+use okboot_common::persist::{ImageHeader, Slot};
+
+/// Bytes of an image written to the card per heartbeat, so that okboot keeps talking to the host
+/// while it writes.
+const STEP: usize = 0x8000;
+
+/// Answer a [`ListImages`](okboot_common::host::ListImages) or a [`DeleteImage`].
+pub fn handle_packet(
+    message_type: MessageType,
+    payload: &[u8],
+    frame_sink: &mut FrameSink,
+    platform: &dyn Platform,
+) -> ProtocolStatus {
+    let result = match message_type {
+        MessageType::ListImages => list(frame_sink, platform),
+        MessageType::DeleteImage => match postcard::from_bytes::<DeleteImage>(payload) {
+            Ok(DeleteImage { slot }) => delete(frame_sink, platform, slot),
+            Err(e) => {
+                legacy_print_string!(
+                    frame_sink,
+                    "[device]: failed to receive Storage/DeleteImage: deserialization error: {}",
+                    e
+                );
+                return ProtocolStatus::Abcon;
+            }
+        },
+        _ => unreachable!("{message_type:?} isn't about stored images"),
+    };
+
+    let sent = match result {
+        Ok(sent) => sent,
+        Err(error) => {
+            legacy_print_string!(
+                frame_sink,
+                "[device]: failed to access the SD card for Storage/{:?}: {}",
+                message_type,
+                error
+            );
+            crate::buf::send(frame_sink, &StorageFailed { error })
+        }
+    };
+    match sent {
+        Ok(()) => ProtocolStatus::Continue,
+        Err(e) => {
+            legacy_print_string!(
+                frame_sink,
+                "[device]: failed to answer Storage/{:?}: {}",
+                message_type,
+                e
+            );
+            ProtocolStatus::Abcon
+        }
+    }
+}
+
+type Sent = Result<(), SendError>;
+
+/// The card's filesystem.
+fn open(platform: &dyn Platform) -> Result<(&dyn BlockDevice, Volume), FatError> {
+    let card = platform.card().ok_or(BlockError::NoCard)?;
+    Ok((card, Volume::open(card)?))
+}
+
+fn list(frame_sink: &mut FrameSink, platform: &dyn Platform) -> Result<Sent, FatError> {
+    let (card, volume) = open(platform)?;
+    let mut count = 0;
+    let mut sent = Ok(());
+    let mut error = Ok(());
+    volume.files(card, |file| {
+        let Some(slot) = Slot::from_file_name(&file.name) else {
+            return true;
+        };
+        match read_header(card, &volume, file) {
+            Ok(Some(header)) => {
+                count += 1;
+                let image = StoredImage {
+                    slot,
+                    len: header.len,
+                    crc: header.crc,
+                    format_details: header.format_details,
+                    signed: header.signature.is_some(),
+                };
+                sent = crate::buf::send(frame_sink, &image);
+                sent.is_ok()
+            }
+            Ok(None) => true,
+            Err(e) => {
+                error = Err(e);
+                false
+            }
+        }
+    })?;
+    error?;
+    Ok(sent.and_then(|()| crate::buf::send(frame_sink, &ImagesListed { count })))
+}
+
+/// The header of the image kept in `file`; `None` if it was cut short, or wasn't written by
+/// okboot.
+fn read_header(
+    card: &dyn BlockDevice,
+    volume: &Volume,
+    file: &DirEntry,
+) -> Result<Option<ImageHeader>, FatError> {
+    let mut header = None;
+    volume.read_file(card, file, |bytes| {
+        header = ImageHeader::decode(bytes);
+        false
+    })?;
+    let file_len = file.len as u64;
+    Ok(header.filter(|header| ImageHeader::LEN as u64 + header.len as u64 == file_len))
+}
+
+fn delete(
+    frame_sink: &mut FrameSink,
+    platform: &dyn Platform,
+    slot: Slot,
+) -> Result<Sent, FatError> {
+    let (card, volume) = open(platform)?;
+    let existed = volume.delete(card, &slot.file_name())?;
+    Ok(crate::buf::send(
+        frame_sink,
+        &ImageDeleted { slot, existed },
+    ))
+}
+
+/// An image kept on the card, as found by [`find`].
+pub struct Kept {
+    volume: Volume,
+    file: DirEntry,
+    pub header: ImageHeader,
+}
+impl Kept {
+    /// Pass the image, without its header, to `load` a few sectors at a time, until it's all been
+    /// read or `load` returns `false`.
+    pub fn read(
+        &self,
+        platform: &dyn Platform,
+        mut load: impl FnMut(&[u8]) -> bool,
+    ) -> Result<(), FatError> {
+        let card = platform.card().ok_or(BlockError::NoCard)?;
+        let mut header_left = ImageHeader::LEN;
+        self.volume.read_file(card, &self.file, |bytes| {
+            let skipped = header_left.min(bytes.len());
+            header_left -= skipped;
+            skipped == bytes.len() || load(&bytes[skipped..])
+        })
+    }
+}
+
+/// The image kept in `slot`, if there is one.
+pub fn find(platform: &dyn Platform, slot: Slot) -> Result<Option<Kept>, FatError> {
+    let (card, volume) = open(platform)?;
+    let Some(file) = volume.find(card, &slot.file_name())? else {
+        return Ok(None);
+    };
+    let header = read_header(card, &volume, &file)?;
+    Ok(header.map(|header| Kept {
+        volume,
+        file,
+        header,
+    }))
+}
+
+/// Writing a program that has just been loaded to the card, a [`STEP`] at a time.
+pub struct Job {
+    slot: Slot,
+    volume: Volume,
+    writer: FileWriter,
+    /// Bytes of the image, as opposed to the file, written so far.
+    written: usize,
+    len: usize,
+}
+impl core::fmt::Debug for Job {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Job")
+            .field("slot", &self.slot)
+            .field("written", &self.written)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+impl Job {
+    /// Make room on the card for the image that `header` describes, to be kept in `slot`.
+    pub fn start(
+        platform: &dyn Platform,
+        slot: Slot,
+        header: ImageHeader,
+    ) -> Result<Self, FatError> {
+        let (card, volume) = open(platform)?;
+        let file_len = ImageHeader::LEN as u32 + header.len;
+        let mut writer = volume.create(card, slot.file_name(), file_len)?;
+        writer.write(&volume, card, &header.encode())?;
+        Ok(Self {
+            slot,
+            volume,
+            writer,
+            written: 0,
+            len: header.len as usize,
+        })
+    }
+
+    /// Bytes of the image written so far, and how many there are.
+    pub fn progress(&self) -> (u32, u32) {
+        (self.written as u32, self.len as u32)
+    }
+
+    /// Write the next [`STEP`] of `image`, the program being kept; `true` once all of it is.
+    pub fn step(&mut self, platform: &dyn Platform, image: &[u8]) -> Result<bool, FatError> {
+        let card = platform.card().ok_or(BlockError::NoCard)?;
+        let end = self.len.min(self.written + STEP);
+        self.writer
+            .write(&self.volume, card, &image[self.written..end])?;
+        self.written = end;
+        Ok(self.written == self.len)
+    }
+
+    /// Put the written file in place of whatever was kept in the slot before; only once
+    /// [`step`](Self::step) has returned `true`.
+    pub fn finish(self, platform: &dyn Platform) -> Result<Slot, FatError> {
+        let card = platform.card().ok_or(BlockError::NoCard)?;
+        self.writer.finish(&self.volume, card)?;
+        Ok(self.slot)
+    }
+}
@@ -1,26 +1,46 @@
 use crate::buf::{FrameSink, SendError};
-use crate::protocol::{ProtocolStatus, Timeouts};
+use crate::platform::{Instant, LOAD_END, Platform};
+use crate::protocol::handshake::Handshake;
+use crate::protocol::persist::Job;
+use crate::protocol::{ProtocolEnum, ProtocolStatus, Timeouts};
 use crate::rpc_println;
 use crate::stub::flat_binary::{Integrity, Relocation};
+use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
+use base_image::{BaseImage, Retainer};
+#[cfg(not(feature = "sim"))]
 use bcm2835_lpa::Peripherals;
 use core::fmt::Debug;
 use core::time::Duration;
-use elf::ElfBytes;
-use elf::abi::{EM_ARM, ET_EXEC, PT_GNU_STACK, PT_LOAD, PT_NOTE, PT_TLS};
-use elf::endian::{EndianParse, LittleEndian};
-use elf::file::Class;
 use elf::segment::Elf32_Phdr;
-use miniz_oxide::inflate::stream::InflateState;
-use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use okboot_common::compression::{Codecs, Decoder};
+use okboot_common::delta::{DeltaError, Patcher};
+use okboot_common::device::Misfit;
+use okboot_common::executable::{self, ElfError};
+use okboot_common::fat::FatError;
 use okboot_common::frame::FrameHeader;
 use okboot_common::host::{Chunk, FormatDetails, Metadata};
-use okboot_common::{MessageType, device, host};
-use quartz::device::bcm2835::timing::Instant;
+use okboot_common::persist::ImageHeader;
+use okboot_common::segments::{self, Segment, SegmentError};
+use okboot_common::{INITIAL_BAUD_RATE, MessageType, device, host};
+use signature::ImageVerifier;
+pub(crate) use signature::VERIFYING_KEY;
 use thiserror::Error;
+use window::{Accept, ChunkWindow, Progress};
+
+mod base_image;
+mod fallback;
+mod signature;
+mod window;
+
+pub(super) use base_image::capacity as base_image_capacity;
+pub(super) use base_image::validate as validate_base_image;
+pub(super) use fallback::boot as boot_from_card;
 
 const CHUNK_SIZE: usize = 0x1000;
+/// Number of chunks the host may have in flight when using the pipelined protocol.
+const WINDOW_SIZE: usize = 8;
 mod timeouts {
     use crate::timeouts::RateRelativeTimeout;
 
@@ -52,19 +72,45 @@ enum S {
     /// expect: [`MetadataAck`], send: [`MetadataReq`]
     RequestMetadata,
     /// expect: [`MetadataAckAck`], send: [`MetadataAck`]
-    AckMetadata(Metadata),
+    ///
+    /// Holds the base image if the host is going to send a delta against it.
+    AckMetadata(Metadata, Option<BaseImage>),
     /// expect: [`Chunk`], send: [`ChunkReq`]
     RequestChunk {
         which: usize,
         count: usize,
         loader: LoaderEnum,
     },
+    /// expect: [`Chunk`], send: [`ChunkAck`](device::ChunkAck), [`ChunkReq`]
+    StreamChunks {
+        window: ChunkWindow,
+        loader: LoaderEnum,
+    },
+    /// expect: nothing, send: [`Persisting`](device::Persisting), then
+    /// [`Persisted`](device::Persisted)
+    ///
+    /// Writes the loaded program, as kept in `image`, to the SD card before it's booted.
+    Persist {
+        booter: Booter,
+        image: BaseImage,
+        job: Box<Job>,
+        started: Instant,
+    },
     /// expect: [`BootingAck`], send: [`Booting`]
     Boot { booter: Booter },
+    /// expect: [`ResumeAck`](host::ResumeAck), send: [`Resume`](device::Resume)
+    ///
+    /// Holds the [`RequestChunk`](S::RequestChunk) or [`StreamChunks`](S::StreamChunks) state of
+    /// a suspended transfer until the host confirms that it's uploading the same program.
+    OfferResume(Box<S>),
 }
 
 pub struct V2 {
     state: S,
+    /// Whether to use the pipelined chunk transfer of [`SupportedProtocol::V3`].
+    ///
+    /// [`SupportedProtocol::V3`]: okboot_common::SupportedProtocol::V3
+    pipelined: bool,
 
     once: bool,
     retry_buffer: bool,
@@ -73,34 +119,72 @@ pub struct V2 {
     baud: u32,
     timeouts: V1Timeouts,
 
-    inflate_state: InflateState,
+    decoder: Decoder,
     remainder: usize,
+
+    sink: ImageSink,
 }
 impl Debug for V2 {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("V2")
             .field("state", &self.state)
+            .field("pipelined", &self.pipelined)
             .field("once", &self.once)
             .field("retry_buffer", &self.retry_buffer)
             .field("heartbeat", &self.heartbeat)
             .field("baud", &self.baud)
             .field("timeouts", &self.timeouts)
-            // .field("inflate_state", "<opaque>")
+            .field("decoder", &self.decoder)
             .finish()
     }
 }
 
 impl V2 {
-    pub fn new(peripherals: &Peripherals, baud: u32) -> Self {
+    pub fn new(platform: &dyn Platform, baud: u32) -> Self {
         Self {
             state: S::RequestMetadata,
+            pipelined: false,
             once: true,
             retry_buffer: false,
-            heartbeat: Instant::now(&peripherals.SYSTMR),
+            heartbeat: Instant::now(platform),
             baud,
             timeouts: V1Timeouts::new_8n1(baud),
-            inflate_state: InflateState::new(DataFormat::Raw),
+            decoder: Decoder::None,
             remainder: 0,
+            sink: ImageSink::default(),
+        }
+    }
+
+    pub fn new_pipelined(platform: &dyn Platform, baud: u32) -> Self {
+        Self {
+            pipelined: true,
+            ..Self::new(platform, baud)
+        }
+    }
+
+    pub fn is_pipelined(&self) -> bool {
+        self.pipelined
+    }
+
+    /// Whether a chunk transfer is underway, i.e. whether there's anything worth keeping around
+    /// for [`resume`](V2::resume) if the host goes away.
+    pub fn is_resumable(&self) -> bool {
+        matches!(self.state, S::RequestChunk { .. } | S::StreamChunks { .. })
+    }
+
+    /// Continue a suspended transfer after the host has redone the handshake at `baud`; the host
+    /// is offered a [`device::Resume`] before anything else.
+    pub fn resume(mut self, platform: &dyn Platform, baud: u32) -> Self {
+        debug_assert!(self.is_resumable());
+        let transfer = core::mem::replace(&mut self.state, S::RequestMetadata);
+        Self {
+            state: S::OfferResume(Box::new(transfer)),
+            once: true,
+            retry_buffer: false,
+            heartbeat: Instant::now(platform),
+            baud,
+            timeouts: V1Timeouts::new_8n1(baud),
+            ..self
         }
     }
 }
@@ -112,7 +196,7 @@ impl super::Protocol for V2 {
         payload: &[u8],
         frame_sink: &mut FrameSink,
         timeouts: &mut Timeouts,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
         inflate_buffer: &mut [u8],
     ) -> ProtocolStatus {
         match frame_header.message_type {
@@ -130,7 +214,21 @@ impl super::Protocol for V2 {
                         return ProtocolStatus::Continue;
                     }
                 };
-                self.recv_metadata(msg, frame_sink, timeouts);
+                self.recv_metadata(msg, None, frame_sink, timeouts, platform);
+            }
+            MessageType::DeltaMetadata => {
+                let msg: host::DeltaMetadata = match postcard::from_bytes(payload) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        rpc_println!(
+                            frame_sink,
+                            "[device/v2] failed to parse payload (V2/DeltaMetadata): {:?}",
+                            e
+                        );
+                        return ProtocolStatus::Continue;
+                    }
+                };
+                self.recv_delta_metadata(msg, frame_sink, timeouts, platform);
             }
             MessageType::MetadataAckAck => {
                 // rpc_println!(frame_sink, "[device/v2] received V2/MetadataAckAck");
@@ -145,7 +243,21 @@ impl super::Protocol for V2 {
                         return ProtocolStatus::Continue;
                     }
                 };
-                self.recv_metadata_ack_ack(msg, frame_sink);
+                self.recv_metadata_ack_ack(msg, frame_sink, platform);
+            }
+            MessageType::ResumeAck => {
+                let msg: host::ResumeAck = match postcard::from_bytes(payload) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        rpc_println!(
+                            frame_sink,
+                            "[device/v2] failed to parse payload (V2/ResumeAck): {:?}",
+                            e
+                        );
+                        return ProtocolStatus::Continue;
+                    }
+                };
+                self.recv_resume_ack(msg, frame_sink, timeouts);
             }
             MessageType::Chunk => {
                 // rpc_println!(frame_sink, "[device/v2] received V2/Chunk");
@@ -160,14 +272,20 @@ impl super::Protocol for V2 {
                         return ProtocolStatus::Continue;
                     }
                 };
-                if !self.recv_chunk(msg, frame_sink, peripherals, inflate_buffer) {
+                if !self.recv_chunk(msg, frame_sink, platform, inflate_buffer) {
                     // if this returns fails, CRC failed or other catastrophic error
                     return ProtocolStatus::Abend;
                 }
             }
+            MessageType::Probe => {
+                // the host has started over, e.g. after reconnecting; it will probe again
+                return self.restart(frame_sink, timeouts, platform);
+            }
             MessageType::BootingAck => {
                 // rpc_println!(frame_sink, "[device/v2] received V2/BootingAck");
-                self.recv_booting_ack(frame_sink, peripherals);
+                if let Some(booter) = self.recv_booting_ack(frame_sink) {
+                    return ProtocolStatus::Boot(booter);
+                }
             }
             otherwise => {
                 rpc_println!(
@@ -183,11 +301,18 @@ impl super::Protocol for V2 {
     fn heartbeat(
         &mut self,
         frame_sink: &mut FrameSink,
-        _timeouts: &mut Timeouts,
-        peripherals: &Peripherals,
+        timeouts: &mut Timeouts,
+        platform: &dyn Platform,
     ) -> ProtocolStatus {
+        if matches!(self.state, S::StreamChunks { .. }) {
+            return self.stream_heartbeat(frame_sink, platform);
+        }
+        if matches!(self.state, S::Persist { .. }) {
+            return self.persist_heartbeat(frame_sink, timeouts, platform);
+        }
+
         let send_once = core::mem::replace(&mut self.once, false);
-        let heartbeat_elapsed = self.heartbeat.elapsed(&peripherals.SYSTMR);
+        let heartbeat_elapsed = self.heartbeat.elapsed(platform);
 
         let should_send
             // A. first time message is being sent
@@ -207,16 +332,16 @@ impl super::Protocol for V2 {
 
         if should_send {
             let send_result = match &self.state {
-                S::RequestMetadata => self.send_metadata_request(peripherals, frame_sink),
-                S::AckMetadata(metadata) => {
-                    self.send_metadata_ack(peripherals, frame_sink, *metadata)
-                }
+                S::RequestMetadata => self.send_metadata_request(frame_sink, platform),
+                S::AckMetadata(metadata, _) => self.send_metadata_ack(frame_sink, *metadata),
                 S::RequestChunk {
                     which,
                     count: _,
                     loader: _,
-                } => self.send_chunk_request(peripherals, frame_sink, *which),
-                S::Boot { .. } => self.send_boot_msg(peripherals, frame_sink),
+                } => self.send_chunk_request(frame_sink, *which),
+                S::StreamChunks { .. } | S::Persist { .. } => unreachable!(),
+                S::Boot { .. } => self.send_boot_msg(frame_sink),
+                S::OfferResume(transfer) => self.send_resume(frame_sink, transfer, platform),
             };
             match send_result {
                 Ok(true) => self.retry_buffer = false,
@@ -224,7 +349,7 @@ impl super::Protocol for V2 {
                 Err(()) => return ProtocolStatus::Abend,
             }
 
-            self.heartbeat = Instant::now(&peripherals.SYSTMR);
+            self.heartbeat = Instant::now(platform);
         }
 
         ProtocolStatus::Continue
@@ -232,11 +357,33 @@ impl super::Protocol for V2 {
 }
 
 impl V2 {
+    fn restart(
+        &mut self,
+        frame_sink: &mut FrameSink,
+        timeouts: &mut Timeouts,
+        platform: &dyn Platform,
+    ) -> ProtocolStatus {
+        rpc_println!(
+            frame_sink,
+            "[device/v2] received Handshake/Probe, restarting"
+        );
+        super::flush_to_fifo(frame_sink, platform);
+        if !platform.set_baud_rate(INITIAL_BAUD_RATE) {
+            return ProtocolStatus::Abend;
+        }
+        *timeouts = Timeouts::new_8n1(INITIAL_BAUD_RATE);
+        let session = core::mem::replace(self, V2::new(platform, INITIAL_BAUD_RATE));
+        ProtocolStatus::Switch(ProtocolEnum::Handshake(Handshake::suspending(
+            session, frame_sink, platform,
+        )))
+    }
     fn recv_metadata(
         &mut self,
         msg: host::Metadata,
+        base: Option<BaseImage>,
         frame_sink: &mut FrameSink,
         timeouts: &mut Timeouts,
+        platform: &dyn Platform,
     ) {
         if !matches!(self.state, S::RequestMetadata) {
             rpc_println!(
@@ -246,39 +393,99 @@ impl V2 {
             );
             return;
         }
-        let ok = match msg.format_details {
-            FormatDetails::Bin { load_address } => {
-                if load_address >= 0x1000_0000 {
-                    rpc_println!(
-                        frame_sink,
-                        "[device/v2] BIN file load address too high (must be below 0x1000_0000)"
-                    );
-                    false
-                } else if (load_address & 3) != 0 {
-                    rpc_println!(
-                        frame_sink,
-                        "[device/v2] BIN file load address must be 4-byte aligned"
-                    );
-                    false
-                } else {
-                    true
+        let ok = match super::device_info(platform).check(msg.format_details, msg.inflated_len) {
+            Ok(()) => {
+                if matches!(msg.format_details, FormatDetails::Elf) {
+                    rpc_println!(frame_sink, "[device/v2] Loading ELF file");
                 }
-            }
-            FormatDetails::Elf => {
-                rpc_println!(frame_sink, "[device/v2] Loading ELF file");
                 true
             }
+            Err(misfit) => {
+                rpc_println!(frame_sink, "[device/v2] can't load program: {}", misfit);
+                false
+            }
         };
-        if ok {
-            self.state = S::AckMetadata(msg);
+        let persist = msg
+            .persist
+            .map(|_| super::device_info(platform).check_persist(msg.inflated_len));
+        let ok = ok
+            && match persist {
+                None | Some(Ok(())) => true,
+                Some(Err(misfit)) => {
+                    rpc_println!(frame_sink, "[device/v2] can't keep program: {}", misfit);
+                    false
+                }
+            };
+        let supported = Codecs::built_in().supports(msg.compression);
+        if !supported {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] program is compressed with {}, which wasn't built in",
+                msg.compression
+            );
+        }
+        if ok && supported {
+            self.state = S::AckMetadata(msg, base);
             self.once = true;
             // override session timeout
             timeouts.override_session_timeout =
                 Some(timeouts::TRY_RESEND_CHUNK.at_baud_8n1(self.baud) * 2)
         }
     }
-    fn recv_metadata_ack_ack(&mut self, msg: host::MetadataAckAck, frame_sink: &mut FrameSink) {
-        let S::AckMetadata(metadata) = &self.state else {
+    fn recv_delta_metadata(
+        &mut self,
+        msg: host::DeltaMetadata,
+        frame_sink: &mut FrameSink,
+        timeouts: &mut Timeouts,
+        platform: &dyn Platform,
+    ) {
+        let Some(base) = BaseImage::find(platform).filter(|base| *base.hash() == msg.base) else {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] received V2/DeltaMetadata against unknown base image, ignoring."
+            );
+            return;
+        };
+        self.recv_metadata(msg.metadata, Some(base), frame_sink, timeouts, platform);
+    }
+    fn recv_resume_ack(
+        &mut self,
+        msg: host::ResumeAck,
+        frame_sink: &mut FrameSink,
+        timeouts: &mut Timeouts,
+    ) {
+        if !matches!(self.state, S::OfferResume(_)) {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] received V2/ResumeAck in state: {:?}, ignoring.",
+                self.state
+            );
+            return;
+        }
+        let S::OfferResume(transfer) = core::mem::replace(&mut self.state, S::RequestMetadata)
+        else {
+            unreachable!()
+        };
+        self.once = true;
+        if !msg.is_ok {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] received V2/ResumeAck(ok=false), requesting metadata"
+            );
+            return;
+        }
+        self.state = *transfer;
+        // same as once the metadata has been acknowledged
+        timeouts.override_session_timeout =
+            Some(timeouts::TRY_RESEND_CHUNK.at_baud_8n1(self.baud) * 2);
+    }
+    fn recv_metadata_ack_ack(
+        &mut self,
+        msg: host::MetadataAckAck,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) {
+        let S::AckMetadata(metadata, base) = &self.state else {
             rpc_println!(
                 frame_sink,
                 "[device/v2] received V2/MetadataAckAck in state: {:?}, ignoring.",
@@ -295,19 +502,23 @@ impl V2 {
             return;
         }
         let chunk_count = (metadata.deflated_len as usize + CHUNK_SIZE - 1) / CHUNK_SIZE;
-        let loader = match metadata.format_details {
-            FormatDetails::Bin { load_address } => LoaderEnum::BinLoader(BinLoader::new(
-                load_address.try_into().expect(
-                    "cannot reach this point with load_address that is not representable as u32",
-                ),
-                metadata.clone(),
-            )),
-            FormatDetails::Elf => LoaderEnum::ElfLoader(ElfLoader::new(metadata.clone())),
-        };
-        self.state = S::RequestChunk {
-            which: 0,
-            count: chunk_count,
-            loader,
+        let loader = LoaderEnum::new(metadata, platform);
+        self.sink.reset(metadata, *base, platform);
+        // a previous attempt may have left the decoder mid-stream or already finished
+        self.decoder = Decoder::new(metadata.compression)
+            .expect("cannot reach this point with a codec that is not built in");
+        self.remainder = 0;
+        self.state = if self.pipelined {
+            S::StreamChunks {
+                window: ChunkWindow::new(chunk_count, WINDOW_SIZE, CHUNK_SIZE),
+                loader,
+            }
+        } else {
+            S::RequestChunk {
+                which: 0,
+                count: chunk_count,
+                loader,
+            }
         };
         self.once = true;
     }
@@ -315,9 +526,13 @@ impl V2 {
         &mut self,
         msg: Chunk,
         frame_sink: &mut FrameSink,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
         inflate_buffer: &mut [u8],
     ) -> bool {
+        if matches!(self.state, S::StreamChunks { .. }) {
+            self.recv_streamed_chunk(msg, platform);
+            return true;
+        }
         let S::RequestChunk {
             which,
             count,
@@ -344,65 +559,22 @@ impl V2 {
             }
 
             loop {
-                // rpc_println!(frame_sink, "[device/v2] inflate on: {:02x?}", &a[..new_end]);
-                let inflate_result = miniz_oxide::inflate::stream::inflate(
-                    &mut self.inflate_state,
-                    &a[..self.remainder],
-                    b,
-                    MZFlush::None,
-                );
-                // rpc_println!(
-                //     frame_sink,
-                //     "[device/v2] inflate to: {:02x?}",
-                //     &b[..inflate_result.bytes_written]
-                // );
-                // rpc_println!(frame_sink, "[device/v2] inflate: {:?}", inflate_result);
-
-                let mut done = false;
-                match inflate_result.status {
-                    Ok(stat) => match stat {
-                        MZStatus::Ok => {
-                            a.copy_within(inflate_result.bytes_consumed..self.remainder, 0);
-                            self.remainder -= inflate_result.bytes_consumed;
-                            if inflate_result.bytes_written == 0 {
-                                break;
-                            }
-                        }
-                        MZStatus::StreamEnd => {
-                            self.remainder -= inflate_result.bytes_consumed;
-                            done = true;
-                        }
-                        MZStatus::NeedDict => unreachable!(), // unused
-                    },
-                    Err(e) => match e {
-                        MZError::Buf => {
-                            // rpc_println!(frame_sink, "[device/v2] failed to make inflate progress");
-                            assert_eq!(inflate_result.bytes_consumed, 0);
-                            assert_eq!(inflate_result.bytes_written, 0);
-                            break;
-                        }
-                        MZError::Data => {
-                            rpc_println!(
-                                frame_sink,
-                                "[device/v2] MZError::Data probably indicates data corruption"
-                            );
-                            return false; // catastrophic
-                        }
-                        e => {
-                            rpc_println!(
-                                frame_sink,
-                                "[device/v2] unexpected error while inflating: {:?}",
-                                e
-                            );
-                            return false; // catastrophic
-                        }
-                    },
-                }
-                if let Err(e) = loader.receive_bytes(&b[..inflate_result.bytes_written]) {
+                let decoded = match self.decoder.decode(&a[..self.remainder], b) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        rpc_println!(frame_sink, "[device/v2] failed to decompress chunk: {}", e);
+                        return false; // catastrophic
+                    }
+                };
+                a.copy_within(decoded.consumed..self.remainder, 0);
+                self.remainder -= decoded.consumed;
+
+                let inflated = &b[..decoded.written];
+                if let Err(e) = self.sink.receive(inflated, usize::MAX, loader, platform) {
                     rpc_println!(frame_sink, "[device/v2] unrecoverable load error: {}", e);
                     return false; // catastrophic
                 }
-                if done {
+                if decoded.finished || decoded.written == 0 {
                     break;
                 }
             }
@@ -434,40 +606,331 @@ impl V2 {
             else {
                 unreachable!()
             };
-            let booter = match Loader::finalize(loader, frame_sink, peripherals) {
-                Ok(booter) => booter,
-                Err(e) => {
-                    rpc_println!(frame_sink, "[device/v2] can't finalize, retrying: {e}");
-                    return false;
+            return self.finish_loading(loader, frame_sink, platform);
+        }
+
+        true
+    }
+    fn finish_loading(
+        &mut self,
+        loader: LoaderEnum,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) -> bool {
+        let metadata = *loader.metadata();
+        let signature = self.sink.verifier.signature();
+        match core::mem::take(&mut self.sink.verifier).verify(platform.verifying_key()) {
+            Ok(true) => log::info!("program signature verified"),
+            Ok(false) => {}
+            Err(error) => {
+                rpc_println!(frame_sink, "[device/v2] refusing to boot: {error}");
+                if let Err(e) = frame_sink.send(&device::BootRefused { error }) {
+                    rpc_println!(
+                        frame_sink,
+                        "[device/v2] failed to send V2/BootRefused: {}",
+                        e
+                    );
                 }
-            };
+                super::flush_to_fifo(frame_sink, platform);
+                return false;
+            }
+        }
+        let booter = match Loader::finalize(loader, frame_sink, platform) {
+            Ok(booter) => booter,
+            Err(e) => {
+                rpc_println!(frame_sink, "[device/v2] can't finalize, retrying: {e}");
+                return false;
+            }
+        };
+        if let Some(retainer) = self.sink.retainer.take() {
+            retainer.commit(platform);
+        }
+        self.once = true;
+        let Some(slot) = metadata.persist else {
             self.state = S::Boot { booter };
+            return true;
+        };
+        // the program is written to the card from where it was retained, once it's all there
+        let image =
+            BaseImage::find(platform).filter(|image| image.len() == metadata.inflated_len as usize);
+        let Some(image) = image else {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] can't keep program: it wasn't retained"
+            );
+            return false;
+        };
+        let header = ImageHeader {
+            len: metadata.inflated_len,
+            crc: metadata.inflated_crc,
+            format_details: metadata.format_details,
+            signature,
+        };
+        match Job::start(platform, slot, header) {
+            Ok(job) => {
+                rpc_println!(frame_sink, "[device/v2] keeping program in slot {slot}");
+                self.state = S::Persist {
+                    booter,
+                    image,
+                    job: Box::new(job),
+                    started: Instant::now(platform),
+                };
+                true
+            }
+            Err(e) => {
+                self.persist_failed(e, frame_sink, platform);
+                false
+            }
+        }
+    }
+    fn persist_heartbeat(
+        &mut self,
+        frame_sink: &mut FrameSink,
+        timeouts: &mut Timeouts,
+        platform: &dyn Platform,
+    ) -> ProtocolStatus {
+        let S::Persist {
+            image,
+            job,
+            started,
+            ..
+        } = &mut self.state
+        else {
+            unreachable!()
+        };
+        // the host has nothing to send until the program is booted, so keep the session alive
+        timeouts.override_session_timeout =
+            Some(started.elapsed(platform) + timeouts::TRY_RESEND_CHUNK.at_baud_8n1(self.baud) * 2);
+        let done = match job.step(platform, image.bytes(platform)) {
+            Ok(done) => done,
+            Err(e) => return self.persist_failed(e, frame_sink, platform),
+        };
+        let (written, len) = job.progress();
+        // progress only; the host hears Booting in the end either way
+        let _ = frame_sink.send(&device::Persisting { written, len });
+        super::flush_to_fifo(frame_sink, platform);
+        if !done {
+            return ProtocolStatus::Continue;
         }
 
-        true
+        let S::Persist { booter, job, .. } =
+            core::mem::replace(&mut self.state, S::RequestMetadata)
+        else {
+            unreachable!()
+        };
+        match job.finish(platform) {
+            Ok(slot) => {
+                rpc_println!(frame_sink, "[device/v2] kept program in slot {slot}");
+                let _ = frame_sink.send(&device::Persisted { slot });
+                self.state = S::Boot { booter };
+                self.once = true;
+                ProtocolStatus::Continue
+            }
+            Err(e) => self.persist_failed(e, frame_sink, platform),
+        }
     }
-    fn recv_booting_ack(&mut self, frame_sink: &mut FrameSink, peripherals: &Peripherals) {
+    /// Tell the host that the program couldn't be kept, and start over rather than boot it.
+    fn persist_failed(
+        &mut self,
+        error: FatError,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) -> ProtocolStatus {
+        rpc_println!(frame_sink, "[device/v2] failed to keep program: {error}");
+        if let Err(e) = frame_sink.send(&device::StorageFailed { error }) {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] failed to send V2/StorageFailed: {}",
+                e
+            );
+        }
+        super::flush_to_fifo(frame_sink, platform);
+        self.state = S::RequestMetadata;
+        self.once = true;
+        ProtocolStatus::Abend
+    }
+    fn recv_streamed_chunk(&mut self, msg: Chunk, platform: &dyn Platform) {
+        let S::StreamChunks { window, loader: _ } = &mut self.state else {
+            unreachable!()
+        };
+        match window.accept(msg.which as usize, msg.bytes) {
+            Accept::Stored => {
+                self.heartbeat = Instant::now(platform);
+            }
+            Accept::Duplicate => {
+                // host may have missed an acknowledgement
+                window.set_ack_pending();
+            }
+            Accept::OutOfWindow => {
+                log::warn!(
+                    "chunk {} outside of window starting at {}, ignoring",
+                    msg.which,
+                    window.next()
+                );
+            }
+            Accept::Oversize => {
+                log::warn!(
+                    "chunk {} has {} bytes, expected at most {}, ignoring",
+                    msg.which,
+                    msg.bytes.len(),
+                    CHUNK_SIZE
+                );
+            }
+        }
+    }
+    fn stream_heartbeat(
+        &mut self,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) -> ProtocolStatus {
+        let send_once = core::mem::replace(&mut self.once, false);
+        let timed_out = self.heartbeat.elapsed(platform) > self.timeouts.try_resend_chunk;
+
+        let S::StreamChunks { window, loader } = &mut self.state else {
+            unreachable!()
+        };
+
+        match window.inflate_step(
+            &mut self.decoder,
+            loader,
+            &mut self.sink,
+            frame_sink,
+            platform,
+        ) {
+            Ok(Progress::Pending) => {}
+            Ok(Progress::Finished) => {
+                let S::StreamChunks { window: _, loader } =
+                    core::mem::replace(&mut self.state, S::RequestMetadata)
+                else {
+                    unreachable!()
+                };
+                return if self.finish_loading(loader, frame_sink, platform) {
+                    ProtocolStatus::Continue
+                } else {
+                    ProtocolStatus::Abend
+                };
+            }
+            Err(()) => return ProtocolStatus::Abend,
+        }
+
+        if send_once {
+            // the initial ChunkAck opens the window
+            window.set_ack_pending();
+            self.heartbeat = Instant::now(platform);
+        } else if timed_out && window.is_waiting() {
+            // host has gone quiet: ask again for everything that's still missing
+            window.resynchronize();
+            self.heartbeat = Instant::now(platform);
+            let _ = frame_sink.send(&device::ChunkReq {
+                which: window.next() as u32,
+            });
+        }
+
+        window.for_each_gap(|which| {
+            frame_sink
+                .send(&device::ChunkReq {
+                    which: which as u32,
+                })
+                .is_ok()
+        });
+
+        if window.take_ack_pending() {
+            let ack = device::ChunkAck {
+                next: window.next() as u32,
+                received: window.received(),
+            };
+            match frame_sink.send(&ack) {
+                Ok(()) => {}
+                Err(SendError::Truncated) => window.set_ack_pending(),
+                Err(e) => {
+                    rpc_println!(frame_sink, "[device/v3] failed to send V3/ChunkAck: {}", e);
+                    return ProtocolStatus::Abend;
+                }
+            }
+        }
+
+        ProtocolStatus::Continue
+    }
+    fn recv_booting_ack(&mut self, frame_sink: &mut FrameSink) -> Option<Booter> {
         if !matches!(self.state, S::Boot { .. }) {
             rpc_println!(
                 frame_sink,
                 "[device/v2] received unexpected V2/BootingAck in state {:?}, ignoring.",
                 self.state
             );
-            return;
+            return None;
         };
         rpc_println!(frame_sink, "[device/v2] received V2/BootingAck, booting");
         let S::Boot { booter } = core::mem::replace(&mut self.state, S::RequestMetadata) else {
             unreachable!()
         };
-        booter.enter(peripherals, frame_sink)
+        Some(booter)
     }
 
+    fn send_resume(
+        &self,
+        frame_sink: &mut FrameSink,
+        transfer: &S,
+        platform: &dyn Platform,
+    ) -> Result<bool, ()> {
+        if !self.send_base_image(frame_sink, platform)? {
+            return Ok(false);
+        }
+        let (next, window_size, loader) = match transfer {
+            S::RequestChunk {
+                which,
+                count: _,
+                loader,
+            } => (*which, 0, loader),
+            S::StreamChunks { window, loader } => (window.next(), WINDOW_SIZE, loader),
+            _ => unreachable!(),
+        };
+        match frame_sink.send(&device::Resume {
+            chunk_size: CHUNK_SIZE as u32,
+            window_size: window_size as u32,
+            next: next as u32,
+            inflated: loader.bytes_loaded() as u32,
+            metadata: *loader.metadata(),
+        }) {
+            Ok(()) => Ok(true),
+            Err(SendError::Truncated) => Ok(false),
+            Err(e) => {
+                rpc_println!(frame_sink, "[device/v2] failed to send V2/Resume: {}", e);
+                Err(())
+            }
+        }
+    }
+    fn send_base_image(
+        &self,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) -> Result<bool, ()> {
+        let Some(base) = BaseImage::find(platform) else {
+            return Ok(true);
+        };
+        match frame_sink.send(&device::BaseImage {
+            len: base.len() as u32,
+            hash: *base.hash(),
+        }) {
+            Ok(()) => Ok(true),
+            Err(SendError::Truncated) => Ok(false),
+            Err(e) => {
+                rpc_println!(frame_sink, "[device/v2] failed to send V2/BaseImage: {}", e);
+                Err(())
+            }
+        }
+    }
     fn send_metadata_request(
         &mut self,
-        _peripherals: &Peripherals,
         frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
     ) -> Result<bool, ()> {
-        match frame_sink.send(&device::MetadataReq {}) {
+        if !self.send_base_image(frame_sink, platform)? {
+            return Ok(false);
+        }
+        match frame_sink.send(&device::MetadataReq {
+            codecs: Codecs::built_in(),
+        }) {
             Ok(()) => Ok(true),
             Err(SendError::Truncated) => Ok(false),
             Err(e) => {
@@ -482,14 +945,22 @@ impl V2 {
     }
     fn send_metadata_ack(
         &mut self,
-        _peripherals: &Peripherals,
         frame_sink: &mut FrameSink,
         metadata: Metadata,
     ) -> Result<bool, ()> {
-        match frame_sink.send(&device::MetadataAck {
-            chunk_size: CHUNK_SIZE as u32,
-            metadata,
-        }) {
+        let send_result = if self.pipelined {
+            frame_sink.send(&device::PipelinedMetadataAck {
+                chunk_size: CHUNK_SIZE as u32,
+                window_size: WINDOW_SIZE as u32,
+                metadata,
+            })
+        } else {
+            frame_sink.send(&device::MetadataAck {
+                chunk_size: CHUNK_SIZE as u32,
+                metadata,
+            })
+        };
+        match send_result {
             Ok(()) => Ok(true),
             Err(SendError::Truncated) => Ok(false),
             Err(e) => {
@@ -502,12 +973,7 @@ impl V2 {
             }
         }
     }
-    fn send_chunk_request(
-        &mut self,
-        _peripherals: &Peripherals,
-        frame_sink: &mut FrameSink,
-        which: usize,
-    ) -> Result<bool, ()> {
+    fn send_chunk_request(&mut self, frame_sink: &mut FrameSink, which: usize) -> Result<bool, ()> {
         match frame_sink.send(&device::ChunkReq {
             which: which as u32,
         }) {
@@ -519,11 +985,7 @@ impl V2 {
             }
         }
     }
-    fn send_boot_msg(
-        &mut self,
-        _peripherals: &Peripherals,
-        frame_sink: &mut FrameSink,
-    ) -> Result<bool, ()> {
+    fn send_boot_msg(&mut self, frame_sink: &mut FrameSink) -> Result<bool, ()> {
         match frame_sink.send(&device::Booting {}) {
             Ok(()) => Ok(true),
             Err(SendError::Truncated) => Ok(false),
@@ -535,39 +997,51 @@ impl V2 {
     }
 }
 
+/// A loaded program, ready to be handed over to one of the relocation stubs.
 #[derive(Debug)]
-enum Booter {
+pub enum Booter {
     Relocation {
         relocation: Relocation,
     },
+    /// Like `Relocation`, but the stub checks the CRC of each of `segments` once they're in place.
+    Segments {
+        relocation: Relocation,
+        segments: Vec<Segment>,
+    },
     Elf {
         program_headers: Vec<Elf32_Phdr>,
         elf: Vec<u8>,
         entry: usize,
     },
+    /// Whatever is already at `entry`, as okdude's `mem jump` asked.
+    Jump {
+        entry: usize,
+    },
 }
 impl Booter {
     fn flat_binary(relocation: Relocation) -> Self {
         Self::Relocation { relocation }
     }
-    fn enter(self, peripherals: &Peripherals, frame_sink: &mut FrameSink) -> ! {
+    #[cfg(not(feature = "sim"))]
+    pub fn enter(self, peripherals: &Peripherals) -> ! {
         match self {
             Self::Relocation { relocation } => unsafe {
-                crate::stub::flat_binary::final_relocation(peripherals, frame_sink, relocation)
+                crate::stub::flat_binary::final_relocation(peripherals, relocation)
+            },
+            Self::Segments {
+                relocation,
+                segments,
+            } => unsafe {
+                crate::stub::segments::final_relocation(peripherals, relocation, &segments)
             },
             Booter::Elf {
                 program_headers,
                 elf,
                 entry,
             } => unsafe {
-                crate::stub::elf::final_relocation(
-                    peripherals,
-                    frame_sink,
-                    program_headers,
-                    &elf,
-                    entry,
-                )
+                crate::stub::elf::final_relocation(peripherals, program_headers, &elf, entry)
             },
+            Booter::Jump { entry } => unsafe { crate::stub::jump(peripherals, entry) },
         }
     }
 }
@@ -578,22 +1052,119 @@ enum LoadError {
     Crc,
     #[error("ELF error: {0}")]
     Elf(ElfError),
+    #[error("delta error: {0}")]
+    Delta(DeltaError),
+    #[error("segment table error: {0}")]
+    Segments(SegmentError),
+    #[error("{0}")]
+    Misfit(Misfit),
+}
+
+/// Everything the inflated stream passes through besides the [`Loader`]: the delta [`Patcher`]
+/// if the host is sending a delta against a [`BaseImage`], the signature verifier, and the copy
+/// of the program that becomes the base image for the next upload.
+#[derive(Default)]
+struct ImageSink {
+    verifier: ImageVerifier,
+    delta: Option<(Patcher, BaseImage)>,
+    retainer: Option<Retainer>,
+}
+impl ImageSink {
+    /// Start over for a new program, delta-encoded against `base` if given.
+    fn reset(&mut self, metadata: &Metadata, base: Option<BaseImage>, platform: &dyn Platform) {
+        self.verifier.reset(metadata);
+        self.delta = base.map(|base| (Patcher::new(), base));
+        self.retainer = Retainer::new(platform, metadata.inflated_len as usize);
+    }
+
+    /// Pass on inflated bytes. When patching a delta, at most `budget` bytes of the program are
+    /// loaded, and the bytes of `inflated` that weren't used up yet have to be passed in again;
+    /// returns how many were.
+    fn receive(
+        &mut self,
+        inflated: &[u8],
+        budget: usize,
+        loader: &mut LoaderEnum,
+        platform: &dyn Platform,
+    ) -> Result<usize, LoadError> {
+        let Self {
+            verifier,
+            delta,
+            retainer,
+        } = self;
+        let mut load = |program: &[u8]| {
+            verifier.update(program);
+            if let Some(retainer) = retainer {
+                retainer.update(program, platform);
+            }
+            loader.receive_bytes(program, platform)
+        };
+        let Some((patcher, base)) = delta else {
+            load(inflated)?;
+            return Ok(inflated.len());
+        };
+        let mut result = Ok(());
+        let consumed = patcher
+            .feed(inflated, base.bytes(platform), budget, |program| {
+                if result.is_ok() {
+                    result = load(program);
+                }
+            })
+            .map_err(LoadError::Delta)?;
+        result.map(|()| consumed)
+    }
+
+    /// Whether [`receive`](ImageSink::receive) has more of the program to load even without
+    /// further input.
+    fn has_output(&self) -> bool {
+        self.delta
+            .as_ref()
+            .is_some_and(|(patcher, _)| patcher.has_output())
+    }
 }
 
 #[enum_dispatch::enum_dispatch]
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)] // enum_dispatch names the variants after the loaders
 enum LoaderEnum {
     BinLoader,
     ElfLoader,
+    SegmentLoader,
+}
+
+impl LoaderEnum {
+    /// The loader for a program described by `metadata`, which has been checked against
+    /// [`device_info`](super::device_info).
+    fn new(metadata: &Metadata, platform: &dyn Platform) -> Self {
+        match metadata.format_details {
+            FormatDetails::Bin { load_address } => Self::BinLoader(BinLoader::new(
+                load_address.try_into().expect(
+                    "cannot reach this point with load_address that is not representable as u32",
+                ),
+                metadata.clone(),
+                platform.image_end(),
+            )),
+            FormatDetails::Elf => Self::ElfLoader(ElfLoader::new(metadata.clone())),
+            FormatDetails::Segments { entry } => Self::SegmentLoader(SegmentLoader::new(
+                entry.try_into().expect(
+                    "cannot reach this point with an entry that is not representable as u32",
+                ),
+                metadata.clone(),
+            )),
+        }
+    }
 }
 
 #[enum_dispatch::enum_dispatch(LoaderEnum)]
 trait Loader: Debug {
-    fn receive_bytes(&mut self, bytes: &[u8]) -> Result<(), LoadError>;
+    fn metadata(&self) -> &Metadata;
+    /// Number of (inflated) bytes received so far.
+    fn bytes_loaded(&self) -> usize;
+    fn receive_bytes(&mut self, bytes: &[u8], platform: &dyn Platform) -> Result<(), LoadError>;
     fn finalize(
         self,
         frame_sink: &mut FrameSink,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
     ) -> Result<Booter, LoadError>;
 }
 
@@ -605,11 +1176,11 @@ struct BinLoader {
     bytes_written: usize,
 }
 impl BinLoader {
-    pub fn new(load_address: u32, metadata: Metadata) -> Self {
+    pub fn new(load_address: u32, metadata: Metadata, image_end: usize) -> Self {
         let relocation = Relocation::calculate(
             load_address as usize,
             metadata.inflated_len as usize,
-            unsafe { crate::stub::locate_end() }.addr(),
+            image_end,
         );
         Self {
             metadata,
@@ -619,15 +1190,17 @@ impl BinLoader {
     }
 }
 impl Loader for BinLoader {
-    fn receive_bytes(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
-        let ptr = unsafe {
-            self.relocation
-                .base_address_ptr
-                .byte_offset(self.bytes_written as isize)
-        };
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+    fn bytes_loaded(&self) -> usize {
+        self.bytes_written
+    }
+    fn receive_bytes(&mut self, bytes: &[u8], platform: &dyn Platform) -> Result<(), LoadError> {
+        let address = self.relocation.base_address + self.bytes_written;
         self.bytes_written += bytes.len();
         unsafe {
-            self.relocation.write_bytes(ptr, bytes);
+            self.relocation.write_bytes(platform, address, bytes);
         }
         Ok(())
     }
@@ -635,19 +1208,20 @@ impl Loader for BinLoader {
     fn finalize(
         self,
         frame_sink: &mut FrameSink,
-        peripherals: &Peripherals,
+        platform: &dyn Platform,
     ) -> Result<Booter, LoadError> {
         rpc_println!(frame_sink, "[device/v2] booter={self:?}");
 
         match unsafe {
             self.relocation.verify_integrity(
+                platform,
                 self.metadata.inflated_crc,
                 self.metadata.inflated_len as usize,
             )
         } {
             Integrity::Ok => {
                 rpc_println!(frame_sink, "[device/v2] CRCs okay, running relocation stub");
-                super::flush_to_fifo(frame_sink, &peripherals.UART1);
+                super::flush_to_fifo(frame_sink, platform);
                 Ok(Booter::flat_binary(self.relocation.clone()))
             }
             Integrity::CrcMismatch {
@@ -660,7 +1234,7 @@ impl Loader for BinLoader {
                     expected,
                     calculated
                 );
-                super::flush_to_fifo(frame_sink, &peripherals.UART1);
+                super::flush_to_fifo(frame_sink, platform);
                 Err(LoadError::Crc)
             }
         }
@@ -669,7 +1243,6 @@ impl Loader for BinLoader {
 
 #[derive(Debug)]
 struct ElfLoader {
-    #[allow(unused)]
     metadata: Metadata,
     bytes: Vec<u8>,
     program_headers: Vec<Elf32_Phdr>,
@@ -678,40 +1251,19 @@ impl ElfLoader {
     pub fn new(metadata: Metadata) -> Self {
         Self {
             metadata,
-            bytes: Vec::with_capacity(metadata.deflated_len as usize),
+            bytes: Vec::with_capacity(metadata.inflated_len as usize),
             program_headers: vec![],
         }
     }
 }
-#[derive(Debug, Error)]
-pub enum ElfError {
-    #[error("error parsing ELF header: {0}")]
-    Parse(elf::ParseError),
-    #[error("expected ELF32, found ELF64")]
-    Class,
-    #[error("expected EM_ARM")]
-    Machine,
-    #[error("expected ET_EXEC")]
-    Type,
-    #[error("expected ELF v1")]
-    Version,
-    #[error("expected entry below 0x1000_0000")]
-    Entry,
-    #[error("expected little-endian ELF binary")]
-    Endianness,
-    #[error("expected e_ident[EI_OSABI] to be 0 (none/sysv)")]
-    OsAbi,
-    #[error("expected a segment table")]
-    NoSegmentTable,
-    #[error("ELF TLS is not supported yet")]
-    Tls,
-    #[error("expected PT_LOAD or PT_TLS segment")]
-    SegmentType,
-    #[error("PT_LOAD must have p_filesz=p_memsz or p_filesz=0")]
-    SegmentSize,
-}
 impl Loader for ElfLoader {
-    fn receive_bytes(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+    fn bytes_loaded(&self) -> usize {
+        self.bytes.len()
+    }
+    fn receive_bytes(&mut self, bytes: &[u8], _platform: &dyn Platform) -> Result<(), LoadError> {
         self.bytes.extend_from_slice(bytes);
 
         Ok(())
@@ -720,95 +1272,179 @@ impl Loader for ElfLoader {
     fn finalize(
         mut self,
         frame_sink: &mut FrameSink,
-        _peripherals: &Peripherals,
+        _platform: &dyn Platform,
     ) -> Result<Booter, LoadError> {
+        // the loader receives the inflated bytes
         let calculated_crc = crc32fast::hash(&self.bytes);
-        if calculated_crc != self.metadata.deflated_crc {
+        if calculated_crc != self.metadata.inflated_crc {
             rpc_println!(
                 frame_sink,
                 "[device/v2] CRC mismatch: expected {:#010x} calculated {:#010x}",
-                self.metadata.deflated_crc,
+                self.metadata.inflated_crc,
                 calculated_crc
             );
             return Err(LoadError::Crc);
         }
-        let eb: ElfBytes<LittleEndian> = match elf::ElfBytes::minimal_parse(&self.bytes) {
-            Ok(eb) => eb,
-            Err(e) => {
-                return Err(LoadError::Elf(ElfError::Parse(e)));
-            }
-        };
-        let ehdr = &eb.ehdr;
-        if !matches!(ehdr.class, Class::ELF32) {
-            return Err(LoadError::Elf(ElfError::Class));
-        }
-        if !ehdr.endianness.is_little() {
-            return Err(LoadError::Elf(ElfError::Endianness));
-        }
-        if ehdr.osabi != 0 {
-            return Err(LoadError::Elf(ElfError::OsAbi));
-        }
+        let program_headers = &mut self.program_headers;
+        let entry = executable::validate(&self.bytes, LOAD_END as u64, |_, segment| {
+            program_headers.push(Elf32_Phdr {
+                p_type: segment.p_type,
+                p_offset: segment.p_offset as u32,
+                p_vaddr: segment.p_vaddr as u32,
+                p_paddr: segment.p_paddr as u32,
+                p_filesz: segment.p_filesz as u32,
+                p_memsz: segment.p_memsz as u32,
+                p_flags: segment.p_flags,
+                p_align: segment.p_align as u32,
+            });
+        })
+        .map_err(LoadError::Elf)?;
 
-        if ehdr.e_type != ET_EXEC {
-            return Err(LoadError::Elf(ElfError::Type));
+        Ok(Booter::Elf {
+            program_headers: self.program_headers,
+            elf: self.bytes,
+            entry: entry as usize,
+        })
+    }
+}
+
+/// Receives a [`FormatDetails::Segments`] program: holds on to the segment table at the start,
+/// then writes each segment's bytes straight to where they go as they arrive, through a side
+/// buffer for the ones that overlap okboot.
+#[derive(Debug)]
+struct SegmentLoader {
+    metadata: Metadata,
+    entry: usize,
+    /// The segment table, as much of it as has arrived.
+    table: Vec<u8>,
+    /// Where the segments go, once the whole table has arrived.
+    layout: Option<(Vec<Segment>, Relocation)>,
+    /// Segment that the next byte belongs to, and how far into it it goes.
+    segment: usize,
+    offset: usize,
+    bytes_received: usize,
+    /// CRC of everything received so far, table included.
+    crc: u32,
+}
+impl SegmentLoader {
+    pub fn new(entry: u32, metadata: Metadata) -> Self {
+        Self {
+            metadata,
+            entry: entry as usize,
+            table: vec![],
+            layout: None,
+            segment: 0,
+            offset: 0,
+            bytes_received: 0,
+            crc: 0,
         }
-        if ehdr.e_machine != EM_ARM {
-            return Err(LoadError::Elf(ElfError::Machine));
+    }
+
+    /// Take as much of the table from the front of `bytes` as is missing, and work out where the
+    /// segments go once it's all there; returns the rest of `bytes`.
+    fn receive_table<'a>(
+        &mut self,
+        bytes: &'a [u8],
+        platform: &dyn Platform,
+    ) -> Result<&'a [u8], LoadError> {
+        let mut wanted = segments::HEADER_LEN;
+        if let Some(header) = self.table.first_chunk() {
+            wanted = segments::table_len(*header).map_err(LoadError::Segments)?;
         }
-        if ehdr.version != 1 {
-            return Err(LoadError::Elf(ElfError::Version));
+        let (table, rest) = bytes.split_at(bytes.len().min(wanted - self.table.len()));
+        self.table.extend_from_slice(table);
+        if self.table.len() < wanted {
+            return Ok(rest);
         }
-        let entry = ehdr.e_entry;
-        if entry >= 0x1000_0000 {
-            return Err(LoadError::Elf(ElfError::Entry));
+        if wanted == segments::HEADER_LEN {
+            // now the header says how long the rest of the table is
+            return self.receive_table(rest, platform);
         }
+        segments::check(&self.table, self.metadata.inflated_len).map_err(LoadError::Segments)?;
+        let list: Vec<Segment> = segments::entries(&self.table).collect();
+        super::device_info(platform)
+            .check_segments(list.iter().copied())
+            .map_err(LoadError::Misfit)?;
+        let start = list[0].address as usize;
+        let end = list.iter().map(|s| s.end()).max().unwrap_or_default() as usize;
+        let relocation =
+            Relocation::calculate(start, end - start, platform.image_end()).with_entry(self.entry);
+        self.layout = Some((list, relocation));
+        Ok(rest)
+    }
+}
+impl Loader for SegmentLoader {
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+    fn bytes_loaded(&self) -> usize {
+        self.bytes_received
+    }
+    fn receive_bytes(&mut self, bytes: &[u8], platform: &dyn Platform) -> Result<(), LoadError> {
+        let mut hasher = crc32fast::Hasher::new_with_initial(self.crc);
+        hasher.update(bytes);
+        self.crc = hasher.finalize();
+        self.bytes_received += bytes.len();
 
-        let Some(segment_table) = eb.segments() else {
-            return Err(LoadError::Elf(ElfError::NoSegmentTable));
+        let mut bytes = bytes;
+        if self.layout.is_none() {
+            bytes = self.receive_table(bytes, platform)?;
+        }
+        let Some((list, relocation)) = &self.layout else {
+            return Ok(());
         };
-        for segment in segment_table.iter() {
-            if segment.p_type == PT_TLS {
-                return Err(LoadError::Elf(ElfError::Tls));
-            } else if segment.p_type == PT_LOAD {
-                // offset - offset in file
-                // vaddr - "virtual" address to load at - we treat this as physical
-                // paddr - physical address - ignored
-                // filesz - size in file
-                // memsz - size in memory
-                // flags
-                // align -  As ``Program Loading'' describes in this chapter of the processor
-                //          supplement, loadable process segments must have congruent values for
-                //          p_vaddr and p_offset, modulo the page size. This member gives the value
-                //          to which the segments are aligned in memory and in the file. Values 0
-                //          and 1 mean no alignment is required. Otherwise, p_align should be a
-                //          positive, integral power of 2, and p_vaddr should equal p_offset, modulo
-                //          p_align.
-                if (segment.p_filesz != segment.p_memsz) && (segment.p_filesz != 0) {
-                    return Err(LoadError::Elf(ElfError::SegmentSize));
-                }
-                self.program_headers.push(Elf32_Phdr {
-                    p_type: segment.p_type,
-                    p_offset: segment.p_offset as u32,
-                    p_vaddr: segment.p_vaddr as u32,
-                    p_paddr: segment.p_paddr as u32,
-                    p_filesz: segment.p_filesz as u32,
-                    p_memsz: segment.p_memsz as u32,
-                    p_flags: segment.p_flags,
-                    p_align: segment.p_align as u32,
-                });
-            } else if segment.p_type == PT_GNU_STACK {
-                rpc_println!(frame_sink, "[device/v2/elf] ignoring PT_GNU_STACK");
-            } else if segment.p_type == PT_NOTE {
-                rpc_println!(frame_sink, "[device/v2/elf] ignoring PT_NOTE");
-            } else {
-                return Err(LoadError::Elf(ElfError::SegmentType));
+        while !bytes.is_empty() {
+            let Some(segment) = list.get(self.segment) else {
+                // segments::check made sure that the table accounts for every byte
+                return Err(LoadError::Segments(SegmentError::Length {
+                    described: self.metadata.inflated_len as u64,
+                    len: self.bytes_received as u32,
+                }));
+            };
+            let n = bytes.len().min(segment.len as usize - self.offset);
+            let address = segment.address as usize + self.offset;
+            unsafe {
+                relocation.write_bytes(platform, address, &bytes[..n]);
+            }
+            bytes = &bytes[n..];
+            self.offset += n;
+            if self.offset == segment.len as usize {
+                self.segment += 1;
+                self.offset = 0;
             }
         }
+        Ok(())
+    }
 
-        Ok(Booter::Elf {
-            program_headers: self.program_headers,
-            elf: self.bytes,
-            entry: entry as usize,
+    fn finalize(
+        self,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) -> Result<Booter, LoadError> {
+        if self.crc != self.metadata.inflated_crc {
+            rpc_println!(
+                frame_sink,
+                "[device/v2] CRC mismatch: expected {:#010x} calculated {:#010x}",
+                self.metadata.inflated_crc,
+                self.crc
+            );
+            return Err(LoadError::Crc);
+        }
+        let Some((list, relocation)) = self.layout else {
+            return Err(LoadError::Segments(SegmentError::Length {
+                described: self.table.len() as u64,
+                len: self.metadata.inflated_len,
+            }));
+        };
+        rpc_println!(
+            frame_sink,
+            "[device/v2] CRC okay, running relocation stub to check {} segments in place",
+            list.len()
+        );
+        super::flush_to_fifo(frame_sink, platform);
+        Ok(Booter::Segments {
+            relocation,
+            segments: list,
         })
     }
 }
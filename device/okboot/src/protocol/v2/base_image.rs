@@ -0,0 +1,149 @@
+//! The image of the last successful upload, kept in
+//! [retained memory](crate::platform::Memory::retained) so that the host can send a
+//! [delta](okboot_common::delta) against it next time.
+//!
+//! Retained memory is laid out as follows:
+//! ```txt
+//! | header (HEADER_SIZE) | slot 0 | slot 1 |
+//! ```
+//! The header names the slot holding the current base image, along with its length and SHA-256
+//! digest. While a program is being uploaded, it's copied into the other slot, so that the base
+//! image stays intact in case the upload fails; once the program has been loaded successfully,
+//! the header is switched over to it.
+use crate::platform::Platform;
+use core::ops::Range;
+use sha2::{Digest, Sha256};
+
+const MAGIC: [u8; 4] = *b"okbi";
+/// Space reserved at the start of retained memory for the header.
+const HEADER_SIZE: usize = 0x1000;
+/// Bytes of the header that are actually used: magic, slot, length, and digest.
+const HEADER_LEN: usize = 4 + 4 + 4 + 32;
+
+/// Addresses of slot `which` in retained memory; empty if there isn't any.
+fn slot(platform: &dyn Platform, which: usize) -> Range<usize> {
+    let retained = platform.retained();
+    if retained.len() <= HEADER_SIZE {
+        return 0..0;
+    }
+    let slot_size = ((retained.len() - HEADER_SIZE) / 2) & !0xfff;
+    let start = retained.start + HEADER_SIZE + which * slot_size;
+    start..start + slot_size
+}
+
+/// Largest program that can be kept as a base image; programs to be
+/// [persisted](okboot_common::persist) are written to the SD card from there, so it's the largest
+/// of those too.
+pub(crate) fn capacity(platform: &dyn Platform) -> usize {
+    slot(platform, 0).len()
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct BaseImage {
+    slot: usize,
+    address: usize,
+    len: usize,
+    hash: [u8; 32],
+}
+impl BaseImage {
+    /// The base image recorded in the header, if any. Only to be trusted once [`validate`] has
+    /// been called.
+    pub fn find(platform: &dyn Platform) -> Option<Self> {
+        let retained = platform.retained();
+        if retained.len() <= HEADER_SIZE {
+            return None;
+        }
+        // SAFETY: nothing but okboot uses retained memory while it's running
+        let header = unsafe { platform.read(retained.start, HEADER_LEN) };
+        if header[0..4] != MAGIC {
+            return None;
+        }
+        let word = |i: usize| u32::from_le_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+        let (which, len) = (word(1) as usize, word(2) as usize);
+        let slot = slot(platform, which);
+        if which > 1 || len > slot.len() {
+            return None;
+        }
+        Some(Self {
+            slot: which,
+            address: slot.start,
+            len,
+            hash: header[12..HEADER_LEN].try_into().unwrap(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+
+    pub fn bytes<'a>(&self, platform: &'a dyn Platform) -> &'a [u8] {
+        // SAFETY: only the other slot is written to while a base image is in use
+        unsafe { platform.read(self.address, self.len) }
+    }
+}
+
+/// Check that the base image is still intact, and forget it if it isn't: programs are free to
+/// use retained memory for whatever they like.
+pub(crate) fn validate(platform: &dyn Platform) {
+    let Some(base) = BaseImage::find(platform) else {
+        return;
+    };
+    if Sha256::digest(base.bytes(platform)).as_slice() == base.hash() {
+        log::info!("keeping {} byte image for delta uploads", base.len());
+    } else {
+        log::info!("previous image was overwritten, delta uploads unavailable");
+        // SAFETY: nothing but okboot uses retained memory while it's running
+        unsafe { platform.write(platform.retained().start, &[0; 4]) };
+    }
+}
+
+/// Copies the program being loaded into the slot that the current base image isn't in.
+pub(super) struct Retainer {
+    slot: usize,
+    address: usize,
+    expected: usize,
+    written: usize,
+    digest: Sha256,
+}
+impl Retainer {
+    /// Returns `None` if a program of `len` bytes doesn't fit into a slot.
+    pub fn new(platform: &dyn Platform, len: usize) -> Option<Self> {
+        let which = BaseImage::find(platform).map_or(0, |base| 1 - base.slot);
+        let slot = slot(platform, which);
+        (len <= slot.len()).then(|| Self {
+            slot: which,
+            address: slot.start,
+            expected: len,
+            written: 0,
+            digest: Sha256::new(),
+        })
+    }
+
+    pub fn update(&mut self, bytes: &[u8], platform: &dyn Platform) {
+        let end = self.written + bytes.len();
+        if end <= self.expected {
+            // SAFETY: nothing but okboot uses retained memory while it's running
+            unsafe { platform.write(self.address + self.written, bytes) };
+            self.digest.update(bytes);
+        }
+        self.written = end;
+    }
+
+    /// Make the copied program the base image for the next upload.
+    pub fn commit(self, platform: &dyn Platform) {
+        if self.written != self.expected {
+            return;
+        }
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&(self.slot as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(self.written as u32).to_le_bytes());
+        header[12..].copy_from_slice(&self.digest.finalize());
+        // SAFETY: nothing but okboot uses retained memory while it's running
+        unsafe { platform.write(platform.retained().start, &header) };
+    }
+}
@@ -0,0 +1,82 @@
+//! Booting the program kept in the [default slot](Slot::DEFAULT) on the SD card, for when no host
+//! turns up. It's loaded by the same [`Loader`]s as an uploaded program, and checked the same way:
+//! against the [`DeviceInfo`](okboot_common::device::DeviceInfo), against its CRC-32, and against
+//! its signature if the platform has a [verifying key](crate::platform::Board::verifying_key).
+use super::signature::ImageVerifier;
+use super::{Booter, LoadError, Loader, LoaderEnum};
+use crate::buf::FrameSink;
+use crate::legacy_print_string;
+use crate::platform::Platform;
+use crate::protocol::persist;
+use okboot_common::compression::Compression;
+use okboot_common::device::{Misfit, SignatureError};
+use okboot_common::fat::FatError;
+use okboot_common::host::{ImageSignature, Metadata};
+use okboot_common::persist::Slot;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+enum FallbackError {
+    #[error("can't read the SD card: {0}")]
+    Storage(FatError),
+    #[error("no program is kept in slot {0}")]
+    Missing(Slot),
+    #[error("can't load the kept program: {0}")]
+    Misfit(Misfit),
+    #[error("failed to load the kept program: {0}")]
+    Load(LoadError),
+    #[error("refusing to boot the kept program: {0}")]
+    Signature(SignatureError),
+}
+
+/// Load the program kept in the default slot, ready to be booted; `None`, having said why, if
+/// there isn't one that can be.
+pub fn boot(frame_sink: &mut FrameSink, platform: &dyn Platform) -> Option<Booter> {
+    match load(frame_sink, platform) {
+        Ok(booter) => Some(booter),
+        Err(e) => {
+            legacy_print_string!(frame_sink, "[device]: not booting from the SD card: {e}");
+            None
+        }
+    }
+}
+
+fn load(frame_sink: &mut FrameSink, platform: &dyn Platform) -> Result<Booter, FallbackError> {
+    let kept = persist::find(platform, Slot::DEFAULT)
+        .map_err(FallbackError::Storage)?
+        .ok_or(FallbackError::Missing(Slot::DEFAULT))?;
+    let header = kept.header;
+    super::super::device_info(platform)
+        .check(header.format_details, header.len)
+        .map_err(FallbackError::Misfit)?;
+    // as if it had been uploaded uncompressed
+    let metadata = Metadata {
+        deflated_crc: header.crc,
+        deflated_len: header.len,
+        inflated_crc: header.crc,
+        inflated_len: header.len,
+        format_details: header.format_details,
+        compression: Compression::None,
+        persist: None,
+        signature: header.signature.map(ImageSignature),
+    };
+    let mut loader = LoaderEnum::new(&metadata, platform);
+    let mut verifier = ImageVerifier::default();
+    verifier.reset(&metadata);
+
+    let mut result = Ok(());
+    kept.read(platform, |bytes| {
+        verifier.update(bytes);
+        result = loader.receive_bytes(bytes, platform);
+        result.is_ok()
+    })
+    .map_err(FallbackError::Storage)?;
+    result.map_err(FallbackError::Load)?;
+    let verified = verifier
+        .verify(platform.verifying_key())
+        .map_err(FallbackError::Signature)?;
+    if verified {
+        log::info!("kept program's signature verified");
+    }
+    Loader::finalize(loader, frame_sink, platform).map_err(FallbackError::Load)
+}
@@ -0,0 +1,79 @@
+use ed25519_dalek::{Signature, VerifyingKey};
+use okboot_common::device::SignatureError;
+use okboot_common::host::{ImageSignature, Metadata};
+use sha2::{Digest, Sha512};
+
+/// Ed25519 public key that programs must be signed with, given as 64 hex digits in the
+/// `OKBOOT_VERIFYING_KEY` environment variable at build time. If it isn't set, any program is
+/// booted, signed or not. It's what [`Board::verifying_key`](crate::platform::Board::verifying_key)
+/// returns unless a platform says otherwise.
+pub(crate) const VERIFYING_KEY: Option<[u8; 32]> = match option_env!("OKBOOT_VERIFYING_KEY") {
+    Some(hex) => Some(parse_hex_key(hex)),
+    None => None,
+};
+
+const fn parse_hex_key(hex: &str) -> [u8; 32] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("OKBOOT_VERIFYING_KEY must only contain hex digits"),
+        }
+    }
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        panic!("OKBOOT_VERIFYING_KEY must be 64 hex digits");
+    }
+    let mut key = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        key[i] = (nibble(hex[2 * i]) << 4) | nibble(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+/// Accumulates the digest of the program as it's inflated, and checks it against the
+/// [`ImageSignature`] that the host sent in its [`Metadata`].
+#[derive(Default)]
+pub(super) struct ImageVerifier {
+    digest: Sha512,
+    signature: Option<Signature>,
+}
+
+impl ImageVerifier {
+    /// Start over for the new program described by `metadata`.
+    pub fn reset(&mut self, metadata: &Metadata) {
+        let mut buf = [0; 16];
+        let prefix = postcard::to_slice(&metadata.format_details, &mut buf)
+            .expect("serialized FormatDetails should fit in 16 bytes");
+        self.digest = Sha512::new_with_prefix(prefix);
+        self.signature = metadata
+            .signature
+            .map(|ImageSignature(signature)| Signature::from_bytes(&signature));
+    }
+
+    /// Add inflated program bytes to the digest.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.digest.update(bytes);
+    }
+
+    /// The signature that the host sent, if it did, to keep along with the program.
+    pub fn signature(&self) -> Option<[u8; ImageSignature::SIGNATURE_LEN]> {
+        self.signature.map(|signature| signature.to_bytes())
+    }
+
+    /// Check the signature against `key`. Returns `Ok(false)` if there's no key to check it
+    /// against.
+    pub fn verify(self, key: Option<[u8; 32]>) -> Result<bool, SignatureError> {
+        let Some(key) = key else {
+            return Ok(false);
+        };
+        let key = VerifyingKey::from_bytes(&key).map_err(|_| SignatureError::Key)?;
+        let signature = self.signature.ok_or(SignatureError::Missing)?;
+        key.verify_prehashed_strict(self.digest, Some(ImageSignature::CONTEXT), &signature)
+            .map_err(|_| SignatureError::Mismatch)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,373 @@
+use super::{ImageSink, LoaderEnum};
+use crate::buf::FrameSink;
+use crate::platform::Platform;
+use crate::rpc_println;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use okboot_common::compression::Decoder;
+
+/// Maximum number of bytes inflated per call to [`ChunkWindow::inflate_step`].
+// The host keeps streaming while we inflate, so each step needs to be short enough that the mini
+// UART's 8-byte FIFO doesn't overrun before the main loop gets back around to it.
+const INFLATE_STEP: usize = 0x100;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum Accept {
+    /// Chunk was stored in the window.
+    Stored,
+    /// Chunk was already received; ignored.
+    Duplicate,
+    /// Chunk lies beyond the window; ignored.
+    OutOfWindow,
+    /// Chunk is larger than the negotiated chunk size; ignored.
+    Oversize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum Progress {
+    /// More input is needed, or there is more output to be had.
+    Pending,
+    /// The compressed stream has been fully decoded.
+    Finished,
+}
+
+/// Reassembly window for the pipelined (V3) chunk transfer.
+///
+/// Chunks arriving from the host are parked in a ring of `size` slots, starting at chunk `next`.
+/// Once the chunk at `next` has arrived, it's moved to the inflate staging buffer, which frees its
+/// slot and advances the window. Inflation happens incrementally in [`inflate_step`], so that a
+/// single call never takes long enough to starve the UART.
+///
+/// [`inflate_step`]: ChunkWindow::inflate_step
+pub(super) struct ChunkWindow {
+    chunk_size: usize,
+    count: usize,
+    size: usize,
+
+    /// First chunk that has not yet been moved to `staging`.
+    next: usize,
+    /// Slot index corresponding to `next`.
+    base: usize,
+    slots: Vec<u8>,
+    lens: Vec<Option<usize>>,
+    /// Bit `i` is set if a [`ChunkReq`](okboot_common::device::ChunkReq) has been sent for chunk
+    /// `next + i` since the last resynchronization.
+    requested: u32,
+
+    staging: Vec<u8>,
+    remainder: usize,
+    output: Vec<u8>,
+    /// Part of `output` that the [`ImageSink`] hasn't used up yet.
+    pending: Range<usize>,
+    /// Whether the compressed stream has been fully decoded into `output`.
+    inflated: bool,
+
+    ack_pending: bool,
+}
+impl core::fmt::Debug for ChunkWindow {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ChunkWindow")
+            .field("chunk_size", &self.chunk_size)
+            .field("count", &self.count)
+            .field("size", &self.size)
+            .field("next", &self.next)
+            .field("received", &self.received())
+            .field("requested", &self.requested)
+            .field("remainder", &self.remainder)
+            .finish()
+    }
+}
+
+impl ChunkWindow {
+    pub fn new(count: usize, size: usize, chunk_size: usize) -> Self {
+        assert!(
+            size > 0 && size <= 32,
+            "window must fit in a ChunkAck bitmap"
+        );
+        Self {
+            chunk_size,
+            count,
+            size,
+            next: 0,
+            base: 0,
+            slots: vec![0; size * chunk_size],
+            lens: vec![None; size],
+            requested: 0,
+            staging: vec![0; 2 * chunk_size],
+            remainder: 0,
+            output: vec![0; INFLATE_STEP],
+            pending: 0..0,
+            inflated: false,
+            ack_pending: true,
+        }
+    }
+
+    fn slot_of(&self, offset: usize) -> usize {
+        (self.base + offset) % self.size
+    }
+
+    /// Cumulative acknowledgement: all chunks before this one have been received.
+    pub fn next(&self) -> usize {
+        self.next
+    }
+
+    /// Selective acknowledgement bitmap, as described in
+    /// [`ChunkAck`](okboot_common::device::ChunkAck).
+    pub fn received(&self) -> u32 {
+        (1..self.size)
+            .filter(|&i| self.lens[self.slot_of(i)].is_some())
+            .fold(0, |acc, i| acc | (1 << (i - 1)))
+    }
+
+    pub fn take_ack_pending(&mut self) -> bool {
+        core::mem::replace(&mut self.ack_pending, false)
+    }
+
+    pub fn set_ack_pending(&mut self) {
+        self.ack_pending = true;
+    }
+
+    /// Forget which chunks have been re-requested, so that [`for_each_gap`] will report every
+    /// missing chunk again.
+    ///
+    /// [`for_each_gap`]: ChunkWindow::for_each_gap
+    pub fn resynchronize(&mut self) {
+        self.requested = 0;
+        self.ack_pending = true;
+    }
+
+    pub fn accept(&mut self, which: usize, bytes: &[u8]) -> Accept {
+        if which < self.next {
+            return Accept::Duplicate;
+        }
+        let offset = which - self.next;
+        if offset >= self.size || which >= self.count {
+            return Accept::OutOfWindow;
+        }
+        if bytes.len() > self.chunk_size {
+            return Accept::Oversize;
+        }
+        let slot = self.slot_of(offset);
+        if self.lens[slot].is_some() {
+            return Accept::Duplicate;
+        }
+        let begin = slot * self.chunk_size;
+        self.slots[begin..begin + bytes.len()].copy_from_slice(bytes);
+        self.lens[slot] = Some(bytes.len());
+        self.ack_pending = true;
+        Accept::Stored
+    }
+
+    /// Call `f` for every chunk which is missing from the window even though a later chunk has
+    /// already arrived, and which has not already been reported since the last
+    /// [`resynchronize`](ChunkWindow::resynchronize). If `f` returns `false`, the chunk is not
+    /// marked as requested and iteration stops.
+    pub fn for_each_gap(&mut self, mut f: impl FnMut(usize) -> bool) {
+        let Some(highest) = (0..self.size)
+            .rev()
+            .find(|&i| self.lens[self.slot_of(i)].is_some())
+        else {
+            return;
+        };
+        for i in 0..highest {
+            if self.lens[self.slot_of(i)].is_none() && (self.requested & (1 << i)) == 0 {
+                if !f(self.next + i) {
+                    return;
+                }
+                self.requested |= 1 << i;
+            }
+        }
+    }
+
+    /// Whether the chunk at the start of the window has not arrived yet.
+    pub fn is_waiting(&self) -> bool {
+        self.next < self.count && self.lens[self.base].is_none()
+    }
+
+    /// Move the chunk at the start of the window into the staging buffer if it has arrived and
+    /// there is room for it.
+    fn pull(&mut self) {
+        if self.next >= self.count {
+            return;
+        }
+        let Some(len) = self.lens[self.base] else {
+            return;
+        };
+        if self.remainder + len > self.staging.len() {
+            return;
+        }
+        let begin = self.base * self.chunk_size;
+        self.staging[self.remainder..self.remainder + len]
+            .copy_from_slice(&self.slots[begin..begin + len]);
+        self.remainder += len;
+        self.lens[self.base] = None;
+        self.base = (self.base + 1) % self.size;
+        self.next += 1;
+        self.requested >>= 1;
+        self.ack_pending = true;
+    }
+
+    /// Perform a bounded amount of inflation, passing the output through `sink` to `loader`.
+    pub fn inflate_step(
+        &mut self,
+        decoder: &mut Decoder,
+        loader: &mut LoaderEnum,
+        sink: &mut ImageSink,
+        frame_sink: &mut FrameSink,
+        platform: &dyn Platform,
+    ) -> Result<Progress, ()> {
+        self.pull();
+
+        // a delta can expand into much more than was inflated; catch up on that first
+        if self.pending.is_empty() && !sink.has_output() && !self.inflated {
+            self.decode(decoder, frame_sink)?;
+        }
+
+        match sink.receive(
+            &self.output[self.pending.clone()],
+            INFLATE_STEP,
+            loader,
+            platform,
+        ) {
+            Ok(consumed) => self.pending.start += consumed,
+            Err(e) => {
+                rpc_println!(frame_sink, "[device/v3] unrecoverable load error: {}", e);
+                return Err(()); // catastrophic
+            }
+        }
+
+        if self.inflated && self.pending.is_empty() && !sink.has_output() {
+            log::debug!("processed last chunk");
+            Ok(Progress::Finished)
+        } else {
+            Ok(Progress::Pending)
+        }
+    }
+
+    fn decode(&mut self, decoder: &mut Decoder, frame_sink: &mut FrameSink) -> Result<(), ()> {
+        let decoded = match decoder.decode(&self.staging[..self.remainder], &mut self.output) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                rpc_println!(frame_sink, "[device/v3] failed to decompress chunk: {}", e);
+                return Err(()); // catastrophic
+            }
+        };
+        self.staging
+            .copy_within(decoded.consumed..self.remainder, 0);
+        self.remainder -= decoded.consumed;
+        self.pending = 0..decoded.written;
+
+        let stalled = decoded.consumed == 0 && decoded.written == 0;
+        if decoded.finished || (self.next == self.count && stalled) {
+            self.inflated = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accept, ChunkWindow};
+    use alloc::vec::Vec;
+
+    /// Move the chunk at the start of the window into staging, and take it back out again as
+    /// inflating it would. Returns the chunk's bytes, or nothing if it couldn't be moved.
+    fn pull(window: &mut ChunkWindow) -> Vec<u8> {
+        window.pull();
+        let staged = window.staging[..window.remainder].to_vec();
+        window.remainder = 0;
+        staged
+    }
+
+    fn gaps(window: &mut ChunkWindow) -> Vec<usize> {
+        let mut gaps = vec![];
+        window.for_each_gap(|which| {
+            gaps.push(which);
+            true
+        });
+        gaps
+    }
+
+    #[test]
+    fn ignores_duplicate_and_out_of_window_chunks() {
+        let mut window = ChunkWindow::new(10, 4, 4);
+        assert_eq!(window.accept(0, &[1, 2, 3, 4]), Accept::Stored);
+        assert_eq!(window.accept(0, &[5, 6, 7, 8]), Accept::Duplicate);
+        assert_eq!(window.accept(4, &[0; 4]), Accept::OutOfWindow);
+        assert_eq!(window.accept(1, &[0; 5]), Accept::Oversize);
+        assert_eq!(pull(&mut window), [1, 2, 3, 4]);
+
+        // the window now covers chunks 1 to 4
+        assert_eq!(window.accept(0, &[5, 6, 7, 8]), Accept::Duplicate);
+        assert_eq!(window.accept(4, &[0; 4]), Accept::Stored);
+        assert_eq!(window.accept(5, &[0; 4]), Accept::OutOfWindow);
+    }
+
+    #[test]
+    fn reports_gaps_in_order_once() {
+        let mut window = ChunkWindow::new(10, 8, 4);
+        for which in [6, 1, 4] {
+            assert_eq!(window.accept(which, &[which as u8]), Accept::Stored);
+        }
+        assert_eq!(gaps(&mut window), [0, 2, 3, 5]);
+        assert_eq!(gaps(&mut window), []);
+
+        // a chunk that couldn't be requested is reported again
+        window.resynchronize();
+        let mut requested = vec![];
+        window.for_each_gap(|which| {
+            requested.push(which);
+            which < 3
+        });
+        assert_eq!(requested, [0, 2, 3]);
+        assert_eq!(gaps(&mut window), [3, 5]);
+    }
+
+    #[test]
+    fn received_bitmap_follows_the_window() {
+        let mut window = ChunkWindow::new(10, 4, 4);
+        window.accept(1, &[1]);
+        window.accept(3, &[3]);
+        assert_eq!(window.received(), 0b101);
+        assert!(window.is_waiting());
+        assert_eq!(pull(&mut window), []);
+
+        window.accept(0, &[0]);
+        assert_eq!(pull(&mut window), [0]);
+        assert_eq!(window.received(), 0b10);
+        assert_eq!(pull(&mut window), [1]);
+        assert_eq!(window.received(), 0b1);
+
+        // into the slots that chunks 0 and 1 left behind
+        window.accept(5, &[5]);
+        window.accept(4, &[4]);
+        assert_eq!(window.received(), 0b111);
+        assert!(window.is_waiting());
+    }
+
+    #[test]
+    fn last_window_stops_at_the_last_chunk() {
+        let mut window = ChunkWindow::new(5, 4, 4);
+        window.accept(0, &[0; 4]);
+        window.accept(1, &[1; 4]);
+        pull(&mut window);
+        pull(&mut window);
+
+        // only chunks 2 to 4 are left, and the last of them is short
+        assert_eq!(window.accept(5, &[5; 4]), Accept::OutOfWindow);
+        assert_eq!(window.accept(4, &[4; 2]), Accept::Stored);
+        assert_eq!(window.accept(3, &[3; 4]), Accept::Stored);
+        assert_eq!(window.received(), 0b11);
+        assert_eq!(gaps(&mut window), [2]);
+        window.accept(2, &[2; 4]);
+        assert_eq!(pull(&mut window), [2; 4]);
+        assert_eq!(pull(&mut window), [3; 4]);
+        assert_eq!(pull(&mut window), [4; 2]);
+
+        assert_eq!(window.next(), 5);
+        assert!(!window.is_waiting());
+        assert_eq!(window.received(), 0);
+        assert_eq!(pull(&mut window), []);
+    }
+}
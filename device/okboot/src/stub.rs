@@ -1,3 +1,4 @@
+#[cfg(not(feature = "sim"))]
 unsafe extern "C" {
     static __symbol_exec_start__: [u8; 0];
     static __symbol_code_start__: [u8; 0];
@@ -11,28 +12,58 @@ unsafe extern "C" {
     pub static __symbol_exec_end__: [u8; 0];
 }
 
+#[cfg(not(feature = "sim"))]
 unsafe extern "C" {
     pub(crate) static __symbol_relocation_stub: [u8; 0];
     pub(crate) static __symbol_relocation_stub_end: [u8; 0];
 }
 
+#[cfg(not(feature = "sim"))]
+pub unsafe fn locate_start() -> *const [u8; 0] {
+    &raw const __symbol_exec_start__
+}
+
+#[cfg(not(feature = "sim"))]
 pub unsafe fn locate_end() -> *const [u8; 0] {
     &raw const __symbol_exec_end__
 }
 
+/// Jump to `entry`, where okdude put a program of its own with `mem write`; nothing needs to be
+/// moved into place first, so there's no stub.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn jump(peripherals: &bcm2835_lpa::Peripherals, entry: usize) -> ! {
+    crate::legacy_print_string_blocking!(&peripherals.UART1, "[device]: jumping to {entry:#x}");
+    crate::mini_uart::mini_uart1_flush_tx(&peripherals.UART1);
+
+    unsafe { quartz::arch::arm1176::mmu::__disable_mmu() };
+
+    unsafe { core::arch::asm!("bx {entry}", entry = in(reg) entry, options(noreturn)) }
+}
+
 pub mod flat_binary {
-    use crate::buf::FrameSink;
+    use crate::platform::Memory;
+    #[cfg(not(feature = "sim"))]
     use crate::stub::{__symbol_relocation_stub, __symbol_relocation_stub_end};
+    #[cfg(not(feature = "sim"))]
     use bcm2835_lpa::Peripherals;
+    #[cfg(not(feature = "sim"))]
     use quartz::arch::arm1176::PAGE_SIZE;
+    #[cfg(not(feature = "sim"))]
     use quartz::arch::arm1176::mmu::__disable_mmu;
 
+    // the simulator doesn't have an MMU, but should put the side buffer in the same place
+    #[cfg(feature = "sim")]
+    const PAGE_SIZE: usize = 0x4000;
+
     #[derive(Clone, Debug)]
     pub struct Relocation {
-        pub base_address_ptr: *mut u8,
-        pub side_buffer_ptr: *mut u8,
+        pub base_address: usize,
+        pub side_buffer: usize,
         pub relocate_first_n_bytes: usize,
-        pub stub_entry: *mut u8,
+        #[cfg_attr(feature = "sim", allow(dead_code))] // only the relocation stub needs it
+        pub stub_entry: usize,
+        /// Where the program starts; `base_address` unless it's [set](Self::with_entry).
+        pub entry: usize,
         relocate: bool,
     }
 
@@ -51,73 +82,72 @@ pub mod flat_binary {
                 // need this to be 4-byte aligned if we want to jump to it
                 let stub_location = (side_buffer_begin + relocation_length + 3) & !3;
                 Relocation {
-                    base_address_ptr: k_base_address as *mut u8,
-                    side_buffer_ptr: side_buffer_begin as *mut u8,
+                    base_address: k_base_address,
+                    side_buffer: side_buffer_begin,
                     relocate_first_n_bytes: relocation_length,
-                    stub_entry: stub_location as *mut u8,
+                    stub_entry: stub_location,
+                    entry: k_base_address,
                     relocate: true,
                 }
             } else {
+                // nothing to copy, but the stub still needs to know where to jump to
                 Relocation {
-                    base_address_ptr: core::ptr::null_mut(),
-                    side_buffer_ptr: core::ptr::null_mut(),
+                    base_address: k_base_address,
+                    side_buffer: 0,
                     relocate_first_n_bytes: 0,
-                    stub_entry: highest_used_address as *mut u8,
+                    stub_entry: highest_used_address,
+                    entry: k_base_address,
                     relocate: false,
                 }
             }
         }
 
-        pub unsafe fn write_bytes(&self, address: *mut u8, bytes: &[u8]) {
-            let (ptr, len) = (bytes.as_ptr(), bytes.len());
-            let write_ptr = if self.relocate
-                && address >= self.base_address_ptr
-                && address
-                    < unsafe {
-                        self.base_address_ptr
-                            .byte_offset(self.relocate_first_n_bytes as isize)
-                    } {
-                unsafe {
-                    self.side_buffer_ptr
-                        .byte_offset(address.byte_offset_from(self.base_address_ptr))
-                }
-            } else {
-                address
-            };
-            unsafe { core::ptr::copy(ptr, write_ptr, len) };
+        pub fn with_entry(self, entry: usize) -> Self {
+            Self { entry, ..self }
         }
 
-        pub unsafe fn verify_integrity(&self, expected_crc: u32, len: usize) -> Integrity {
-            // let peri = unsafe { Peripherals::steal() };
+        /// Write `bytes` to `address`, or to the side buffer for the part of them that has to be
+        /// relocated.
+        pub unsafe fn write_bytes(&self, memory: &dyn Memory, address: usize, bytes: &[u8]) {
+            let split = self.relocated_len(address, bytes.len());
+            let (relocated, in_place) = bytes.split_at(split);
+            if !relocated.is_empty() {
+                let side_address = self.side_buffer + (address - self.base_address);
+                unsafe { memory.write(side_address, relocated) };
+            }
+            if !in_place.is_empty() {
+                unsafe { memory.write(address + split, in_place) };
+            }
+        }
+
+        /// Check the CRC of the `len` bytes that were written from `base_address`.
+        pub unsafe fn verify_integrity(
+            &self,
+            memory: &dyn Memory,
+            expected_crc: u32,
+            len: usize,
+        ) -> Integrity {
+            unsafe { self.verify_range(memory, self.base_address, len, expected_crc) }
+        }
+
+        /// Check the CRC of the `len` bytes that were written from `address`, wherever they
+        /// ended up.
+        pub unsafe fn verify_range(
+            &self,
+            memory: &dyn Memory,
+            address: usize,
+            len: usize,
+            expected_crc: u32,
+        ) -> Integrity {
             let mut hasher = crc32fast::Hasher::new();
-            // crate::print_rpc!(fs, "[device:v1]: verifying integrity (1)");
-            // fs._flush_to_fifo(&rz.peri.UART1);
-            if self.relocate {
-                // crate::print_rpc!(fs, "[device:v1]: verifying integrity (2)");
-                // fs._flush_to_fifo(&rz.peri.UART1);
-                let side_buf = unsafe {
-                    core::slice::from_raw_parts(self.side_buffer_ptr, self.relocate_first_n_bytes)
-                };
-                // legacy_print_string_blocking!(&peri.UART1, "[v2/rel] crc buf {:02x?}", side_buf,);
-                hasher.update(side_buf);
+            let split = self.relocated_len(address, len);
+            if split > 0 {
+                let side_address = self.side_buffer + (address - self.base_address);
+                hasher.update(unsafe { memory.read(side_address, split) });
             }
-            // let a = self.base_address_ptr.byte_offset(self.relocate_first_n_bytes as isize);
-            // let b = len - self.relocate_first_n_bytes;
-            // crate::print_rpc!(fs, "[device:v1]: verifying integrity (3) / {len}:{} / {a:#?}:{b}", self.relocate_first_n_bytes);
-            // fs._flush_to_fifo(&rz.peri.UART1);
-            if len > self.relocate_first_n_bytes {
-                let inplace_buf = unsafe {
-                    core::slice::from_raw_parts(
-                        self.base_address_ptr
-                            .byte_offset(self.relocate_first_n_bytes as isize),
-                        len - self.relocate_first_n_bytes,
-                    )
-                };
-                // legacy_print_string_blocking!(&peri.UART1, "[v2/rel] crc buf {:02x?}", inplace_buf,);
-                hasher.update(inplace_buf);
+            if len > split {
+                hasher.update(unsafe { memory.read(address + split, len - split) });
             }
-            // crate::print_rpc!(fs, "[device:v1]: verifying integrity (4)");
-            // fs._flush_to_fifo(&rz.peri.UART1);
 
             let final_crc = hasher.finalize();
 
@@ -130,6 +160,16 @@ pub mod flat_binary {
                 }
             }
         }
+
+        /// How many of the `len` bytes from `address` go to the side buffer; they're always the
+        /// first ones.
+        fn relocated_len(&self, address: usize, len: usize) -> usize {
+            let relocated_end = self.base_address + self.relocate_first_n_bytes;
+            if !self.relocate || address < self.base_address || address >= relocated_end {
+                return 0;
+            }
+            len.min(relocated_end - address)
+        }
     }
 
     pub enum Integrity {
@@ -137,16 +177,13 @@ pub mod flat_binary {
         CrcMismatch { expected: u32, calculated: u32 },
     }
 
-    pub unsafe fn final_relocation(
-        peripherals: &Peripherals,
-        fs: &mut FrameSink,
-        relocation: Relocation,
-    ) -> ! {
-        let stub_dst = relocation.stub_entry;
-        let kernel_dst = relocation.base_address_ptr;
-        let kernel_src = relocation.side_buffer_ptr;
+    #[cfg(not(feature = "sim"))]
+    pub unsafe fn final_relocation(peripherals: &Peripherals, relocation: Relocation) -> ! {
+        let stub_dst = core::ptr::with_exposed_provenance_mut::<u8>(relocation.stub_entry);
+        let kernel_dst = core::ptr::with_exposed_provenance_mut::<u8>(relocation.base_address);
+        let kernel_src = core::ptr::with_exposed_provenance_mut::<u8>(relocation.side_buffer);
         let kernel_copy_len = relocation.relocate_first_n_bytes;
-        let kernel_entry = relocation.base_address_ptr;
+        let kernel_entry = core::ptr::with_exposed_provenance_mut::<u8>(relocation.entry);
 
         let stub_begin = &raw const __symbol_relocation_stub;
         let stub_end = &raw const __symbol_relocation_stub_end;
@@ -177,7 +214,6 @@ pub mod flat_binary {
             "[device:v1]: Loaded relocation-stub, jumping"
         );
 
-        crate::protocol::flush_to_fifo(fs, &peripherals.UART1);
         crate::mini_uart::mini_uart1_flush_tx(&peripherals.UART1);
 
         unsafe { __disable_mmu() };
@@ -196,13 +232,97 @@ pub mod flat_binary {
     }
 }
 
+#[cfg(not(feature = "sim"))]
+unsafe extern "C" {
+    pub(crate) static __symbol_relocation_segments: [u8; 0];
+    pub(crate) static __symbol_relocation_segments_end: [u8; 0];
+}
+
+/// Segmented programs go through their own stub, which checks each segment's CRC once it's been
+/// moved into place, and hangs with the activity LED on instead of jumping if one doesn't match.
+#[cfg(not(feature = "sim"))]
+pub mod segments {
+    use crate::stub::flat_binary::Relocation;
+    use crate::stub::{__symbol_relocation_segments, __symbol_relocation_segments_end};
+    use bcm2835_lpa::Peripherals;
+    use okboot_common::segments::Segment;
+    use quartz::arch::arm1176::mmu::__disable_mmu;
+
+    pub unsafe fn final_relocation(
+        peripherals: &Peripherals,
+        relocation: Relocation,
+        segments: &[Segment],
+    ) -> ! {
+        // the stub reads the table a word at a time, and the last segment can end anywhere
+        let stub_dst =
+            core::ptr::with_exposed_provenance_mut::<u8>((relocation.stub_entry + 3) & !3);
+        let kernel_dst = core::ptr::with_exposed_provenance_mut::<u8>(relocation.base_address);
+        let kernel_src = core::ptr::with_exposed_provenance_mut::<u8>(relocation.side_buffer);
+        let kernel_copy_len = relocation.relocate_first_n_bytes;
+        let kernel_entry = core::ptr::with_exposed_provenance_mut::<u8>(relocation.entry);
+
+        let stub_begin = &raw const __symbol_relocation_segments;
+        let stub_end = &raw const __symbol_relocation_segments_end;
+
+        let stub_len = unsafe { stub_end.byte_offset_from(stub_begin) as usize };
+
+        // the table goes right after the stub (which is a whole number of words long), where the
+        // copy won't reach it either
+        let table_dst = unsafe { stub_dst.add(stub_len).cast::<[u32; 3]>() };
+        let table_len = segments.len();
+
+        crate::legacy_print_string_blocking!(
+            &peripherals.UART1,
+            "[device:v2]: segment relocation stub parameters:"
+        );
+        crate::legacy_print_string_blocking!(
+            &peripherals.UART1,
+            "\tstub destination={stub_dst:#?}"
+        );
+        crate::legacy_print_string_blocking!(&peripherals.UART1, "\tstub length={stub_len:#?}");
+        crate::legacy_print_string_blocking!(&peripherals.UART1, "\tcopy to={kernel_dst:#?}");
+        crate::legacy_print_string_blocking!(&peripherals.UART1, "\tcopy from={kernel_src:#?}");
+        crate::legacy_print_string_blocking!(&peripherals.UART1, "\tcopy bytes={kernel_copy_len}");
+        crate::legacy_print_string_blocking!(&peripherals.UART1, "\tsegments={table_len}");
+        crate::legacy_print_string_blocking!(&peripherals.UART1, "\tentry={kernel_entry:#?}");
+
+        unsafe {
+            core::ptr::copy(stub_begin as *const u8, stub_dst, stub_len);
+            for (i, segment) in segments.iter().enumerate() {
+                table_dst
+                    .add(i)
+                    .write([segment.address, segment.len, segment.crc]);
+            }
+        }
+
+        crate::mini_uart::mini_uart1_flush_tx(&peripherals.UART1);
+
+        unsafe { __disable_mmu() };
+
+        unsafe {
+            core::arch::asm!(
+            "bx {t0}",
+            in("r0") kernel_dst,
+            in("r1") kernel_src,
+            in("r2") kernel_copy_len,
+            in("r3") kernel_entry,
+            in("r4") table_dst,
+            in("r5") table_len,
+            t0 = in(reg) stub_dst,
+            options(noreturn),
+            )
+        }
+    }
+}
+
+#[cfg(not(feature = "sim"))]
 unsafe extern "C" {
     pub(crate) static __symbol_relocation_elf: [u8; 0];
     pub(crate) static __symbol_relocation_elf_end: [u8; 0];
 }
 
+#[cfg(not(feature = "sim"))]
 pub mod elf {
-    use crate::buf::FrameSink;
     use crate::legacy_print_string_blocking;
     use crate::stub::{__symbol_relocation_elf, __symbol_relocation_elf_end};
     use alloc::vec::Vec;
@@ -217,7 +337,6 @@ pub mod elf {
 
     pub unsafe fn final_relocation(
         peripherals: &Peripherals,
-        fs: &mut FrameSink,
         pheaders: Vec<Elf32_Phdr>,
         elf: &[u8],
         entry: usize,
@@ -268,7 +387,6 @@ pub mod elf {
 
         unsafe { core::ptr::copy(stub_begin.cast(), stub_dst, stub_len) };
 
-        crate::protocol::flush_to_fifo(fs, &peripherals.UART1);
         crate::mini_uart::mini_uart1_flush_tx(&peripherals.UART1);
 
         unsafe { __disable_mmu() };
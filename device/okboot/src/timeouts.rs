@@ -37,5 +37,39 @@ pub const BYTE_READ: RateRelativeTimeout = RateRelativeTimeout::from_bytes(2);
 /// Amount of time after which a session can automatically time out
 pub const SESSION_EXPIRES: RateRelativeTimeout =
     RateRelativeTimeout::from_bytes(12288 /* 0x3000 */);
+/// Amount of time after a session expires during which a transfer it interrupted can be resumed
+pub const SESSION_RESUMABLE: RateRelativeTimeout =
+    RateRelativeTimeout::from_bytes(8 * 12288 /* 8 * SESSION_EXPIRES */);
 /// Interval at which to send GET_PROG_INFO polls
 pub const GET_PROG_INFO_INTERVAL: Duration = Duration::from_millis(300);
+/// Amount of time to wait for a `BaudProbe` at a newly negotiated baud rate before falling back to
+/// the initial baud rate
+pub const BAUD_PROBE: Duration = Duration::from_millis(500);
+/// How long to wait for a host before booting the program kept in the
+/// [default slot](okboot_common::persist::Slot::DEFAULT) on the SD card, if there is one. Given in
+/// seconds in the `OKBOOT_SD_BOOT_TIMEOUT` environment variable at build time, where 0 means never;
+/// defaults to 10 seconds.
+pub const SD_BOOT: Option<Duration> = match option_env!("OKBOOT_SD_BOOT_TIMEOUT") {
+    Some(seconds) => match parse_seconds(seconds) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    },
+    None => Some(Duration::from_secs(10)),
+};
+
+const fn parse_seconds(decimal: &str) -> u64 {
+    let decimal = decimal.as_bytes();
+    if decimal.is_empty() {
+        panic!("OKBOOT_SD_BOOT_TIMEOUT must be a number of seconds");
+    }
+    let mut seconds = 0;
+    let mut i = 0;
+    while i < decimal.len() {
+        match decimal[i] {
+            c @ b'0'..=b'9' => seconds = seconds * 10 + (c - b'0') as u64,
+            _ => panic!("OKBOOT_SD_BOOT_TIMEOUT must be a number of seconds"),
+        }
+        i += 1;
+    }
+    seconds
+}
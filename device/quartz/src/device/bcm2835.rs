@@ -1,3 +1,5 @@
+pub mod emmc;
+pub mod mailbox;
 pub mod mini_uart;
 pub mod soft_uart;
 pub mod timing;
@@ -0,0 +1,287 @@
+//! The EMMC controller, as an SD card driver: enough of the SD protocol to bring a card up in 4-bit
+//! mode and read and write its blocks, a few at a time, without DMA or interrupts.
+//!
+//! The firmware boots from the card, so by the time anything runs, the card is powered and GPIO
+//! 48-53 are already routed to the controller.
+use crate::arch::arm1176::dsb;
+use crate::device::bcm2835::mailbox;
+use crate::device::bcm2835::timing::{Instant, delay_micros};
+use bcm2835_lpa::{EMMC, SYSTMR, VCMAILBOX};
+use core::time::Duration;
+use okboot_common::fat::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// What the EMMC clock usually runs at, if the firmware won't say.
+const DEFAULT_BASE_CLOCK: u32 = 250_000_000;
+/// Clock while the card is identified, and once it's been selected.
+const IDENTIFICATION_CLOCK: u32 = 400_000;
+const TRANSFER_CLOCK: u32 = 25_000_000;
+
+/// How long a command or a block may take before it's given up on.
+const TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the card may take to power up, answering ACMD41 busy meanwhile.
+const POWER_UP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// CONTROL0
+const C0_HCTL_DWIDTH: u32 = 1 << 1;
+// CONTROL1
+const C1_CLK_INTLEN: u32 = 1 << 0;
+const C1_CLK_STABLE: u32 = 1 << 1;
+const C1_CLK_EN: u32 = 1 << 2;
+const C1_DATA_TOUNIT_MAX: u32 = 0xe << 16;
+const C1_SRST_HC: u32 = 1 << 24;
+const C1_SRST_CMD: u32 = 1 << 25;
+const C1_SRST_DATA: u32 = 1 << 26;
+// STATUS
+const SR_CMD_INHIBIT: u32 = 1 << 0;
+const SR_DAT_INHIBIT: u32 = 1 << 1;
+// INTERRUPT
+const INT_CMD_DONE: u32 = 1 << 0;
+const INT_DATA_DONE: u32 = 1 << 1;
+const INT_WRITE_RDY: u32 = 1 << 4;
+const INT_READ_RDY: u32 = 1 << 5;
+const INT_ERR: u32 = 1 << 15;
+const INT_ALL: u32 = 0xffff_ffff;
+// CMDTM
+const TM_BLKCNT_EN: u32 = 1 << 1;
+const TM_AUTO_CMD12: u32 = 1 << 2;
+const TM_DAT_DIR_READ: u32 = 1 << 4;
+const TM_MULTI_BLOCK: u32 = 1 << 5;
+const CMD_RSPNS_136: u32 = 1 << 16;
+const CMD_RSPNS_48: u32 = 2 << 16;
+const CMD_RSPNS_48_BUSY: u32 = 3 << 16;
+const CMD_CRCCHK_EN: u32 = 1 << 19;
+const CMD_IXCHK_EN: u32 = 1 << 20;
+const CMD_ISDATA: u32 = 1 << 21;
+
+/// How each kind of response is asked for; R3 (the OCR) has neither a CRC nor an index.
+const R1: u32 = CMD_RSPNS_48 | CMD_CRCCHK_EN | CMD_IXCHK_EN;
+const R1B: u32 = CMD_RSPNS_48_BUSY | CMD_CRCCHK_EN | CMD_IXCHK_EN;
+const R2: u32 = CMD_RSPNS_136 | CMD_CRCCHK_EN;
+const R3: u32 = CMD_RSPNS_48;
+const R6: u32 = R1;
+const R7: u32 = R1;
+
+/// Voltage window asked for in ACMD41: 2.7-3.6V.
+const OCR_VOLTAGES: u32 = 0x00ff_8000;
+const OCR_HCS: u32 = 1 << 30;
+const OCR_POWERED_UP: u32 = 1 << 31;
+/// CMD8's argument: 2.7-3.6V, and a check pattern that the card echoes.
+const IF_COND: u32 = 0x1aa;
+
+/// An SD card, identified and selected, in 4-bit mode.
+pub struct Card<'a> {
+    emmc: &'a EMMC,
+    st: &'a SYSTMR,
+    /// The card's relative address, in the top half, as commands take it.
+    rca: u32,
+    /// Whether the card is addressed in blocks rather than bytes, as SDHC and SDXC cards are.
+    block_addressed: bool,
+}
+impl<'a> Card<'a> {
+    /// Reset the controller and bring up the card behind it.
+    pub fn init(emmc: &'a EMMC, mailbox: &VCMAILBOX, st: &'a SYSTMR) -> Result<Self, BlockError> {
+        let base_clock = mailbox::clock_rate(mailbox, mailbox::CLOCK_EMMC);
+        let base_clock = base_clock.unwrap_or(DEFAULT_BASE_CLOCK);
+        let mut card = Self {
+            emmc,
+            st,
+            rca: 0,
+            block_addressed: false,
+        };
+        dsb();
+        let result = card.identify(base_clock);
+        dsb();
+        result.map(|()| card)
+    }
+
+    fn identify(&mut self, base_clock: u32) -> Result<(), BlockError> {
+        let emmc = self.emmc;
+        unsafe {
+            emmc.control0().write(|w| w.bits(0));
+            emmc.control2().write(|w| w.bits(0));
+        }
+        self.reset(C1_SRST_HC)?;
+        self.set_clock(base_clock, IDENTIFICATION_CLOCK)?;
+        unsafe {
+            emmc.irpt_en().write(|w| w.bits(0));
+            emmc.irpt_mask().write(|w| w.bits(INT_ALL));
+            emmc.interrupt().write(|w| w.bits(INT_ALL));
+        }
+
+        self.command(0, 0, 0)?;
+        // only version 2 cards answer CMD8, and only they can be high capacity
+        let version_2 = match self.command(8, IF_COND, R7) {
+            Ok(echo) if echo & 0xfff == IF_COND => true,
+            Ok(_) => return Err(BlockError::NoCard),
+            Err(_) => {
+                self.reset(C1_SRST_CMD)?;
+                false
+            }
+        };
+        let hcs = if version_2 { OCR_HCS } else { 0 };
+        let started = Instant::now(self.st);
+        let ocr = loop {
+            let ocr = match self.app_command(41, OCR_VOLTAGES | hcs, R3) {
+                Ok(ocr) => ocr,
+                Err(BlockError::Timeout { .. }) => return Err(BlockError::NoCard),
+                Err(e) => return Err(e),
+            };
+            if ocr & OCR_POWERED_UP != 0 {
+                break ocr;
+            }
+            if started.elapsed(self.st) > POWER_UP_TIMEOUT {
+                return Err(BlockError::Timeout { command: 41 });
+            }
+            delay_micros(self.st, 10_000);
+        };
+        self.block_addressed = ocr & OCR_HCS != 0;
+
+        self.command(2, 0, R2)?;
+        self.rca = self.command(3, 0, R6)? & 0xffff_0000;
+        self.command(7, self.rca, R1B)?;
+        // 4-bit bus
+        self.app_command(6, 2, R1)?;
+        emmc.control0()
+            .modify(|r, w| unsafe { w.bits(r.bits() | C0_HCTL_DWIDTH) });
+        if !self.block_addressed {
+            self.command(16, SECTOR_SIZE as u32, R1)?;
+        }
+        self.set_clock(base_clock, TRANSFER_CLOCK)
+    }
+
+    /// Reset the parts of the controller in `which`, and wait for it to finish.
+    fn reset(&self, which: u32) -> Result<(), BlockError> {
+        let control1 = self.emmc.control1();
+        control1.modify(|r, w| unsafe { w.bits(r.bits() | which) });
+        self.wait(0, || control1.read().bits() & which == 0)
+    }
+
+    /// Run the card's clock off `base_clock`, as close to `rate` as the divider allows without
+    /// going over.
+    fn set_clock(&self, base_clock: u32, rate: u32) -> Result<(), BlockError> {
+        let control1 = self.emmc.control1();
+        self.wait(0, || {
+            self.emmc.status().read().bits() & (SR_CMD_INHIBIT | SR_DAT_INHIBIT) == 0
+        })?;
+        control1.modify(|r, w| unsafe { w.bits(r.bits() & !C1_CLK_EN) });
+        // 10 bits of divider, of twice the clock
+        let divider = base_clock.div_ceil(2 * rate).clamp(1, 0x3ff);
+        let frequency = ((divider & 0xff) << 8) | ((divider >> 8) << 6);
+        let bits = C1_DATA_TOUNIT_MAX | frequency | C1_CLK_INTLEN;
+        unsafe { control1.write(|w| w.bits(bits)) };
+        self.wait(0, || control1.read().bits() & C1_CLK_STABLE != 0)?;
+        control1.modify(|r, w| unsafe { w.bits(r.bits() | C1_CLK_EN) });
+        delay_micros(self.st, 2000);
+        Ok(())
+    }
+
+    /// Send CMD55, then ACMD`index`.
+    fn app_command(&self, index: u32, arg: u32, response: u32) -> Result<u32, BlockError> {
+        self.command(55, self.rca, R1)?;
+        self.command(index, arg, response)
+    }
+
+    /// Send CMD`index`, and wait for its response, of which the first word is returned.
+    fn command(&self, index: u32, arg: u32, flags: u32) -> Result<u32, BlockError> {
+        let emmc = self.emmc;
+        // anything that uses the data lines, busy signal included, has to wait for them too
+        let busy = flags & CMD_RSPNS_48_BUSY == CMD_RSPNS_48_BUSY;
+        let inhibit = if busy || flags & CMD_ISDATA != 0 {
+            SR_CMD_INHIBIT | SR_DAT_INHIBIT
+        } else {
+            SR_CMD_INHIBIT
+        };
+        self.wait(index, || emmc.status().read().bits() & inhibit == 0)?;
+        unsafe {
+            emmc.interrupt().write(|w| w.bits(INT_ALL));
+            emmc.arg1().write(|w| w.bits(arg));
+            emmc.cmdtm().write(|w| w.bits((index << 24) | flags));
+        }
+        self.wait_for(index, INT_CMD_DONE)?;
+        Ok(emmc.resp0().read().bits())
+    }
+
+    /// Wait for the interrupt flag `flag`, and clear it; an error flag is cleared too, and the
+    /// command and data lines reset.
+    fn wait_for(&self, command: u32, flag: u32) -> Result<(), BlockError> {
+        let interrupt = self.emmc.interrupt();
+        let mut status = 0;
+        self.wait(command, || {
+            status = interrupt.read().bits();
+            status & (flag | INT_ERR) != 0
+        })?;
+        if status & INT_ERR != 0 {
+            unsafe { interrupt.write(|w| w.bits(INT_ALL)) };
+            self.reset(C1_SRST_CMD | C1_SRST_DATA)?;
+            return Err(BlockError::Failed { command, status });
+        }
+        unsafe { interrupt.write(|w| w.bits(flag)) };
+        Ok(())
+    }
+
+    /// Spin until `done`, or for [`TIMEOUT`] on behalf of CMD`command`.
+    fn wait(&self, command: u32, mut done: impl FnMut() -> bool) -> Result<(), BlockError> {
+        let started = Instant::now(self.st);
+        while !done() {
+            if started.elapsed(self.st) > TIMEOUT {
+                return Err(BlockError::Timeout { command });
+            }
+        }
+        Ok(())
+    }
+
+    /// Start reading, or writing, `blocks` blocks at `lba`, returning the command that did; CMD18
+    /// and CMD25 have the controller stop the card with CMD12 once they're done.
+    fn start_transfer(&self, lba: u32, blocks: usize, read: bool) -> Result<u32, BlockError> {
+        let address = if self.block_addressed {
+            lba
+        } else {
+            lba * SECTOR_SIZE as u32
+        };
+        let (index, mut flags) = match (read, blocks) {
+            (true, 1) => (17, 0),
+            (true, _) => (18, TM_MULTI_BLOCK | TM_AUTO_CMD12),
+            (false, 1) => (24, 0),
+            (false, _) => (25, TM_MULTI_BLOCK | TM_AUTO_CMD12),
+        };
+        if read {
+            flags |= TM_DAT_DIR_READ;
+        }
+        let block_count = ((blocks as u32) << 16) | SECTOR_SIZE as u32;
+        unsafe { self.emmc.blksizecnt().write(|w| w.bits(block_count)) };
+        self.command(index, address, R1 | CMD_ISDATA | TM_BLKCNT_EN | flags)?;
+        Ok(index)
+    }
+}
+impl BlockDevice for Card<'_> {
+    fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), BlockError> {
+        dsb();
+        let blocks = buf.len() / SECTOR_SIZE;
+        let index = self.start_transfer(lba, blocks, true)?;
+        for block in buf.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_for(index, INT_READ_RDY)?;
+            for word in block.chunks_exact_mut(4) {
+                word.copy_from_slice(&self.emmc.data().read().bits().to_le_bytes());
+            }
+        }
+        self.wait_for(index, INT_DATA_DONE)?;
+        dsb();
+        Ok(())
+    }
+
+    fn write(&self, lba: u32, buf: &[u8]) -> Result<(), BlockError> {
+        dsb();
+        let blocks = buf.len() / SECTOR_SIZE;
+        let index = self.start_transfer(lba, blocks, false)?;
+        for block in buf.chunks_exact(SECTOR_SIZE) {
+            self.wait_for(index, INT_WRITE_RDY)?;
+            for word in block.chunks_exact(4) {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                unsafe { self.emmc.data().write(|w| w.bits(word)) };
+            }
+        }
+        self.wait_for(index, INT_DATA_DONE)?;
+        dsb();
+        Ok(())
+    }
+}
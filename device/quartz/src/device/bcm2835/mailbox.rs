@@ -0,0 +1,94 @@
+//! The VideoCore mailbox's property interface, through which the firmware answers questions about
+//! the board.
+use crate::arch::arm1176::dsb;
+use bcm2835_lpa::VCMAILBOX;
+
+/// Property tags, ARM to VideoCore.
+const PROPERTY_CHANNEL: u32 = 8;
+/// Bit 31 of the write mailbox's status register.
+const MAILBOX_FULL: u32 = 1 << 31;
+const REQUEST: u32 = 0;
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+/// Set in a tag's request/response code once the firmware has answered it.
+const TAG_RESPONSE: u32 = 0x8000_0000;
+/// Words in a message besides the tag's value buffer: size and code for the whole buffer, then
+/// the tag's id, buffer size and request/response code, and finally the end tag.
+const OVERHEAD_WORDS: usize = 6;
+const MAX_WORDS: usize = 16;
+
+pub const TAG_BOARD_REVISION: u32 = 0x0001_0002;
+pub const TAG_BOARD_SERIAL: u32 = 0x0001_0004;
+pub const TAG_GET_CLOCK_RATE: u32 = 0x0003_0002;
+
+/// Clock ids for [`TAG_GET_CLOCK_RATE`].
+pub const CLOCK_EMMC: u32 = 1;
+
+#[repr(C, align(16))]
+struct Message([u32; MAX_WORDS]);
+
+/// Ask the firmware for the property `tag`, which takes no arguments and answers with `N` words.
+pub fn get_property<const N: usize>(mailbox: &VCMAILBOX, tag: u32) -> Option<[u32; N]> {
+    get_property_with(mailbox, tag, &[])
+}
+
+/// Like [`get_property`], for a property that takes `args`.
+pub fn get_property_with<const N: usize>(
+    mailbox: &VCMAILBOX,
+    tag: u32,
+    args: &[u32],
+) -> Option<[u32; N]> {
+    // the value buffer holds the arguments, and then the answer
+    let len = N.max(args.len());
+    assert!(len + OVERHEAD_WORDS <= MAX_WORDS);
+    let mut message = Message([0; MAX_WORDS]);
+    let words = &mut message.0;
+    words[0] = ((len + OVERHEAD_WORDS) * 4) as u32;
+    words[1] = REQUEST;
+    words[2] = tag;
+    words[3] = (len * 4) as u32;
+    words[4] = REQUEST;
+    words[5..5 + args.len()].copy_from_slice(args);
+    // the rest of the value buffer, then the end tag, are already zero
+
+    let words = message.0.as_mut_ptr();
+    dsb();
+    while mailbox.status1().read().bits() & MAILBOX_FULL != 0 {}
+    let address = words.expose_provenance() as u32;
+    unsafe {
+        mailbox
+            .write()
+            .write_with_zero(|w| w.bits(address | PROPERTY_CHANNEL))
+    };
+    loop {
+        while mailbox.status0().read().empty().bit_is_set() {}
+        if mailbox.read().read().bits() & 0xf == PROPERTY_CHANNEL {
+            break;
+        }
+    }
+    dsb();
+
+    // the firmware wrote the response behind the compiler's back
+    let word = |i: usize| unsafe { words.add(i).read_volatile() };
+    if word(1) != RESPONSE_SUCCESS || word(4) & TAG_RESPONSE == 0 {
+        return None;
+    }
+    Some(core::array::from_fn(|i| word(5 + i)))
+}
+
+/// The board's serial number, as Linux shows it in `/proc/cpuinfo`.
+pub fn board_serial(mailbox: &VCMAILBOX) -> Option<u64> {
+    let [low, high] = get_property::<2>(mailbox, TAG_BOARD_SERIAL)?;
+    Some(((high as u64) << 32) | low as u64)
+}
+
+/// The board's revision code, which says which model it is and how much memory it has.
+pub fn board_revision(mailbox: &VCMAILBOX) -> Option<u32> {
+    let [revision] = get_property::<1>(mailbox, TAG_BOARD_REVISION)?;
+    Some(revision)
+}
+
+/// The rate of the clock `clock`, in Hz.
+pub fn clock_rate(mailbox: &VCMAILBOX, clock: u32) -> Option<u32> {
+    let [id, rate] = get_property_with::<2>(mailbox, TAG_GET_CLOCK_RATE, &[clock])?;
+    (id == clock && rate != 0).then_some(rate)
+}
@@ -8,6 +8,25 @@ pub const fn baud_to_clock_divider(baud_rate: u32) -> u16 {
     ((MINI_UART_CLOCK_RATE / (8 * baud_rate)) - 1) as u16
 }
 
+/// Like [`baud_to_clock_divider`], but returns `None` if `baud_rate` is out of the range that the
+/// divider register can express.
+pub const fn checked_baud_to_clock_divider(baud_rate: u32) -> Option<u16> {
+    if baud_rate == 0 || baud_rate > MINI_UART_CLOCK_RATE / 8 {
+        return None;
+    }
+    let divider = (MINI_UART_CLOCK_RATE / (8 * baud_rate)) - 1;
+    if divider > u16::MAX as u32 {
+        None
+    } else {
+        Some(divider as u16)
+    }
+}
+
+/// Calculate the baud rate that the Mini UART actually runs at for a given clock divider.
+pub const fn clock_divider_to_baud(clock_divider: u16) -> u32 {
+    MINI_UART_CLOCK_RATE / (8 * (clock_divider as u32 + 1))
+}
+
 pub fn muart1_init(gpio: &GPIO, aux: &AUX, uart: &UART1, clock_divider: u16) {
     // TODO: check interrupts disabled
 
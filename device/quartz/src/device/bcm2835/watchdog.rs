@@ -1,4 +1,5 @@
-use bcm2835_lpa::PM;
+use bcm2835_lpa::{PM, UART1};
+use okboot_common::host::Reboot;
 
 pub fn restart(pm: &PM) -> ! {
     // get 12 bits for wdog(), <time> = .time * 16 at <clock>
@@ -11,3 +12,52 @@ pub fn restart(pm: &PM) -> ! {
 
     loop {}
 }
+
+/// Watches what the host sends for a [`Reboot`] message, so that okdude can hand the device back
+/// to okboot without anyone having to power-cycle it.
+pub struct RebootListener {
+    frame: [u8; Reboot::FRAME_LEN],
+    /// How many bytes of `frame` have just been received.
+    matched: usize,
+}
+impl Default for RebootListener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl RebootListener {
+    pub fn new() -> Self {
+        Self {
+            frame: Reboot.frame_bytes(),
+            matched: 0,
+        }
+    }
+
+    /// Look at the next byte received from the host; `true` once a whole [`Reboot`] has arrived.
+    pub fn feed(&mut self, byte: u8) -> bool {
+        // the frame starts with a run of 0x55, so a mismatch doesn't necessarily mean starting
+        // over; keep the longest start of the frame that what was received still ends with
+        let matched = self.matched;
+        self.matched = (0..=matched)
+            .rev()
+            .find(|&k| self.frame[k] == byte && self.frame[..k] == self.frame[matched - k..matched])
+            .map_or(0, |k| k + 1);
+        if self.matched == Reboot::FRAME_LEN {
+            self.matched = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Restart the device if a [`Reboot`] is among the bytes waiting in UART1's receive FIFO; for
+    /// programs that don't otherwise read from UART1, to call now and then (e.g. from their main
+    /// loop). Programs that do should [`feed`](Self::feed) what they read instead.
+    pub fn poll(&mut self, uart: &UART1, pm: &PM) {
+        while uart.stat().read().data_ready().bit_is_set() {
+            if self.feed(uart.io().read().data().bits()) {
+                restart(pm);
+            }
+        }
+    }
+}
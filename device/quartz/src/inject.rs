@@ -0,0 +1,64 @@
+//! Reading what okdude injected into the program when it uploaded it: arguments, environment
+//! variables, a build ID, the boot time and whole files.
+//!
+//! The program reserves a section for each of them in its linker script, marked `SHT_PROGBITS` so
+//! that it takes up room in the file for okdude to fill in, with symbols at either end:
+//!
+//! ```text
+//! .data.args ALIGN(256) (TYPE=SHT_PROGBITS) : ALIGN(256) {
+//!     __symbol_args_begin__ = .;
+//!     BYTE(0);
+//!     . = . + 0xff;
+//!     __symbol_args_end__ = .;
+//! }
+//! ```
+//!
+//! then gets at it with [`section`] and reads it with the functions here. The section names, and
+//! how their contents are laid out, are in [`okboot_common::inject`]; a section that okdude had
+//! nothing to put in reads as empty.
+use okboot_common::inject::{self, List};
+
+pub use okboot_common::inject::{ARGS, BOOT_TIME, BUILD_ID, ENV};
+
+/// The section between the linker symbols at `begin` and `end`.
+///
+/// # Safety
+///
+/// `begin..end` must be a section of the program that nothing writes to.
+pub unsafe fn section(begin: *const u8, end: *const u8) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(begin, end.addr() - begin.addr()) }
+}
+
+/// The arguments in an [`ARGS`] section, up to the first that isn't UTF-8.
+pub fn args(section: &[u8]) -> impl Iterator<Item = &str> {
+    List::new(section).map_while(|arg| core::str::from_utf8(arg).ok())
+}
+
+/// The environment variables in an [`ENV`] section, as `(key, value)`.
+pub fn vars(section: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    args(section).filter_map(|var| var.split_once('='))
+}
+
+/// The value of the environment variable `key` in an [`ENV`] section.
+pub fn var<'a>(section: &'a [u8], key: &str) -> Option<&'a str> {
+    vars(section)
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+}
+
+/// The build ID in a [`BUILD_ID`] section, if there is one.
+pub fn build_id(section: &[u8]) -> Option<&str> {
+    inject::blob(section)
+        .filter(|id| !id.is_empty())
+        .and_then(|id| core::str::from_utf8(id).ok())
+}
+
+/// When the program was uploaded, in seconds since the Unix epoch, from a [`BOOT_TIME`] section.
+pub fn boot_time(section: &[u8]) -> Option<u64> {
+    inject::boot_time(section)
+}
+
+/// The file injected into `section`; empty if there wasn't one.
+pub fn file(section: &[u8]) -> &[u8] {
+    inject::blob(section).unwrap_or_default()
+}
@@ -7,4 +7,5 @@
 
 pub mod arch;
 pub mod device;
+pub mod inject;
 pub mod sync;
@@ -0,0 +1,43 @@
+//! The last program uploaded to each device, kept on disk so that the next upload can be a delta
+//! against it.
+use std::path::{Path, PathBuf};
+
+/// `$XDG_CACHE_HOME/okdude`, or `~/.cache/okdude`.
+pub fn default_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("okdude"))
+}
+
+/// Where in `dir` to keep the last program uploaded to `device`.
+pub fn image_path(dir: &Path, device: &Path) -> PathBuf {
+    let name: String = device
+        .to_string_lossy()
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{name}.img"))
+}
+
+pub fn load(path: &Path) -> Option<Vec<u8>> {
+    std::fs::read(path).ok()
+}
+
+pub fn store(path: &Path, image: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // so that an interrupted write doesn't leave a truncated image behind
+    let partial = path.with_extension("img.partial");
+    std::fs::write(&partial, image)?;
+    std::fs::rename(&partial, path)
+}
@@ -0,0 +1,157 @@
+//! Choosing how to compress the upload, among the codecs that the device can decode.
+use crate::Error;
+use eyre::Result;
+use okboot_common::compression::{Codecs, Compression};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Codecs that `--compression auto` and `--compression smallest` choose from.
+const CANDIDATES: [Compression; 6] = [
+    Compression::None,
+    Compression::Deflate { level: 1 },
+    Compression::Deflate {
+        level: DEFLATE_LEVEL,
+    },
+    Compression::Deflate { level: 9 },
+    Compression::Lz4,
+    Compression::Heatshrink {
+        window: HEATSHRINK_WINDOW,
+        lookahead: HEATSHRINK_LOOKAHEAD,
+    },
+];
+const DEFLATE_LEVEL: u8 = 5;
+const HEATSHRINK_WINDOW: u8 = 11;
+const HEATSHRINK_LOOKAHEAD: u8 = 4;
+
+/// Value of `--compression`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CompressionArg {
+    /// Whichever codec gets the program onto the device soonest at the negotiated baud rate.
+    Auto,
+    /// Whichever codec makes the upload smallest.
+    Smallest,
+    Fixed(Compression),
+}
+impl FromStr for CompressionArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, params) = match s.split_once(':') {
+            Some((name, params)) => (name, Some(params)),
+            None => (s, None),
+        };
+        let number = |x: &str| {
+            x.parse::<u8>()
+                .map_err(|e| format!("invalid parameter {x:?} for {name}: {e}"))
+        };
+        let compression = match (name, params) {
+            ("auto", None) => return Ok(Self::Auto),
+            ("smallest", None) => return Ok(Self::Smallest),
+            ("none", None) => Compression::None,
+            ("deflate", None) => Compression::Deflate {
+                level: DEFLATE_LEVEL,
+            },
+            ("deflate", Some(level)) => Compression::Deflate {
+                level: number(level)?,
+            },
+            ("lz4", None) => Compression::Lz4,
+            ("heatshrink", None) => Compression::Heatshrink {
+                window: HEATSHRINK_WINDOW,
+                lookahead: HEATSHRINK_LOOKAHEAD,
+            },
+            ("heatshrink", Some(params)) => {
+                let (window, lookahead) = params
+                    .split_once(',')
+                    .ok_or("expected heatshrink:WINDOW,LOOKAHEAD")?;
+                Compression::Heatshrink {
+                    window: number(window)?,
+                    lookahead: number(lookahead)?,
+                }
+            }
+            _ => {
+                return Err("expected auto, smallest, none, deflate[:LEVEL], lz4 or \
+                     heatshrink[:WINDOW,LOOKAHEAD]"
+                    .to_string())
+            }
+        };
+        if !compression.is_valid() {
+            return Err(format!(
+                "{compression} is out of range; deflate levels go up to 10, and heatshrink needs \
+                 4 <= WINDOW <= 15 and 3 <= LOOKAHEAD < WINDOW"
+            ));
+        }
+        Ok(Self::Fixed(compression))
+    }
+}
+impl Display for CompressionArg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressionArg::Auto => write!(f, "auto"),
+            CompressionArg::Smallest => write!(f, "smallest"),
+            CompressionArg::Fixed(compression) => write!(f, "{compression}"),
+        }
+    }
+}
+impl CompressionArg {
+    /// The codecs to choose from, given the ones that the device can decode.
+    pub(crate) fn candidates(self, codecs: Codecs) -> Result<Vec<Compression>> {
+        Ok(match self {
+            CompressionArg::Fixed(compression) if codecs.supports(compression) => {
+                vec![compression]
+            }
+            CompressionArg::Fixed(compression) => {
+                return Err(Error::Unsupported(format!(
+                    "the device wasn't built with a decoder for {compression}"
+                ))
+                .into())
+            }
+            CompressionArg::Auto | CompressionArg::Smallest => CANDIDATES
+                .into_iter()
+                .filter(|&compression| codecs.supports(compression))
+                .collect(),
+        })
+    }
+}
+
+/// What a transfer of the program over the serial line looks like.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Link {
+    pub baud_rate: u32,
+    /// Whether the device decompresses while further chunks arrive, rather than between them.
+    pub pipelined: bool,
+}
+impl Link {
+    /// Rough estimate of how long it takes for the device to receive and decompress a program of
+    /// `inflated_len` bytes, compressed down to `compressed_len` bytes with `compression`.
+    pub(crate) fn upload_time(
+        &self,
+        compression: Compression,
+        compressed_len: usize,
+        inflated_len: usize,
+    ) -> Duration {
+        // 8N1: ten bits on the wire per byte
+        let transfer =
+            Duration::from_secs_f64(compressed_len as f64 * 10.0 / self.baud_rate as f64);
+        let decode = match decode_rate(compression) {
+            Some(rate) => Duration::from_secs_f64(inflated_len as f64 / rate as f64),
+            None => Duration::ZERO,
+        };
+        if self.pipelined {
+            transfer.max(decode)
+        } else {
+            transfer + decode
+        }
+    }
+}
+
+/// Rough rate at which a BCM2835 decompresses `compression`, in bytes of output per second;
+/// `None` if it takes no time worth mentioning.
+fn decode_rate(compression: Compression) -> Option<u64> {
+    match compression {
+        Compression::None => None,
+        Compression::Deflate { .. } => Some(6_000_000),
+        Compression::Lz4 => Some(40_000_000),
+        Compression::Heatshrink { .. } => Some(3_000_000),
+    }
+}
@@ -0,0 +1,51 @@
+use okboot_common::fat::FatError;
+use okboot_common::monitor::Refusal;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Why an [`Uploader`](crate::Uploader) failed.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to open {}: {source}", path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The program can't be uploaded as it is, e.g. because its arguments don't fit.
+    #[error("invalid program: {0}")]
+    InvalidImage(String),
+    /// The device doesn't support something that the upload needs, e.g. a compression codec.
+    #[error("unsupported by the device: {0}")]
+    Unsupported(String),
+    /// The device turned the program down.
+    #[error("the device refused the program: {0}")]
+    Refused(String),
+    /// okboot's monitor wouldn't touch some memory, or okdude wouldn't ask it to.
+    #[error("refused to access memory: {0}")]
+    MemoryRefused(#[from] Refusal),
+    /// okboot couldn't read or write its SD card.
+    #[error("the device's SD card failed: {0}")]
+    Storage(#[from] FatError),
+    /// The device stopped answering partway through the upload, and couldn't be reconnected to.
+    #[error("lost connection to the device")]
+    LinkLost,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Anything else that went wrong while talking to the device.
+    #[error("{0}")]
+    Protocol(String),
+}
+impl From<eyre::Report> for Error {
+    fn from(report: eyre::Report) -> Self {
+        // internally, errors are reports; whatever typed error caused them is still in there
+        match report.downcast::<Error>() {
+            Ok(e) => e,
+            Err(report) => match report.downcast::<io::Error>() {
+                Ok(e) => Error::Io(e),
+                Err(report) => Error::Protocol(format!("{report:#}")),
+            },
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Program files in formats that okboot doesn't load itself, converted to ones that it does.
+//!
+//! Intel HEX and Motorola S-record files, as some vendor toolchains emit, and U-Boot uImages all
+//! say where they're to be loaded, so they become [`FormatDetails::Bin`] programs loaded there.
+//! Files whose pieces are too far apart for that, or that are entered somewhere other than where
+//! they start, become [`FormatDetails::Segments`] programs instead.
+use crate::Error;
+use okboot_common::host::FormatDetails;
+use okboot_common::segments;
+
+#[cfg(test)]
+mod tests;
+
+/// Largest hole between two pieces of a HEX or S-record file that is filled in with zeroes to
+/// upload them as one image; pieces further apart are uploaded as separate segments.
+pub const MAX_GAP: u64 = 0x10_0000;
+
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+const UIMAGE_HEADER_LEN: usize = 64;
+const IH_ARCH_ARM: u8 = 2;
+const IH_TYPE_STANDALONE: u8 = 1;
+const IH_TYPE_KERNEL: u8 = 2;
+const IH_COMP_NONE: u8 = 0;
+
+/// A program converted from another format, ready to upload.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Program {
+    pub image: Vec<u8>,
+    pub format_details: FormatDetails,
+}
+
+/// Read an Intel HEX file.
+pub fn from_intel_hex(file: &[u8]) -> Result<Program, Error> {
+    let text = std::str::from_utf8(file).map_err(|_| invalid("Intel HEX file isn't text"))?;
+    let mut pieces = Pieces::default();
+    let mut base = 0;
+    let mut entry = None;
+    let mut ended = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let at = |e: &str| invalid(&format!("line {}: {e}", i + 1));
+        if ended {
+            return Err(at("record after the end-of-file record"));
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| at("expected a record starting with `:`"))?;
+        let bytes = hex_bytes(record).ok_or_else(|| at("expected pairs of hex digits"))?;
+        if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+            return Err(at("record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(at("bad checksum"));
+        }
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+        let data = &bytes[4..bytes.len() - 1];
+        match (bytes[3], data) {
+            (0x00, data) => pieces.add(base + address, data),
+            (0x01, _) => ended = true,
+            (0x02, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u64) << 4,
+            (0x03, &[cs_high, cs_low, ip_high, ip_low]) => {
+                let cs = u16::from_be_bytes([cs_high, cs_low]) as u64;
+                entry = Some((cs << 4) + u16::from_be_bytes([ip_high, ip_low]) as u64);
+            }
+            (0x04, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u64) << 16,
+            (0x05, &[a, b, c, d]) => entry = Some(u32::from_be_bytes([a, b, c, d]) as u64),
+            (kind @ 0x02..=0x05, data) => {
+                return Err(at(&format!(
+                    "record type {kind:02x} with {} bytes of data",
+                    data.len()
+                )));
+            }
+            (kind, _) => return Err(at(&format!("unknown record type {kind:02x}"))),
+        }
+    }
+    if !ended {
+        return Err(invalid("Intel HEX file has no end-of-file record"));
+    }
+    pieces.into_program(entry)
+}
+
+/// Read a Motorola S-record file.
+pub fn from_srecord(file: &[u8]) -> Result<Program, Error> {
+    let text = std::str::from_utf8(file).map_err(|_| invalid("S-record file isn't text"))?;
+    let mut pieces = Pieces::default();
+    let mut entry = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let at = |e: &str| invalid(&format!("line {}: {e}", i + 1));
+        let mut chars = line.chars();
+        let (Some('S' | 's'), Some(kind)) = (chars.next(), chars.next()) else {
+            return Err(at("expected a record starting with `S`"));
+        };
+        let address_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(at(&format!("unknown record type S{kind}"))),
+        };
+        let bytes = hex_bytes(chars.as_str()).ok_or_else(|| at("expected pairs of hex digits"))?;
+        if bytes.len() < 2 + address_len || bytes.len() != 1 + bytes[0] as usize {
+            return Err(at("record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0xff {
+            return Err(at("bad checksum"));
+        }
+        let address = bytes[1..1 + address_len]
+            .iter()
+            .fold(0u64, |address, &b| address << 8 | b as u64);
+        let data = &bytes[1 + address_len..bytes.len() - 1];
+        match kind {
+            '1' | '2' | '3' => pieces.add(address, data),
+            '7' | '8' | '9' => entry = Some(address),
+            // header and record counts
+            _ => {}
+        }
+    }
+    pieces.into_program(entry)
+}
+
+/// Read a U-Boot uImage holding a single, uncompressed ARM kernel or standalone program.
+pub fn from_uimage(file: &[u8]) -> Result<Program, Error> {
+    let header = file
+        .get(..UIMAGE_HEADER_LEN)
+        .filter(|header| be32(header, 0) == UIMAGE_MAGIC)
+        .ok_or_else(|| invalid("not a uImage"))?;
+    let mut zeroed = header.to_vec();
+    zeroed[4..8].fill(0);
+    if crc32fast::hash(&zeroed) != be32(header, 4) {
+        return Err(invalid("uImage header has a bad checksum"));
+    }
+    let size = be32(header, 12) as usize;
+    let load = be32(header, 16) as u64;
+    let entry = be32(header, 20) as u64;
+    let (arch, kind, comp) = (header[29], header[30], header[31]);
+    let name = &header[32..];
+    let name = String::from_utf8_lossy(&name[..name.iter().position(|&b| b == 0).unwrap_or(32)]);
+
+    if arch != IH_ARCH_ARM {
+        return Err(invalid(&format!(
+            "uImage `{name}` is for architecture {arch}, not ARM"
+        )));
+    }
+    // anything else is a ramdisk, a device tree, several images in one and the like
+    if kind != IH_TYPE_KERNEL && kind != IH_TYPE_STANDALONE {
+        return Err(Error::Unsupported(format!(
+            "uImage `{name}` is of type {kind}; only kernel and standalone uImages can be uploaded"
+        )));
+    }
+    if comp != IH_COMP_NONE {
+        return Err(Error::Unsupported(format!(
+            "uImage `{name}` is compressed (type {comp}); only uncompressed uImages can be \
+             uploaded"
+        )));
+    }
+    let data = file
+        .get(UIMAGE_HEADER_LEN..UIMAGE_HEADER_LEN + size)
+        .ok_or_else(|| invalid(&format!("uImage `{name}` is cut short")))?;
+    if crc32fast::hash(data) != be32(header, 24) {
+        return Err(invalid(&format!("uImage `{name}` has a bad data checksum")));
+    }
+    tracing::info!("uImage `{name}`: {size} bytes, loaded at {load:#x}");
+    let mut pieces = Pieces::default();
+    pieces.add(load, data);
+    pieces.into_program(Some(entry))
+}
+
+/// Data from a HEX or S-record file, where it goes.
+#[derive(Default)]
+struct Pieces(Vec<(u64, Vec<u8>)>);
+impl Pieces {
+    fn add(&mut self, address: u64, data: &[u8]) {
+        match self.0.last_mut() {
+            Some((start, piece)) if *start + piece.len() as u64 == address => {
+                piece.extend_from_slice(data)
+            }
+            _ => self.0.push((address, data.to_vec())),
+        }
+    }
+
+    /// The pieces laid out in one image, with zeroes in between, or in as many segments as it
+    /// takes to keep the zeroes down to [`MAX_GAP`] at a time.
+    fn into_program(mut self, entry: Option<u64>) -> Result<Program, Error> {
+        self.0.sort_by_key(|(address, _)| *address);
+        let Some(&(start, _)) = self.0.first() else {
+            return Err(invalid("file has no data"));
+        };
+        let mut runs: Vec<(u64, Vec<u8>)> = vec![];
+        for (address, piece) in self.0 {
+            match runs.last_mut() {
+                Some((run_start, run)) if address < *run_start + run.len() as u64 => {
+                    return Err(invalid(&format!(
+                        "data at {address:#x} overlaps data before it"
+                    )));
+                }
+                Some((run_start, run)) if address - (*run_start + run.len() as u64) <= MAX_GAP => {
+                    run.resize((address - *run_start) as usize, 0);
+                    run.extend_from_slice(&piece);
+                }
+                _ => runs.push((address, piece)),
+            }
+        }
+        for (address, run) in &runs {
+            if address + run.len() as u64 > u32::MAX as u64 + 1 {
+                return Err(invalid(&format!(
+                    "data at {address:#x} is out of reach of 32-bit addresses"
+                )));
+            }
+        }
+        // okboot starts `Bin` programs at their start, so one that's entered elsewhere is sent
+        // as a single segment instead
+        if runs.len() == 1 && entry.is_none_or(|entry| entry == start) {
+            let (_, image) = runs.pop().unwrap();
+            return Ok(Program {
+                image,
+                format_details: FormatDetails::Bin {
+                    load_address: start,
+                },
+            });
+        }
+        if runs.len() > segments::MAX_SEGMENTS as usize {
+            return Err(invalid(&format!(
+                "data is spread over {} pieces, but at most {} can be uploaded",
+                runs.len(),
+                segments::MAX_SEGMENTS
+            )));
+        }
+        if runs.len() == 1 {
+            tracing::info!(
+                "entry point isn't where the program starts, so it's uploaded as a segment"
+            );
+        } else {
+            tracing::info!(
+                "data is spread out, so it's uploaded as {} segments",
+                runs.len()
+            );
+        }
+        let image = segments::encode(
+            runs.iter()
+                .map(|(address, run)| (*address as u32, run.as_slice())),
+        );
+        Ok(Program {
+            image,
+            format_details: FormatDetails::Segments {
+                entry: entry.unwrap_or(start),
+            },
+        })
+    }
+}
+
+fn hex_bytes(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidImage(message.to_string())
+}
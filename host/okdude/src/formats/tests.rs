@@ -0,0 +1,186 @@
+//! Tests of converting Intel HEX, S-record and uImage files.
+use super::{from_intel_hex, from_srecord, from_uimage, Program, MAX_GAP};
+use crate::Error;
+use okboot_common::host::FormatDetails;
+use okboot_common::segments;
+
+/// An Intel HEX record, checksum and all.
+fn ihex(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(sum.wrapping_neg());
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{digits}\n")
+}
+
+/// A Motorola S-record, count and checksum and all.
+fn srec(kind: u8, address: &[u8], data: &[u8]) -> String {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(!sum);
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!("S{kind}{digits}\n")
+}
+
+/// A uImage of `data`, of type `kind` and compressed as `comp` says.
+fn uimage(load: u32, entry: u32, kind: u8, comp: u8, data: &[u8]) -> Vec<u8> {
+    let mut header = vec![];
+    for word in [0x2705_1956, 0, 0, data.len() as u32, load, entry] {
+        header.extend_from_slice(&u32::to_be_bytes(word));
+    }
+    header.extend_from_slice(&crc32fast::hash(data).to_be_bytes());
+    // os, arch, type, comp
+    header.extend_from_slice(&[5, 2, kind, comp]);
+    header.extend_from_slice(b"okeanos test");
+    header.resize(64, 0);
+    let hcrc = crc32fast::hash(&header);
+    header[4..8].copy_from_slice(&hcrc.to_be_bytes());
+    header.extend_from_slice(data);
+    header
+}
+
+fn bin(load_address: u64, image: &[u8]) -> Program {
+    Program {
+        image: image.to_vec(),
+        format_details: FormatDetails::Bin { load_address },
+    }
+}
+
+#[test]
+fn reads_intel_hex() {
+    let file = [
+        ihex(0x04, 0, &[0x00, 0x01]),
+        ihex(0x00, 0x8000, &[1, 2, 3, 4]),
+        ihex(0x00, 0x8004, &[5, 6]),
+        ihex(0x00, 0x8008, &[9]),
+        ihex(0x05, 0, &[0x00, 0x01, 0x80, 0x00]),
+        ihex(0x01, 0, &[]),
+    ]
+    .concat();
+    assert_eq!(
+        from_intel_hex(file.as_bytes()).unwrap(),
+        bin(0x1_8000, &[1, 2, 3, 4, 5, 6, 0, 0, 9])
+    );
+}
+
+#[test]
+fn reads_intel_hex_entered_past_start_as_segment() {
+    let file = [
+        ihex(0x00, 0x8000, &[1, 2, 3, 4]),
+        ihex(0x05, 0, &[0x00, 0x00, 0x80, 0x04]),
+        ihex(0x01, 0, &[]),
+    ]
+    .concat();
+    assert_eq!(
+        from_intel_hex(file.as_bytes()).unwrap(),
+        Program {
+            image: segments::encode([(0x8000, &[1, 2, 3, 4][..])].into_iter()),
+            format_details: FormatDetails::Segments { entry: 0x8004 },
+        }
+    );
+}
+
+#[test]
+fn rejects_malformed_intel_hex() {
+    let data = ihex(0x00, 0x8000, &[1, 2, 3, 4]);
+    let eof = ihex(0x01, 0, &[]);
+    assert!(from_intel_hex(data.as_bytes()).is_err());
+    let corrupted = data.replace("01020304", "01020305") + &eof;
+    assert!(from_intel_hex(corrupted.as_bytes()).is_err());
+    assert!(from_intel_hex(format!("{eof}{data}").as_bytes()).is_err());
+    assert!(from_intel_hex(eof.as_bytes()).is_err());
+    let overlapping = [data.clone(), ihex(0x00, 0x8002, &[0]), eof.clone()].concat();
+    assert!(from_intel_hex(overlapping.as_bytes()).is_err());
+}
+
+#[test]
+fn reads_srecord() {
+    let file = [
+        srec(0, &[0, 0], b"okeanos"),
+        srec(3, &[0, 0, 0x80, 0x00], &[1, 2, 3, 4]),
+        srec(3, &[0, 0, 0x80, 0x04], &[5, 6]),
+        srec(5, &[0, 2], &[]),
+        srec(7, &[0, 0, 0x80, 0x00], &[]),
+    ]
+    .concat();
+    assert_eq!(
+        from_srecord(file.as_bytes()).unwrap(),
+        bin(0x8000, &[1, 2, 3, 4, 5, 6])
+    );
+}
+
+#[test]
+fn reads_srecord_pieces_far_apart_as_segments() {
+    let far = 0x8000 + 4 + MAX_GAP as u32 + 1;
+    let file = [
+        srec(3, &0x8000u32.to_be_bytes(), &[1, 2, 3, 4]),
+        srec(3, &far.to_be_bytes(), &[5]),
+        srec(7, &0x8000u32.to_be_bytes(), &[]),
+    ]
+    .concat();
+    assert_eq!(
+        from_srecord(file.as_bytes()).unwrap(),
+        Program {
+            image: segments::encode([(0x8000, &[1, 2, 3, 4][..]), (far, &[5][..])].into_iter()),
+            format_details: FormatDetails::Segments { entry: 0x8000 },
+        }
+    );
+}
+
+#[test]
+fn rejects_srecord_past_32_bit_addresses() {
+    // all in one piece, which would otherwise be sent as it is
+    let file = srec(3, &0xffff_fffeu32.to_be_bytes(), &[1, 2, 3, 4]);
+    let error = from_srecord(file.as_bytes()).unwrap_err();
+    assert!(
+        matches!(&error, Error::InvalidImage(e) if e.contains("out of reach")),
+        "{error}"
+    );
+}
+
+#[test]
+fn reads_uimage() {
+    let file = uimage(0x8000, 0x8000, 2, 0, &[1, 2, 3, 4]);
+    assert_eq!(from_uimage(&file).unwrap(), bin(0x8000, &[1, 2, 3, 4]));
+    // standalone
+    let file = uimage(0x8000, 0x8000, 1, 0, &[1, 2, 3, 4]);
+    assert_eq!(from_uimage(&file).unwrap(), bin(0x8000, &[1, 2, 3, 4]));
+    let file = uimage(0x8000, 0x8002, 2, 0, &[1, 2, 3, 4]);
+    assert_eq!(
+        from_uimage(&file).unwrap(),
+        Program {
+            image: segments::encode([(0x8000, &[1, 2, 3, 4][..])].into_iter()),
+            format_details: FormatDetails::Segments { entry: 0x8002 },
+        }
+    );
+}
+
+#[test]
+fn rejects_unusable_uimage() {
+    let mut corrupted = uimage(0x8000, 0x8000, 2, 0, &[1, 2, 3, 4]);
+    corrupted[64] = 0;
+    assert!(matches!(
+        from_uimage(&corrupted),
+        Err(Error::InvalidImage(_))
+    ));
+    // gzip
+    assert!(matches!(
+        from_uimage(&uimage(0x8000, 0x8000, 2, 1, &[1, 2, 3, 4])),
+        Err(Error::Unsupported(_))
+    ));
+    // ramdisk
+    let error = from_uimage(&uimage(0x8000, 0x8000, 3, 0, &[1, 2, 3, 4])).unwrap_err();
+    assert!(
+        matches!(&error, Error::Unsupported(e) if e.contains("type 3")),
+        "{error}"
+    );
+    assert!(matches!(
+        from_uimage(b"\x7fELF"),
+        Err(Error::InvalidImage(_))
+    ));
+}
@@ -0,0 +1,276 @@
+//! Debugging programs with GDB: okdude takes GDB's connection, and relays its packets to and from
+//! the program's agent (see [`okboot_common::gdb`]) in [`GdbPacket`](MessageType::GdbPacket)
+//! frames, while passing on whatever else the program prints.
+//!
+//! okdude speaks the parts of the remote protocol that are about getting packets across — framing,
+//! checksums, acknowledgements and escapes — so the frames carry packets' bodies just as they are.
+//! The agent only listens while the program is halted, so a running program can't be interrupted;
+//! breakpoints are the way to stop it.
+use crate::run::Scanner;
+use crate::{Console, Error};
+use okboot_common::device::Exit;
+use okboot_common::frame::write_frame;
+use okboot_common::MessageType;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+/// How long to wait for GDB when the program has nothing to say.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Escapes bytes in packets that would otherwise be taken for framing, by following it with the
+/// byte xor [`ESCAPE_XOR`].
+const ESCAPE: u8 = b'}';
+const ESCAPE_XOR: u8 = 0x20;
+
+/// What GDB sends to interrupt a running program.
+const INTERRUPT: u8 = 0x03;
+
+/// Something that GDB sent.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum FromGdb {
+    /// A packet's body, unescaped.
+    Packet(Vec<u8>),
+    /// A packet with the wrong checksum, which GDB should send again.
+    Corrupt,
+    Interrupt,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+enum ReadState {
+    /// Between packets, where acknowledgements and interrupts are.
+    #[default]
+    Idle,
+    Body,
+    /// Just after an [`ESCAPE`].
+    Escaped,
+    /// After the `#`, with the first digit of the checksum if it has arrived.
+    Checksum(Option<u8>),
+}
+
+/// Picks packets out of what GDB sends.
+#[derive(Debug, Default)]
+struct Reader {
+    state: ReadState,
+    body: Vec<u8>,
+    /// Of the bytes between `$` and `#`, as they were sent.
+    sum: u8,
+}
+impl Reader {
+    /// Look at the next byte from GDB; returns what it sent once `byte` completes it.
+    fn feed(&mut self, byte: u8) -> Option<FromGdb> {
+        match self.state {
+            ReadState::Idle => match byte {
+                b'$' => {
+                    self.body.clear();
+                    self.sum = 0;
+                    self.state = ReadState::Body;
+                }
+                INTERRUPT => return Some(FromGdb::Interrupt),
+                // acknowledgements of what okdude sent, which TCP has already made sure of
+                _ => {}
+            },
+            ReadState::Body | ReadState::Escaped if byte == b'#' => {
+                self.state = ReadState::Checksum(None)
+            }
+            ReadState::Body => {
+                self.sum = self.sum.wrapping_add(byte);
+                if byte == ESCAPE {
+                    self.state = ReadState::Escaped;
+                } else {
+                    self.body.push(byte);
+                }
+            }
+            ReadState::Escaped => {
+                self.sum = self.sum.wrapping_add(byte);
+                self.body.push(byte ^ ESCAPE_XOR);
+                self.state = ReadState::Body;
+            }
+            ReadState::Checksum(None) => self.state = ReadState::Checksum(Some(byte)),
+            ReadState::Checksum(Some(first)) => {
+                self.state = ReadState::Idle;
+                let checksum = std::str::from_utf8(&[first, byte])
+                    .ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                return Some(if checksum == Some(self.sum) {
+                    FromGdb::Packet(std::mem::take(&mut self.body))
+                } else {
+                    FromGdb::Corrupt
+                });
+            }
+        }
+        None
+    }
+}
+
+/// The packet to send GDB with `body`.
+fn packet(body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![b'$'];
+    for &byte in body {
+        if matches!(byte, b'$' | b'#' | b'*' | ESCAPE) {
+            packet.extend_from_slice(&[ESCAPE, byte ^ ESCAPE_XOR]);
+        } else {
+            packet.push(byte);
+        }
+    }
+    let sum = packet[1..]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    packet.extend_from_slice(format!("#{sum:02x}").as_bytes());
+    packet
+}
+
+/// Where the program is, as far as GDB's packets go.
+struct Session {
+    /// Whether the program's agent is listening for packets.
+    halted: bool,
+    /// Whether GDB resumed the program, and is waiting for it to halt again.
+    resumed: bool,
+    /// Whether GDB detached, and is waiting for the agent to say that it's done.
+    detaching: bool,
+    /// Packets from GDB that arrived before the program first halted.
+    pending: VecDeque<Vec<u8>>,
+}
+impl Session {
+    /// Pass `body` on to the program's agent.
+    fn forward(&mut self, console: &mut Console, body: &[u8]) -> io::Result<()> {
+        let mut frame = vec![];
+        write_frame(MessageType::GdbPacket, body, |b| frame.extend_from_slice(b));
+        console.write_all(&frame)?;
+        console.flush()?;
+        match body.first() {
+            Some(b'c' | b's') => {
+                self.halted = false;
+                self.resumed = true;
+            }
+            Some(b'D') => self.detaching = true,
+            Some(b'k') => self.halted = false,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Let the GDB connected to `gdb` debug the program, passing whatever else it prints to `output`,
+/// until it exits or GDB goes away. Returns the program's exit code, if it exited.
+///
+/// The program should already be running its agent, or about to; until it first halts, GDB's
+/// packets are held back.
+pub fn debug(
+    console: &mut Console,
+    gdb: TcpStream,
+    mut output: impl FnMut(&[u8]) -> io::Result<()>,
+) -> Result<Option<i32>, Error> {
+    let mut to_gdb = gdb.try_clone()?;
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut gdb = gdb;
+        let mut reader = Reader::default();
+        let mut buf = [0; 0x1000];
+        while let Ok(n @ 1..) = gdb.read(&mut buf) {
+            for &byte in &buf[..n] {
+                if let Some(from_gdb) = reader.feed(byte) {
+                    if tx.send(from_gdb).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut session = Session {
+        halted: false,
+        resumed: false,
+        detaching: false,
+        pending: VecDeque::new(),
+    };
+    let mut scanner = Scanner::new(&[MessageType::Exit, MessageType::GdbPacket]);
+    let mut buf = [0; 0x1000];
+    loop {
+        let available = console.transport().bytes_to_read()?;
+        if available > 0 {
+            let n = match console.read(&mut buf[..available.min(0x1000)]) {
+                Ok(0) => return Err(Error::LinkLost),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::TimedOut => 0,
+                Err(e) => return Err(e.into()),
+            };
+            let mut text = vec![];
+            for &byte in &buf[..n] {
+                match scanner.feed(byte, &mut text) {
+                    Some((MessageType::Exit, payload)) => {
+                        output(&text)?;
+                        let Some(Exit { code }) = Exit::from_payload(&payload) else {
+                            continue;
+                        };
+                        if session.resumed {
+                            let _ = to_gdb.write_all(&packet(format!("W{code:02x}").as_bytes()));
+                        }
+                        return Ok(Some(code));
+                    }
+                    Some((_, body)) if !session.halted => {
+                        // the stop reply, which GDB is waiting for if it resumed the program;
+                        // otherwise the program halted by itself, and GDB will ask why
+                        session.halted = true;
+                        if std::mem::take(&mut session.resumed) {
+                            to_gdb.write_all(&packet(&body))?;
+                        }
+                    }
+                    Some((_, body)) => {
+                        to_gdb.write_all(&packet(&body))?;
+                        if session.detaching {
+                            output(&text)?;
+                            return Ok(None);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            output(&text)?;
+            while session.halted {
+                let Some(body) = session.pending.pop_front() else {
+                    break;
+                };
+                session.forward(console, &body)?;
+            }
+        }
+
+        // only wait for GDB if there's no more output to show
+        let timeout = if available > 0 {
+            Duration::ZERO
+        } else {
+            POLL_INTERVAL
+        };
+        match rx.recv_timeout(timeout) {
+            Ok(FromGdb::Packet(body)) => {
+                to_gdb.write_all(b"+")?;
+                if session.halted {
+                    session.forward(console, &body)?;
+                } else {
+                    session.pending.push_back(body);
+                }
+            }
+            Ok(FromGdb::Corrupt) => to_gdb.write_all(b"-")?,
+            Ok(FromGdb::Interrupt) => {
+                if !session.halted {
+                    tracing::warn!(
+                        "a running program can't be interrupted; set a breakpoint to stop it"
+                    );
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // let the program run on, rather than leaving it halted for good
+                if session.halted {
+                    session.forward(console, b"D")?;
+                }
+                return Ok(None);
+            }
+        }
+    }
+}
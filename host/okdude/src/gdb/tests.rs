@@ -0,0 +1,261 @@
+//! Tests of the bridge between GDB and a program's agent.
+use super::{debug, packet, FromGdb, Reader, INTERRUPT};
+use crate::transport::{ClearBuffer, Transport};
+use crate::Console;
+use okboot_common::device::Exit;
+use okboot_common::frame::{write_frame, FrameLayer, FrameOutput};
+use okboot_common::gdb::{Agent, Resume, Stop, Target, WatchKind, REGISTERS};
+use okboot_common::{MessageType, COBS_XOR};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+fn read(bytes: &[u8]) -> Vec<FromGdb> {
+    let mut reader = Reader::default();
+    bytes.iter().filter_map(|&byte| reader.feed(byte)).collect()
+}
+
+#[test]
+fn reads_packets_acks_and_interrupts() {
+    let mut bytes = b"+".to_vec();
+    bytes.extend_from_slice(b"$m8000,4#95");
+    bytes.push(INTERRUPT);
+    bytes.extend_from_slice(b"-$g#00");
+    assert_eq!(
+        read(&bytes),
+        [
+            FromGdb::Packet(b"m8000,4".to_vec()),
+            FromGdb::Interrupt,
+            FromGdb::Corrupt,
+        ]
+    );
+}
+
+#[test]
+fn escapes_packets() {
+    assert_eq!(packet(b"OK"), b"$OK#9a");
+    let body = b"X8000,4:$#}*".to_vec();
+    let escaped = packet(&body);
+    assert!(escaped.starts_with(b"$X8000,4:}\x04}\x03}]}\x0a#"));
+    assert_eq!(read(&escaped), [FromGdb::Packet(body)]);
+}
+
+/// A program with a little memory, halted in its agent.
+struct Program {
+    registers: [u32; REGISTERS],
+    memory: [u8; 16],
+}
+impl Target for Program {
+    fn registers(&mut self) -> [u32; REGISTERS] {
+        self.registers
+    }
+
+    fn set_registers(&mut self, registers: &[u32; REGISTERS]) {
+        self.registers = *registers;
+    }
+
+    fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool {
+        let start = address as usize;
+        match self.memory.get(start..start + buf.len()) {
+            Some(memory) => {
+                buf.copy_from_slice(memory);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+        let start = address as usize;
+        match self.memory.get_mut(start..start + data.len()) {
+            Some(memory) => {
+                memory.copy_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_breakpoint(&mut self, _: u32) -> bool {
+        false
+    }
+
+    fn remove_breakpoint(&mut self, _: u32) -> bool {
+        false
+    }
+
+    fn add_watchpoint(&mut self, _: WatchKind, _: u32, _: u32) -> bool {
+        false
+    }
+
+    fn remove_watchpoint(&mut self, _: WatchKind, _: u32, _: u32) -> bool {
+        false
+    }
+}
+
+/// A device whose program runs its agent on whatever okdude sends it: it prints a line and halts
+/// whenever it's resumed, and exits with 137 when it's killed.
+struct Device {
+    program: Program,
+    agent: Agent,
+    decoder: FrameLayer,
+    packet: Vec<u8>,
+    /// What the device has sent, for okdude to read.
+    sent: VecDeque<u8>,
+}
+impl Device {
+    fn new() -> Self {
+        let mut device = Self {
+            program: Program {
+                registers: [0; REGISTERS],
+                memory: std::array::from_fn(|i| i as u8),
+            },
+            agent: Agent::new(),
+            decoder: FrameLayer::new(COBS_XOR),
+            packet: vec![],
+            sent: VecDeque::new(),
+        };
+        device.print(b"booted\n");
+        device.halt();
+        device
+    }
+
+    fn print(&mut self, text: &[u8]) {
+        self.sent.extend(text);
+    }
+
+    fn send(sent: &mut VecDeque<u8>, message_type: MessageType, payload: &[u8]) {
+        write_frame(message_type, payload, |b| sent.extend(b));
+    }
+
+    fn halt(&mut self) {
+        let Self {
+            program,
+            agent,
+            sent,
+            ..
+        } = self;
+        agent.stopped(program, Stop::Trap, |reply| {
+            Self::send(sent, MessageType::GdbPacket, reply)
+        });
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match self.decoder.feed(byte) {
+            Ok(FrameOutput::Payload(byte)) => self.packet.push(byte),
+            Ok(FrameOutput::Finished) => {
+                let packet = std::mem::take(&mut self.packet);
+                let Self {
+                    program,
+                    agent,
+                    sent,
+                    ..
+                } = self;
+                let resume = agent.handle(program, &packet, |reply| {
+                    Self::send(sent, MessageType::GdbPacket, reply)
+                });
+                match resume {
+                    Some(Resume::Continue | Resume::Step) => {
+                        self.print(b"running\n");
+                        self.program.registers[15] += 4;
+                        self.halt();
+                    }
+                    Some(Resume::Kill) => {
+                        let mut frame = vec![];
+                        Exit { code: 137 }.frame(|b| frame.extend_from_slice(b));
+                        self.sent.extend(frame);
+                    }
+                    Some(Resume::Detach) | None => {}
+                }
+            }
+            Ok(_) => {}
+            Err(e) => panic!("device got a broken frame: {e}"),
+        }
+    }
+}
+impl Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.sent.read(buf)? {
+            0 => Err(ErrorKind::TimedOut.into()),
+            n => Ok(n),
+        }
+    }
+}
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.receive(byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Transport for Device {
+    fn baud_rate(&self) -> u32 {
+        okboot_common::INITIAL_BAUD_RATE
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn bytes_to_read(&mut self) -> io::Result<usize> {
+        Ok(self.sent.len())
+    }
+
+    fn clear(&mut self, _: ClearBuffer) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Send GDB's packet with `body`, and return the body of the reply.
+fn ask(gdb: &mut TcpStream, body: &str) -> String {
+    gdb.write_all(&packet(body.as_bytes())).unwrap();
+    let mut reader = Reader::default();
+    let mut acked = false;
+    loop {
+        let mut byte = [0];
+        gdb.read_exact(&mut byte).expect("okdude stopped answering");
+        acked |= byte == *b"+";
+        if let Some(from_okdude) = reader.feed(byte[0]) {
+            assert!(acked, "okdude didn't acknowledge {body}");
+            let FromGdb::Packet(reply) = from_okdude else {
+                panic!("okdude sent {from_okdude:?}");
+            };
+            return String::from_utf8(reply).unwrap();
+        }
+    }
+}
+
+#[test]
+fn relays_between_gdb_and_agent() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let okdude = std::thread::spawn(move || {
+        let mut console = Console {
+            transport: Box::new(Device::new()),
+        };
+        let mut printed = vec![];
+        let code = debug(&mut console, stream, |text| {
+            printed.extend_from_slice(text);
+            Ok(())
+        });
+        (code.expect("debugging failed"), printed)
+    });
+
+    assert_eq!(ask(&mut gdb, "?"), "T05");
+    assert_eq!(ask(&mut gdb, "m4,4"), "04050607");
+    assert_eq!(ask(&mut gdb, "X0,2:#}"), "OK");
+    assert_eq!(ask(&mut gdb, "m0,3"), "237d02");
+    assert_eq!(ask(&mut gdb, "c"), "T05");
+    assert_eq!(ask(&mut gdb, "pf"), "04000000");
+    gdb.write_all(&packet(b"k")).unwrap();
+
+    let (code, printed) = okdude.join().unwrap();
+    assert_eq!(code, Some(137));
+    assert_eq!(printed, b"booted\nrunning\n");
+}
@@ -0,0 +1,239 @@
+//! Filling in sections of ELF programs as they're uploaded, laid out as
+//! [`okboot_common::inject`] describes; programs read them back with `quartz::inject`.
+//!
+//! What to inject comes from [`Uploader`](crate::Uploader) settings, or from a manifest, one item
+//! per line: what it is, then its value, separated by whitespace. `#` starts a comment, and files
+//! are found relative to the manifest.
+//!
+//! ```text
+//! arg       --verbose
+//! env       LOG=debug
+//! build-id  v1.2.3
+//! file      .data.splash  splash.bin
+//! ```
+//!
+//! The boot time goes into any program with a section for it, and so does the program's own GNU
+//! build ID, unless it's given one.
+use crate::Error;
+use elf::endian::LittleEndian;
+use elf::note::Note;
+use elf::ElfBytes;
+use okboot_common::inject::{self, ARGS, BOOT_TIME, BUILD_ID, ENV};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[cfg(test)]
+mod tests;
+
+/// Where the linker puts the GNU build ID, if it was asked to make one.
+const GNU_BUILD_ID: &str = ".note.gnu.build-id";
+
+/// What to put in the program's sections.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Injections {
+    pub args: Vec<String>,
+    /// Environment variables, as `(key, value)`.
+    pub env: Vec<(String, String)>,
+    pub build_id: Option<String>,
+    /// Files to fill sections with, as `(section, file)`.
+    pub files: Vec<(String, PathBuf)>,
+}
+impl Injections {
+    /// Read the manifest at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&text, dir)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display())))
+    }
+
+    /// Read a manifest whose files are relative to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut injections = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (what, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim_start();
+            if value.is_empty() {
+                return Err(format!("line {}: expected a value after `{what}`", i + 1));
+            }
+            match what {
+                "arg" => injections.args.push(value.to_string()),
+                "env" => injections
+                    .env
+                    .push(parse_var(value).map_err(|e| format!("line {}: {e}", i + 1))?),
+                "build-id" => injections.build_id = Some(value.to_string()),
+                "file" => {
+                    let (section, file) =
+                        parse_file(value).map_err(|e| format!("line {}: {e}", i + 1))?;
+                    injections.files.push((section, dir.join(file)));
+                }
+                _ => return Err(format!("line {}: unknown item `{what}`", i + 1)),
+            }
+        }
+        Ok(injections)
+    }
+
+    /// Add what's in `other`, whose build ID wins if it has one.
+    pub fn extend(&mut self, other: Injections) {
+        self.args.extend(other.args);
+        self.env.extend(other.env);
+        self.build_id = other.build_id.or(self.build_id.take());
+        self.files.extend(other.files);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// `KEY=VALUE`, as `(key, value)`.
+pub fn parse_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, found `{var}`")),
+    }
+}
+
+/// `SECTION=FILE` on the command line, or `SECTION FILE` in a manifest.
+pub fn parse_file(spec: &str) -> Result<(String, PathBuf), String> {
+    match spec.split_once(['=', ' ', '\t']) {
+        Some((section, file)) if !section.is_empty() && !file.trim_start().is_empty() => {
+            Ok((section.to_string(), PathBuf::from(file.trim_start())))
+        }
+        _ => Err(format!("expected a section and a file, found `{spec}`")),
+    }
+}
+
+/// Something to write into a section.
+struct Write {
+    section: String,
+    bytes: Vec<u8>,
+    /// What it is, for messages.
+    what: String,
+    /// Whether it was asked for, so that a program without the section is an error rather than
+    /// just not interested.
+    asked: bool,
+}
+
+/// Fill in the sections of the ELF program `image` that there's something for.
+pub(crate) fn inject(image: &mut [u8], injections: &Injections) -> Result<(), Error> {
+    let elf = ElfBytes::<LittleEndian>::minimal_parse(image)
+        .map_err(|e| Error::InvalidImage(format!("failed to parse ELF file: {e}")))?;
+    let env: Vec<String> = injections
+        .env
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    let mut writes = vec![
+        Write {
+            section: ARGS.to_string(),
+            bytes: inject::encode_list(injections.args.iter().map(|arg| arg.as_bytes())),
+            what: "arguments".to_string(),
+            asked: !injections.args.is_empty(),
+        },
+        Write {
+            section: ENV.to_string(),
+            bytes: inject::encode_list(env.iter().map(|var| var.as_bytes())),
+            what: "environment variables".to_string(),
+            asked: !injections.env.is_empty(),
+        },
+    ];
+    let build_id = match &injections.build_id {
+        Some(id) => Some((id.clone(), true)),
+        None => gnu_build_id(&elf).map(|id| (id, false)),
+    };
+    if let Some((id, asked)) = build_id {
+        writes.push(Write {
+            section: BUILD_ID.to_string(),
+            bytes: inject::encode_blob(id.as_bytes()),
+            what: format!("build ID {id}"),
+            asked,
+        });
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    writes.push(Write {
+        section: BOOT_TIME.to_string(),
+        bytes: inject::encode_blob(&now.as_secs().to_le_bytes()),
+        what: "boot time".to_string(),
+        asked: false,
+    });
+    for (section, path) in &injections.files {
+        let file = std::fs::read(path).map_err(|source| Error::Open {
+            path: path.clone(),
+            source,
+        })?;
+        writes.push(Write {
+            section: section.clone(),
+            bytes: inject::encode_blob(&file),
+            what: path.display().to_string(),
+            asked: true,
+        });
+    }
+
+    let mut places = vec![];
+    for write in writes {
+        let header = elf
+            .section_header_by_name(&write.section)
+            .map_err(|e| Error::InvalidImage(format!("failed to parse section table: {e}")))?;
+        let Some(header) = header else {
+            if write.asked {
+                return Err(Error::InvalidImage(format!(
+                    "the program has no `{}` section to put {} in",
+                    write.section, write.what
+                )));
+            }
+            continue;
+        };
+        if header.sh_type == elf::abi::SHT_NOBITS {
+            return Err(Error::InvalidImage(format!(
+                "`{}` takes up no room in the file for {}; it needs to be SHT_PROGBITS",
+                write.section, write.what
+            )));
+        }
+        if write.bytes.len() as u64 > header.sh_size {
+            return Err(Error::InvalidImage(format!(
+                "{} bytes of {} don't fit in `{}`, which has room for {}",
+                write.bytes.len(),
+                write.what,
+                write.section,
+                header.sh_size
+            )));
+        }
+        let offset = header.sh_offset as usize;
+        places.push((offset..offset + write.bytes.len(), write));
+    }
+
+    for (range, write) in places {
+        let Some(place) = image.get_mut(range) else {
+            return Err(Error::InvalidImage(format!(
+                "`{}` runs past the end of the file",
+                write.section
+            )));
+        };
+        place.copy_from_slice(&write.bytes);
+        if write.asked {
+            tracing::info!("inserted {} in {}", write.what, write.section);
+        } else {
+            tracing::debug!("inserted {} in {}", write.what, write.section);
+        }
+    }
+    Ok(())
+}
+
+/// The program's GNU build ID, in hex, if the linker gave it one.
+fn gnu_build_id(elf: &ElfBytes<LittleEndian>) -> Option<String> {
+    let header = elf.section_header_by_name(GNU_BUILD_ID).ok()??;
+    elf.section_data_as_notes(&header)
+        .ok()?
+        .find_map(|note| match note {
+            Note::GnuBuildId(id) => Some(id.0.iter().map(|b| format!("{b:02x}")).collect()),
+            _ => None,
+        })
+}
@@ -0,0 +1,158 @@
+//! Tests of reading manifests and filling in sections.
+use super::{inject, Injections};
+use crate::Error;
+use okboot_common::inject::{self as layout, List};
+use std::path::{Path, PathBuf};
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_NOBITS: u32 = 8;
+
+/// ELF file with nothing in it but `sections`, each given as its name, type and size, and filled
+/// with zeroes.
+fn elf(sections: &[(&str, u32, u32)]) -> Vec<u8> {
+    let mut shstrtab = b"\0.shstrtab\0".to_vec();
+    let mut names = vec![];
+    for (name, _, _) in sections {
+        names.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(name.as_bytes());
+        shstrtab.push(0);
+    }
+    let mut offsets = vec![];
+    let mut offset = 52 + shstrtab.len() as u32;
+    for (_, sh_type, size) in sections {
+        offsets.push(offset);
+        if *sh_type != SHT_NOBITS {
+            offset += size;
+        }
+    }
+    let shoff = (offset + 3) & !3;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let u16 = |elf: &mut Vec<u8>, x: u16| elf.extend_from_slice(&x.to_le_bytes());
+    u16(&mut elf, 2); // ET_EXEC
+    u16(&mut elf, 40); // EM_ARM
+    let words = |elf: &mut Vec<u8>, xs: &[u32]| {
+        xs.iter()
+            .for_each(|x| elf.extend_from_slice(&x.to_le_bytes()))
+    };
+    // e_version, e_entry, e_phoff, e_shoff, e_flags
+    words(&mut elf, &[1, 0x8000, 0, shoff, 0]);
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    for x in [52, 32, 0, 40, sections.len() as u16 + 2, 1] {
+        u16(&mut elf, x);
+    }
+    elf.extend_from_slice(&shstrtab);
+    elf.resize(shoff as usize, 0);
+    // null section, then the names; name, type, flags, addr, offset, size, link, info,
+    // addralign, entsize
+    words(&mut elf, &[0; 10]);
+    words(
+        &mut elf,
+        &[1, 3, 0, 0, 52, shstrtab.len() as u32, 0, 0, 1, 0],
+    );
+    for (i, (_, sh_type, size)) in sections.iter().enumerate() {
+        words(
+            &mut elf,
+            &[names[i], *sh_type, 3, 0, offsets[i], *size, 0, 0, 4, 0],
+        );
+    }
+    elf
+}
+
+/// Contents of the section called `name` in `elf`.
+fn section<'a>(elf: &'a [u8], name: &str) -> &'a [u8] {
+    let file = elf::ElfBytes::<elf::endian::LittleEndian>::minimal_parse(elf).unwrap();
+    let header = file.section_header_by_name(name).unwrap().unwrap();
+    let offset = header.sh_offset as usize;
+    &elf[offset..offset + header.sh_size as usize]
+}
+
+#[test]
+fn parses_manifest() {
+    let injections = Injections::parse(
+        "# what    value\n\
+         arg       --verbose\n\
+         arg       two words\n\
+         \n\
+         env       LOG=debug  # chatty\n\
+         build-id  v1.2.3\n\
+         file      .data.splash  splash.bin\n",
+        Path::new("assets"),
+    )
+    .expect("failed to parse manifest");
+    assert_eq!(
+        injections,
+        Injections {
+            args: vec!["--verbose".to_string(), "two words".to_string()],
+            env: vec![("LOG".to_string(), "debug".to_string())],
+            build_id: Some("v1.2.3".to_string()),
+            files: vec![(
+                ".data.splash".to_string(),
+                PathBuf::from("assets/splash.bin")
+            )],
+        }
+    );
+}
+
+#[test]
+fn rejects_malformed_manifest() {
+    let dir = Path::new("");
+    assert!(Injections::parse("arg\n", dir).is_err());
+    assert!(Injections::parse("env LOG\n", dir).is_err());
+    assert!(Injections::parse("env =debug\n", dir).is_err());
+    assert!(Injections::parse("file .data.splash\n", dir).is_err());
+    assert!(Injections::parse("argv --verbose\n", dir).is_err());
+}
+
+#[test]
+fn fills_in_sections() {
+    let mut image = elf(&[
+        (".data.args", SHT_PROGBITS, 0x40),
+        (".data.env", SHT_PROGBITS, 0x40),
+        (".data.build_id", SHT_PROGBITS, 0x20),
+        (".data.boot_time", SHT_PROGBITS, 0x10),
+    ]);
+    let injections = Injections {
+        args: vec!["hello".to_string(), "four".to_string()],
+        env: vec![("LOG".to_string(), "debug".to_string())],
+        build_id: Some("v1.2.3".to_string()),
+        files: vec![],
+    };
+    inject(&mut image, &injections).expect("failed to inject");
+    assert_eq!(
+        List::new(section(&image, ".data.args")).collect::<Vec<_>>(),
+        [&b"hello"[..], b"four"]
+    );
+    assert_eq!(
+        List::new(section(&image, ".data.env")).collect::<Vec<_>>(),
+        [b"LOG=debug"]
+    );
+    assert_eq!(
+        layout::blob(section(&image, ".data.build_id")),
+        Some(&b"v1.2.3"[..])
+    );
+    assert!(layout::boot_time(section(&image, ".data.boot_time")).is_some_and(|time| time > 0));
+}
+
+#[test]
+fn leaves_programs_without_sections_alone() {
+    let image = elf(&[]);
+    let mut injected = image.clone();
+    inject(&mut injected, &Injections::default()).expect("failed to inject");
+    assert_eq!(injected, image);
+}
+
+#[test]
+fn explains_missing_and_unusable_sections() {
+    let args = Injections {
+        args: vec!["hello".to_string()],
+        ..Injections::default()
+    };
+    let message = |sections: &[(&str, u32, u32)]| match inject(&mut elf(sections), &args) {
+        Err(Error::InvalidImage(message)) => message,
+        result => panic!("expected InvalidImage, got {result:?}"),
+    };
+    assert!(message(&[]).contains("no `.data.args` section"));
+    assert!(message(&[(".data.args", SHT_NOBITS, 0x100)]).contains("SHT_PROGBITS"));
+    assert!(message(&[(".data.args", SHT_PROGBITS, 8)]).contains("room for 8"));
+}
@@ -0,0 +1,312 @@
+//! Finding the devices attached to this machine, and telling them apart.
+//!
+//! Boards are told apart by the serial number that okboot reports, along with the rest of its
+//! [`Info`], in answer to an [`Identify`] message. A [`Boards`] file gives them names, one board per line: the name, then
+//! the serial number in hex, separated by whitespace. `#` starts a comment.
+//!
+//! ```text
+//! # name    serial
+//! rack-a1   00000000a1b2c3d4
+//! rack-a2   00000000e5f6a7b8
+//! ```
+use crate::transport::Transport;
+use crate::upload::{self, TTY_TIMEOUT};
+use crate::{Error, OutputHook};
+use okboot_common::compression::Codecs;
+use okboot_common::device::DeviceInfo;
+use okboot_common::host::{Formats, Identify};
+use okboot_common::monitor::MemoryMap;
+use okboot_common::MessageType;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[cfg(test)]
+mod tests;
+
+/// Prefixes of the names in `/dev` that USB serial adapters show up as.
+pub const PATTERNS: [&str; 6] = [
+    "ttyUSB",
+    "ttyACM",
+    "tty.usbserial",
+    "cu.usbserial",
+    "tty,SLAB_USB",
+    "cu.SLAB_USB",
+];
+
+/// How many times to ask a device which board it is before concluding that it isn't listening.
+const IDENTIFY_TRIES: usize = 3;
+
+/// Serial ports in `/dev` that could have a device behind them, most recently attached first.
+pub fn candidates() -> io::Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir("/dev")? {
+        let Ok(entry) = entry else { continue };
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if !PATTERNS.iter().any(|pattern| name.starts_with(pattern)) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.file_type().is_char_device() {
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        found.push((modified, entry.path()));
+    }
+    found.sort_by(|a, b| b.cmp(a));
+    Ok(found.into_iter().map(|(_, path)| path).collect())
+}
+
+/// What a device says about itself; an owned [`DeviceInfo`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Info {
+    pub serial: u64,
+    pub board_revision: u32,
+    pub cpu_id: u32,
+    pub version: String,
+    pub git_hash: String,
+    pub image_end: u32,
+    pub load_end: u32,
+    pub max_elf_len: u32,
+    pub formats: Formats,
+    pub codecs: Codecs,
+    pub memory_map: MemoryMap,
+    pub max_persist_len: u32,
+}
+impl Info {
+    /// The message this was read from, for [`DeviceInfo::check`] and the like.
+    pub fn device_info(&self) -> DeviceInfo<'_> {
+        DeviceInfo {
+            serial: self.serial,
+            board_revision: self.board_revision,
+            cpu_id: self.cpu_id,
+            version: &self.version,
+            git_hash: &self.git_hash,
+            image_end: self.image_end,
+            load_end: self.load_end,
+            max_elf_len: self.max_elf_len,
+            formats: self.formats,
+            codecs: self.codecs,
+            memory_map: self.memory_map,
+            max_persist_len: self.max_persist_len,
+        }
+    }
+}
+impl From<DeviceInfo<'_>> for Info {
+    fn from(info: DeviceInfo<'_>) -> Self {
+        Self {
+            serial: info.serial,
+            board_revision: info.board_revision,
+            cpu_id: info.cpu_id,
+            version: info.version.to_string(),
+            git_hash: info.git_hash.to_string(),
+            image_end: info.image_end,
+            load_end: info.load_end,
+            max_elf_len: info.max_elf_len,
+            formats: info.formats,
+            codecs: info.codecs,
+            memory_map: info.memory_map,
+            max_persist_len: info.max_persist_len,
+        }
+    }
+}
+impl Display for Info {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "serial number:  {:016x}", self.serial)?;
+        writeln!(f, "board revision: {:08x}", self.board_revision)?;
+        writeln!(f, "CPU ID:         {:08x}", self.cpu_id)?;
+        match self.git_hash.as_str() {
+            "" => writeln!(f, "okboot:         {}", self.version)?,
+            hash => writeln!(f, "okboot:         {} ({hash})", self.version)?,
+        }
+        writeln!(
+            f,
+            "load range:     below {:#010x}; okboot ends at {:#010x}",
+            self.load_end, self.image_end
+        )?;
+        writeln!(f, "largest ELF:    {} bytes", self.max_elf_len)?;
+        writeln!(f, "formats:        {}", self.formats)?;
+        writeln!(f, "codecs:         {}", self.codecs)?;
+        match self.max_persist_len {
+            0 => writeln!(f, "SD card:        none")?,
+            len => writeln!(f, "SD card:        keeps programs of up to {len} bytes")?,
+        }
+        let map = &self.memory_map;
+        writeln!(f, "RAM:            {}", map.ram)?;
+        if !map.peripherals.is_empty() {
+            writeln!(f, "peripherals:    {}", map.peripherals)?;
+        }
+        write!(f, "okboot uses:    {} (image)", map.okboot)?;
+        for (region, what) in [(map.heap, "heap"), (map.uart, "UART")] {
+            if !region.is_empty() {
+                write!(f, ", {region} ({what})")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ask the device at the other end of `transport` what it is. `None` if it doesn't say, e.g.
+/// because it's running a program rather than okboot, or an okboot too old to know how.
+pub fn identify(transport: &mut dyn Transport) -> Result<Option<Info>, Error> {
+    let mut output = OutputHook::default();
+    for _ in 0..IDENTIFY_TRIES {
+        upload::send(&Identify {}, transport)?;
+        match upload::recv_with_print_string(transport, &mut output, TTY_TIMEOUT) {
+            Ok(Some((MessageType::DeviceInfo, payload))) => {
+                match postcard::from_bytes::<DeviceInfo>(&payload) {
+                    Ok(info) => return Ok(Some(info.into())),
+                    Err(e) => tracing::debug!("failed to deserialize DeviceInfo: {e}"),
+                }
+            }
+            Ok(Some((message_type, _))) => {
+                tracing::debug!("received {message_type:?} in response to Identify");
+            }
+            Ok(None) => {}
+            Err(e) => match Error::from(e) {
+                Error::LinkLost => return Err(Error::LinkLost),
+                e => tracing::debug!("failed to receive DeviceInfo: {e}"),
+            },
+        }
+    }
+    Ok(None)
+}
+
+/// [`identify`] the device behind the serial port at `device`.
+pub fn identify_device(device: &Path) -> Result<Option<Info>, Error> {
+    identify(&mut *crate::open(device.to_path_buf())?)
+}
+
+/// A serial port that could have a device behind it, as found by [`scan`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Found {
+    pub device: PathBuf,
+    /// What's behind it, if it said.
+    pub info: Option<Info>,
+}
+impl Found {
+    /// Which board is behind it, if it said.
+    pub fn serial(&self) -> Option<u64> {
+        self.info.as_ref().map(|info| info.serial)
+    }
+}
+
+/// [`identify`] whatever is behind each of the [`candidates`], all at once.
+pub fn scan() -> io::Result<Vec<Found>> {
+    let candidates = candidates()?;
+    Ok(std::thread::scope(|scope| {
+        let probes: Vec<_> = candidates
+            .into_iter()
+            .map(|device| {
+                scope.spawn(move || {
+                    let info = identify_device(&device).unwrap_or_else(|e| {
+                        tracing::warn!("failed to identify {}: {e}", device.display());
+                        None
+                    });
+                    Found { device, info }
+                })
+            })
+            .collect();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().expect("identifying a device panicked"))
+            .collect()
+    }))
+}
+
+/// A board that has been given a name.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Board {
+    pub name: String,
+    pub serial: u64,
+}
+
+/// Names for boards, as listed in a boards file.
+#[derive(Debug, Clone, Default)]
+pub struct Boards {
+    boards: Vec<Board>,
+}
+impl Boards {
+    /// Read the boards file at `path`; an empty list if there isn't one.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).map_err(|e| {
+                io::Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display()))
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut boards: Vec<Board> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, serial) = match fields[..] {
+                [] => continue,
+                [name, serial] => (name, serial),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected a name and a serial number",
+                        i + 1
+                    ))
+                }
+            };
+            let serial = parse_serial(serial)
+                .ok_or_else(|| format!("line {}: invalid serial number `{serial}`", i + 1))?;
+            if boards.iter().any(|board| board.name == name) {
+                return Err(format!("line {}: `{name}` is listed more than once", i + 1));
+            }
+            boards.push(Board {
+                name: name.to_string(),
+                serial,
+            });
+        }
+        Ok(Self { boards })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Board> {
+        self.boards.iter()
+    }
+
+    /// Name of the board with `serial`, if it has one.
+    pub fn name_of(&self, serial: u64) -> Option<&str> {
+        self.boards
+            .iter()
+            .find(|board| board.serial == serial)
+            .map(|board| board.name.as_str())
+    }
+
+    /// Serial number of the board called `board`, or else `board` itself read as a serial number.
+    pub fn serial_of(&self, board: &str) -> Option<u64> {
+        self.boards
+            .iter()
+            .find(|b| b.name == board)
+            .map(|b| b.serial)
+            .or_else(|| parse_serial(board))
+    }
+}
+
+/// `$XDG_CONFIG_HOME/okdude/boards`, or `~/.config/okdude/boards`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("okdude").join("boards"))
+}
+
+/// Hex, like Linux shows it in `/proc/cpuinfo`, with or without a leading `0x`.
+fn parse_serial(serial: &str) -> Option<u64> {
+    let digits = serial.strip_prefix("0x").unwrap_or(serial);
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    u64::from_str_radix(digits, 16).ok()
+}
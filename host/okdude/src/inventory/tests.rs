@@ -0,0 +1,39 @@
+//! Tests of reading [`Boards`] files.
+use super::{Board, Boards};
+
+#[test]
+fn parses_boards() {
+    let boards = Boards::parse(
+        "# name    serial\n\
+         rack-a1   00000000a1b2c3d4\n\
+         \n\
+         rack-a2   0xe5f6a7b8  # the flaky one\n",
+    )
+    .expect("failed to parse boards");
+    assert_eq!(
+        boards.iter().cloned().collect::<Vec<_>>(),
+        [
+            Board {
+                name: "rack-a1".to_string(),
+                serial: 0xa1b2_c3d4,
+            },
+            Board {
+                name: "rack-a2".to_string(),
+                serial: 0xe5f6_a7b8,
+            },
+        ]
+    );
+    assert_eq!(boards.name_of(0xe5f6_a7b8), Some("rack-a2"));
+    assert_eq!(boards.serial_of("rack-a1"), Some(0xa1b2_c3d4));
+    // not a name, so a serial number
+    assert_eq!(boards.serial_of("0123abcd"), Some(0x0123_abcd));
+    assert_eq!(boards.serial_of("rack-b1"), None);
+}
+
+#[test]
+fn rejects_malformed_boards() {
+    assert!(Boards::parse("rack-a1\n").is_err());
+    assert!(Boards::parse("rack-a1 not-hex\n").is_err());
+    assert!(Boards::parse("rack-a1 00000000000000001\n").is_err());
+    assert!(Boards::parse("rack-a1 1\nrack-a1 2\n").is_err());
+}
@@ -0,0 +1,346 @@
+//! Uploads programs to devices running okboot.
+//!
+//! An [`Uploader`] negotiates a protocol with the device, uploads the program and has the device
+//! boot it, then hands over the device's [`Console`]. The `okdude` binary is a thin wrapper around
+//! it.
+
+pub mod cache;
+pub mod compression;
+mod error;
+pub mod formats;
+pub mod gdb;
+pub mod inject;
+pub mod inventory;
+pub mod monitor;
+pub mod persist;
+pub mod remote;
+/// Running booted programs to completion, as tests.
+pub mod run;
+/// Signing programs for devices that only boot signed programs.
+pub mod sign;
+mod suboot;
+/// Links to the device.
+pub mod transport;
+mod tty;
+mod upload;
+mod v2;
+
+pub use error::Error;
+pub use transport::Transport;
+pub use tty::Tty;
+
+use compression::CompressionArg;
+use ed25519_dalek::SigningKey;
+use inject::Injections;
+use okboot_common::device;
+use okboot_common::host::{self, FormatDetails};
+use okboot_common::persist::Slot;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+pub const DEFAULT_BAUD_RATES: [u32; 4] = [1_500_000, 921_600, 460_800, 115_200];
+
+/// How far along an upload is, as reported to [`Uploader::on_progress`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Progress {
+    /// Bytes of the (compressed) upload that have been sent.
+    pub sent: u64,
+    /// Size of the (compressed) upload.
+    pub total: u64,
+}
+
+/// Something the device said during the upload, as passed to [`Uploader::on_device_output`].
+#[derive(Debug, Copy, Clone)]
+pub enum DeviceOutput<'a> {
+    /// Unstructured text, e.g. from okboot's `rpc_println!`.
+    Print(&'a str),
+    /// A structured log record.
+    Log(&'a device::Log<'a>),
+}
+
+type ProgressCallback = Box<dyn FnMut(Progress) + Send>;
+type OutputCallback = Box<dyn FnMut(DeviceOutput<'_>) + Send>;
+
+/// Uploads a program to a device running okboot, and has the device boot it.
+pub struct Uploader {
+    connection: Connection,
+    config: Config,
+    hooks: Hooks,
+}
+
+enum Connection {
+    Device(PathBuf),
+    Transport(Box<dyn Transport>),
+}
+
+/// Everything about the upload that isn't a callback.
+pub(crate) struct Config {
+    pub image: Vec<u8>,
+    pub format_details: FormatDetails,
+    pub injections: Injections,
+    pub baud_rates: Vec<u32>,
+    pub sign_key: Option<SigningKey>,
+    /// Where the last program uploaded to the device is kept, for delta uploads; `None` if they're
+    /// disabled.
+    pub image_cache: Option<PathBuf>,
+    pub compression: CompressionArg,
+    /// Where on the device's SD card to keep the program, if anywhere.
+    pub persist: Option<Slot>,
+}
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub progress: ProgressHook,
+    pub output: OutputHook,
+}
+
+/// [`Uploader::on_progress`], if set.
+#[derive(Default)]
+pub(crate) struct ProgressHook(Option<ProgressCallback>);
+impl ProgressHook {
+    pub fn report(&mut self, sent: usize, total: usize) {
+        if let Some(callback) = &mut self.0 {
+            callback(Progress {
+                sent: sent.min(total) as u64,
+                total: total as u64,
+            });
+        }
+    }
+}
+
+/// [`Uploader::on_device_output`], or else `tracing`.
+#[derive(Default)]
+pub(crate) struct OutputHook(Option<OutputCallback>);
+impl OutputHook {
+    pub fn emit(&mut self, output: DeviceOutput<'_>) {
+        match (&mut self.0, output) {
+            (Some(callback), output) => callback(output),
+            (None, DeviceOutput::Print(text)) => tracing::info!("< {}", text.trim_end()),
+            (None, DeviceOutput::Log(record)) => v2::emit_device_log(record),
+        }
+    }
+}
+
+impl Uploader {
+    /// Upload `image` to the device behind the serial port at `device`, which may be on another
+    /// machine that [serves](remote::serve) it, as in `tcp://lab:2217`.
+    pub fn new(device: impl Into<PathBuf>, image: Vec<u8>, format_details: FormatDetails) -> Self {
+        Self::with_connection(Connection::Device(device.into()), image, format_details)
+    }
+
+    /// Upload `image` to the device at the other end of `transport`.
+    pub fn with_transport(
+        transport: impl Transport + 'static,
+        image: Vec<u8>,
+        format_details: FormatDetails,
+    ) -> Self {
+        Self::with_connection(
+            Connection::Transport(Box::new(transport)),
+            image,
+            format_details,
+        )
+    }
+
+    fn with_connection(
+        connection: Connection,
+        image: Vec<u8>,
+        format_details: FormatDetails,
+    ) -> Self {
+        Self {
+            connection,
+            config: Config {
+                image,
+                format_details,
+                injections: Injections::default(),
+                baud_rates: DEFAULT_BAUD_RATES.to_vec(),
+                sign_key: None,
+                image_cache: None,
+                compression: CompressionArg::Auto,
+                persist: None,
+            },
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Arguments to place in the `.data.args` section of an ELF program.
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.injections.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Everything to place in the sections of an ELF program, arguments included; see [`inject`].
+    pub fn injections(mut self, injections: Injections) -> Self {
+        self.config.injections = injections;
+        self
+    }
+
+    /// Baud rates to propose to the device; the fastest one that the device accepts (and that
+    /// works) is used for the upload.
+    pub fn baud_rates(mut self, baud_rates: &[u32]) -> Self {
+        let mut baud_rates = baud_rates.to_vec();
+        // fallback after a failed baud probe only ever tries slower rates
+        baud_rates.sort_unstable_by(|a, b| b.cmp(a));
+        baud_rates.dedup();
+        self.config.baud_rates = baud_rates;
+        self
+    }
+
+    /// Sign the program with `key`, for devices built with `OKBOOT_VERIFYING_KEY`.
+    pub fn sign_key(mut self, key: SigningKey) -> Self {
+        self.config.sign_key = Some(key);
+        self
+    }
+
+    /// Keep the program at `path` once it has booted, and upload only what changed from the one
+    /// kept there before, if the device still has it; see [`cache::image_path`].
+    pub fn image_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.image_cache = Some(path.into());
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionArg) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    /// Have okboot keep the program in `slot` on the device's SD card before it boots it, so that
+    /// the device comes back with it after a power cycle; see [`persist`].
+    pub fn persist(mut self, slot: Slot) -> Self {
+        self.config.persist = Some(slot);
+        self
+    }
+
+    /// Call `callback` as the upload makes progress.
+    pub fn on_progress(mut self, callback: impl FnMut(Progress) + Send + 'static) -> Self {
+        self.hooks.progress = ProgressHook(Some(Box::new(callback)));
+        self
+    }
+
+    /// Call `callback` with whatever the device says before it boots the program; by default,
+    /// it's logged through `tracing`.
+    pub fn on_device_output(
+        mut self,
+        callback: impl FnMut(DeviceOutput<'_>) + Send + 'static,
+    ) -> Self {
+        self.hooks.output = OutputHook(Some(Box::new(callback)));
+        self
+    }
+
+    /// Upload the program and have the device boot it, over whichever protocol the device
+    /// supports.
+    pub fn upload(self) -> Result<Console, Error> {
+        let Self {
+            connection,
+            mut config,
+            mut hooks,
+        } = self;
+        match config.format_details {
+            FormatDetails::Elf => inject::inject(&mut config.image, &config.injections)?,
+            _ if !config.injections.is_empty() => {
+                return Err(Error::InvalidImage(
+                    "only ELF programs have sections to inject into".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        let mut transport: Box<dyn Transport> = match connection {
+            Connection::Device(path) => open(path)?,
+            Connection::Transport(transport) => transport,
+        };
+        upload::boot(&config, &mut *transport, &mut hooks)?;
+        Ok(Console { transport })
+    }
+}
+
+/// Open the serial port at `path`, or connect to the one that it names on another machine, the
+/// way okboot expects to be spoken to at first.
+fn open(path: PathBuf) -> Result<Box<dyn Transport>, Error> {
+    if let Some(address) = remote::address(&path) {
+        return match remote::Remote::connect(address, upload::TTY_TIMEOUT) {
+            Ok(remote) => Ok(Box::new(remote)),
+            Err(source) => Err(Error::Open { path, source }),
+        };
+    }
+    let mut tty = Tty::new(&path, okboot_common::INITIAL_BAUD_RATE).map_err(|e| Error::Open {
+        path,
+        source: io::Error::other(e),
+    })?;
+    tty.set_timeout(upload::TTY_TIMEOUT)?;
+    Ok(Box::new(tty))
+}
+
+/// How [`Console::reupload`] went.
+pub enum Reupload {
+    /// The device booted the program; this is its console.
+    Booted(Console),
+    /// The upload failed, but the device's console was opened again, with okboot running.
+    Failed(Error, Console),
+}
+
+/// The device's end of the link, once it has booted the program; usually its serial console.
+pub struct Console {
+    transport: Box<dyn Transport>,
+}
+impl Console {
+    pub fn transport(&mut self) -> &mut dyn Transport {
+        &mut *self.transport
+    }
+
+    pub fn into_transport(self) -> Box<dyn Transport> {
+        self.transport
+    }
+
+    /// Hand the device back to okboot: send the program a [`Reboot`](host::Reboot) message, which
+    /// it will act on if it listens for them, and reset the device too if the transport can.
+    pub fn reboot(&mut self) -> io::Result<()> {
+        let mut frame = vec![];
+        host::Reboot.frame(|b| frame.extend_from_slice(b));
+        self.transport.write_all(&frame)?;
+        self.transport.flush()?;
+        match self.transport.reset() {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Hand the device back to okboot, as [`reboot`](Console::reboot) does, and have `uploader`
+    /// upload to it again, e.g. once the program has been rebuilt. If that upload fails, the
+    /// device's console is opened again anyway, so that it can be kept open until the next try.
+    /// Returns an error if that isn't possible: the device has gone away, or `uploader` was given
+    /// a [`Transport`] rather than a device.
+    pub fn reupload(mut self, uploader: Uploader) -> Result<Reupload, Error> {
+        if let Err(e) = self.reboot() {
+            tracing::warn!("failed to reboot the device: {e}");
+        }
+        let device = match &uploader.connection {
+            Connection::Device(path) => Some(path.clone()),
+            Connection::Transport(_) => None,
+        };
+        // the upload opens the device afresh
+        drop(self);
+        match (uploader.upload(), device) {
+            (Ok(console), _) => Ok(Reupload::Booted(console)),
+            (Err(e), Some(path)) => Ok(Reupload::Failed(
+                e,
+                Console {
+                    transport: open(path)?,
+                },
+            )),
+            (Err(e), None) => Err(e),
+        }
+    }
+}
+impl Read for Console {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transport.read(buf)
+    }
+}
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
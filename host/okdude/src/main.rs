@@ -1,20 +1,29 @@
 #![feature(iter_intersperse)]
 #![feature(assert_matches)]
-#![feature(unsigned_is_multiple_of)]
 
-mod echo;
-mod suboot;
-mod tty;
-mod upload;
-mod v2;
+mod terminal;
 
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, Parser, Subcommand};
+use eyre::{eyre, WrapErr};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use okboot_common::host::FormatDetails;
+use okboot_common::persist::Slot;
+use okdude::compression::CompressionArg;
+use okdude::formats;
+use okdude::inject::{self, Injections};
+use okdude::inventory::{self, Boards, Found};
+use okdude::monitor::{self, Monitor};
+use okdude::persist::{self, Images};
+use okdude::remote;
+use okdude::run::{Outcome, RunUntil};
+use okdude::{cache, gdb, run, sign, Console, Progress, Uploader, DEFAULT_BAUD_RATES};
 use std::ffi::OsStr;
-use std::fs::DirEntry;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::FileTypeExt;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use terminal::SessionLog;
 use tracing_subscriber::EnvFilter;
 
 pub const DEFAULT_LOAD_ADDRESS: u64 = 0x8000;
@@ -28,143 +37,719 @@ fn main() {
 
     let args = parse_args();
 
-    if let Err(e) = upload::upload(args) {
+    let [target] = &args.targets[..] else {
+        std::process::exit(upload_all(&args));
+    };
+    let result = build(&args).and_then(|()| upload(&args, target, &MultiProgress::new()));
+    let result = result.and_then(|mut console| match (&args.run_until, &args.gdb) {
+        (Some(until), _) => {
+            let log = Mutex::new(
+                args.log_file
+                    .as_deref()
+                    .map(SessionLog::create)
+                    .transpose()?,
+            );
+            let outcome = run(&mut console, until, |text| show(text, &log))?;
+            std::process::exit(outcome.exit_code());
+        }
+        (None, Some(address)) => {
+            let log = Mutex::new(
+                args.log_file
+                    .as_deref()
+                    .map(SessionLog::create)
+                    .transpose()?,
+            );
+            let code = debug(&mut console, address, |text| show(text, &log))?;
+            std::process::exit(code);
+        }
+        (None, None) => terminal::terminal(&args, target, console),
+    });
+    if let Err(e) = result {
         tracing::error!("failed to upload: {e}");
         std::process::exit(1);
     }
 }
 
-pub struct Args {
-    device: PathBuf,
+/// Upload the program to all of the targets at once, then run it on each of them if `--run-until`
+/// says to. Returns the status to exit with: the first target's that didn't exit with 0, if any.
+fn upload_all(args: &Args) -> i32 {
+    if let Err(e) = build(args) {
+        tracing::error!("{e}");
+        return 1;
+    }
+    let log = match args.log_file.as_deref().map(SessionLog::create).transpose() {
+        Ok(log) => Mutex::new(log),
+        Err(e) => {
+            tracing::error!("{e}");
+            return 1;
+        }
+    };
+    let bars = MultiProgress::new();
+    let codes: Vec<i32> = std::thread::scope(|scope| {
+        let uploads: Vec<_> = args
+            .targets
+            .iter()
+            .map(|target| {
+                let (log, bars) = (&log, &bars);
+                scope.spawn(move || {
+                    let _span = tracing::info_span!("board", name = %target.name).entered();
+                    let result = upload(args, target, bars).and_then(|mut console| {
+                        let Some(until) = &args.run_until else {
+                            return Ok(0);
+                        };
+                        let mut labeled = Labeled::new(&target.name);
+                        let outcome =
+                            run(&mut console, until, |text| show(&labeled.lines(text), log))?;
+                        show(&labeled.rest(), log)?;
+                        Ok(outcome.exit_code())
+                    });
+                    result.unwrap_or_else(|e| {
+                        tracing::error!("failed to upload: {e}");
+                        1
+                    })
+                })
+            })
+            .collect();
+        uploads
+            .into_iter()
+            .map(|upload| upload.join().unwrap_or(1))
+            .collect()
+    });
+    codes.into_iter().find(|&code| code != 0).unwrap_or(0)
+}
+
+/// Write `text` to stdout, and to the session log if there is one.
+fn show(text: &[u8], log: &Mutex<Option<SessionLog>>) -> io::Result<()> {
+    let mut log = log.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(log) = &mut *log {
+        log.write(text)?;
+    }
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(text)?;
+    stdout.flush()
+}
+
+/// Run the build command, if there is one.
+fn build(args: &Args) -> eyre::Result<()> {
+    let Some(command) = &args.build else {
+        return Ok(());
+    };
+    tracing::info!("running `{command}`");
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .wrap_err_with(|| eyre!("failed to run `{command}`"))?;
+    if !status.success() {
+        return Err(eyre!("`{command}` failed ({status})"));
+    }
+    Ok(())
+}
+
+/// Upload the program to `target` as `args` say, and return the device's console.
+fn upload(args: &Args, target: &Target, bars: &MultiProgress) -> eyre::Result<Console> {
+    Ok(uploader(args, target, bars)?.upload()?)
+}
+
+/// Read the program and get ready to upload it to `target` as `args` say.
+fn uploader(args: &Args, target: &Target, bars: &MultiProgress) -> eyre::Result<Uploader> {
+    let (image, format_details) = read_program(args)?;
+    let mut uploader = Uploader::new(&target.device, image, format_details)
+        .injections(args.injections.clone())
+        .baud_rates(&args.baud_rates)
+        .compression(args.compression);
+    if let Some(path) = &args.sign_key {
+        uploader = uploader.sign_key(sign::load_signing_key(path)?);
+    }
+    if let Some(path) = &target.image_cache {
+        uploader = uploader.image_cache(path);
+    }
+    if let Some(slot) = args.persist {
+        uploader = uploader.persist(slot);
+    }
+    if !args.quiet {
+        let label = (args.targets.len() > 1).then(|| target.name.clone());
+        uploader = uploader.on_progress(progress_bar(bars.clone(), label));
+    }
+    Ok(uploader)
+}
+
+/// Read the program, converted to a format that okboot loads if it isn't in one already.
+fn read_program(args: &Args) -> eyre::Result<(Vec<u8>, FormatDetails)> {
+    let file = std::fs::read(&args.file)
+        .wrap_err_with(|| eyre!("failed to open {}", args.file.display()))?;
+    let program = match args.object_type {
+        ObjectType::Elf => return Ok((file, FormatDetails::Elf)),
+        ObjectType::Bin => {
+            let load_address = args.load_address;
+            return Ok((file, FormatDetails::Bin { load_address }));
+        }
+        ObjectType::IntelHex => formats::from_intel_hex(&file),
+        ObjectType::Srec => formats::from_srecord(&file),
+        ObjectType::Uimage => formats::from_uimage(&file),
+    };
+    let program = program.wrap_err_with(|| eyre!("failed to read {}", args.file.display()))?;
+    Ok((program.image, program.format_details))
+}
+
+/// Pass the program's output to `output` until `until` says it's done, and report how it went.
+fn run(
+    console: &mut Console,
+    until: &RunUntil,
+    output: impl FnMut(&[u8]) -> io::Result<()>,
+) -> eyre::Result<Outcome> {
+    let outcome = run::run_until(console, until, output)?;
+    match &outcome {
+        Outcome::Exited(code) => tracing::info!("program exited with code {code}"),
+        Outcome::Passed(line) => tracing::info!("program passed: {line}"),
+        Outcome::Failed(line) => tracing::error!("program failed: {line}"),
+        Outcome::TimedOut => tracing::error!("program timed out"),
+    }
+    // a program that exits goes back to okboot by itself; any other may still be running
+    if !matches!(outcome, Outcome::Exited(_)) {
+        if let Err(e) = console.reboot() {
+            tracing::warn!("failed to reboot the device: {e}");
+        }
+    }
+    Ok(outcome)
+}
+
+/// Wait for GDB to connect to `address`, then let it debug the program until the program exits or
+/// GDB goes away, passing whatever else the program prints to `output`. Returns the status to exit
+/// with: the program's exit code, or 0 if it's still running.
+fn debug(
+    console: &mut Console,
+    address: &str,
+    output: impl FnMut(&[u8]) -> io::Result<()>,
+) -> eyre::Result<i32> {
+    // like gdbserver's `:PORT`, but only for GDB on this machine
+    let address = match address.strip_prefix(':') {
+        Some(port) => format!("localhost:{port}"),
+        None => address.to_string(),
+    };
+    let listener = TcpListener::bind(&address)
+        .wrap_err_with(|| eyre!("failed to listen for GDB on {address}"))?;
+    tracing::info!(
+        "waiting for GDB; connect with `target remote {}`",
+        listener.local_addr()?
+    );
+    let (stream, peer) = listener.accept()?;
+    tracing::info!("GDB connected from {peer}");
+    match gdb::debug(console, stream, output)? {
+        Some(code) => {
+            tracing::info!("program exited with code {code}");
+            Ok(code)
+        }
+        None => {
+            tracing::info!("GDB went away; the program runs on");
+            Ok(0)
+        }
+    }
+}
+
+/// Starts each complete line of a board's output with its name, so that several boards' output
+/// can be told apart.
+struct Labeled<'a> {
+    name: &'a str,
+    /// The line so far, until it's complete.
+    line: Vec<u8>,
+}
+impl<'a> Labeled<'a> {
+    fn new(name: &'a str) -> Self {
+        Self { name, line: vec![] }
+    }
+
+    /// The lines that `text` completes, labeled.
+    fn lines(&mut self, text: &[u8]) -> Vec<u8> {
+        let mut lines = vec![];
+        for &byte in text {
+            self.line.push(byte);
+            if byte == b'\n' {
+                lines.extend_from_slice(format!("[{}] ", self.name).as_bytes());
+                lines.append(&mut self.line);
+            }
+        }
+        lines
+    }
+
+    /// The incomplete line at the end of the output, if there is one, labeled and completed.
+    fn rest(&mut self) -> Vec<u8> {
+        if self.line.is_empty() {
+            return vec![];
+        }
+        self.lines(b"\n")
+    }
+}
+
+/// Draws a progress bar for each attempt at uploading the program, labeled if there's more than
+/// one device.
+fn progress_bar(bars: MultiProgress, label: Option<String>) -> impl FnMut(Progress) + Send {
+    let mut pb: Option<ProgressBar> = None;
+    move |progress| {
+        if pb.as_ref().is_some_and(|pb| progress.sent < pb.position()) {
+            // the upload started over
+            pb = None;
+        }
+        let pb = pb.get_or_insert_with(|| {
+            let pb = bars.add(ProgressBar::new(progress.total));
+            let template = if label.is_some() {
+                "{prefix:12} [{elapsed_precise}] {bar:48.cyan/blue} [{bytes:}/{total_bytes}] \
+                 {bytes_per_sec}"
+            } else {
+                "[{elapsed_precise}] {bar:60.cyan/blue} [{bytes:}/{total_bytes}] {bytes_per_sec}"
+            };
+            pb.set_style(
+                ProgressStyle::with_template(template).expect("progress bar template is valid"),
+            );
+            if let Some(label) = &label {
+                pb.set_prefix(label.clone());
+            }
+            pb
+        });
+        pb.set_position(progress.sent);
+    }
+}
+
+struct Args {
+    /// Devices to upload to; with more than one, okdude doesn't connect to the console.
+    targets: Vec<Target>,
     quiet: bool,
     file: PathBuf,
-    format_details: FormatDetails,
-    args: Vec<String>,
+    object_type: ObjectType,
+    /// Where to load BIN files; the other formats say where they go.
+    load_address: u64,
+    /// What to put in the program's sections, from --manifest and the flags after it.
+    injections: Injections,
+    baud_rates: Vec<u32>,
+    sign_key: Option<PathBuf>,
+    compression: CompressionArg,
+    /// When to stop running the program, if it's run as a test rather than interactively.
+    run_until: Option<RunUntil>,
+    log_file: Option<PathBuf>,
+    /// Upload the program again whenever it changes.
+    watch: bool,
+    /// What to watch for changes with `watch`: FILE, unless told otherwise.
+    watch_paths: Vec<PathBuf>,
+    /// Shell command to run before each upload.
+    build: Option<String>,
+    /// Where to wait for GDB, to debug the program rather than connect to its console.
+    gdb: Option<String>,
+    /// Where on the SD card to keep the program, if anywhere.
+    persist: Option<Slot>,
+}
+
+/// A device to upload to.
+struct Target {
+    /// What to call it: the name of the board, or else the device.
+    name: String,
+    device: PathBuf,
+    /// Where the last program uploaded to the device is kept, for delta uploads; `None` if they're
+    /// disabled.
+    image_cache: Option<PathBuf>,
 }
 
 fn parse_args() -> Args {
     let args = CmdArgs::parse();
+    match &args.command {
+        Some(Command::Serve { listen, device }) => serve(listen, device.clone()),
+        Some(Command::Mem { .. } | Command::Images { .. }) | None => {}
+    }
 
-    let device = args.device.unwrap_or_else(|| {
-        tracing::warn!("no device specified, searching for suitable TTY");
-        if let Some(most_recent_device) = find_most_recent_tty() {
-            tracing::info!("using device {}", most_recent_device.display());
-            most_recent_device
-        } else {
-            tracing::error!("failed to find suitable TTY device");
-            tracing::error!(
-                "expected device in /dev like one of: {}",
-                PATTERNS
-                    .iter()
-                    .map(|p| p.to_string() + "*")
-                    .intersperse(", ".to_string())
-                    .collect::<String>()
-            );
+    let boards = args
+        .boards
+        .clone()
+        .or_else(inventory::default_path)
+        .map(|path| Boards::load(&path))
+        .transpose()
+        .unwrap_or_else(|e| {
+            tracing::error!("failed to read boards: {e}");
             std::process::exit(1);
-        }
-    });
+        })
+        .unwrap_or_default();
+    if args.list {
+        list(&boards);
+    }
 
-    let extension_hint = args
+    let devices = if args.all {
+        every_board(&boards)
+    } else if args.device.is_empty() {
+        vec![(most_recent_tty(), None)]
+    } else {
+        find_devices(&args.device, &boards)
+    };
+    if args.info {
+        info(&devices);
+    }
+    if let Some(Command::Mem { command }) = &args.command {
+        let [(device, _)] = &devices[..] else {
+            CmdArgs::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "mem only works with a single device",
+                )
+                .exit();
+        };
+        mem(device, command);
+    }
+    if let Some(Command::Images { command }) = &args.command {
+        let [(device, _)] = &devices[..] else {
+            CmdArgs::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "images only works with a single device",
+                )
+                .exit();
+        };
+        images(device, command);
+    }
+    let file = args
         .file
+        .expect("FILE is required unless listing devices or showing their info");
+    if devices.len() > 1 && args.watch {
+        CmdArgs::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--watch only works with a single device",
+            )
+            .exit();
+    }
+    if devices.len() > 1 && args.gdb.is_some() {
+        CmdArgs::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--gdb only works with a single device",
+            )
+            .exit();
+    }
+    let cache_dir = if args.no_delta {
+        None
+    } else {
+        args.cache_dir.or_else(cache::default_dir)
+    };
+    let targets = devices
+        .into_iter()
+        .map(|(device, name)| Target {
+            name: name.unwrap_or_else(|| device.display().to_string()),
+            image_cache: cache_dir
+                .as_ref()
+                .map(|dir| cache::image_path(dir, &device)),
+            device,
+        })
+        .collect();
+
+    let extension_hint = file
         .extension()
         .map(OsStr::to_ascii_lowercase)
-        .map(|os_str| {
-            if os_str == "bin" {
-                Some(ObjectType::Bin)
-            } else if os_str == "elf" {
-                Some(ObjectType::Elf)
-            } else {
-                None
-            }
+        .and_then(|os_str| match os_str.to_str()? {
+            "bin" => Some(ObjectType::Bin),
+            "elf" => Some(ObjectType::Elf),
+            "hex" | "ihex" | "ihx" => Some(ObjectType::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(ObjectType::Srec),
+            "uimg" | "uimage" => Some(ObjectType::Uimage),
+            _ => None,
         })
-        .flatten();
+        .or_else(|| (file.file_name()? == "uImage").then_some(ObjectType::Uimage));
+    let mut injections = args
+        .manifest
+        .as_deref()
+        .map(Injections::load)
+        .transpose()
+        .unwrap_or_else(|e| {
+            tracing::error!("failed to read manifest: {e}");
+            std::process::exit(1);
+        })
+        .unwrap_or_default();
+    injections.extend(Injections {
+        args: args.arg,
+        env: args.env,
+        build_id: args.build_id,
+        files: args.inject,
+    });
+
     let object_type = args.override_object_type.or(extension_hint);
     let Some(object_type) = object_type else {
         CmdArgs::command()
             .error(
                 clap::error::ErrorKind::ValueValidation,
-                "file must be .elf or .bin, or --override-object-type must be specified",
+                "file must be .elf, .bin, .hex, .srec or a uImage, or --override-object-type \
+                 must be specified",
             )
             .exit();
     };
 
-    let format_details = match object_type {
-        ObjectType::Elf => {
-            if args.load_address.is_some() {
-                CmdArgs::command()
-                    .error(
-                        clap::error::ErrorKind::ArgumentConflict,
-                        "--load-address can only be specified if file is BIN",
-                    )
-                    .exit();
-            }
-            FormatDetails::Elf
-        }
-        ObjectType::Bin => {
-            let load_address = args.load_address.unwrap_or_else(|| {
-                tracing::warn!(
-                    "no load address specified for object of type BIN, using default load address of {:x}",
-                    DEFAULT_LOAD_ADDRESS
-                );
+    if object_type != ObjectType::Bin && args.load_address.is_some() {
+        CmdArgs::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--load-address can only be specified if file is BIN",
+            )
+            .exit();
+    }
+    if object_type != ObjectType::Elf && !injections.is_empty() {
+        CmdArgs::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--arg, --env, --build-id, --inject and --manifest can only be specified \
+                 if file type is ELF",
+            )
+            .exit();
+    }
+    let load_address = args.load_address.unwrap_or_else(|| {
+        if object_type == ObjectType::Bin {
+            tracing::warn!(
+                "no load address specified for object of type BIN, using default load address of {:x}",
                 DEFAULT_LOAD_ADDRESS
-            });
-            if !args.arg.is_empty() {
-                CmdArgs::command()
-                    .error(
-                        clap::error::ErrorKind::ArgumentConflict,
-                        "--arg can only be specified if file type is ELF",
-                    )
-                    .exit();
-            }
-            FormatDetails::Bin { load_address }
+            );
         }
+        DEFAULT_LOAD_ADDRESS
+    });
+
+    let baud_rates = args.baud;
+    if baud_rates.contains(&0) {
+        CmdArgs::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--baud rates must be nonzero",
+            )
+            .exit();
+    }
+
+    let watch_paths = if args.watch_path.is_empty() {
+        if args.watch && args.build.is_some() {
+            tracing::warn!(
+                "with --watch, --build only runs once FILE has changed; give FILE's sources to \
+                 --watch-path to rebuild it whenever they change"
+            );
+        }
+        vec![file.clone()]
+    } else {
+        args.watch_path
     };
 
     Args {
-        device,
+        targets,
         quiet: args.quiet,
-        file: args.file,
-        format_details,
-        args: args.arg,
+        file,
+        object_type,
+        load_address,
+        injections,
+        baud_rates,
+        sign_key: args.sign_key,
+        compression: args.compression,
+        run_until: args.run_until.then(|| RunUntil {
+            pass: args.pass,
+            fail: args.fail,
+            timeout: args.timeout.map(Duration::from_secs),
+        }),
+        log_file: args.log_file,
+        watch: args.watch,
+        watch_paths,
+        build: args.build,
+        gdb: args.gdb,
+        persist: args.persist,
     }
 }
 
-static PATTERNS: [&str; 6] = [
-    "ttyUSB",
-    "ttyACM",
-    "tty.usbserial",
-    "cu.usbserial",
-    "tty,SLAB_USB",
-    "cu.SLAB_USB",
-];
-fn find_most_recent_tty() -> Option<PathBuf> {
-    let Some(dev) = std::fs::read_dir("/dev").ok() else {
-        tracing::error!("Failed to open /dev");
-        return None;
-    };
-    dev.filter_map(|entry| -> Option<(DirEntry, std::fs::Metadata)> {
-        entry.ok().and_then(|e| {
-            let metadata = e.metadata().ok()?;
+/// Serve the serial port at `device`, or else the most recently attached one, to okdudes on other
+/// machines that connect to `listen`, until interrupted.
+fn serve(listen: &str, device: Option<PathBuf>) -> ! {
+    let device = device.unwrap_or_else(most_recent_tty);
+    let listener = TcpListener::bind(listen).unwrap_or_else(|e| {
+        tracing::error!("failed to listen on {listen}: {e}");
+        std::process::exit(1);
+    });
+    match listener.local_addr() {
+        Ok(address) => println!("serving {} on {address}", device.display()),
+        Err(e) => tracing::warn!("failed to get the address being listened on: {e}"),
+    }
+    if let Err(e) = remote::serve(&listener, &device) {
+        tracing::error!("failed to serve {}: {e}", device.display());
+    }
+    std::process::exit(1);
+}
+
+/// Print the devices attached to this machine and which boards they are, then exit.
+fn list(boards: &Boards) -> ! {
+    let found = scan();
+    for found in &found {
+        let device = found.device.display();
+        match found.serial() {
+            Some(serial) => println!(
+                "{device}\t{serial:016x}\t{}",
+                boards.name_of(serial).unwrap_or("-")
+            ),
+            None => println!("{device}\t-\t(not running okboot)"),
+        }
+    }
+    for board in boards.iter() {
+        if !found.iter().any(|f| f.serial() == Some(board.serial)) {
+            println!("(not found)\t{:016x}\t{}", board.serial, board.name);
+        }
+    }
+    std::process::exit(0);
+}
+
+/// Print what each of the `devices` says about itself, then exit.
+fn info(devices: &[(PathBuf, Option<String>)]) -> ! {
+    let mut status = 0;
+    for (device, name) in devices {
+        let label = match name {
+            Some(name) => format!("{} ({name})", device.display()),
+            None => device.display().to_string(),
+        };
+        match inventory::identify_device(device) {
+            Ok(Some(info)) => println!("{label}\n{info}\n"),
+            Ok(None) => {
+                tracing::error!("{label} didn't answer; is it running okboot?");
+                status = 1;
+            }
+            Err(e) => {
+                tracing::error!("failed to identify {label}: {e}");
+                status = 1;
+            }
+        }
+    }
+    std::process::exit(status);
+}
 
-            let ft = e.file_type().ok()?;
-            if !ft.is_char_device() {
-                return None;
+/// Carry out an `okdude mem` command on `device`, then exit.
+fn mem(device: &Path, command: &MemCommand) -> ! {
+    let result = Monitor::connect(device).and_then(|mut monitor| match *command {
+        MemCommand::Read { address, len } => {
+            let bytes = monitor.read(address, len)?;
+            print!("{}", monitor::format_words(address, &bytes));
+            Ok(())
+        }
+        MemCommand::Write {
+            address,
+            ref words,
+            ref file,
+        } => {
+            let bytes = match file {
+                Some(file) => std::fs::read(file)?,
+                None => words.iter().flat_map(|word| word.to_le_bytes()).collect(),
             };
+            monitor.write(address, &bytes)
+        }
+        MemCommand::Dump {
+            address,
+            len,
+            ref out,
+        } => {
+            let bytes = monitor.read(address, len)?;
+            Ok(std::fs::write(out, bytes)?)
+        }
+        MemCommand::Crc { address, len } => {
+            println!("{:08x}", monitor.crc(address, len)?);
+            Ok(())
+        }
+        MemCommand::Jump { address } => {
+            monitor.jump(address)?;
+            tracing::info!("jumped to {address:#x}");
+            Ok(())
+        }
+    });
+    if let Err(e) = result {
+        tracing::error!("{e}");
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
 
-            let path_buf = e.path();
-            let file_name = path_buf.file_name()?;
-            if !PATTERNS.iter().any(|pattern| {
-                std::str::from_utf8(file_name.as_bytes())
-                    .map(|f| f.starts_with(pattern))
-                    .unwrap_or(false)
-            }) {
-                return None;
+/// Carry out an `okdude images` command on `device`, then exit.
+fn images(device: &Path, command: &ImagesCommand) -> ! {
+    let result = Images::connect(device).and_then(|mut images| match *command {
+        ImagesCommand::List => {
+            for image in images.list()? {
+                println!("{}", persist::describe(&image));
+            }
+            Ok(())
+        }
+        ImagesCommand::Delete { slot } => {
+            if !images.delete(slot)? {
+                tracing::warn!("there was no program in slot {slot}");
+            }
+            Ok(())
+        }
+    });
+    if let Err(e) = result {
+        tracing::error!("{e}");
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+/// Identify whatever is behind each of the serial ports that could have a device behind it.
+fn scan() -> Vec<Found> {
+    inventory::scan().unwrap_or_else(|e| {
+        tracing::error!("failed to search /dev: {e}");
+        std::process::exit(1);
+    })
+}
+
+/// Every device that says which board it is, along with that board's name, if it has one.
+fn every_board(boards: &Boards) -> Vec<(PathBuf, Option<String>)> {
+    let devices: Vec<_> = scan()
+        .into_iter()
+        .filter_map(|found| {
+            let name = boards.name_of(found.serial()?).map(str::to_string);
+            Some((found.device, name))
+        })
+        .collect();
+    if devices.is_empty() {
+        tracing::error!("failed to find any devices running okboot");
+        std::process::exit(1);
+    }
+    devices
+}
+
+/// Devices for `--device`s, which can be paths to the devices themselves, or the names or serial
+/// numbers of boards, which have to be searched for.
+fn find_devices(specs: &[String], boards: &Boards) -> Vec<(PathBuf, Option<String>)> {
+    let mut found = None;
+    specs
+        .iter()
+        .map(|spec| {
+            if Path::new(spec).exists() || remote::address(Path::new(spec)).is_some() {
+                return (PathBuf::from(spec), None);
+            }
+            let Some(serial) = boards.serial_of(spec) else {
+                tracing::error!("{spec} is neither a device nor the name of a board");
+                std::process::exit(1);
+            };
+            let found = found.get_or_insert_with(scan);
+            let Some(Found { device, .. }) = found.iter().find(|f| f.serial() == Some(serial))
+            else {
+                tracing::error!("failed to find {spec} ({serial:016x}); is it running okboot?");
+                std::process::exit(1);
             };
-            Some((e, metadata))
+            tracing::info!("found {spec} at {}", device.display());
+            (device.clone(), Some(spec.clone()))
         })
-    })
-    .max_by_key(|(_, m)| m.modified().expect("Metadata::modified() not available"))
-    .map(|(e, _)| e.path().to_path_buf())
+        .collect()
+}
+
+/// The most recently attached device, for when none was specified.
+fn most_recent_tty() -> PathBuf {
+    tracing::warn!("no device specified, searching for suitable TTY");
+    let candidates = inventory::candidates().unwrap_or_else(|e| {
+        tracing::error!("failed to search /dev: {e}");
+        vec![]
+    });
+    if let Some(most_recent_device) = candidates.into_iter().next() {
+        tracing::info!("using device {}", most_recent_device.display());
+        return most_recent_device;
+    }
+    tracing::error!("failed to find suitable TTY device");
+    tracing::error!(
+        "expected device in /dev like one of: {}",
+        inventory::PATTERNS
+            .iter()
+            .map(|p| p.to_string() + "*")
+            .intersperse(", ".to_string())
+            .collect::<String>()
+    );
+    std::process::exit(1);
 }
 
 #[derive(clap::ValueEnum, Debug, Copy, Clone, Eq, PartialEq, Default)]
@@ -172,32 +757,241 @@ pub enum ObjectType {
     #[default]
     Elf,
     Bin,
+    /// Intel HEX
+    IntelHex,
+    /// Motorola S-records
+    Srec,
+    /// U-Boot uImage
+    Uimage,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Serve a serial port over TCP, so that okdude on other machines can upload to the device
+    /// behind it with `--device tcp://HOST:PORT`. Anyone who can connect can use the device
+    Serve {
+        /// Address to listen on
+        #[arg(long, value_name = "ADDRESS", default_value_t = format!("0.0.0.0:{}", remote::DEFAULT_PORT))]
+        listen: String,
+        /// Serial port to serve; will try to autodetect if not specified
+        device: Option<PathBuf>,
+    },
+    /// Read and write the memory and peripherals' registers of a device that's waiting for a
+    /// program, or jump into it; --device says which. Whatever okboot needs for itself is off
+    /// limits
+    Mem {
+        #[command(subcommand)]
+        command: MemCommand,
+    },
+    /// List and delete the programs that okboot keeps on the SD card of a device that's waiting
+    /// for a program (see --persist); --device says which
+    Images {
+        #[command(subcommand)]
+        command: ImagesCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ImagesCommand {
+    /// Print each kept program's slot, length, CRC-32 and format
+    List,
+    /// Delete the program kept in SLOT
+    Delete { slot: Slot },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum MemCommand {
+    /// Print the LEN bytes at ADDRESS, four words to a line
+    Read {
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        address: u32,
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        len: u32,
+    },
+    /// Write WORDs, or the contents of --file, to ADDRESS, and check that they read back
+    Write {
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        address: u32,
+        /// 32-bit words, written little-endian
+        #[arg(value_parser = clap_num::maybe_hex::<u32>, required_unless_present = "file")]
+        words: Vec<u32>,
+        #[arg(long, value_name = "FILE", conflicts_with = "words")]
+        file: Option<PathBuf>,
+    },
+    /// Save the LEN bytes at ADDRESS to a file
+    Dump {
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        address: u32,
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        len: u32,
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+    },
+    /// Print the CRC-32 of the LEN bytes at ADDRESS, as the device works it out
+    Crc {
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        address: u32,
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        len: u32,
+    },
+    /// Have okboot jump to ADDRESS, as if it had booted a program there
+    Jump {
+        #[arg(value_parser = clap_num::maybe_hex::<u32>)]
+        address: u32,
+    },
 }
 
 #[derive(clap::Parser, Debug, Clone)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct CmdArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /* General settings
      */
-    /// USB device to write to; will try to autodetect if not specified
-    #[arg(short, long)]
-    pub(crate) device: Option<PathBuf>,
+    /// Device to write to: a serial port, one served by `okdude serve` as tcp://HOST:PORT, or the
+    /// name (see --boards) or serial number of a board; will try to autodetect if not specified. With more than one, the program is uploaded to
+    /// all of them at once, and run on each with --run-until
+    #[arg(short, long, value_delimiter = ',')]
+    pub(crate) device: Vec<String>,
+
+    /// Upload to every board that's running okboot
+    #[arg(long, conflicts_with = "device")]
+    pub all: bool,
+
+    /// File naming boards by serial number, one per line; defaults to
+    /// `$XDG_CONFIG_HOME/okdude/boards`
+    #[arg(long, value_name = "FILE")]
+    pub boards: Option<PathBuf>,
+
+    /// List the devices attached to this machine and which boards they are, then exit
+    #[arg(long)]
+    pub list: bool,
+
+    /// Print what the device says about itself (which board it is, which okboot it's running and
+    /// what it can load), then exit
+    #[arg(long, conflicts_with = "list")]
+    pub info: bool,
 
     /// Silence all output
     #[arg(short, long)]
     pub quiet: bool,
 
+    /// Comma-separated baud rates to propose to the device; the fastest one the device accepts
+    /// (and that works) is used for the upload
+    #[arg(short, long, value_delimiter = ',', default_values_t = DEFAULT_BAUD_RATES)]
+    pub baud: Vec<u32>,
+
+    /// Treat FILE as this type, whatever it's called
     #[arg(long)]
     pub override_object_type: Option<ObjectType>,
 
+    /// Ed25519 secret key (32 raw bytes or 64 hex digits) to sign the program with; required by
+    /// devices built with `OKBOOT_VERIFYING_KEY`
+    #[arg(long)]
+    pub sign_key: Option<PathBuf>,
+
+    /// Directory in which to keep the last program uploaded to each device, so that only what
+    /// changed has to be uploaded next time; defaults to `$XDG_CACHE_HOME/okdude`
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Always upload the whole program, rather than a delta against the previous one
+    #[arg(long)]
+    pub no_delta: bool,
+
+    /// How to compress the program: auto (whatever gets it onto the device soonest at the
+    /// negotiated baud rate), smallest, none, deflate[:LEVEL], lz4 or heatshrink[:WINDOW,LOOKAHEAD]
+    #[arg(long, default_value_t = CompressionArg::Auto)]
+    pub compression: CompressionArg,
+
+    /// Append everything the program prints to this file, each line stamped with the seconds since
+    /// the session started
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Reset the device and upload the program again whenever FILE, or what --watch-path names,
+    /// changes, without leaving the console
+    #[arg(long, conflicts_with = "run_until")]
+    pub watch: bool,
+
+    /// File or directory to watch with --watch instead of FILE, e.g. the sources that --build
+    /// builds FILE from; directories are watched with everything in them that isn't hidden. May
+    /// be given more than once
+    #[arg(long, value_name = "PATH", requires = "watch")]
+    pub watch_path: Vec<PathBuf>,
+
+    /// Shell command to run before each upload, e.g. to build FILE; with --watch, give its sources
+    /// to --watch-path so that changing them runs it
+    #[arg(long, value_name = "COMMAND")]
+    pub build: Option<String>,
+
+    /// Once the program is uploaded, wait for GDB to connect to ADDRESS (`:PORT` only takes
+    /// connections from this machine) and let it debug the program, which has to run a GDB agent,
+    /// as bismuth's `--arg gdb` does; exits with the program's exit code
+    #[arg(long, value_name = "ADDRESS", conflicts_with_all = ["run_until", "watch"])]
+    pub gdb: Option<String>,
+
+    /// Have okboot keep the program on the device's SD card, in SLOT (`--persist=SLOT`, or
+    /// DEFAULT), so that the device comes back with it after a power cycle; `okdude images` lists
+    /// and deletes what's kept
+    #[arg(
+        long,
+        value_name = "SLOT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "DEFAULT"
+    )]
+    pub persist: Option<Slot>,
+
+    /* Running programs as tests
+     */
+    /// Don't echo the console; exit once the program sends an exit code or prints a line matching
+    /// --pass or --fail, with the program's exit code, 0 or 1 respectively
+    #[arg(long)]
+    pub run_until: bool,
+
+    /// With --run-until, pass once the program prints a line containing TEXT
+    #[arg(long, value_name = "TEXT", requires = "run_until")]
+    pub pass: Vec<String>,
+
+    /// With --run-until, fail once the program prints a line containing TEXT
+    #[arg(long, value_name = "TEXT", requires = "run_until")]
+    pub fail: Vec<String>,
+
+    /// With --run-until, give up and exit with 124 if the program is still running after this
+    /// many seconds
+    #[arg(long, value_name = "SECONDS", requires = "run_until")]
+    pub timeout: Option<u64>,
+
     /* BEGIN FILE TYPE: .bin
      */
     #[arg(short, long, value_parser = clap_num::maybe_hex::<u64>)]
     pub load_address: Option<u64>,
 
-    #[arg(required = true)]
-    pub file: PathBuf,
+    #[arg(required_unless_present_any = ["list", "info"])]
+    pub file: Option<PathBuf>,
 
+    /* Injected into ELF files; see `okdude::inject`
+     */
+    /// Argument to pass to the program, in its `.data.args` section
     #[arg(short, long, action = clap::ArgAction::Append, default_values_t = Vec::<String>::new())]
     pub arg: Vec<String>,
+
+    /// Environment variable to pass to the program, in its `.data.env` section
+    #[arg(long, value_name = "KEY=VALUE", value_parser = inject::parse_var)]
+    pub env: Vec<(String, String)>,
+
+    /// Build ID to put in the program's `.data.build_id` section, instead of its GNU build ID
+    #[arg(long, value_name = "ID")]
+    pub build_id: Option<String>,
+
+    /// Fill the program's SECTION with the contents of FILE
+    #[arg(long, value_name = "SECTION=FILE", value_parser = inject::parse_file)]
+    pub inject: Vec<(String, PathBuf)>,
+
+    /// File listing what to inject into the program, one item per line (`arg`, `env`,
+    /// `build-id` or `file`, then its value); flags are added to what it says
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
 }
@@ -0,0 +1,212 @@
+//! Reading and writing a device's memory while okboot waits for a program, and jumping into it:
+//! okdude's side of [`okboot_common::monitor`], behind `okdude mem`.
+//!
+//! Every request is checked against the [`MemoryMap`] that the device reports before it's sent,
+//! and okboot checks it again before it does anything; ranges longer than [`MAX_CHUNK`] are split
+//! up here.
+use crate::transport::Transport;
+use crate::upload;
+use crate::{inventory, Console, Error, OutputHook};
+use okboot_common::device::{Jumping, MemCrcResult, MemData, MemRefused};
+use okboot_common::host::{Jump, MemCrc, MemRead, MemWrite};
+use okboot_common::monitor::{Access, MemoryMap, Space, MAX_CHUNK};
+use okboot_common::{EncodeMessageType, MessageType, INITIAL_BAUD_RATE};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+/// How long the device may take to answer a request for up to [`MAX_CHUNK`] bytes: long enough
+/// for them to cross the link at the [`INITIAL_BAUD_RATE`], twice over.
+const CHUNK_TIMEOUT: Duration =
+    Duration::from_millis(1000 + 2 * 10_000 * MAX_CHUNK as u64 / INITIAL_BAUD_RATE as u64);
+
+/// How many bytes a second the device is assumed to get through when it works out a CRC, for
+/// [`Monitor::crc`]'s timeout.
+const CRC_RATE: u64 = 16 << 20;
+
+/// A connection to okboot's monitor.
+pub struct Monitor {
+    transport: Box<dyn Transport>,
+    memory_map: MemoryMap,
+    output: OutputHook,
+}
+impl Monitor {
+    /// Connect to the device behind the serial port at `device`, which may be on another machine
+    /// that [serves](crate::remote::serve) it, and ask for its memory map.
+    pub fn connect(device: &Path) -> Result<Self, Error> {
+        Self::with_transport(crate::open(device.to_path_buf())?)
+    }
+
+    /// Like [`connect`](Self::connect), to the device at the other end of `transport`.
+    pub fn with_transport(mut transport: Box<dyn Transport>) -> Result<Self, Error> {
+        let Some(info) = inventory::identify(&mut *transport)? else {
+            return Err(Error::Protocol(
+                "the device didn't answer; is it running okboot?".to_string(),
+            ));
+        };
+        Ok(Self {
+            transport,
+            memory_map: info.memory_map,
+            output: OutputHook::default(),
+        })
+    }
+
+    /// What may be read, written and jumped to.
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    /// Read the `len` bytes at `address`.
+    pub fn read(&mut self, address: u32, len: u32) -> Result<Vec<u8>, Error> {
+        self.memory_map.check(Access::Read, address, len)?;
+        let mut bytes = Vec::with_capacity(len as usize);
+        for (address, len) in chunks(address, len) {
+            let payload = self.request(&MemRead { address, len }, CHUNK_TIMEOUT)?;
+            let data: MemData = decode(&payload)?;
+            if data.address != address || data.bytes.len() != len as usize {
+                return Err(Error::Protocol(format!(
+                    "asked for {len} bytes at {address:#x}, but got {} at {:#x}",
+                    data.bytes.len(),
+                    data.address
+                )));
+            }
+            bytes.extend_from_slice(data.bytes);
+        }
+        Ok(bytes)
+    }
+
+    /// Write `bytes` to `address`, and check that they read back as written. Peripherals'
+    /// registers needn't read back as written, so for them, what does read back is only logged.
+    pub fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), Error> {
+        let space = self
+            .memory_map
+            .check(Access::Write, address, bytes.len() as u32)?;
+        for (chunk_address, len) in chunks(address, bytes.len() as u32) {
+            let offset = (chunk_address - address) as usize;
+            let chunk = &bytes[offset..offset + len as usize];
+            let message = MemWrite {
+                address: chunk_address,
+                bytes: chunk,
+            };
+            let result: MemCrcResult = decode(&self.request(&message, CHUNK_TIMEOUT)?)?;
+            let expected = crc32fast::hash(chunk);
+            if result.crc == expected {
+                continue;
+            }
+            match space {
+                Space::Ram => {
+                    return Err(Error::Protocol(format!(
+                        "{len} bytes written at {chunk_address:#x} read back with CRC {:#010x} \
+                         rather than {expected:#010x}",
+                        result.crc
+                    )))
+                }
+                Space::Peripherals => tracing::info!(
+                    "registers at {chunk_address:#x} read back differently from what was written"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// The CRC-32 of the `len` bytes at `address`, as the device works it out.
+    pub fn crc(&mut self, address: u32, len: u32) -> Result<u32, Error> {
+        self.memory_map.check(Access::Read, address, len)?;
+        let timeout = CHUNK_TIMEOUT + Duration::from_millis(1000 * len as u64 / CRC_RATE);
+        let result: MemCrcResult = decode(&self.request(&MemCrc { address, len }, timeout)?)?;
+        Ok(result.crc)
+    }
+
+    /// Have okboot jump to `address`, and return the device's console, which now belongs to
+    /// whatever was there.
+    pub fn jump(mut self, address: u32) -> Result<Console, Error> {
+        self.memory_map.check(Access::Jump, address, 0)?;
+        let _: Jumping = decode(&self.request(&Jump { address }, CHUNK_TIMEOUT)?)?;
+        Ok(Console {
+            transport: self.transport,
+        })
+    }
+
+    /// Send `message` and wait for the answer, returning its payload; a [`MemRefused`] is turned
+    /// into an error, and anything else is skipped.
+    fn request<M: Request>(&mut self, message: &M, timeout: Duration) -> Result<Vec<u8>, Error> {
+        upload::send(message, &mut *self.transport)?;
+        let deadline = Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let received =
+                upload::recv_with_print_string(&mut *self.transport, &mut self.output, remaining)?;
+            match received {
+                Some((message_type, payload)) if message_type == M::ANSWER => return Ok(payload),
+                Some((MessageType::MemRefused, payload)) => {
+                    let MemRefused { refusal } = decode(&payload)?;
+                    return Err(refusal.into());
+                }
+                Some((message_type, _)) => {
+                    tracing::debug!("received {message_type:?} in response to {:?}", M::TYPE);
+                }
+                None => break,
+            }
+        }
+        Err(Error::Protocol(format!(
+            "the device didn't answer {:?}",
+            M::TYPE
+        )))
+    }
+}
+
+/// A message for the monitor, and the type of its answer when it isn't refused.
+trait Request: EncodeMessageType + Serialize + Debug {
+    const ANSWER: MessageType;
+}
+impl Request for MemRead {
+    const ANSWER: MessageType = MessageType::MemData;
+}
+impl Request for MemWrite<'_> {
+    const ANSWER: MessageType = MessageType::MemCrcResult;
+}
+impl Request for MemCrc {
+    const ANSWER: MessageType = MessageType::MemCrcResult;
+}
+impl Request for Jump {
+    const ANSWER: MessageType = MessageType::Jumping;
+}
+
+/// Deserialize an answer from the monitor.
+fn decode<'a, A: Deserialize<'a> + EncodeMessageType>(payload: &'a [u8]) -> Result<A, Error> {
+    postcard::from_bytes(payload)
+        .map_err(|e| Error::Protocol(format!("failed to deserialize {:?}: {e}", A::TYPE)))
+}
+
+/// Split the `len` bytes at `address` into pieces of up to [`MAX_CHUNK`] bytes.
+fn chunks(address: u32, len: u32) -> impl Iterator<Item = (u32, u32)> {
+    (0..len)
+        .step_by(MAX_CHUNK as usize)
+        .map(move |offset| (address + offset, (len - offset).min(MAX_CHUNK)))
+}
+
+/// `bytes`, read from `address`, as lines of up to four little-endian words each, after the
+/// address of the first; any bytes left over after the last whole word are shown on their own.
+pub fn format_words(address: u32, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (line, bytes) in bytes.chunks(16).enumerate() {
+        let _ = write!(text, "{:08x}:", address as usize + 16 * line);
+        let words = bytes.chunks_exact(4);
+        let rest = words.remainder();
+        for word in words {
+            let _ = write!(
+                text,
+                " {:08x}",
+                u32::from_le_bytes(word.try_into().unwrap())
+            );
+        }
+        for byte in rest {
+            let _ = write!(text, " {byte:02x}");
+        }
+        text.push('\n');
+    }
+    text
+}
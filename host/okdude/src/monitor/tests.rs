@@ -0,0 +1,28 @@
+//! Tests of splitting up and showing memory; the monitor itself is tested against the simulated
+//! device along with uploads.
+use super::{chunks, format_words};
+use okboot_common::monitor::MAX_CHUNK;
+
+#[test]
+fn splits_long_ranges() {
+    assert_eq!(
+        chunks(0x8000, 2 * MAX_CHUNK + 3).collect::<Vec<_>>(),
+        [
+            (0x8000, MAX_CHUNK),
+            (0x8000 + MAX_CHUNK, MAX_CHUNK),
+            (0x8000 + 2 * MAX_CHUNK, 3),
+        ]
+    );
+    assert_eq!(chunks(0x8000, 0).count(), 0);
+}
+
+#[test]
+fn formats_words() {
+    let bytes: Vec<u8> = (0..22).collect();
+    assert_eq!(
+        format_words(0x2020_0000, &bytes),
+        "20200000: 03020100 07060504 0b0a0908 0f0e0d0c\n\
+         20200010: 13121110 14 15\n"
+    );
+    assert_eq!(format_words(0x2020_0000, &[]), "");
+}
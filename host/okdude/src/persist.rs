@@ -0,0 +1,135 @@
+//! Programs kept on a device's SD card: okdude's side of [`okboot_common::persist`], behind
+//! `okdude images` and `--persist`.
+//!
+//! Programs are kept by uploading them with [`Uploader::persist`](crate::Uploader::persist);
+//! [`Images`] lists and deletes them while okboot waits for a program.
+use crate::transport::Transport;
+use crate::upload;
+use crate::{inventory, Error, OutputHook};
+use okboot_common::device::{ImageDeleted, ImagesListed, StorageFailed, StoredImage};
+use okboot_common::host::{DeleteImage, ListImages};
+use okboot_common::persist::Slot;
+use okboot_common::{EncodeMessageType, MessageType};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long the device may take to answer, or to send the next image in a listing; okboot reads
+/// a sector or two of the card for each.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to a device with an SD card.
+pub struct Images {
+    transport: Box<dyn Transport>,
+    output: OutputHook,
+}
+impl Images {
+    /// Connect to the device behind the serial port at `device`, which may be on another machine
+    /// that [serves](crate::remote::serve) it, and check that it has a card.
+    pub fn connect(device: &Path) -> Result<Self, Error> {
+        Self::with_transport(crate::open(device.to_path_buf())?)
+    }
+
+    /// Like [`connect`](Self::connect), to the device at the other end of `transport`.
+    pub fn with_transport(mut transport: Box<dyn Transport>) -> Result<Self, Error> {
+        let Some(info) = inventory::identify(&mut *transport)? else {
+            return Err(Error::Protocol(
+                "the device didn't answer; is it running okboot?".to_string(),
+            ));
+        };
+        if info.max_persist_len == 0 {
+            return Err(Error::Unsupported(
+                "the device has no SD card to keep programs on".to_string(),
+            ));
+        }
+        Ok(Self {
+            transport,
+            output: OutputHook::default(),
+        })
+    }
+
+    /// Every program kept on the card.
+    pub fn list(&mut self) -> Result<Vec<StoredImage>, Error> {
+        upload::send(&ListImages {}, &mut *self.transport)?;
+        let mut images = vec![];
+        loop {
+            let answers = [MessageType::StoredImage, MessageType::ImagesListed];
+            let (message_type, payload) = self.recv(ListImages::TYPE, &answers)?;
+            match message_type {
+                MessageType::StoredImage => images.push(decode(&payload)?),
+                _ => {
+                    let ImagesListed { count } = decode(&payload)?;
+                    if count as usize != images.len() {
+                        return Err(Error::Protocol(format!(
+                            "the device listed {} images, but said there were {count}",
+                            images.len()
+                        )));
+                    }
+                    return Ok(images);
+                }
+            }
+        }
+    }
+
+    /// Delete the program kept in `slot`; `false` if there wasn't one.
+    pub fn delete(&mut self, slot: Slot) -> Result<bool, Error> {
+        upload::send(&DeleteImage { slot }, &mut *self.transport)?;
+        let (_, payload) = self.recv(DeleteImage::TYPE, &[MessageType::ImageDeleted])?;
+        let deleted: ImageDeleted = decode(&payload)?;
+        if deleted.slot != slot {
+            return Err(Error::Protocol(format!(
+                "asked to delete slot {slot}, but the device deleted {}",
+                deleted.slot
+            )));
+        }
+        Ok(deleted.existed)
+    }
+
+    /// Wait for the next of the `answers` to the request of type `request`, skipping anything
+    /// else; a [`StorageFailed`] is turned into an error.
+    fn recv(
+        &mut self,
+        request: MessageType,
+        answers: &[MessageType],
+    ) -> Result<(MessageType, Vec<u8>), Error> {
+        let deadline = Instant::now() + TIMEOUT;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let received =
+                upload::recv_with_print_string(&mut *self.transport, &mut self.output, remaining)?;
+            match received {
+                Some((message_type, payload)) if answers.contains(&message_type) => {
+                    return Ok((message_type, payload))
+                }
+                Some((MessageType::StorageFailed, payload)) => {
+                    let StorageFailed { error } = decode(&payload)?;
+                    return Err(error.into());
+                }
+                Some((message_type, _)) => {
+                    tracing::debug!("received {message_type:?} in response to {request:?}");
+                }
+                None => break,
+            }
+        }
+        Err(Error::Protocol(format!(
+            "the device didn't answer {request:?}"
+        )))
+    }
+}
+
+/// Deserialize an answer about the card.
+fn decode<'a, A: Deserialize<'a> + EncodeMessageType>(payload: &'a [u8]) -> Result<A, Error> {
+    postcard::from_bytes(payload)
+        .map_err(|e| Error::Protocol(format!("failed to deserialize {:?}: {e}", A::TYPE)))
+}
+
+/// `image` as a line of `okdude images list`.
+pub fn describe(image: &StoredImage) -> String {
+    format!(
+        "{:<8}  {:>9} bytes  crc {:08x}  {:?}{}",
+        image.slot,
+        image.len,
+        image.crc,
+        image.format_details,
+        if image.signed { "  signed" } else { "" }
+    )
+}
@@ -0,0 +1,446 @@
+//! Devices attached to another machine, which serves their serial port over TCP with
+//! `okdude serve`, and which okdude reaches with `--device tcp://HOST:PORT`.
+//!
+//! The link speaks the subset of telnet's COM-PORT-OPTION ([RFC 2217]) that okdude needs: data
+//! goes both ways as is, apart from `IAC` bytes, which are doubled, and the client changes the baud
+//! rate, purges buffers and drives the control lines with subnegotiations, each of which the
+//! server answers once it's done. Other telnet negotiation is ignored.
+//!
+//! [RFC 2217]: https://www.rfc-editor.org/rfc/rfc2217
+use crate::transport::{ClearBuffer, Transport};
+use crate::tty::{self, Tty};
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+use okboot_common::INITIAL_BAUD_RATE;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+/// What `--device` starts with for devices served by `okdude serve`.
+pub const SCHEME: &str = "tcp://";
+
+/// Port that `okdude serve` listens on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 2217;
+
+/// How long to wait for the server to answer a command; changing the baud rate waits for
+/// everything written before it to be sent, which takes a while at slow baud rates.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to keep trying to connect to the server again when reconnecting.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const IAC: u8 = 255;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const WILL: u8 = 251;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const COM_PORT_OPTION: u8 = 44;
+
+/// Commands from the client; the server answers each with its code plus [`ANSWER`] and the same
+/// value.
+const SET_BAUDRATE: u8 = 1;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+const ANSWER: u8 = 100;
+
+/// Values of [`SET_CONTROL`].
+const DTR_ON: u8 = 8;
+const DTR_OFF: u8 = 9;
+const RTS_ON: u8 = 11;
+const RTS_OFF: u8 = 12;
+
+/// Values of [`PURGE_DATA`]: what the server received from the device, what it's yet to send it,
+/// or both.
+const PURGE_RECEIVED: u8 = 1;
+const PURGE_UNSENT: u8 = 2;
+const PURGE_BOTH: u8 = 3;
+
+/// The `HOST:PORT` that `device` names, if it's a device served by `okdude serve`.
+pub fn address(device: &Path) -> Option<&str> {
+    device.to_str()?.strip_prefix(SCHEME)
+}
+
+/// Append `bytes` to `out` as data.
+fn escape(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == IAC {
+            out.push(IAC);
+        }
+    }
+}
+
+/// The COM-PORT-OPTION subnegotiation for `code` with `value`.
+fn command(code: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![IAC, SB, COM_PORT_OPTION, code];
+    escape(value, &mut out);
+    out.extend_from_slice(&[IAC, SE]);
+    out
+}
+
+/// Something that came over the link.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Item {
+    Data(u8),
+    /// A COM-PORT-OPTION subnegotiation.
+    Command {
+        code: u8,
+        value: Vec<u8>,
+    },
+}
+
+/// Picks data and commands out of the bytes that come over the link.
+#[derive(Debug, Default)]
+struct Decoder {
+    state: State,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Data,
+    /// After an `IAC`.
+    Iac,
+    /// After `IAC WILL` and the like, which are followed by an option.
+    Negotiation,
+    /// In a subnegotiation, after an `IAC` if `iac`.
+    Subnegotiation { bytes: Vec<u8>, iac: bool },
+}
+
+impl Decoder {
+    fn feed(&mut self, byte: u8) -> Option<Item> {
+        match (&mut self.state, byte) {
+            (State::Data, IAC) => self.state = State::Iac,
+            (State::Data, byte) => return Some(Item::Data(byte)),
+            (State::Iac, IAC) => {
+                self.state = State::Data;
+                return Some(Item::Data(IAC));
+            }
+            (State::Iac, SB) => {
+                self.state = State::Subnegotiation {
+                    bytes: Vec::new(),
+                    iac: false,
+                }
+            }
+            (State::Iac, WILL..=DONT) => self.state = State::Negotiation,
+            // anything else is a telnet command of no interest
+            (State::Iac | State::Negotiation, _) => self.state = State::Data,
+            (
+                State::Subnegotiation {
+                    iac: iac @ false, ..
+                },
+                IAC,
+            ) => *iac = true,
+            (State::Subnegotiation { bytes, iac }, byte) if !*iac || byte == IAC => {
+                bytes.push(byte);
+                *iac = false;
+            }
+            (State::Subnegotiation { bytes, .. }, byte) => {
+                let bytes = std::mem::take(bytes);
+                self.state = State::Data;
+                if let (SE, [COM_PORT_OPTION, code, value @ ..]) = (byte, &bytes[..]) {
+                    return Some(Item::Command {
+                        code: *code,
+                        value: value.to_vec(),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The client's end of the link to a device served by `okdude serve`.
+pub struct Remote {
+    address: String,
+    stream: TcpStream,
+    decoder: Decoder,
+    /// Data that has arrived but not been read yet.
+    received: VecDeque<u8>,
+    baud_rate: u32,
+    timeout: Duration,
+}
+
+impl Remote {
+    /// Connect to the device served at `address`; reads time out after `timeout`.
+    pub fn connect(address: &str, timeout: Duration) -> io::Result<Self> {
+        let mut remote = Self {
+            address: address.to_string(),
+            stream: Self::open(address)?,
+            decoder: Decoder::default(),
+            received: VecDeque::new(),
+            baud_rate: INITIAL_BAUD_RATE,
+            timeout,
+        };
+        remote.stream.write_all(&[IAC, WILL, COM_PORT_OPTION])?;
+        Ok(remote)
+    }
+
+    fn open(address: &str) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    /// Read what has come over the link, waiting until `deadline` for something to, and return
+    /// the commands in it.
+    fn receive(&mut self, deadline: Instant) -> io::Result<Vec<(u8, Vec<u8>)>> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut buf = [0; 0x1000];
+        let n = match self.stream.read(&mut buf) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::BrokenPipe, "server hung up")),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(ErrorKind::TimedOut.into()),
+            Err(e) => return Err(e),
+        };
+        Ok(self.decode(&buf[..n]))
+    }
+
+    /// Keep the data in `bytes`, which came over the link, and return the commands in them.
+    fn decode(&mut self, bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut commands = vec![];
+        for &byte in bytes {
+            match self.decoder.feed(byte) {
+                Some(Item::Data(byte)) => self.received.push_back(byte),
+                Some(Item::Command { code, value }) => commands.push((code, value)),
+                None => {}
+            }
+        }
+        commands
+    }
+
+    /// Have the server carry out `code` with `value`, and wait until it has.
+    fn command(&mut self, code: u8, value: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&command(code, value))?;
+        let deadline = Instant::now() + ANSWER_TIMEOUT;
+        loop {
+            let commands = self.receive(deadline).map_err(|e| match e.kind() {
+                ErrorKind::TimedOut => io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{} didn't answer command {code}", self.address),
+                ),
+                _ => e,
+            })?;
+            if commands.iter().any(|(answer, _)| *answer == code + ANSWER) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Set or clear both DTR and RTS.
+    fn set_lines(&mut self, asserted: bool) -> io::Result<()> {
+        let (dtr, rts) = match asserted {
+            true => (DTR_ON, RTS_ON),
+            false => (DTR_OFF, RTS_OFF),
+        };
+        self.command(SET_CONTROL, &[dtr])?;
+        self.command(SET_CONTROL, &[rts])
+    }
+}
+
+impl Read for Remote {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = Instant::now() + self.timeout;
+        while self.received.is_empty() {
+            self.receive(deadline)?;
+        }
+        let n = buf.len().min(self.received.len());
+        for (slot, byte) in buf.iter_mut().zip(self.received.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Remote {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len());
+        escape(buf, &mut out);
+        self.stream.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for Remote {
+    fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn bytes_to_read(&mut self) -> io::Result<usize> {
+        if !self.received.is_empty() {
+            return Ok(self.received.len());
+        }
+        // what has arrived may be nothing but negotiation and answers, which a read would wait
+        // past for data, so it's decoded here
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 0x1000];
+        let read = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(0) => return Err(io::Error::new(ErrorKind::BrokenPipe, "server hung up")),
+            Ok(n) => {
+                self.decode(&buf[..n]);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        Ok(self.received.len())
+    }
+
+    fn clear(&mut self, buffer: ClearBuffer) -> io::Result<()> {
+        let purge = match buffer {
+            ClearBuffer::Input => PURGE_RECEIVED,
+            ClearBuffer::Output => PURGE_UNSENT,
+            ClearBuffer::All => PURGE_BOTH,
+        };
+        self.command(PURGE_DATA, &[purge])?;
+        // everything that arrived before the answer was received before the purge
+        if purge != PURGE_UNSENT {
+            self.received.clear();
+        }
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        loop {
+            match Self::connect(&self.address, self.timeout) {
+                Ok(remote) => {
+                    *self = remote;
+                    return Ok(());
+                }
+                Err(e) if start.elapsed() > RECONNECT_TIMEOUT => return Err(e),
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.set_lines(false)?;
+        std::thread::sleep(tty::RESET_PULSE);
+        self.set_lines(true)
+    }
+}
+
+/// Serve the serial port at `device` to whoever connects to `listener`, one client at a time.
+/// The port is opened afresh for each client, at [`INITIAL_BAUD_RATE`], so that a client that
+/// reconnects finds it the way it would a serial port of its own.
+pub fn serve(listener: &TcpListener, device: &Path) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        tracing::info!("{peer} connected");
+        match serve_client(stream, device) {
+            Ok(()) => tracing::info!("{peer} disconnected"),
+            Err(e) => tracing::warn!("lost {peer}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+fn serve_client(mut stream: TcpStream, device: &Path) -> io::Result<()> {
+    let mut tty = Tty::new(device, INITIAL_BAUD_RATE).map_err(io::Error::other)?;
+    stream.set_nodelay(true)?;
+    stream.write_all(&[IAC, DO, COM_PORT_OPTION])?;
+    let mut decoder = Decoder::default();
+    let mut buf = [0; 0x1000];
+    loop {
+        let ready = |fd: &PollFd| {
+            fd.revents()
+                .is_some_and(|r| r.intersects(PollFlags::POLLIN | PollFlags::POLLHUP))
+        };
+        let (from_client, from_device) = {
+            let mut fds = [
+                PollFd::new(stream.as_fd(), PollFlags::POLLIN),
+                PollFd::new(tty.as_fd(), PollFlags::POLLIN),
+            ];
+            nix::poll::poll(&mut fds, PollTimeout::NONE)?;
+            (ready(&fds[0]), ready(&fds[1]))
+        };
+
+        if from_device {
+            let n = match tty.read_nonblocking(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::BrokenPipe, "device went away")),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e),
+            };
+            let mut out = Vec::with_capacity(n);
+            escape(&buf[..n], &mut out);
+            stream.write_all(&out)?;
+        }
+
+        if from_client {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            let mut data = vec![];
+            for &byte in &buf[..n] {
+                match decoder.feed(byte) {
+                    Some(Item::Data(byte)) => data.push(byte),
+                    Some(Item::Command { code, value }) => {
+                        // the command applies to what was written before it
+                        tty.write_all(&std::mem::take(&mut data))?;
+                        carry_out(&mut tty, code, &value)?;
+                        stream.write_all(&command(code + ANSWER, &value))?;
+                    }
+                    None => {}
+                }
+            }
+            tty.write_all(&data)?;
+        }
+    }
+}
+
+/// Carry out the client's command `code` with `value` on `tty`.
+fn carry_out(tty: &mut Tty, code: u8, value: &[u8]) -> io::Result<()> {
+    match (code, value) {
+        (SET_BAUDRATE, &[a, b, c, d]) => {
+            let baud_rate = u32::from_be_bytes([a, b, c, d]);
+            tracing::debug!("switching to {baud_rate} baud");
+            tty.set_baud_rate(baud_rate)
+        }
+        (SET_CONTROL, &[control @ (DTR_ON | DTR_OFF | RTS_ON | RTS_OFF)]) => {
+            let line = match control {
+                DTR_ON | DTR_OFF => libc::TIOCM_DTR,
+                _ => libc::TIOCM_RTS,
+            };
+            // not every serial port has them (pseudoterminals don't), and resetting is best-effort
+            if let Err(e) = tty.set_lines(line, matches!(control, DTR_ON | RTS_ON)) {
+                tracing::warn!("failed to set control lines: {e}");
+            }
+            Ok(())
+        }
+        (PURGE_DATA, &[PURGE_RECEIVED]) => tty.clear(ClearBuffer::Input),
+        (PURGE_DATA, &[PURGE_UNSENT]) => tty.clear(ClearBuffer::Output),
+        (PURGE_DATA, &[PURGE_BOTH]) => tty.clear(ClearBuffer::All),
+        _ => {
+            tracing::warn!("ignoring command {code} with value {value:02x?}");
+            Ok(())
+        }
+    }
+}
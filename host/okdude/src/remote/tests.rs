@@ -0,0 +1,98 @@
+//! Tests of the link between okdude and `okdude serve`.
+use super::{
+    command, escape, Decoder, Item, Remote, ANSWER, COM_PORT_OPTION, DO, IAC, PURGE_DATA,
+    SET_BAUDRATE, WILL,
+};
+use crate::transport::Transport;
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::fd::AsFd;
+use std::sync::mpsc;
+use std::time::Duration;
+
+fn decode(bytes: &[u8]) -> Vec<Item> {
+    let mut decoder = Decoder::default();
+    bytes
+        .iter()
+        .filter_map(|&byte| decoder.feed(byte))
+        .collect()
+}
+
+#[test]
+fn escapes_iac_in_data() {
+    let mut bytes = vec![];
+    escape(&[1, IAC, 2, IAC, IAC], &mut bytes);
+    assert_eq!(bytes, [1, IAC, IAC, 2, IAC, IAC, IAC, IAC]);
+    assert_eq!(
+        decode(&bytes),
+        [1, IAC, 2, IAC, IAC].map(Item::Data).to_vec()
+    );
+}
+
+#[test]
+fn picks_out_commands_and_skips_negotiation() {
+    let mut bytes = vec![IAC, WILL, COM_PORT_OPTION, b'a'];
+    // 0xff in the value has to be escaped too
+    bytes.extend_from_slice(&command(SET_BAUDRATE, &0x00ff_0000u32.to_be_bytes()));
+    bytes.push(b'b');
+    // another option's subnegotiation
+    bytes.extend_from_slice(&[IAC, 250, 24, 0, IAC, 240]);
+    bytes.extend_from_slice(&command(PURGE_DATA, &[1]));
+    assert_eq!(
+        decode(&bytes),
+        [
+            Item::Data(b'a'),
+            Item::Command {
+                code: SET_BAUDRATE,
+                value: vec![0, 0xff, 0, 0],
+            },
+            Item::Data(b'b'),
+            Item::Command {
+                code: PURGE_DATA,
+                value: vec![1],
+            },
+        ]
+    );
+}
+
+/// Wait for `remote`'s server to have sent something.
+fn wait_for_server(remote: &Remote) {
+    let mut fds = [PollFd::new(remote.stream.as_fd(), PollFlags::POLLIN)];
+    let ready = nix::poll::poll(&mut fds, PollTimeout::from(1000u16)).expect("failed to poll");
+    assert_eq!(ready, 1, "server didn't send anything");
+}
+
+#[test]
+fn counts_only_data_as_ready_to_read() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (next_tx, next) = mpsc::channel();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // as `okdude serve` opens, and as it answers a command, with no data at all
+        stream.write_all(&[IAC, DO, COM_PORT_OPTION]).unwrap();
+        next.recv().unwrap();
+        let mut answer = command(PURGE_DATA + ANSWER, &[1]);
+        stream.write_all(&answer).unwrap();
+        next.recv().unwrap();
+        answer.extend_from_slice(&[b'o', IAC, IAC, b'k']);
+        stream.write_all(&answer).unwrap();
+        next.recv().unwrap();
+    });
+
+    let mut remote = Remote::connect(&address, Duration::from_millis(100)).unwrap();
+    wait_for_server(&remote);
+    assert_eq!(remote.bytes_to_read().unwrap(), 0);
+    next_tx.send(()).unwrap();
+    wait_for_server(&remote);
+    assert_eq!(remote.bytes_to_read().unwrap(), 0);
+    next_tx.send(()).unwrap();
+    wait_for_server(&remote);
+    assert_eq!(remote.bytes_to_read().unwrap(), 3);
+    let mut buf = [0; 3];
+    remote.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [b'o', IAC, b'k']);
+    next_tx.send(()).unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,176 @@
+use crate::{Console, Error};
+use okboot_common::device::Exit;
+use okboot_common::frame::{FrameLayer, FrameOutput};
+use okboot_common::{MessageType, COBS_XOR, PREAMBLE_BYTES};
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+/// When [`run_until`] should stop, other than when the program sends an [`Exit`] message.
+#[derive(Debug, Clone, Default)]
+pub struct RunUntil {
+    /// A line of output containing any of these means that the program passed.
+    pub pass: Vec<String>,
+    /// A line of output containing any of these means that the program failed; checked before
+    /// `pass`.
+    pub fail: Vec<String>,
+    /// Give up on the program after this long.
+    pub timeout: Option<Duration>,
+}
+
+/// Why [`run_until`] stopped.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    /// The program sent an [`Exit`] message with this code.
+    Exited(i32),
+    /// This line matched one of the [`pass`](RunUntil::pass) patterns.
+    Passed(String),
+    /// This line matched one of the [`fail`](RunUntil::fail) patterns.
+    Failed(String),
+    TimedOut,
+}
+impl Outcome {
+    /// Status to exit with, as if the program had run on the host: its own exit code, 0 if it
+    /// passed, 1 if it failed, and 124 if it timed out (like `timeout(1)`).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Exited(code) => *code,
+            Outcome::Passed(_) => 0,
+            Outcome::Failed(_) => 1,
+            Outcome::TimedOut => 124,
+        }
+    }
+}
+
+/// Pass whatever the program prints to `output` until it exits, prints a line matching one of
+/// `until`'s patterns, or runs out of time.
+pub fn run_until(
+    console: &mut Console,
+    until: &RunUntil,
+    mut output: impl FnMut(&[u8]) -> std::io::Result<()>,
+) -> Result<Outcome, Error> {
+    let deadline = until.timeout.map(|timeout| Instant::now() + timeout);
+    let mut scanner = Scanner::new(&[MessageType::Exit]);
+    let mut line = vec![];
+    let mut buf = [0; 256];
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(Outcome::TimedOut);
+        }
+        let n = match console.read(&mut buf) {
+            Ok(0) => return Err(Error::LinkLost),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut text = vec![];
+        let exit = buf[..n]
+            .iter()
+            .find_map(|&byte| scanner.feed(byte, &mut text))
+            .and_then(|(_, payload)| Exit::from_payload(&payload));
+        output(&text)?;
+        for &byte in &text {
+            if byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            line.clear();
+            if until
+                .fail
+                .iter()
+                .any(|pattern| text.contains(pattern.as_str()))
+            {
+                return Ok(Outcome::Failed(text));
+            }
+            if until
+                .pass
+                .iter()
+                .any(|pattern| text.contains(pattern.as_str()))
+            {
+                return Ok(Outcome::Passed(text));
+            }
+        }
+        if let Some(exit) = exit {
+            return Ok(Outcome::Exited(exit.code));
+        }
+    }
+}
+
+/// Picks frames of some types, such as [`Exit`], out of the program's output; everything else is
+/// text.
+pub struct Scanner {
+    wanted: &'static [MessageType],
+    /// Bytes that may turn out to be part of a frame.
+    held: Vec<u8>,
+    frame: Option<Frame>,
+}
+
+struct Frame {
+    decoder: FrameLayer,
+    /// Known once the header has arrived.
+    message_type: Option<MessageType>,
+    payload: Vec<u8>,
+}
+
+impl Scanner {
+    pub fn new(wanted: &'static [MessageType]) -> Self {
+        Self {
+            wanted,
+            held: vec![],
+            frame: None,
+        }
+    }
+
+    /// Add `byte` to `text`, or hold on to it until it's clear whether it belongs to a frame.
+    /// Returns the type and payload of a wanted frame once `byte` completes one.
+    pub fn feed(&mut self, byte: u8, text: &mut Vec<u8>) -> Option<(MessageType, Vec<u8>)> {
+        self.held.push(byte);
+        let Some(frame) = &mut self.frame else {
+            if self.held.ends_with(&PREAMBLE_BYTES) {
+                let mut decoder = FrameLayer::new(COBS_XOR);
+                decoder.skip_preamble();
+                self.frame = Some(Frame {
+                    decoder,
+                    message_type: None,
+                    payload: vec![],
+                });
+                self.held.truncate(self.held.len() - PREAMBLE_BYTES.len());
+                text.append(&mut self.held);
+                self.held.extend_from_slice(&PREAMBLE_BYTES);
+            } else {
+                // keep as much as could still be the start of a preamble
+                let keep = (1..PREAMBLE_BYTES.len())
+                    .rev()
+                    .find(|&n| self.held.ends_with(&PREAMBLE_BYTES[..n]))
+                    .unwrap_or(0);
+                text.extend(self.held.drain(..self.held.len() - keep));
+            }
+            return None;
+        };
+        match frame.decoder.feed(byte) {
+            Ok(FrameOutput::Header(header)) if self.wanted.contains(&header.message_type) => {
+                frame.message_type = Some(header.message_type);
+                None
+            }
+            Ok(FrameOutput::Payload(byte)) => {
+                frame.payload.push(byte);
+                None
+            }
+            Ok(FrameOutput::Finished) => {
+                let frame = self.frame.take()?;
+                self.held.clear();
+                Some((frame.message_type?, frame.payload))
+            }
+            Ok(FrameOutput::Skip) => None,
+            // whatever this is, it's not a wanted frame, so the program must have printed it
+            Ok(_) | Err(_) => {
+                self.frame = None;
+                text.append(&mut self.held);
+                None
+            }
+        }
+    }
+}
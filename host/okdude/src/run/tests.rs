@@ -0,0 +1,102 @@
+//! Tests of [`run_until`] against canned console output.
+use super::{run_until, Outcome, RunUntil};
+use crate::transport::{ClearBuffer, Transport};
+use crate::Console;
+use okboot_common::device::Exit;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Console output that arrives all at once, followed by silence.
+struct Canned(VecDeque<u8>);
+impl Read for Canned {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf)? {
+            0 => Err(ErrorKind::TimedOut.into()),
+            n => Ok(n),
+        }
+    }
+}
+impl Write for Canned {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Transport for Canned {
+    fn baud_rate(&self) -> u32 {
+        okboot_common::INITIAL_BAUD_RATE
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn bytes_to_read(&mut self) -> io::Result<usize> {
+        Ok(self.0.len())
+    }
+
+    fn clear(&mut self, _: ClearBuffer) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `output` through [`run_until`], and return why it stopped and what it printed.
+fn run(output: Vec<u8>, until: RunUntil) -> (Outcome, String) {
+    let mut console = Console {
+        transport: Box::new(Canned(output.into())),
+    };
+    let mut printed = vec![];
+    let outcome = run_until(&mut console, &until, |text| {
+        printed.extend_from_slice(text);
+        Ok(())
+    })
+    .expect("run failed");
+    (outcome, String::from_utf8(printed).unwrap())
+}
+
+#[test]
+fn exits_with_program_code() {
+    let mut output = b"UUUU running\n".to_vec();
+    Exit { code: 3 }.frame(|b| output.extend_from_slice(b));
+    output.extend_from_slice(b"Rebooting.\n");
+    let (outcome, printed) = run(output, RunUntil::default());
+    assert_eq!(outcome, Outcome::Exited(3));
+    assert_eq!(printed, "UUUU running\n");
+}
+
+#[test]
+fn matches_pass_and_fail_patterns() {
+    let output = b"UUU^ is not a frame\ntest result: ok\n1 FAILED, 1 passed\n".to_vec();
+    let until = RunUntil {
+        pass: vec!["passed".to_string(), "ok".to_string()],
+        fail: vec!["FAILED".to_string()],
+        timeout: None,
+    };
+    let (outcome, printed) = run(output, until);
+    assert_eq!(outcome, Outcome::Passed("test result: ok".to_string()));
+    assert_eq!(printed.lines().next(), Some("UUU^ is not a frame"));
+
+    let output = b"1 FAILED, 1 passed\n".to_vec();
+    let until = RunUntil {
+        pass: vec!["passed".to_string()],
+        fail: vec!["FAILED".to_string()],
+        timeout: None,
+    };
+    let (outcome, _) = run(output, until);
+    assert_eq!(outcome, Outcome::Failed("1 FAILED, 1 passed".to_string()));
+}
+
+#[test]
+fn times_out() {
+    let until = RunUntil {
+        timeout: Some(Duration::from_millis(50)),
+        ..RunUntil::default()
+    };
+    let (outcome, printed) = run(b"still going".to_vec(), until);
+    assert_eq!(outcome, Outcome::TimedOut);
+    assert_eq!(printed, "still going");
+}
@@ -0,0 +1,49 @@
+use ed25519_dalek::{Signature, SigningKey};
+use eyre::{eyre, Result, WrapErr};
+use okboot_common::host::{FormatDetails, ImageSignature};
+use sha2::{Digest, Sha512};
+use std::path::Path;
+
+/// Load an Ed25519 secret key from `path`, which holds either the raw 32-byte seed or the seed as
+/// 64 hex digits (e.g. as made by `openssl rand -hex 32`).
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let contents =
+        std::fs::read(path).wrap_err_with(|| eyre!("failed to read {}", path.display()))?;
+    let seed: [u8; 32] = if let Ok(seed) = contents.as_slice().try_into() {
+        seed
+    } else {
+        let hex = std::str::from_utf8(&contents)
+            .ok()
+            .map(str::trim)
+            .filter(|hex| hex.len() == 64)
+            .ok_or_else(|| eyre!("{}: expected 32 raw bytes or 64 hex digits", path.display()))?;
+        let mut seed = [0; 32];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .wrap_err_with(|| eyre!("{}: invalid hex digit", path.display()))?;
+        }
+        seed
+    };
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Produce the signature carried by [`ImageSignature`] for the (uncompressed) program `image`.
+pub fn sign_image(
+    key: &SigningKey,
+    format_details: &FormatDetails,
+    image: &[u8],
+) -> Result<Signature> {
+    let prefix = postcard::to_stdvec(format_details)?;
+    let digest = Sha512::new_with_prefix(prefix).chain_update(image);
+    key.sign_prehashed(digest, Some(ImageSignature::CONTEXT))
+        .wrap_err("failed to sign program")
+}
+
+/// Hex-encoded public key, in the form okboot expects in `OKBOOT_VERIFYING_KEY`.
+pub fn verifying_key_hex(key: &SigningKey) -> String {
+    key.verifying_key()
+        .as_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -1,22 +1,19 @@
-use crate::tty::{ClearBuffer, Tty};
-use crate::Args;
+use crate::transport::{ClearBuffer, Transport, TransportExt};
+use crate::{Config, DeviceOutput, Error, OutputHook};
 use color_eyre::{eyre, Section};
-use eyre::bail;
 use okboot_common::host::FormatDetails;
 use okboot_common::su_boot::Command;
-use std::io::{self, ErrorKind, Read, Write};
-use std::process::exit;
+use std::io::{self, ErrorKind};
 
 struct Write32<'a> {
-    inner: &'a mut Tty,
-    quiet: bool,
+    inner: &'a mut dyn Transport,
 }
 impl<'a> Write32<'a> {
-    pub fn new(inner: &'a mut Tty, quiet: bool) -> Self {
-        Self { inner, quiet }
+    pub fn new(inner: &'a mut dyn Transport) -> Self {
+        Self { inner }
     }
     fn write32_le(&mut self, w: u32, ctrl: bool) -> io::Result<()> {
-        if !self.quiet && ctrl {
+        if ctrl {
             tracing::trace!("> writing {w:#010x}");
         }
         self.inner.write32_le(w)
@@ -24,37 +21,40 @@ impl<'a> Write32<'a> {
 }
 
 fn with_write32(
-    tty: &mut Tty,
-    quiet: bool,
+    tty: &mut dyn Transport,
     f: impl FnOnce(Write32) -> io::Result<()>,
 ) -> io::Result<()> {
-    f(Write32::new(tty, quiet))
+    f(Write32::new(tty))
 }
 
-pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
+pub(crate) fn run(
+    config: &Config,
+    tty: &mut dyn Transport,
+    output: &mut OutputHook,
+) -> eyre::Result<()> {
     tracing::info!("[suboot]: Using legacy SU-BOOT protocol");
 
     tracing::debug!("[suboot] clearing buffers");
     tracing::warn!("[suboot] WARNING: any PRINT_STRINGs previously sent will be discarded");
     tty.clear(ClearBuffer::All)?;
 
-    let FormatDetails::Bin { load_address } = args.format_details else {
-        tracing::error!(
-            "[suboot] legacy su-boot does not support {}",
-            args.format_details
-        );
-        bail!("unsupported format");
+    let FormatDetails::Bin { load_address } = config.format_details else {
+        return Err(Error::Unsupported(format!(
+            "legacy su-boot does not support {}",
+            config.format_details
+        ))
+        .into());
     };
 
-    let prog_data = std::fs::read(args.file.as_path())?;
+    let prog_data = &config.image;
 
-    let crc32 = crc32fast::hash(&prog_data);
+    let crc32 = crc32fast::hash(prog_data);
 
     // PUT_PROG_INFO
 
     tracing::debug!("[suboot] writing PUT_PROG_INFO");
 
-    with_write32(tty, args.quiet, |mut w| {
+    with_write32(tty, |mut w| {
         w.write32_le(Command::PutProgInfo as u32, true)?;
         w.write32_le(load_address.try_into().unwrap(), true)?;
         w.write32_le(prog_data.len().try_into().unwrap(), true)?;
@@ -69,7 +69,9 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
     loop {
         let byte = tty.read8();
         if let Err(e) = byte {
-            if e.kind() == ErrorKind::TimedOut {
+            if e.kind() == ErrorKind::BrokenPipe {
+                return Err(Error::LinkLost.into());
+            } else if e.kind() == ErrorKind::TimedOut {
                 tracing::trace!("[suboot] failed to read from tty: {}", e);
             } else {
                 tracing::debug!("[suboot] failed to read from tty: {}", e);
@@ -109,7 +111,7 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
                 if len > 0 {
                     let mut v = vec![0; len as usize];
                     let _ = tty.read_exact(&mut v);
-                    tracing::info!("< {}", String::from_utf8_lossy(&v));
+                    output.emit(DeviceOutput::Print(&String::from_utf8_lossy(&v)));
                 }
             }
             (0, 0, _) => {}
@@ -125,10 +127,10 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
 
     if switch == 1 {
         // BOOT_ERROR
-        tracing::error!(
-            "[suboot] current settings would lead to a code collision on the device! Aborting."
-        );
-        exit(1);
+        return Err(Error::Refused(
+            "current settings would lead to a code collision on the device".to_string(),
+        )
+        .into());
     } else if switch == 2 {
         // GET_CODE
         tracing::debug!("[suboot] received GET_CODE");
@@ -141,21 +143,19 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
         .with_note(|| "while reading retransmitted CRC")?;
 
     if retransmitted_crc != crc32 {
-        tracing::error!(
-            "[suboot] bad CRC: sent {crc32:#010x}, received {retransmitted_crc:#010x}! Aborting."
-        );
-        exit(1);
+        return Err(Error::Refused(format!(
+            "bad CRC: sent {crc32:#010x}, received {retransmitted_crc:#010x}"
+        ))
+        .into());
     }
     tracing::info!("[suboot] received correct CRC, sending data");
 
     // PUT_CODE
 
     tracing::debug!("[suboot] writing PUT_CODE");
-    with_write32(tty, args.quiet, |mut w| {
-        w.write32_le(Command::PutCode as u32, true)
-    })?;
+    with_write32(tty, |mut w| w.write32_le(Command::PutCode as u32, true))?;
     tracing::debug!("[suboot] writing data");
-    tty.write_all(&prog_data)?;
+    tty.write_all(prog_data)?;
     tracing::info!("[suboot] finished writing data");
 
     // wait for BOOT_START ?, BOOT_SUCCESS, BOOT_ERROR
@@ -165,7 +165,9 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
     loop {
         let byte = tty.read8();
         if let Err(e) = byte {
-            if e.kind() == ErrorKind::TimedOut {
+            if e.kind() == ErrorKind::BrokenPipe {
+                return Err(Error::LinkLost.into());
+            } else if e.kind() == ErrorKind::TimedOut {
                 tracing::trace!("failed to read from tty: {}", e);
             } else {
                 tracing::debug!("failed to read from tty: {}", e);
@@ -203,7 +205,7 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
                 if len > 0 {
                     let mut v = vec![0; len as usize];
                     let _ = tty.read_exact(&mut v);
-                    tracing::info!("< {}", String::from_utf8_lossy(&v));
+                    output.emit(DeviceOutput::Print(&String::from_utf8_lossy(&v)));
                 }
             }
             (0, 0, _) => {}
@@ -218,10 +220,10 @@ pub(crate) fn run(args: &Args, tty: &mut Tty) -> eyre::Result<()> {
     }
     if switch == 1 {
         // BOOT_ERROR
-        tracing::error!(
-            "[suboot] current settings would lead to a code collision on the device! Aborting."
-        );
-        exit(1);
+        return Err(Error::Refused(
+            "current settings would lead to a code collision on the device".to_string(),
+        )
+        .into());
     } else if switch == 2 {
         // BOOT_SUCCESS
         tracing::info!("[suboot] device booted successfully.");
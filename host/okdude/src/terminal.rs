@@ -0,0 +1,359 @@
+use crate::{build, uploader, Args, Target};
+use color_eyre::eyre;
+use eyre::WrapErr;
+use indicatif::MultiProgress;
+use nix::sys::termios::{self, InputFlags, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+use okboot_common::device::Exit;
+use okboot_common::MessageType;
+use okdude::run::Scanner;
+use okdude::{Console, Reupload};
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Prefix for local commands, as in `telnet`: Ctrl-].
+const ESCAPE: u8 = 0x1d;
+
+const HELP: &str = "Ctrl-] then: q quit, r reboot the device into okboot, u reboot the device and \
+                    upload the program again, x toggle hex view, Ctrl-] send Ctrl-], ? show this \
+                    help";
+
+/// How long to wait for keystrokes when the device has nothing to say.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often to check whether the program has changed, with `--watch`.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Connect the terminal to the device's console until the user quits, passing keystrokes through
+/// as they're typed.
+pub fn terminal(args: &Args, target: &Target, mut console: Console) -> eyre::Result<()> {
+    let _raw_mode = RawMode::enable()?;
+    let mut keys = Some(keys());
+    let mut log = args
+        .log_file
+        .as_deref()
+        .map(SessionLog::create)
+        .transpose()?;
+    let mut watcher = args.watch.then(|| Watcher::new(&args.watch_paths));
+    let mut display = Display::default();
+    // a program that exits says so in a frame, rather than as text
+    let mut scanner = Scanner::new(&[MessageType::Exit]);
+    let mut escaped = false;
+    let mut buf = vec![0; 0x1000];
+    tracing::info!("connected to the device's console; {HELP}");
+    loop {
+        if let Some(watcher) = &mut watcher {
+            if let Some(path) = watcher.changed() {
+                tracing::info!("{} changed", path.display());
+                console = reupload(args, target, console)?;
+                watcher.mark_seen();
+            }
+        }
+
+        let available = console.transport().bytes_to_read()?;
+        if available > 0 {
+            let len = available.min(buf.len());
+            let n = match console.read(&mut buf[..len]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::TimedOut => 0,
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| format!("lost {}", target.device.display()));
+                }
+            };
+            let mut show = |text: &mut Vec<u8>| -> io::Result<()> {
+                display.show(text)?;
+                if let Some(log) = &mut log {
+                    log.write(text)?;
+                }
+                text.clear();
+                Ok(())
+            };
+            let mut text = vec![];
+            for &byte in &buf[..n] {
+                let Some((_, payload)) = scanner.feed(byte, &mut text) else {
+                    continue;
+                };
+                show(&mut text)?;
+                if let Some(Exit { code }) = Exit::from_payload(&payload) {
+                    // the device goes back to okboot, so the console stays open
+                    tracing::info!("program exited with code {code}");
+                }
+            }
+            show(&mut text)?;
+        }
+
+        // only wait for keystrokes if there's no more output to show
+        let timeout = if available > 0 {
+            Duration::ZERO
+        } else {
+            POLL_INTERVAL
+        };
+        let Some(rx) = &keys else {
+            // nothing more to send, but the device may still have something to say
+            std::thread::sleep(timeout);
+            continue;
+        };
+        let typed = match rx.recv_timeout(timeout) {
+            Ok(typed) => typed,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                keys = None;
+                continue;
+            }
+        };
+        let mut send = Vec::with_capacity(typed.len());
+        for key in typed {
+            if !escaped {
+                if key == ESCAPE {
+                    escaped = true;
+                } else {
+                    send.push(key);
+                }
+                continue;
+            }
+            escaped = false;
+            match key {
+                ESCAPE => send.push(ESCAPE),
+                b'q' | b'Q' | b'.' => return Ok(()),
+                b'r' | b'R' => reboot(&mut console),
+                b'u' | b'U' => {
+                    console = reupload(args, target, console)?;
+                    if let Some(watcher) = &mut watcher {
+                        watcher.mark_seen();
+                    }
+                }
+                b'x' | b'X' => display.toggle_hex()?,
+                _ => tracing::info!("{HELP}"),
+            }
+        }
+        console.write_all(&send)?;
+    }
+}
+
+/// Run the build command if there is one, then reboot the device and upload the program again.
+/// If any of that fails, the console stays connected to the device for the next try; it's only an
+/// error if the device has gone away.
+fn reupload(args: &Args, target: &Target, console: Console) -> eyre::Result<Console> {
+    let uploader = build(args).and_then(|()| uploader(args, target, &MultiProgress::new()));
+    let uploader = match uploader {
+        Ok(uploader) => uploader,
+        Err(e) => {
+            tracing::error!("{e}; leaving the device as it is");
+            return Ok(console);
+        }
+    };
+    tracing::info!("rebooting the device to upload the program again");
+    let outcome = console
+        .reupload(uploader)
+        .wrap_err_with(|| format!("lost {}", target.device.display()))?;
+    match outcome {
+        Reupload::Booted(console) => {
+            tracing::info!("reconnected to the device's console");
+            Ok(console)
+        }
+        Reupload::Failed(e, console) => {
+            tracing::error!("failed to upload: {e}; still connected to the device's console");
+            Ok(console)
+        }
+    }
+}
+
+fn reboot(console: &mut Console) {
+    match console.reboot() {
+        Ok(()) => tracing::info!("asked the device to reboot"),
+        Err(e) => tracing::error!("failed to reboot the device: {e}"),
+    }
+}
+
+/// Whatever is typed, as it's typed.
+fn keys() -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0; 64];
+        loop {
+            match io::stdin().lock().read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    tracing::error!("failed to read from stdin: {e}");
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Notices when the program, or whatever it's built from, has been rewritten.
+struct Watcher {
+    paths: Vec<PathBuf>,
+    /// When the watched files were last modified, as of the last upload.
+    seen: Option<SystemTime>,
+    /// When the watched files were last modified, and which path that was under, as of the last
+    /// check.
+    latest: Option<(SystemTime, usize)>,
+    next_check: Instant,
+}
+impl Watcher {
+    fn new(paths: &[PathBuf]) -> Self {
+        let mut watcher = Self {
+            paths: paths.to_vec(),
+            seen: None,
+            latest: None,
+            next_check: Instant::now() + WATCH_INTERVAL,
+        };
+        watcher.mark_seen();
+        watcher
+    }
+
+    /// The path that has changed since the program was last uploaded, if one has, and has stayed
+    /// the same for a while, so that it isn't caught halfway through being written.
+    fn changed(&mut self) -> Option<&Path> {
+        if Instant::now() < self.next_check {
+            return None;
+        }
+        self.next_check = Instant::now() + WATCH_INTERVAL;
+        let latest = self.latest();
+        let settled = latest.is_some() && latest == self.latest;
+        self.latest = latest;
+        match latest {
+            Some((modified, i)) if settled && Some(modified) != self.seen => Some(&self.paths[i]),
+            _ => None,
+        }
+    }
+
+    /// Take the watched files as they are now to be what was last uploaded.
+    fn mark_seen(&mut self) {
+        self.latest = self.latest();
+        self.seen = self.latest.map(|(modified, _)| modified);
+    }
+
+    fn latest(&self) -> Option<(SystemTime, usize)> {
+        (self.paths.iter().enumerate())
+            .filter_map(|(i, path)| Some((modified(path)?, i)))
+            .max()
+    }
+}
+
+/// When anything at `path` was last modified: for a directory, the directory itself or anything
+/// in it, however deep, that isn't hidden.
+fn modified(path: &Path) -> Option<SystemTime> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let own = metadata.modified().ok();
+    if !metadata.is_dir() {
+        return own;
+    }
+    let entries = std::fs::read_dir(path).ok()?.filter_map(Result::ok);
+    entries
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| modified(&entry.path()))
+        .chain(own)
+        .max()
+}
+
+/// Keeps the terminal from buffering lines, echoing keystrokes or acting on Ctrl-C and the like,
+/// so that they all go to the device, until dropped.
+struct RawMode {
+    original: Termios,
+}
+impl RawMode {
+    /// `None` if stdin isn't a terminal.
+    fn enable() -> eyre::Result<Option<Self>> {
+        if !io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        let original = termios::tcgetattr(io::stdin()).wrap_err("failed to get terminal mode")?;
+        let mut raw = original.clone();
+        raw.local_flags &=
+            !(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN);
+        // output processing stays on, so that the device's newlines still return the carriage
+        raw.input_flags &= !(InputFlags::IXON | InputFlags::IXOFF);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &raw)
+            .wrap_err("failed to put terminal in raw mode")?;
+        Ok(Some(Self { original }))
+    }
+}
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Shows what the device says, as text or as a hex dump.
+#[derive(Default)]
+struct Display {
+    /// Bytes on the current line of the hex dump, if that's what's shown.
+    hex: Option<usize>,
+}
+impl Display {
+    fn show(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        match &mut self.hex {
+            None => stdout.write_all(bytes)?,
+            Some(column) => {
+                for byte in bytes {
+                    write!(stdout, "{byte:02x}")?;
+                    *column += 1;
+                    if *column == 16 {
+                        writeln!(stdout)?;
+                        *column = 0;
+                    } else {
+                        write!(stdout, " ")?;
+                    }
+                }
+            }
+        }
+        stdout.flush()
+    }
+
+    fn toggle_hex(&mut self) -> io::Result<()> {
+        match self.hex.take() {
+            Some(0) => {}
+            Some(_) => writeln!(io::stdout())?,
+            None => self.hex = Some(0),
+        }
+        Ok(())
+    }
+}
+
+/// Copy of everything the device says, with each line stamped with the seconds since the log was
+/// opened.
+pub struct SessionLog {
+    file: BufWriter<File>,
+    start: Instant,
+    line_start: bool,
+}
+impl SessionLog {
+    pub fn create(path: &Path) -> eyre::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("failed to open {}", path.display()))?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            start: Instant::now(),
+            line_start: true,
+        })
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for line in bytes.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                write!(self.file, "[{:>12.6}] ", self.start.elapsed().as_secs_f64())?;
+            }
+            self.file.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        self.file.flush()
+    }
+}
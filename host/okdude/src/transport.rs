@@ -0,0 +1,62 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+#[derive(Debug, Copy, Clone)]
+pub enum ClearBuffer {
+    Input,
+    #[allow(unused)]
+    Output,
+    All,
+}
+
+/// A link to a device running okboot, such as the [`Tty`](crate::tty::Tty) of a USB serial
+/// adapter.
+///
+/// Reads must not block indefinitely: if no data arrives within a short while (okdude uses
+/// 100ms for serial ports), they should fail with [`ErrorKind::TimedOut`]. A transport that has
+/// gone away for good should fail reads and writes with [`ErrorKind::BrokenPipe`].
+pub trait Transport: Read + Write + Send {
+    /// Baud rate that the link is currently running at.
+    fn baud_rate(&self) -> u32;
+
+    /// Switch the link to `baud_rate`, once anything written so far has been sent. Transports that
+    /// don't have a baud rate can simply remember it.
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()>;
+
+    /// Number of bytes that can be read without blocking.
+    fn bytes_to_read(&mut self) -> io::Result<usize>;
+
+    /// Discard data that has been received but not read, or written but not sent.
+    fn clear(&mut self, buffer: ClearBuffer) -> io::Result<()>;
+
+    /// Re-establish the link after it dropped partway through an upload, e.g. because the serial
+    /// adapter was unplugged. Transports that can't do so fail with [`ErrorKind::Unsupported`].
+    fn reconnect(&mut self) -> io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Reset the device, e.g. by pulsing a control line that's wired to its reset pin. Transports
+    /// that can't do so fail with [`ErrorKind::Unsupported`].
+    fn reset(&mut self) -> io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+/// Fixed-size reads and writes for the SU-BOOT protocol and the handshake.
+pub(crate) trait TransportExt: Transport {
+    fn write32_le(&mut self, w: u32) -> io::Result<()> {
+        self.write_all(&u32::to_le_bytes(w))
+    }
+
+    fn read32_le(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read8(&mut self) -> io::Result<u8> {
+        let mut buf: [u8; 1] = [0];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+}
+impl<T: Transport + ?Sized> TransportExt for T {}
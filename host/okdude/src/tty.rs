@@ -1,31 +1,30 @@
+use crate::transport::{ClearBuffer, Transport};
 use color_eyre::{eyre, Result};
 use nix::poll::{PollFd, PollFlags, PollTimeout};
-use nix::{ioctl_read, ioctl_write_ptr_bad};
+use nix::{ioctl_read_bad, ioctl_write_ptr_bad};
 use std::ffi::{c_int, CString};
-use std::io::{ErrorKind, Read, Write};
+use std::io::{Read, Write};
 use std::mem::MaybeUninit;
-use std::os::fd::BorrowedFd;
+use std::os::fd::{AsFd, BorrowedFd};
 use std::path::{Path, PathBuf};
-use std::process::exit;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{io, ptr, slice};
 use tracing::instrument;
 
-#[derive(Debug, Copy, Clone)]
-pub enum ClearBuffer {
-    #[allow(unused)]
-    Input,
-    #[allow(unused)]
-    Output,
-    All,
-}
+/// How long to wait for the serial adapter to reappear when reconnecting.
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long DTR and RTS are deasserted for when resetting the device.
+pub(crate) const RESET_PULSE: Duration = Duration::from_millis(100);
 
 pub struct Tty {
     fd: c_int,
     default_timeout: Duration,
     path: PathBuf,
+    baud_rate: u32,
 }
 
+#[cfg(any(target_os = "ios", target_os = "macos"))]
 const IOSSIOSPEED: libc::c_ulong = 0x80045402;
 ioctl_write_ptr_bad!(
     #[cfg(any(target_os = "ios", target_os = "macos"))]
@@ -34,7 +33,9 @@ ioctl_write_ptr_bad!(
     libc::speed_t
 );
 
-ioctl_read!(fionread, b'f', 127, libc::c_int);
+ioctl_read_bad!(fionread, libc::FIONREAD, libc::c_int);
+ioctl_write_ptr_bad!(tiocmbis, libc::TIOCMBIS, libc::c_int);
+ioctl_write_ptr_bad!(tiocmbic, libc::TIOCMBIC, libc::c_int);
 
 impl Tty {
     pub fn path(&self) -> &Path {
@@ -49,7 +50,6 @@ impl Tty {
         self._read_timeout(buf, timeout)
     }
 
-    #[allow(dead_code)]
     pub fn read_nonblocking(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if buf.len() == 0 {
             return Ok(0);
@@ -72,6 +72,20 @@ impl Tty {
     }
 }
 
+impl AsFd for Tty {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for Tty {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
 impl Read for Tty {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         // Invariants:
@@ -170,6 +184,7 @@ impl Tty {
                     fd,
                     default_timeout: Duration::new(0, 0),
                     path: path.as_ref().to_path_buf(),
+                    baud_rate: 0,
                 };
                 this.set_baud_rate(baud)?;
                 // tracing::trace!("finished setting baud rate");
@@ -183,38 +198,19 @@ impl Tty {
         }
     }
 
-    pub fn set_baud_rate(&mut self, baud: u32) -> Result<()> {
-        unsafe { self._set_speed(baud, true) }
+    /// Assert the control `lines` (`TIOCM_DTR`, `TIOCM_RTS` or both), or deassert them.
+    pub(crate) fn set_lines(&mut self, lines: c_int, asserted: bool) -> io::Result<()> {
+        match asserted {
+            true => unsafe { tiocmbis(self.fd, &lines) }?,
+            false => unsafe { tiocmbic(self.fd, &lines) }?,
+        };
+        Ok(())
     }
+
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
         self.default_timeout = timeout;
         Ok(())
     }
-    pub fn clear(&mut self, cb: ClearBuffer) -> Result<()> {
-        let r = match cb {
-            ClearBuffer::Input => unsafe { libc::tcflush(self.fd, libc::TCIFLUSH) },
-            ClearBuffer::Output => unsafe { libc::tcflush(self.fd, libc::TCOFLUSH) },
-            ClearBuffer::All => unsafe { libc::tcflush(self.fd, libc::TCIOFLUSH) },
-        };
-        if r == -1 {
-            Err(nix::errno::Errno::last().into())
-        } else {
-            Ok(())
-        }
-    }
-
-    pub fn bytes_to_read(&self) -> Result<usize> {
-        let mut data = MaybeUninit::uninit();
-        let bytes = {
-            let res = unsafe { fionread(self.fd, data.as_mut_ptr())? };
-            if res == -1 {
-                return Err(nix::errno::Errno::last().into());
-            } else {
-                unsafe { data.assume_init() }
-            }
-        };
-        Ok(bytes as usize)
-    }
 
     /// Returns Ok(n>0) if bytes were read
     fn _read_timeout(&mut self, buf: &mut [u8], t: Duration) -> std::io::Result<usize> {
@@ -319,6 +315,10 @@ impl Tty {
     }
 
     #[instrument(skip(self))]
+    #[cfg_attr(
+        not(any(target_os = "ios", target_os = "macos")),
+        allow(unused_variables)
+    )]
     unsafe fn _set_speed(&mut self, baud: u32, drain: bool) -> Result<()> {
         let mut tios = {
             let mut tios_fake: MaybeUninit<libc::termios> = MaybeUninit::uninit();
@@ -469,13 +469,14 @@ impl Tty {
             eyre::bail!("failed to tcsetattr: error={}", nix::errno::Errno::last());
         }
 
-        let speed = baud as libc::speed_t;
-
         // FIX: we end up needing to ignore errors from iossiospeed
         // XXX(mc): seems like we aren't actually ignoring them?
         // XXX(mc): nvm, iossiospeed fails if it's a PTY, which is the case when using socat for
         //          debugging
+        // elsewhere, only pseudoterminals (i.e. the tests) are supported, which ignore the speed
+        #[cfg(any(target_os = "ios", target_os = "macos"))]
         unsafe {
+            let speed = baud as libc::speed_t;
             let _ = iossiospeed(self.fd, &raw const speed);
             // .map_err(|c_err| eyre::eyre!("failed to set speed (iossiospeed): {}", c_err))?
         };
@@ -483,26 +484,66 @@ impl Tty {
     }
 }
 
-fn pipe_error(e: &io::Error) {
-    if e.kind() == ErrorKind::BrokenPipe {
-        tracing::error!("device disconnected. aborting.");
-        exit(1);
+impl Transport for Tty {
+    fn baud_rate(&self) -> u32 {
+        self.baud_rate
     }
-}
-impl Tty {
-    pub fn write32_le(&mut self, w: u32) -> io::Result<()> {
-        self.write_all(&u32::to_le_bytes(w))
+
+    fn set_baud_rate(&mut self, baud: u32) -> io::Result<()> {
+        unsafe { self._set_speed(baud, true).map_err(io::Error::other)? };
+        self.baud_rate = baud;
+        Ok(())
     }
 
-    pub fn read32_le(&mut self) -> io::Result<u32> {
-        let mut buf = [0; 4];
-        self.read_exact(&mut buf).inspect_err(pipe_error)?;
-        Ok(u32::from_le_bytes(buf))
+    fn bytes_to_read(&mut self) -> io::Result<usize> {
+        let mut data = MaybeUninit::uninit();
+        let bytes = {
+            let res = unsafe { fionread(self.fd, data.as_mut_ptr())? };
+            if res == -1 {
+                return Err(nix::errno::Errno::last().into());
+            } else {
+                unsafe { data.assume_init() }
+            }
+        };
+        Ok(bytes as usize)
+    }
+
+    fn clear(&mut self, cb: ClearBuffer) -> io::Result<()> {
+        let r = match cb {
+            ClearBuffer::Input => unsafe { libc::tcflush(self.fd, libc::TCIFLUSH) },
+            ClearBuffer::Output => unsafe { libc::tcflush(self.fd, libc::TCOFLUSH) },
+            ClearBuffer::All => unsafe { libc::tcflush(self.fd, libc::TCIOFLUSH) },
+        };
+        if r == -1 {
+            Err(nix::errno::Errno::last().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        loop {
+            match Tty::new(&self.path, okboot_common::INITIAL_BAUD_RATE) {
+                Ok(mut tty) => {
+                    tty.default_timeout = self.default_timeout;
+                    // whatever the device sent before the link dropped is of no use now
+                    tty.clear(ClearBuffer::Input)?;
+                    *self = tty;
+                    return Ok(());
+                }
+                Err(e) if start.elapsed() > REOPEN_TIMEOUT => return Err(io::Error::other(e)),
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
     }
 
-    pub fn read8(&mut self) -> io::Result<u8> {
-        let mut buf: [u8; 1] = [0];
-        self.read_exact(&mut buf).inspect_err(pipe_error)?;
-        Ok(buf[0])
+    fn reset(&mut self) -> io::Result<()> {
+        // the lines are asserted while the port is open; boards wired for auto-reset (through a
+        // capacitor to their reset pin) reset when they're asserted again
+        let lines: c_int = libc::TIOCM_DTR | libc::TIOCM_RTS;
+        self.set_lines(lines, false)?;
+        std::thread::sleep(RESET_PULSE);
+        self.set_lines(lines, true)
     }
 }
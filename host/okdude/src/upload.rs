@@ -1,66 +1,207 @@
-use crate::tty::Tty;
-use crate::{echo, Args};
+use crate::inventory::Info;
+use crate::transport::{Transport, TransportExt};
+use crate::{Config, DeviceOutput, Error, Hooks, OutputHook};
 use eyre::{bail, eyre, Context, Result};
-use okboot_common::device::AllowedVersions;
+use okboot_common::device::{AllowedVersions, BaudProbeAck, BaudRateChoice, Misfit};
+use okboot_common::executable::{self, Hazard};
 use okboot_common::frame::{BufferedEncoder, EncodeState, FrameLayer, FrameOutput};
-use okboot_common::host::UseVersion;
+use okboot_common::host::{BaudProbe, FormatDetails, ProposeBaudRates};
+use okboot_common::segments::{self, SegmentError};
 use okboot_common::{EncodeMessageType, MessageType, SupportedProtocol, COBS_XOR};
 use serde::Serialize;
 use std::fmt::Debug;
-use std::io::{ErrorKind, Read, Write};
-use std::process::exit;
+use std::io::ErrorKind;
 use std::time::{Duration, Instant};
 use tracing::instrument;
 
-const TTY_TIMEOUT: Duration = Duration::from_millis(100);
+pub(crate) const TTY_TIMEOUT: Duration = Duration::from_millis(100);
 const PROMOTION_TRIES: usize = 1;
+/// Number of `BaudProbe`s to send at a newly negotiated baud rate before giving up on it.
+const BAUD_PROBE_TRIES: usize = 3;
+/// How long to wait after a failed baud probe; must exceed the device's `BAUD_PROBE` timeout so
+/// that the device is back at the initial baud rate when we retry.
+const BAUD_PROBE_BACKOFF: Duration = Duration::from_millis(700);
+/// How many times to reconnect to a device that went quiet partway through an upload.
+const RECONNECT_TRIES: usize = 3;
+/// Number of handshakes to attempt when reconnecting; the device may take a while to notice that
+/// the link dropped.
+const RECONNECT_PROMOTION_TRIES: usize = 10;
 
-pub fn upload(args: Args) -> Result<()> {
-    let mut tty = Tty::new(&args.device, okboot_common::INITIAL_BAUD_RATE)?;
-    tty.set_timeout(TTY_TIMEOUT)?;
+enum Promotion {
+    Promoted(SupportedProtocol),
+    /// Handshake succeeded, but the device and host couldn't communicate at the chosen baud rate.
+    BaudRateFailed(u32),
+    Failed,
+}
+
+/// Upload the program and have the device boot it, over whichever protocol the device supports.
+pub(crate) fn boot(config: &Config, tty: &mut dyn Transport, hooks: &mut Hooks) -> Result<()> {
+    let info = crate::inventory::identify(tty)?;
+    match &info {
+        Some(info) => tracing::info!(
+            "device is board {:016x}, running okboot {}",
+            info.serial,
+            info.version
+        ),
+        None => tracing::debug!("device didn't say what it can load; only checking the program"),
+    }
+    check_program(config, info.as_ref())?;
 
     // Two things we do here:
     //  first, we try to upgrade out of the LEGACY protocol, trying N number of times
     //  second, we try to upload using the legacy protocol
+    let Some(mut protocol) = promote(config, tty, &mut hooks.output, PROMOTION_TRIES) else {
+        if config.persist.is_some() {
+            return Err(Error::Unsupported("SU-BOOT can't keep programs".to_string()).into());
+        }
+        tracing::warn!("attempting upload using SU-BOOT protocol");
 
-    enum Mode {
-        Legacy,
-        Okdude(UseVersion),
-    }
-    let mut mode = Mode::Legacy;
-    for attempt in 1..=PROMOTION_TRIES {
-        if let Some(version) = try_promotion_handshake(&args, &mut tty) {
-            mode = Mode::Okdude(version);
-            break;
-        } else {
-            tracing::warn!("failed attempt {attempt}/{PROMOTION_TRIES} to promote protocol");
-            std::thread::sleep(Duration::from_millis(700));
+        return crate::suboot::run(config, tty, &mut hooks.output);
+    };
+    let mut reconnects = 0;
+    loop {
+        tracing::debug!("using okdude protocol version {:08x}", protocol as u32);
+
+        match crate::v2::upload(config, tty, protocol, hooks) {
+            Err(e)
+                if matches!(e.downcast_ref(), Some(Error::LinkLost))
+                    && reconnects < RECONNECT_TRIES =>
+            {
+                reconnects += 1;
+                tracing::warn!("reconnecting to resume upload ({reconnects}/{RECONNECT_TRIES})");
+                match tty.reconnect() {
+                    Err(reconnect) if reconnect.kind() == ErrorKind::Unsupported => return Err(e),
+                    reconnected => reconnected?,
+                }
+                protocol = promote(config, tty, &mut hooks.output, RECONNECT_PROMOTION_TRIES)
+                    .ok_or(Error::LinkLost)?;
+            }
+            result => return result,
         }
     }
-    match mode {
-        Mode::Legacy => {
-            tracing::warn!("attempting upload using SU-BOOT protocol");
+}
 
-            crate::suboot::run(&args, &mut tty)
+/// Turn the program down before sending any of it if okboot would: ELF files and segment tables
+/// are checked the same way that okboot checks them, and if the device said what it can load, the
+/// program has to fit.
+fn check_program(config: &Config, info: Option<&Info>) -> Result<()> {
+    let misfit = |misfit: Misfit| match misfit {
+        Misfit::Format(_) | Misfit::NoStorage => Error::Unsupported(misfit.to_string()),
+        _ => Error::InvalidImage(misfit.to_string()),
+    };
+    let len = u32::try_from(config.image.len()).unwrap_or(u32::MAX);
+    if let Some(info) = info {
+        info.device_info()
+            .check(config.format_details, len)
+            .map_err(misfit)?;
+    }
+    if config.persist.is_some() {
+        let info = info.ok_or_else(|| {
+            Error::Unsupported("the device didn't say whether it can keep programs".to_string())
+        })?;
+        info.device_info().check_persist(len).map_err(misfit)?;
+    }
+    match config.format_details {
+        FormatDetails::Elf => {
+            // without a device to say otherwise, the range checks are left to the device
+            let load_end = info.map_or(u64::MAX, |info| info.load_end as u64);
+            let image_end = info.map_or(executable::IMAGE_START, |info| info.image_end as u64);
+            let mut hazards = vec![];
+            executable::validate(&config.image, load_end, |index, segment| {
+                hazards.extend(executable::hazards(index, &segment, image_end));
+            })
+            .map_err(|e| Error::InvalidImage(e.to_string()))?;
+            for hazard in hazards {
+                match hazard {
+                    Hazard::OverlapsStub { .. } => tracing::warn!("{hazard}"),
+                    // usual for programs linked where okboot is, and harmless
+                    Hazard::OverlapsOkboot { .. } => tracing::info!("{hazard}"),
+                }
+            }
         }
-        Mode::Okdude(version) => {
-            tracing::debug!("using okdude protocol version {:08x}", version.version);
+        FormatDetails::Segments { .. } => {
+            let invalid = |e: SegmentError| Error::InvalidImage(e.to_string());
+            let header = config.image.first_chunk().ok_or_else(|| {
+                Error::InvalidImage("program is too short to hold a segment table".to_string())
+            })?;
+            let table_len = segments::table_len(*header).map_err(invalid)?;
+            let table = config.image.get(..table_len).ok_or_else(|| {
+                Error::InvalidImage("program is too short to hold its segment table".to_string())
+            })?;
+            segments::check(table, len).map_err(invalid)?;
+            if let Some(info) = info {
+                info.device_info()
+                    .check_segments(segments::entries(table))
+                    .map_err(misfit)?;
+            }
+        }
+        FormatDetails::Bin { .. } => {}
+    }
+    Ok(())
+}
 
-            crate::v2::upload(&args, &mut tty)
+/// Try to upgrade out of the legacy protocol, giving up after `tries` failed handshakes.
+fn promote(
+    config: &Config,
+    tty: &mut dyn Transport,
+    output: &mut OutputHook,
+    tries: usize,
+) -> Option<SupportedProtocol> {
+    let mut baud_rates = config.baud_rates.clone();
+    let mut attempt = 1;
+    while attempt <= tries {
+        match try_promotion_handshake(tty, output, &baud_rates) {
+            Promotion::Promoted(version) => return Some(version),
+            Promotion::BaudRateFailed(baud_rate) if !baud_rates.is_empty() => {
+                tracing::warn!("failed to communicate at {baud_rate}Bd, retrying at slower rates");
+                baud_rates.retain(|&b| b < baud_rate);
+                std::thread::sleep(BAUD_PROBE_BACKOFF);
+            }
+            Promotion::BaudRateFailed(_) | Promotion::Failed => {
+                tracing::warn!("failed attempt {attempt}/{tries} to promote protocol");
+                std::thread::sleep(Duration::from_millis(700));
+                attempt += 1;
+            }
         }
-    }?;
+    }
+    None
+}
 
-    echo::echo(&args, &mut tty)
+fn try_promotion_handshake(
+    tty: &mut dyn Transport,
+    output: &mut OutputHook,
+    baud_rates: &[u32],
+) -> Promotion {
+    match try_version_handshake(tty, output, baud_rates) {
+        Some((protocol, baud_rate)) => {
+            if try_baud_probe(tty, output, baud_rate) {
+                Promotion::Promoted(protocol)
+            } else {
+                if let Err(e) = tty.set_baud_rate(okboot_common::INITIAL_BAUD_RATE) {
+                    tracing::error!("[host]: failed to reset baud rate: {e}");
+                    return Promotion::Failed;
+                }
+                Promotion::BaudRateFailed(baud_rate)
+            }
+        }
+        None => Promotion::Failed,
+    }
 }
 
-fn try_promotion_handshake(_args: &Args, tty: &mut Tty) -> Option<UseVersion> {
-    const PROMOTION_RECV_TIMEOUT: Duration = Duration::from_millis(100);
+const PROMOTION_RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Negotiate the protocol version and baud rate, and switch the TTY to that baud rate.
+fn try_version_handshake(
+    tty: &mut dyn Transport,
+    output: &mut OutputHook,
+    baud_rates: &[u32],
+) -> Option<(SupportedProtocol, u32)> {
     if let Err(e) = send(&okboot_common::host::Probe {}, tty) {
         tracing::error!("[host]: failed to send Probe: {e}");
         return None;
     }
 
-    let msg = match recv_with_print_string(tty, PROMOTION_RECV_TIMEOUT) {
+    let msg = match recv_with_print_string(tty, output, PROMOTION_RECV_TIMEOUT) {
         Ok(Some(m)) => m,
         Ok(None) => {
             tracing::debug!("[host]: received no AllowedVersions within timeout.");
@@ -86,7 +227,7 @@ fn try_promotion_handshake(_args: &Args, tty: &mut Tty) -> Option<UseVersion> {
         }
     };
     let allowed_versions: Vec<u32> = allowed_versions.iter().collect();
-    static SUPPORTED_VERSIONS: &[u32] = &[0x0000_0002];
+    static SUPPORTED_VERSIONS: &[u32] = &[0x0000_0002, 0x0000_0003];
     let choice = allowed_versions
         .iter()
         .rev()
@@ -97,28 +238,104 @@ fn try_promotion_handshake(_args: &Args, tty: &mut Tty) -> Option<UseVersion> {
         return None;
     };
 
-    let config = UseVersion { version };
-    if let Err(e) = send(&config, tty) {
-        tracing::error!("[host]: failed to send UseVersion: {e}");
+    let protocol = SupportedProtocol::try_from(version).unwrap();
+
+    tracing::debug!("[host]: proposing baud rates {baud_rates:?}");
+    if let Err(e) = send(&ProposeBaudRates::new(version, baud_rates), tty) {
+        tracing::error!("[host]: failed to send ProposeBaudRates: {e}");
         return None;
     }
+    let msg = match recv_with_print_string(tty, output, PROMOTION_RECV_TIMEOUT) {
+        Ok(Some(m)) => m,
+        Ok(None) => {
+            tracing::debug!("[host]: received no BaudRateChoice within timeout.");
+            return None;
+        }
+        Err(e) => {
+            tracing::error!("[host]: failed to receive message: {e:?}");
+            return None;
+        }
+    };
+    if msg.0 != MessageType::BaudRateChoice {
+        tracing::error!(
+            "[host]: received message type {:?} in response to ProposeBaudRates",
+            msg.0
+        );
+        return None;
+    }
+    let choice: BaudRateChoice = match postcard::from_bytes(&msg.1) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("[host]: failed to deserialize BaudRateChoice message payload: {e}");
+            return None;
+        }
+    };
+    tracing::info!("[host]: device chose baud rate {}Bd", choice.baud_rate);
 
-    let new_baud_rate = SupportedProtocol::try_from(config.version)
-        .unwrap()
-        .baud_rate();
-
-    if let Err(e) = tty.set_baud_rate(new_baud_rate) {
+    if let Err(e) = tty.set_baud_rate(choice.baud_rate) {
         tracing::error!("[host]: failed to set baud rate: {e}");
         return None;
     }
 
-    Some(config)
+    Some((protocol, choice.baud_rate))
+}
+
+/// Check that the device can hear us (and we it) at the newly negotiated baud rate.
+fn try_baud_probe(tty: &mut dyn Transport, output: &mut OutputHook, baud_rate: u32) -> bool {
+    // give the device a moment to switch over
+    std::thread::sleep(Duration::from_millis(50));
+    // distinguishes our probes from garbage that happens to decode; the device switches protocols
+    // as soon as it acks, so an ack for an earlier probe of this round is as good as any
+    let first_nonce = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    for attempt in 1..=BAUD_PROBE_TRIES {
+        let nonce = first_nonce.wrapping_add(attempt as u32);
+        if let Err(e) = send(&BaudProbe { nonce }, tty) {
+            tracing::error!("[host]: failed to send BaudProbe: {e}");
+            return false;
+        }
+        match recv_with_print_string(tty, output, PROMOTION_RECV_TIMEOUT) {
+            Ok(Some((MessageType::BaudProbeAck, payload))) => {
+                match postcard::from_bytes::<BaudProbeAck>(&payload) {
+                    Ok(ack)
+                        if ack.nonce.wrapping_sub(first_nonce).wrapping_sub(1) < attempt as u32 =>
+                    {
+                        tracing::debug!("[host]: verified baud rate {baud_rate}Bd");
+                        return true;
+                    }
+                    Ok(ack) => {
+                        tracing::warn!(
+                            "[host]: BaudProbeAck nonce mismatch: last sent {nonce:08x}, received {:08x}",
+                            ack.nonce
+                        );
+                    }
+                    Err(e) => {
+                        tracing::warn!("[host]: failed to deserialize BaudProbeAck: {e}");
+                    }
+                }
+            }
+            Ok(Some((mt, _))) => {
+                tracing::warn!("[host]: received message type {mt:?} in response to BaudProbe");
+            }
+            Ok(None) => {
+                tracing::debug!("[host]: no BaudProbeAck within timeout");
+            }
+            Err(e) => {
+                tracing::debug!("[host]: failed to receive BaudProbeAck: {e:?}");
+            }
+        }
+        tracing::debug!("[host]: baud probe attempt {attempt}/{BAUD_PROBE_TRIES} failed");
+    }
+    false
 }
 
 /// Special cased blocking recv with timeout that handles `PRINT_STRING`s.
-#[instrument(skip(tty))]
-fn recv_with_print_string(
-    tty: &mut Tty,
+#[instrument(skip(tty, output))]
+pub(crate) fn recv_with_print_string(
+    tty: &mut dyn Transport,
+    output: &mut OutputHook,
     timeout: Duration,
 ) -> Result<Option<(MessageType, Vec<u8>)>> {
     // can't use the normal decoder because we might get `PRINT_STRING`s
@@ -138,7 +355,7 @@ fn recv_with_print_string(
             }
             Err(e) if e.kind() == ErrorKind::BrokenPipe => {
                 tracing::trace!("device disconnected. aborting.");
-                exit(1)
+                return Err(Error::LinkLost.into());
             }
             e @ Err(_) => e?,
         };
@@ -159,7 +376,7 @@ fn recv_with_print_string(
                     let _ = tty
                         .read_exact(&mut v[..])
                         .inspect_err(|e| tracing::error!("failed to read in PRINT_STRING: {e}"));
-                    tracing::info!("< {}", String::from_utf8_lossy(&v));
+                    output.emit(DeviceOutput::Print(&String::from_utf8_lossy(&v)));
                 }
                 0
             }
@@ -185,7 +402,7 @@ fn recv_with_print_string(
             }
             Err(e) if e.kind() == ErrorKind::BrokenPipe => {
                 tracing::trace!("device disconnected. aborting.");
-                exit(1)
+                return Err(Error::LinkLost.into());
             }
             e @ Err(_) => e?,
         };
@@ -265,7 +482,10 @@ pub fn encode<M: EncodeMessageType + Serialize + Debug>(message: &M) -> Result<V
     Ok(wire_bytes)
 }
 
-fn send<M: EncodeMessageType + Serialize + Debug>(message: &M, tty: &mut Tty) -> Result<()> {
+pub(crate) fn send<M: EncodeMessageType + Serialize + Debug>(
+    message: &M,
+    tty: &mut dyn Transport,
+) -> Result<()> {
     let wire_bytes = encode(message)?;
 
     // write to the TTY
@@ -278,3 +498,6 @@ fn send<M: EncodeMessageType + Serialize + Debug>(message: &M, tty: &mut Tty) ->
     // finished
     Ok(())
 }
+
+#[cfg(test)]
+mod tests;
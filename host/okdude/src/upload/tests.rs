@@ -0,0 +1,1088 @@
+//! End-to-end tests of [`Uploader`] against okboot's protocol state machine, simulated on the host
+//! by [`okboot::platform::sim`]; a pseudoterminal stands in for the USB serial adapter.
+use crate::compression::CompressionArg;
+use crate::monitor::Monitor;
+use crate::persist::Images;
+use crate::{inventory, remote, DeviceOutput, Error, OutputHook, Progress, Reupload, Uploader};
+use ed25519_dalek::SigningKey;
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+use nix::pty::{openpty, OpenptyResult, Winsize};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use okboot::platform::sim::{
+    Booted, RamDisk, Simulator, BOARD_REVISION, CPU_ID, IMAGE_END, IMAGE_START, MEMORY_SIZE,
+    SERIAL_NUMBER, TIME_SCALE,
+};
+use okboot::platform::{LineStatus, Transport};
+use okboot_common::compression::Compression;
+use okboot_common::device::MemRefused;
+use okboot_common::host::{FormatDetails, Jump, MemRead, MemWrite};
+use okboot_common::monitor::{InUse, Refusal};
+use okboot_common::persist::Slot;
+use okboot_common::segments;
+use okboot_common::{MessageType, INITIAL_BAUD_RATE};
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long an upload may take before the test is considered hung.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Both ends of an upload spin while they wait, and okboot's timeouts assume they're scheduled
+/// promptly, so only one upload runs at a time.
+static UPLOADING: Mutex<()> = Mutex::new(());
+
+/// Decides what happens to the `n`th byte the device receives; `None` drops it.
+type Fault = Box<dyn FnMut(usize, u8) -> Option<u8> + Send>;
+
+/// How the simulated device's serial line behaves.
+struct Line {
+    fault: Fault,
+    /// Fastest baud rate the device agrees to.
+    max_baud_rate: u32,
+    /// Last baud rate the device switched to.
+    baud_rate: Arc<AtomicU32>,
+    /// Once the device has received this many bytes, nothing gets through in either direction
+    /// for a while, as if the serial adapter had been unplugged.
+    cut: Option<(usize, Duration)>,
+}
+impl Default for Line {
+    fn default() -> Self {
+        Self {
+            fault: Box::new(|_, byte| Some(byte)),
+            max_baud_rate: u32::MAX,
+            baud_rate: Arc::new(AtomicU32::new(INITIAL_BAUD_RATE)),
+            cut: None,
+        }
+    }
+}
+
+/// The device's end of the pseudoterminal.
+struct PtyTransport {
+    master: OwnedFd,
+    line: RefCell<Line>,
+    received: Cell<usize>,
+    pending: Cell<Option<u8>>,
+    cut_until: Cell<Option<Instant>>,
+}
+impl PtyTransport {
+    fn new(master: OwnedFd, line: Line) -> Self {
+        Self {
+            master,
+            line: RefCell::new(line),
+            received: Cell::new(0),
+            pending: Cell::new(None),
+            cut_until: Cell::new(None),
+        }
+    }
+
+    fn is_cut(&self) -> bool {
+        self.cut_until
+            .get()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn poll(&self, flags: PollFlags) -> bool {
+        let mut fds = [PollFd::new(self.master.as_fd(), flags)];
+        nix::poll::poll(&mut fds, PollTimeout::ZERO).expect("failed to poll pseudoterminal") == 1
+            && fds[0].revents().is_some_and(|r| r.intersects(flags))
+    }
+}
+impl Transport for PtyTransport {
+    fn line_status(&self) -> LineStatus {
+        while self.pending.get().is_none() && self.poll(PollFlags::POLLIN) {
+            let mut byte = [0];
+            if nix::unistd::read(self.master.as_raw_fd(), &mut byte) != Ok(1) {
+                break;
+            }
+            let n = self.received.replace(self.received.get() + 1);
+            let mut line = self.line.borrow_mut();
+            if let Some((at, duration)) = line.cut {
+                if n == at {
+                    self.cut_until.set(Some(Instant::now() + duration));
+                }
+            }
+            if !self.is_cut() {
+                self.pending.set((line.fault)(n, byte[0]));
+            }
+        }
+        if self.pending.get().is_none() {
+            // nothing to do but spin, so let the host get on with it
+            std::thread::yield_now();
+        }
+        LineStatus {
+            data_ready: self.pending.get().is_some(),
+            tx_ready: self.poll(PollFlags::POLLOUT),
+            overrun: false,
+        }
+    }
+
+    fn read_byte(&self) -> u8 {
+        self.pending.take().expect("no byte to read")
+    }
+
+    fn write_byte(&self, byte: u8) {
+        if self.is_cut() {
+            return;
+        }
+        let written = nix::unistd::write(&self.master, &[byte]);
+        assert_eq!(written, Ok(1), "failed to write to pseudoterminal");
+    }
+
+    fn flush(&self) {}
+
+    fn closest_baud_rate(&self, baud_rate: u32) -> Option<u32> {
+        (baud_rate <= self.line.borrow().max_baud_rate).then_some(baud_rate)
+    }
+
+    fn set_baud_rate(&self, baud_rate: u32) -> bool {
+        self.line
+            .borrow()
+            .baud_rate
+            .store(baud_rate, Ordering::SeqCst);
+        true
+    }
+}
+
+/// A new pseudoterminal, as `(master, slave, path)`, set up the way a serial adapter would be.
+fn pty() -> (OwnedFd, OwnedFd, PathBuf) {
+    let OpenptyResult { master, slave } =
+        openpty(None::<&Winsize>, None::<&Termios>).expect("failed to open pseudoterminal");
+    // `Tty` doesn't clear every input processing flag, which real serial adapters don't need
+    let mut termios = tcgetattr(&slave).expect("failed to get pseudoterminal attributes");
+    cfmakeraw(&mut termios);
+    tcsetattr(&slave, SetArg::TCSANOW, &termios).expect("failed to make pseudoterminal raw");
+    let path = nix::unistd::ttyname(&slave).expect("failed to get pseudoterminal name");
+    (master, slave, path)
+}
+
+/// Upload `file` to a simulated device through a new pseudoterminal, and return what it booted.
+fn upload(format_details: FormatDetails, file: &[u8], args: &[&str], line: Line) -> Booted {
+    upload_with(format_details, file, args, line, |uploader| uploader, None)
+}
+
+/// Like [`upload`], but letting `configure` adjust the [`Uploader`] (say, to keep the uploaded
+/// program for delta uploads), and starting the device with the `memory` of a previous upload, as
+/// if it had been soft-rebooted.
+fn upload_with(
+    format_details: FormatDetails,
+    file: &[u8],
+    args: &[&str],
+    line: Line,
+    configure: impl FnOnce(Uploader) -> Uploader,
+    memory: Option<Box<[u8]>>,
+) -> Booted {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::new(line, memory);
+    let uploader = configure(
+        Uploader::new(&device.path, file.to_vec(), format_details).args(args.iter().copied()),
+    );
+    device.upload(uploader)
+}
+
+/// A simulated device, running on its own thread, behind a new pseudoterminal.
+struct Device {
+    path: PathBuf,
+    /// Kept open until the device has booted, so that the pseudoterminal doesn't hang up.
+    slave: OwnedFd,
+    booted: mpsc::Receiver<Option<Booted>>,
+}
+impl Device {
+    /// Start the device with the `memory` of a previous upload, if any.
+    fn new(line: Line, memory: Option<Box<[u8]>>) -> Self {
+        Self::start(line, memory, |simulator| simulator)
+    }
+
+    /// Start the device fresh, with an SD card.
+    fn with_card(line: Line, card: RamDisk) -> Self {
+        Self::start(line, None, |simulator| simulator.with_card(card))
+    }
+
+    /// Start the device with the `memory` of a previous upload, if any, and let `configure` adjust
+    /// the [`Simulator`] (say, to give it an SD card).
+    fn start(
+        line: Line,
+        memory: Option<Box<[u8]>>,
+        configure: impl FnOnce(Simulator<PtyTransport>) -> Simulator<PtyTransport> + Send + 'static,
+    ) -> Self {
+        let (master, slave, path) = pty();
+        let (device_tx, booted) = mpsc::channel();
+        std::thread::spawn(move || {
+            let transport = PtyTransport::new(master, line);
+            let simulator = match memory {
+                Some(memory) => Simulator::with_memory(transport, memory),
+                None => Simulator::new(transport),
+            };
+            let _ = device_tx.send(configure(simulator).run());
+        });
+        Self {
+            path,
+            slave,
+            booted,
+        }
+    }
+
+    /// Start the device fresh, and start it fresh again every time it boots a program, as if it
+    /// were reset as soon as the program started, until it has booted `boots` programs.
+    fn rebooting(boots: usize) -> Self {
+        let (master, slave, path) = pty();
+        let (device_tx, booted) = mpsc::channel();
+        std::thread::spawn(move || {
+            for _ in 0..boots {
+                let master = master.try_clone().expect("failed to clone pseudoterminal");
+                let transport = PtyTransport::new(master, Line::default());
+                let _ = device_tx.send(Simulator::new(transport).run());
+            }
+        });
+        Self {
+            path,
+            slave,
+            booted,
+        }
+    }
+
+    /// Have `uploader` upload to the device, and return what it booted.
+    fn upload(self, uploader: Uploader) -> Booted {
+        let (host_tx, host_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = host_tx.send(uploader.upload().map(drop));
+        });
+
+        let uploaded = host_rx.recv_timeout(TIMEOUT).expect("upload timed out");
+        uploaded.expect("upload failed");
+        let booted = self
+            .booted
+            .recv_timeout(TIMEOUT)
+            .expect("device didn't boot")
+            .expect("device fell back to SU-BOOT");
+        drop(self.slave);
+        booted
+    }
+}
+
+/// Incompressible, so that the upload takes a good number of chunks.
+fn program(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Minimal ARM executable with a single segment: `text` followed by an empty `.data.args`
+/// section of `args_len` bytes.
+fn elf(vaddr: u32, text: &[u8], args_len: u32) -> Vec<u8> {
+    const SEGMENT_OFFSET: u32 = 0x100;
+    const SHSTRTAB: &[u8] = b"\0.data.args\0.shstrtab\0";
+    let segment_len = text.len() as u32 + args_len;
+    let shstrtab_offset = SEGMENT_OFFSET + segment_len;
+    let shoff = (shstrtab_offset + SHSTRTAB.len() as u32 + 3) & !3;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let u16 = |elf: &mut Vec<u8>, x: u16| elf.extend_from_slice(&x.to_le_bytes());
+    u16(&mut elf, 2); // ET_EXEC
+    u16(&mut elf, 40); // EM_ARM
+    let words = |elf: &mut Vec<u8>, xs: &[u32]| {
+        xs.iter()
+            .for_each(|x| elf.extend_from_slice(&x.to_le_bytes()))
+    };
+    // e_version, e_entry, e_phoff, e_shoff, e_flags
+    words(&mut elf, &[1, vaddr, 52, shoff, 0]);
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    for x in [52, 32, 1, 40, 3, 2] {
+        u16(&mut elf, x);
+    }
+    // PT_LOAD: p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_flags, p_align
+    words(
+        &mut elf,
+        &[
+            1,
+            SEGMENT_OFFSET,
+            vaddr,
+            vaddr,
+            segment_len,
+            segment_len,
+            7,
+            4,
+        ],
+    );
+    elf.resize(SEGMENT_OFFSET as usize, 0);
+    elf.extend_from_slice(text);
+    elf.resize(shstrtab_offset as usize, 0);
+    elf.extend_from_slice(SHSTRTAB);
+    elf.resize(shoff as usize, 0);
+    // null section; name, type, flags, addr, offset, size, link, info, addralign, entsize
+    words(&mut elf, &[0; 10]);
+    let args_offset = SEGMENT_OFFSET + text.len() as u32;
+    let args_addr = vaddr + text.len() as u32;
+    words(
+        &mut elf,
+        &[1, 1, 3, args_addr, args_offset, args_len, 0, 0, 4, 0],
+    );
+    words(
+        &mut elf,
+        &[
+            11,
+            3,
+            0,
+            0,
+            shstrtab_offset,
+            SHSTRTAB.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+    );
+    elf
+}
+
+#[test]
+fn boots_bin() {
+    let program = program(0xa123);
+    let booted = upload(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &program,
+        &[],
+        Line::default(),
+    );
+    assert_eq!(booted.entry, 0x8000);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn identifies_device_before_upload() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::new(Line::default(), None);
+    let info = inventory::identify_device(&device.path)
+        .expect("failed to identify device")
+        .expect("device didn't say what it is");
+    assert_eq!(info.serial, SERIAL_NUMBER);
+    assert_eq!(info.board_revision, BOARD_REVISION);
+    assert_eq!(info.cpu_id, CPU_ID);
+    assert_eq!(info.image_end, IMAGE_END as u32);
+    assert!(!info.version.is_empty());
+    assert!(info.formats.supports(FormatDetails::Elf));
+
+    // still ready for an upload
+    let program = program(0x1000);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let uploader = Uploader::new(&device.path, program.clone(), format_details);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn reads_and_writes_memory_before_upload() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::new(Line::default(), None);
+    let mut monitor = Monitor::connect(&device.path).expect("failed to connect to monitor");
+    assert_eq!(monitor.memory_map().okboot.end, IMAGE_END as u32);
+
+    // more than one chunk, so that it's split up
+    let bytes = program(0x2345);
+    let len = bytes.len() as u32;
+    monitor
+        .write(0x20_0000, &bytes)
+        .expect("failed to write memory");
+    let read = monitor.read(0x20_0000, len).expect("failed to read memory");
+    assert!(read == bytes);
+    let crc = monitor.crc(0x20_0000, len).expect("failed to CRC memory");
+    assert_eq!(crc, crc32fast::hash(&bytes));
+
+    // okboot's image can be read, but not written
+    monitor
+        .read(IMAGE_START as u32, 0x100)
+        .expect("failed to read okboot");
+    let error = monitor
+        .write(IMAGE_END as u32 - 2, &[0; 4])
+        .expect_err("overwrote okboot");
+    assert!(
+        matches!(
+            error,
+            Error::MemoryRefused(Refusal::InUse {
+                region: InUse::Okboot,
+                ..
+            })
+        ),
+        "{error}"
+    );
+    drop(monitor);
+
+    // still ready for an upload
+    let program = program(0x1000);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let uploader = Uploader::new(&device.path, program.clone(), format_details);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+    assert!(booted.memory[0x20_0000..0x20_0000 + bytes.len()] == bytes[..]);
+}
+
+#[test]
+fn device_refuses_memory_outside_its_map() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::new(Line::default(), None);
+    // straight to the device, which has to check for itself
+    let mut transport = crate::open(device.path.clone()).expect("failed to open device");
+    let mut output = OutputHook::default();
+    let requests = [
+        super::encode(&MemWrite {
+            address: IMAGE_START as u32,
+            bytes: &[0; 4],
+        }),
+        super::encode(&MemRead {
+            address: MEMORY_SIZE as u32,
+            len: 4,
+        }),
+        super::encode(&Jump { address: 0x20_0002 }),
+    ];
+    let mut refusals = vec![];
+    for request in requests {
+        transport
+            .write_all(&request.expect("failed to encode request"))
+            .expect("failed to send request");
+        let answer = super::recv_with_print_string(&mut *transport, &mut output, TIMEOUT)
+            .expect("failed to receive answer")
+            .expect("device didn't answer");
+        assert_eq!(answer.0, MessageType::MemRefused);
+        let MemRefused { refusal } =
+            postcard::from_bytes(&answer.1).expect("failed to deserialize MemRefused");
+        refusals.push(refusal);
+    }
+    assert!(
+        matches!(
+            refusals[..],
+            [
+                Refusal::InUse {
+                    region: InUse::Okboot,
+                    ..
+                },
+                Refusal::Unmapped { .. },
+                Refusal::Unaligned { .. },
+            ]
+        ),
+        "{refusals:?}"
+    );
+}
+
+#[test]
+fn jumps_into_memory() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::new(Line::default(), None);
+    let mut monitor = Monitor::connect(&device.path).expect("failed to connect to monitor");
+    let program = program(0x100);
+    monitor
+        .write(0x20_0000, &program)
+        .expect("failed to write program");
+    monitor.jump(0x20_0000).expect("failed to jump");
+    let booted = device
+        .booted
+        .recv_timeout(TIMEOUT)
+        .expect("device didn't jump")
+        .expect("device fell back to SU-BOOT");
+    assert_eq!(booted.entry, 0x20_0000);
+    assert!(booted.memory[0x20_0000..0x20_0000 + program.len()] == program[..]);
+}
+
+/// Size of the simulated SD cards, in sectors: 16MiB.
+const CARD_SECTORS: u32 = 0x8000;
+
+#[test]
+fn keeps_program_on_card() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::with_card(Line::default(), RamDisk::formatted(CARD_SECTORS));
+    let program = program(0x1_2345);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let slot = Slot::new("test").unwrap();
+    let uploader = Uploader::new(&device.path, program.clone(), format_details).persist(slot);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+
+    // after a power cycle
+    let card = booted.card.expect("device lost its card");
+    let device = Device::with_card(Line::default(), card);
+    let mut images = Images::connect(&device.path).expect("failed to connect to device");
+    let listed = images.list().expect("failed to list images");
+    assert_eq!(listed.len(), 1, "{listed:?}");
+    assert_eq!(listed[0].slot, slot);
+    assert_eq!(listed[0].len, program.len() as u32);
+    assert_eq!(listed[0].crc, crc32fast::hash(&program));
+    assert_eq!(listed[0].format_details, format_details);
+    assert!(!listed[0].signed);
+
+    assert!(images.delete(slot).expect("failed to delete image"));
+    assert!(!images.delete(slot).expect("failed to delete image"));
+    assert!(images.list().expect("failed to list images").is_empty());
+}
+
+#[test]
+fn refuses_to_keep_program_without_card() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::new(Line::default(), None);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let uploader =
+        Uploader::new(&device.path, program(0x1000), format_details).persist(Slot::DEFAULT);
+    let error = match uploader.upload() {
+        Ok(_) => panic!("expected the program to be turned down"),
+        Err(e) => e,
+    };
+    assert!(matches!(error, Error::Unsupported(_)), "{error}");
+    let error = match Images::connect(&device.path) {
+        Ok(_) => panic!("expected a device without a card to be turned down"),
+        Err(e) => e,
+    };
+    assert!(matches!(error, Error::Unsupported(_)), "{error}");
+}
+
+/// How long the simulated device waits for a host before booting the program kept on its card; a
+/// second of real time, since the simulated clock runs slow.
+const SD_BOOT_TIMEOUT: Duration = Duration::from_millis(1000 / TIME_SCALE);
+
+/// A card with `program` kept in the default slot, as an upload with `--persist` leaves it.
+fn card_with_default(program: &[u8], format_details: FormatDetails) -> RamDisk {
+    let device = Device::with_card(Line::default(), RamDisk::formatted(CARD_SECTORS));
+    let uploader =
+        Uploader::new(&device.path, program.to_vec(), format_details).persist(Slot::DEFAULT);
+    device.upload(uploader).card.expect("device lost its card")
+}
+
+#[test]
+fn boots_kept_program_without_host() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let program = program(0x2_3456);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let card = card_with_default(&program, format_details);
+
+    // after a power cycle, with nobody at the other end
+    let device = Device::start(Line::default(), None, |simulator| {
+        simulator
+            .with_card(card)
+            .with_sd_boot_timeout(SD_BOOT_TIMEOUT)
+    });
+    let booted = device
+        .booted
+        .recv_timeout(TIMEOUT)
+        .expect("device didn't boot from its card")
+        .expect("device fell back to SU-BOOT");
+    assert_eq!(booted.entry, 0x8000);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn prefers_host_to_kept_program() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let card = card_with_default(&program(0x1000), format_details);
+
+    let device = Device::start(Line::default(), None, |simulator| {
+        simulator
+            .with_card(card)
+            .with_sd_boot_timeout(SD_BOOT_TIMEOUT)
+    });
+    let program = program(0x1_0000).into_iter().rev().collect::<Vec<_>>();
+    let uploader = Uploader::new(&device.path, program.clone(), format_details);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+/// A simulated device that only boots programs signed with `key`.
+fn verifying_device(key: &SigningKey) -> Device {
+    let verifying_key = key.verifying_key().to_bytes();
+    Device::start(Line::default(), None, move |simulator| {
+        simulator.with_verifying_key(verifying_key)
+    })
+}
+
+#[test]
+fn boots_signed_program() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let device = verifying_device(&key);
+    let program = program(0x1_2345);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let uploader = Uploader::new(&device.path, program.clone(), format_details).sign_key(key);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+/// Have a simulated device that only boots programs signed with `key` turn down a program signed
+/// with `signed_with`, if at all, and return why; checks that the device is still ready for an
+/// upload afterwards.
+fn refuse_signed(key: SigningKey, signed_with: Option<SigningKey>) -> Error {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = verifying_device(&key);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let mut uploader = Uploader::new(&device.path, program(0x1_2345), format_details);
+    if let Some(signed_with) = signed_with {
+        uploader = uploader.sign_key(signed_with);
+    }
+    let error = match uploader.upload() {
+        Ok(_) => panic!("expected the program to be turned down"),
+        Err(e) => e,
+    };
+
+    let program = program(0x1000);
+    let uploader = Uploader::new(&device.path, program.clone(), format_details).sign_key(key);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+    error
+}
+
+#[test]
+fn refuses_unsigned_program() {
+    let error = refuse_signed(SigningKey::from_bytes(&[7; 32]), None);
+    assert!(
+        matches!(&error, Error::Refused(e) if e.contains("not signed")),
+        "{error}"
+    );
+}
+
+#[test]
+fn refuses_program_signed_with_another_key() {
+    // to the device, that's no different from a program tampered with after it was signed
+    let error = refuse_signed(
+        SigningKey::from_bytes(&[7; 32]),
+        Some(SigningKey::from_bytes(&[8; 32])),
+    );
+    assert!(
+        matches!(&error, Error::Refused(e) if e.contains("does not match")),
+        "{error}"
+    );
+}
+
+#[test]
+fn reuploads_after_failed_upload() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let device = Device::rebooting(2);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let before = program(0x1000);
+    let console = Uploader::new(&device.path, before.clone(), format_details)
+        .upload()
+        .expect("upload failed");
+    let booted = device
+        .booted
+        .recv_timeout(TIMEOUT)
+        .expect("device didn't boot")
+        .expect("device fell back to SU-BOOT");
+    assert!(booted.memory[0x8000..0x8000 + before.len()] == before[..]);
+
+    // runs past the end of loadable memory, so the device stays in okboot
+    let oversize = FormatDetails::Bin {
+        load_address: 0x0fff_8000,
+    };
+    let uploader = Uploader::new(&device.path, program(0x1_0000), oversize);
+    let console = match console.reupload(uploader).expect("lost the device") {
+        Reupload::Failed(Error::InvalidImage(_), console) => console,
+        Reupload::Failed(e, _) => panic!("failed to upload for the wrong reason: {e}"),
+        Reupload::Booted(_) => panic!("expected the program to be turned down"),
+    };
+
+    let after = program(0x2000).into_iter().rev().collect::<Vec<_>>();
+    let uploader = Uploader::new(&device.path, after.clone(), format_details);
+    match console.reupload(uploader).expect("lost the device") {
+        Reupload::Booted(_) => {}
+        Reupload::Failed(e, _) => panic!("failed to upload again: {e}"),
+    }
+    let booted = device
+        .booted
+        .recv_timeout(TIMEOUT)
+        .expect("device didn't boot again")
+        .expect("device fell back to SU-BOOT");
+    assert!(booted.memory[0x8000..0x8000 + after.len()] == after[..]);
+}
+
+/// Have a simulated device turn down `file`, and return why; checks that none of it was sent, and
+/// that the device is still ready for an upload afterwards.
+fn refuse(format_details: FormatDetails, file: &[u8]) -> Error {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let (line, delivered) = counting_line();
+    let device = Device::new(line, None);
+    let uploader = Uploader::new(&device.path, file.to_vec(), format_details);
+    let error = match uploader.upload() {
+        Ok(_) => panic!("expected the program to be turned down"),
+        Err(e) => e,
+    };
+    // nothing but Identify
+    assert!(delivered.load(Ordering::SeqCst) < 0x100);
+
+    let program = program(0x1000);
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let uploader = Uploader::new(&device.path, program.clone(), format_details);
+    let booted = device.upload(uploader);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+    error
+}
+
+#[test]
+fn rejects_oversize_program_before_sending_it() {
+    // runs past the end of loadable memory
+    let format_details = FormatDetails::Bin {
+        load_address: 0x0fff_8000,
+    };
+    let error = refuse(format_details, &program(0x1_0000));
+    assert!(matches!(error, Error::InvalidImage(_)), "{error}");
+}
+
+#[test]
+fn rejects_invalid_elf_before_sending_it() {
+    let mut file = elf(0x8000, &program(0x100), 0x40);
+    // p_memsz of the only segment, which now disagrees with p_filesz
+    file[72..76].copy_from_slice(&0x200_u32.to_le_bytes());
+    let error = refuse(FormatDetails::Elf, &file);
+    assert!(
+        matches!(&error, Error::InvalidImage(e) if e.contains("segment 0")),
+        "{error}"
+    );
+}
+
+#[test]
+fn boots_bin_above_okboot() {
+    let program = program(0x3000);
+    let load_address = IMAGE_END + 0x1_0000;
+    let booted = upload(
+        FormatDetails::Bin {
+            load_address: load_address as u64,
+        },
+        &program,
+        &[],
+        Line::default(),
+    );
+    assert_eq!(booted.entry, load_address);
+    assert!(booted.memory[load_address..load_address + program.len()] == program[..]);
+}
+
+#[test]
+fn boots_segments() {
+    // one below okboot's end, one straddling it and one far above it
+    let pieces = [
+        (0x8000, program(0xa123)),
+        (IMAGE_END - 0x1000, program(0x3000)),
+        (0x0800_0000, program(0x2345)),
+    ];
+    let file = segments::encode(
+        pieces
+            .iter()
+            .map(|(address, bytes)| (*address as u32, bytes.as_slice())),
+    );
+    let booted = upload(
+        FormatDetails::Segments { entry: 0x8004 },
+        &file,
+        &[],
+        Line::default(),
+    );
+    assert_eq!(booted.entry, 0x8004);
+    for (address, bytes) in &pieces {
+        assert!(booted.memory[*address..*address + bytes.len()] == bytes[..]);
+    }
+}
+
+#[test]
+fn rejects_segments_out_of_range_before_sending_it() {
+    let file = segments::encode([(0x8000, &[0; 4][..]), (0x0fff_f000, &[0; 0x2000])].into_iter());
+    let error = refuse(FormatDetails::Segments { entry: 0x8000 }, &file);
+    assert!(
+        matches!(&error, Error::InvalidImage(e) if e.contains("would run past")),
+        "{error}"
+    );
+}
+
+#[test]
+fn boots_elf_with_args() {
+    let text = program(0x2345);
+    let elf = elf(0x8000, &text, 0x100);
+    let booted = upload(FormatDetails::Elf, &elf, &["hello"], Line::default());
+    assert_eq!(booted.entry, 0x8000);
+    assert!(booted.memory[0x8000..0x8000 + text.len()] == text[..]);
+    let args = 0x8000 + text.len();
+    assert_eq!(
+        booted.memory[args..args + 16],
+        [1, 0, 0, 0, 5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o', 0, 0, 0]
+    );
+}
+
+#[test]
+fn reports_progress_and_device_output() {
+    let program = program(0x10000);
+    let progress = Arc::new(Mutex::new(vec![]));
+    let printed = Arc::new(Mutex::new(vec![]));
+    upload_with(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &program,
+        &[],
+        Line::default(),
+        |uploader| {
+            let progress = Arc::clone(&progress);
+            let printed = Arc::clone(&printed);
+            uploader
+                .on_progress(move |p: Progress| progress.lock().unwrap().push(p))
+                .on_device_output(move |output| {
+                    if let DeviceOutput::Print(text) = output {
+                        printed.lock().unwrap().push(text.to_owned());
+                    }
+                })
+        },
+        None,
+    );
+    let progress = progress.lock().unwrap();
+    assert!(progress.is_sorted_by_key(|p| p.sent));
+    let last = progress.last().expect("no progress reported");
+    assert_eq!(last.sent, last.total);
+    assert!(printed
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|text| text.starts_with("[device/v2]")));
+}
+
+#[test]
+fn negotiates_slower_baud_rate() {
+    let line = Line {
+        max_baud_rate: 1_000_000,
+        ..Line::default()
+    };
+    let baud_rate = Arc::clone(&line.baud_rate);
+    let program = program(0x1000);
+    let booted = upload(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &program,
+        &[],
+        line,
+    );
+    assert_eq!(baud_rate.load(Ordering::SeqCst), 921_600);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn boots_bin_through_okdude_serve() {
+    let _uploading = UPLOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let line = Line::default();
+    let baud_rate = Arc::clone(&line.baud_rate);
+    let device = Device::new(line, None);
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to listen");
+    let address = listener.local_addr().expect("failed to get address");
+    let served = device.path.clone();
+    std::thread::spawn(move || remote::serve(&listener, &served));
+
+    let program = program(0x3000);
+    let uploader = Uploader::new(
+        format!("{}{address}", remote::SCHEME),
+        program.clone(),
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+    );
+    let booted = device.upload(uploader);
+    assert_eq!(baud_rate.load(Ordering::SeqCst), 1_500_000);
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn retransmits_lost_chunk() {
+    // well past the handshake, in the middle of the second chunk
+    let line = Line {
+        fault: Box::new(|n, byte| (!(0x1800..0x1900).contains(&n)).then_some(byte)),
+        ..Line::default()
+    };
+    let program = program(0x8000);
+    let booted = upload(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &program,
+        &[],
+        line,
+    );
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn recovers_from_corrupted_chunk() {
+    // the frame CRC catches this, and the chunk is requested again
+    let line = Line {
+        fault: Box::new(|n, byte| Some(if n == 0x2345 { byte ^ 0x10 } else { byte })),
+        ..Line::default()
+    };
+    let program = program(0x8000);
+    let booted = upload(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &program,
+        &[],
+        line,
+    );
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+}
+
+#[test]
+fn resumes_after_link_drop() {
+    let (line, delivered) = counting_line();
+    let line = Line {
+        // long enough for the host to give up on the link, well into the upload
+        cut: Some((0x14000, Duration::from_secs(10))),
+        ..line
+    };
+    let program = program(0x20000);
+    let booted = upload(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &program,
+        &[],
+        line,
+    );
+    assert!(booted.memory[0x8000..0x8000 + program.len()] == program[..]);
+    // starting over would have meant sending the first 0x14000 bytes twice
+    assert!(delivered.load(Ordering::SeqCst) < program.len() * 3 / 2);
+}
+
+/// Counts the bytes that reach the device.
+fn counting_line() -> (Line, Arc<AtomicUsize>) {
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let line = Line {
+        fault: {
+            let delivered = Arc::clone(&delivered);
+            Box::new(move |_, byte| {
+                delivered.fetch_add(1, Ordering::SeqCst);
+                Some(byte)
+            })
+        },
+        ..Line::default()
+    };
+    (line, delivered)
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("okdude-{}-{name}", std::process::id()))
+}
+
+#[test]
+fn uploads_delta_after_soft_reboot() {
+    let image_cache = temp_path("uploads_delta_after_soft_reboot.img");
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let before = program(0x20000);
+    let booted = upload_with(
+        format_details,
+        &before,
+        &[],
+        Line::default(),
+        |uploader| uploader.image_cache(&image_cache),
+        None,
+    );
+    assert!(booted.memory[0x8000..0x8000 + before.len()] == before[..]);
+
+    let mut after = before.clone();
+    after[0x1000..0x1100].fill(0xaa);
+    after.splice(0x10000..0x10000, [0x55; 0x40]);
+    let (line, delivered) = counting_line();
+    let booted = upload_with(
+        format_details,
+        &after,
+        &[],
+        line,
+        |uploader| uploader.image_cache(&image_cache),
+        Some(booted.memory),
+    );
+    let _ = std::fs::remove_file(&image_cache);
+    assert!(booted.memory[0x8000..0x8000 + after.len()] == after[..]);
+    assert!(delivered.load(Ordering::SeqCst) < after.len() / 8);
+}
+
+#[test]
+fn falls_back_to_full_upload_without_base_image() {
+    let image_cache = temp_path("falls_back_to_full_upload_without_base_image.img");
+    std::fs::write(&image_cache, program(0x8000)).expect("failed to write image cache");
+    let mut after = program(0x8000);
+    after[0x100..0x200].fill(0xaa);
+    // the device has just been powered on, so it doesn't have the cached program
+    let (line, delivered) = counting_line();
+    let booted = upload_with(
+        FormatDetails::Bin {
+            load_address: 0x8000,
+        },
+        &after,
+        &[],
+        line,
+        |uploader| uploader.image_cache(&image_cache),
+        None,
+    );
+    let _ = std::fs::remove_file(&image_cache);
+    assert!(booted.memory[0x8000..0x8000 + after.len()] == after[..]);
+    assert!(delivered.load(Ordering::SeqCst) > after.len());
+}
+
+#[test]
+fn boots_with_each_codec() {
+    let format_details = FormatDetails::Bin {
+        load_address: 0x8000,
+    };
+    let file = program(0x400).repeat(0x20);
+    for compression in [
+        Compression::None,
+        Compression::Deflate { level: 9 },
+        Compression::Lz4,
+        Compression::Heatshrink {
+            window: 11,
+            lookahead: 4,
+        },
+    ] {
+        let (line, delivered) = counting_line();
+        let booted = upload_with(
+            format_details,
+            &file,
+            &[],
+            line,
+            |uploader| uploader.compression(CompressionArg::Fixed(compression)),
+            None,
+        );
+        assert!(
+            booted.memory[0x8000..0x8000 + file.len()] == file[..],
+            "{compression}"
+        );
+        let delivered = delivered.load(Ordering::SeqCst);
+        if compression == Compression::None {
+            assert!(delivered > file.len());
+        } else {
+            assert!(
+                delivered < file.len() / 4,
+                "{compression}: {delivered} bytes"
+            );
+        }
+    }
+}
@@ -1,28 +1,47 @@
-use crate::tty::Tty;
-use crate::Args;
-use elf::endian::LittleEndian;
-use elf::ElfBytes;
-use eyre::{bail, ensure, eyre, Result, WrapErr};
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::cache;
+use crate::compression::{CompressionArg, Link};
+use crate::transport::Transport;
+use crate::{Config, DeviceOutput, Error, Hooks, OutputHook, ProgressHook};
+use eyre::{bail, ensure, Result};
+use okboot_common::compression::{self, Codecs, Compression};
 use okboot_common::frame::{FrameHeader, FrameLayer, FrameOutput};
-use okboot_common::host::FormatDetails;
-use okboot_common::{device, host, EncodeMessageType, MessageType, COBS_XOR, INITIAL_BAUD_RATE};
+use okboot_common::host::{FormatDetails, ImageSignature};
+use okboot_common::persist::Slot;
+use okboot_common::{
+    device, host, EncodeMessageType, MessageType, SupportedProtocol, COBS_XOR, INITIAL_BAUD_RATE,
+};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::fmt::Debug;
-use std::io::{Read, Write};
+use std::io::ErrorKind;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
-pub struct Decoder {
+/// The simulated device's clock runs slower than ours.
+#[cfg(test)]
+const DEVICE_TIME_SCALE: u32 = okboot::platform::sim::TIME_SCALE as u32;
+#[cfg(not(test))]
+const DEVICE_TIME_SCALE: u32 = 1;
+
+/// How long the device may stay quiet before we consider the link lost; by then, the device has
+/// given up on the session too (after twice the time it takes to send 16 chunks at `baud_rate`).
+fn link_timeout(baud_rate: u32) -> Duration {
+    const BITS: u64 = 2 * 16 * 0x1000 * 10;
+    Duration::from_micros(BITS * 1_000_000 / baud_rate.max(1) as u64) * DEVICE_TIME_SCALE
+}
+
+pub struct Decoder<'a> {
     received_messages: Sender<(MessageType, Vec<u8>)>,
+    output: &'a mut OutputHook,
 
     decoder: FrameLayer,
     frame_header: Option<FrameHeader>,
     buffer: Vec<u8>,
 }
 
-impl Decoder {
+impl Decoder<'_> {
     fn reset(&mut self) {
         self.decoder.reset();
         self.frame_header = None;
@@ -55,12 +74,16 @@ impl Decoder {
                             );
                         };
                         if frame_header.message_type == MessageType::PrintString {
-                            tracing::info!(
-                                "< {}",
-                                std::str::from_utf8(&self.buffer)
-                                    .unwrap_or("<invalid UTF-8>")
-                                    .trim_end()
-                            );
+                            self.output.emit(DeviceOutput::Print(
+                                std::str::from_utf8(&self.buffer).unwrap_or("<invalid UTF-8>"),
+                            ));
+                        } else if frame_header.message_type == MessageType::Log {
+                            match postcard::from_bytes(&self.buffer) {
+                                Ok(record) => self.output.emit(DeviceOutput::Log(&record)),
+                                Err(e) => {
+                                    tracing::error!("[v2] failed to deserialize device Log: {e}")
+                                }
+                            }
                         } else {
                             self.received_messages.send((
                                 frame_header.message_type,
@@ -74,10 +97,8 @@ impl Decoder {
                     FrameOutput::LegacyPrintStringByte(length, byte) => {
                         self.buffer.push(byte);
                         if length == self.buffer.len() {
-                            tracing::info!(
-                                "< {}",
-                                String::from_utf8_lossy(&self.buffer).trim_end()
-                            );
+                            self.output
+                                .emit(DeviceOutput::Print(&String::from_utf8_lossy(&self.buffer)));
                             // in theory, only need self.buffer.clear() here
                             self.reset();
                         }
@@ -93,22 +114,51 @@ impl Decoder {
     }
 }
 
+/// Forward a device log record to the `tracing` subscriber under the device's own target, so that
+/// `RUST_LOG` applies to it (e.g. `RUST_LOG=okboot::protocol=debug`).
+pub(crate) fn emit_device_log(record: &device::Log) {
+    let level = match record.level {
+        device::LogLevel::Error => log::Level::Error,
+        device::LogLevel::Warn => log::Level::Warn,
+        device::LogLevel::Info => log::Level::Info,
+        device::LogLevel::Debug => log::Level::Debug,
+        device::LogLevel::Trace => log::Level::Trace,
+    };
+    let secs = record.timestamp_us / 1_000_000;
+    let micros = record.timestamp_us % 1_000_000;
+    if let Err(e) = tracing_log::format_trace(
+        &log::Record::builder()
+            .level(level)
+            .target(record.target)
+            .args(format_args!(
+                "< [{secs:>5}.{micros:06}] {}",
+                record.message.trim_end()
+            ))
+            .build(),
+    ) {
+        tracing::error!("[v2] failed to forward device Log: {e}");
+    }
+}
+
 pub fn drive(
     outgoing_messages: Receiver<Vec<u8>>,
     mut decoder: Decoder,
-    tty: &mut Tty,
+    tty: &mut dyn Transport,
     close: Arc<AtomicBool>,
 ) -> Result<()> {
     'drive: loop {
-        if close.load(Ordering::SeqCst) {
-            break 'drive Ok(());
-        }
+        // checked before draining the queue, so that the last messages (i.e. the BootingAck) still
+        // make it out
+        let closing = close.load(Ordering::SeqCst);
 
         'push: loop {
             match outgoing_messages.try_recv() {
                 Ok(m) => {
                     if let Err(e) = tty.write_all(m.as_slice()) {
                         tracing::error!("[v2] failed to write queued message: {e}");
+                        if e.kind() == ErrorKind::BrokenPipe {
+                            break 'drive Err(e.into());
+                        }
                     }
                     if let Err(e) = tty.flush() {
                         tracing::error!("[v2] failed to flush message: {e}");
@@ -118,22 +168,25 @@ pub fn drive(
                 Err(TryRecvError::Disconnected) => break 'drive Ok(()),
             }
         }
+        if closing {
+            break 'drive Ok(());
+        }
 
-        if let Ok(can_read_n) = tty.bytes_to_read() {
-            if can_read_n > 0 {
-                let mut v = vec![0; can_read_n];
-                let buf = match tty.read(&mut v) {
-                    Ok(buf_len) => &v[..buf_len],
-                    Err(e) => {
-                        tracing::error!("[v2] failed to read from tty: {e}");
-                        continue 'drive;
-                    }
-                };
-
-                if let Err(e) = decoder.process_incoming_bytes(buf) {
-                    tracing::error!("[v2] failed to process incoming bytes: {e}");
+        // only fails once the serial adapter has gone away
+        let can_read_n = tty.bytes_to_read()?;
+        if can_read_n > 0 {
+            let mut v = vec![0; can_read_n];
+            let buf = match tty.read(&mut v) {
+                Ok(buf_len) => &v[..buf_len],
+                Err(e) => {
+                    tracing::error!("[v2] failed to read from tty: {e}");
                     continue 'drive;
                 }
+            };
+
+            if let Err(e) = decoder.process_incoming_bytes(buf) {
+                tracing::error!("[v2] failed to process incoming bytes: {e}");
+                continue 'drive;
             }
         }
     }
@@ -146,8 +199,168 @@ struct Info {
     pub compressed_crc: u32,
     pub decompressed_crc: u32,
 
+    pub compression: Compression,
+    /// Where the device is to keep the program, if anywhere.
+    pub persist: Option<Slot>,
+    pub signature: Option<ImageSignature>,
+
     pub chunk_size: usize,
     // pub num_compressed_chunks: usize,
+    /// Number of chunks that may be in flight at once; only used by [`SupportedProtocol::V3`].
+    pub window_size: usize,
+}
+
+/// What's actually uploaded: the compressed program, or a compressed delta against the program
+/// that was last uploaded to the device, if it still has that and the delta is quicker to upload.
+struct Payload {
+    program: Vec<u8>,
+    /// Hash of the previously uploaded program, and the delta against it.
+    delta: Option<([u8; 32], Vec<u8>)>,
+    /// Every compressed version made so far: whether it's of the delta, the codec, and the bytes.
+    encoded: Vec<(bool, Compression, Vec<u8>)>,
+    /// Index of the version in `encoded` that has been chosen.
+    chosen: Option<usize>,
+}
+impl Payload {
+    fn new(program: &[u8], previous: Option<&[u8]>) -> Self {
+        let delta = previous.map(|base| {
+            (
+                Sha256::digest(base).into(),
+                okboot_common::delta::diff(base, program),
+            )
+        });
+        Self {
+            program: program.to_vec(),
+            delta,
+            encoded: vec![],
+            chosen: None,
+        }
+    }
+
+    /// Whether to consider uploading the program itself, and the delta, given the base image
+    /// that the device reported (if any).
+    fn sources(&self, base: Option<device::BaseImage>) -> &'static [bool] {
+        let base = base.map(|base| base.hash);
+        match &self.delta {
+            Some((hash, _)) if Some(*hash) == base => &[false, true],
+            _ => &[false],
+        }
+    }
+
+    /// Index into `encoded` of the program (or delta) compressed with `compression`.
+    fn encode(&mut self, is_delta: bool, compression: Compression) -> usize {
+        if let Some(i) = self
+            .encoded
+            .iter()
+            .position(|(d, c, _)| (*d, *c) == (is_delta, compression))
+        {
+            return i;
+        }
+        let source = match &self.delta {
+            Some((_, delta)) if is_delta => delta,
+            _ => &self.program,
+        };
+        let bytes = compression::encode(source, compression)
+            .expect("only codecs that okboot-common was built with are chosen");
+        self.encoded.push((is_delta, compression, bytes));
+        self.encoded.len() - 1
+    }
+
+    /// Choose between the program and the delta, and among the `candidates` codecs, according to
+    /// `goal`, and update `info` accordingly. Returns the hash of the base image if the delta was
+    /// chosen.
+    fn select(
+        &mut self,
+        base: Option<device::BaseImage>,
+        candidates: &[Compression],
+        goal: CompressionArg,
+        link: Link,
+        info: &mut Info,
+    ) -> Option<[u8; 32]> {
+        let mut best: Option<(usize, Duration)> = None;
+        for &is_delta in self.sources(base) {
+            for &compression in candidates {
+                let i = self.encode(is_delta, compression);
+                let (_, _, bytes) = &self.encoded[i];
+                let source_len = match &self.delta {
+                    Some((_, delta)) if is_delta => delta.len(),
+                    _ => self.program.len(),
+                };
+                let time = link.upload_time(compression, bytes.len(), source_len);
+                let better = best.is_none_or(|(j, best_time)| {
+                    let best_len = self.encoded[j].2.len();
+                    match goal {
+                        CompressionArg::Smallest => (bytes.len(), time) < (best_len, best_time),
+                        _ => (time, bytes.len()) < (best_time, best_len),
+                    }
+                });
+                if better {
+                    best = Some((i, time));
+                }
+            }
+        }
+        let (i, time) = best?;
+        if self.chosen != Some(i) {
+            let (is_delta, compression, bytes) = &self.encoded[i];
+            if *is_delta {
+                tracing::info!("[v2] device still has the previous program, uploading a delta");
+            }
+            let uncompressed = link.upload_time(Compression::None, self.program.len(), 0);
+            tracing::info!(
+                "[v2] compressed {} byte program to {} bytes with {compression} ({:.1}x), saving \
+                 about {:.1?} at {} baud",
+                self.program.len(),
+                bytes.len(),
+                self.program.len() as f64 / bytes.len().max(1) as f64,
+                uncompressed.saturating_sub(time),
+                link.baud_rate,
+            );
+        }
+        self.choose(i, info);
+        self.delta
+            .as_ref()
+            .map(|(hash, _)| *hash)
+            .filter(|_| self.encoded[i].0)
+    }
+
+    /// Choose whichever version `metadata` describes, as the device offers to resume uploading
+    /// it; if there isn't one, `info` is left as it is, and the device will be told that it's
+    /// not the right transfer.
+    fn select_resumed(
+        &mut self,
+        base: Option<device::BaseImage>,
+        metadata: &host::Metadata,
+        info: &mut Info,
+    ) {
+        if !Codecs::built_in().supports(metadata.compression) {
+            return;
+        }
+        for &is_delta in self.sources(base) {
+            let i = self.encode(is_delta, metadata.compression);
+            let (_, _, bytes) = &self.encoded[i];
+            if bytes.len() == metadata.deflated_len as usize
+                && crc32fast::hash(bytes) == metadata.deflated_crc
+            {
+                self.choose(i, info);
+                return;
+            }
+        }
+    }
+
+    fn choose(&mut self, i: usize, info: &mut Info) {
+        self.chosen = Some(i);
+        let (_, compression, bytes) = &self.encoded[i];
+        info.compressed_len = bytes.len() as u32;
+        info.compressed_crc = crc32fast::hash(bytes);
+        info.compression = *compression;
+    }
+
+    fn compressed(&self) -> &[u8] {
+        match self.chosen {
+            Some(i) => &self.encoded[i].2,
+            None => &[],
+        }
+    }
 }
 
 type Tx = Sender<Vec<u8>>;
@@ -157,73 +370,83 @@ fn send<M: EncodeMessageType + Serialize + Debug>(msg: &M, tx: &mut Tx) -> Resul
     Ok(())
 }
 
-fn serialize_args(args: &Args) -> Vec<u8> {
-    let v: Vec<Vec<u8>> = args.args.iter().map(|x| x.clone().into_bytes()).collect();
-    let mut v_out = vec![];
-    v_out.extend_from_slice(&u32::to_le_bytes(v.len() as u32));
-    for sub_v in &v {
-        v_out.extend_from_slice(&u32::to_le_bytes(sub_v.len() as u32));
-        v_out.extend_from_slice(&sub_v);
-        v_out.extend(std::iter::repeat_n(0u8, 4 - (sub_v.len() % 4)));
-        assert!(v_out.len().is_multiple_of(4));
-    }
-    v_out
-}
-
 fn upload_inner(
-    args: &Args,
+    config: &Config,
+    protocol: SupportedProtocol,
+    baud_rate: u32,
+    link_timeout: Duration,
     mut out_tx: Tx,
     in_rx: Receiver<(MessageType, Vec<u8>)>,
+    progress: &mut ProgressHook,
 ) -> Result<()> {
-    let mut uncompressed = std::fs::read(&args.file)
-        .with_context(|| eyre!("failed to open {}", args.file.display()))?;
-
-    if matches!(args.format_details, FormatDetails::Elf) {
-        let arg_vector = serialize_args(args);
-        let elf = ElfBytes::<LittleEndian>::minimal_parse(&uncompressed)
-            .expect("failed to parse input ELF file");
-        let stack = elf
-            .section_header_by_name(".data.args")
-            .expect("section table should be parseable")
-            .expect("file should have .data.args section");
-        let offset = stack.sh_offset as usize;
-        let size = stack.sh_size as usize;
-        if arg_vector.len() >= size {
-            tracing::error!("arguments would occupy more space than .data.args section");
-            std::process::exit(1);
-        }
-        let _ = elf;
-        tracing::debug!("Found .data.args : offset={offset} size={size}");
-        uncompressed[offset..offset + arg_vector.len()].copy_from_slice(&arg_vector);
-        tracing::info!("inserted arguments in .data.args");
-    }
+    let uncompressed = config.image.as_slice();
 
-    let compressed = miniz_oxide::deflate::compress_to_vec(&uncompressed, 5);
-    // tracing::info!("[v2] compressed: {compressed:x?}");
+    let previous = config.image_cache.as_deref().and_then(cache::load);
+    let mut payload = Payload::new(uncompressed, previous.as_deref());
     tracing::info!("[v2] original file length: {}", uncompressed.len());
-    tracing::info!("[v2] compressed file length: {}", compressed.len());
-    let crc = crc32fast::hash(&uncompressed);
-    let crc_compressed = crc32fast::hash(&compressed);
+    let crc = crc32fast::hash(uncompressed);
+    let signature = match &config.sign_key {
+        Some(key) => {
+            let signature = crate::sign::sign_image(key, &config.format_details, uncompressed)?;
+            tracing::info!(
+                "[v2] signed program with key {}",
+                crate::sign::verifying_key_hex(key)
+            );
+            Some(ImageSignature(signature.to_bytes()))
+        }
+        None => None,
+    };
+    // filled in by Payload::select once the device says what it can decode
     let mut info = Info {
-        compressed_len: compressed.len() as u32,
+        compressed_len: 0,
         decompressed_len: uncompressed.len() as u32,
 
-        compressed_crc: crc_compressed,
+        compressed_crc: 0,
         decompressed_crc: crc,
 
+        compression: Compression::None,
+        persist: config.persist,
+        signature,
+
         chunk_size: 0,
         // num_compressed_chunks: 0,
+        window_size: 0,
+    };
+    // first chunk that hasn't been streamed yet (V3 only)
+    let mut next_unsent = 0;
+    // sent by the device ahead of MetadataReq and Resume
+    let mut base_image = None;
+    let link = Link {
+        baud_rate,
+        pipelined: protocol == SupportedProtocol::V3,
     };
-    let mut progress_bar = ProgressBar::new_spinner();
 
     tracing::info!("[v2] waiting for device to commence upload process");
 
+    let mut last_heard = Instant::now();
     loop {
-        match in_rx.try_recv() {
+        let received = in_rx.try_recv();
+        if received.is_ok() {
+            last_heard = Instant::now();
+        }
+        match received {
             Ok((typ, msg)) => match typ {
                 MessageType::AllowedVersions => {
                     tracing::warn!("[v2] ignoring leftover Handshake/AllowedVersions");
                 }
+                MessageType::BaseImage => {
+                    let msg: device::BaseImage = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v2] failed to deserialize incoming message (BaseImage): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    tracing::debug!("[v2] received V2/BaseImage(len={})", msg.len);
+                    base_image = Some(msg);
+                }
                 MessageType::MetadataReq => {
                     let msg: device::MetadataReq = match postcard::from_bytes(&msg) {
                         Ok(x) => x,
@@ -234,7 +457,21 @@ fn upload_inner(
                             continue;
                         }
                     };
-                    dispatch_metadata_req(msg, &info, &args.format_details, &mut out_tx);
+                    let candidates = config.compression.candidates(msg.codecs)?;
+                    if candidates.is_empty() {
+                        return Err(Error::Unsupported(
+                            "the device can't decode anything that okdude can encode".to_string(),
+                        )
+                        .into());
+                    }
+                    let base = payload.select(
+                        base_image.take(),
+                        &candidates,
+                        config.compression,
+                        link,
+                        &mut info,
+                    );
+                    dispatch_metadata_req(msg, &info, &config.format_details, base, &mut out_tx);
                 }
                 MessageType::MetadataAck => {
                     let msg: device::MetadataAck = match postcard::from_bytes(&msg) {
@@ -246,16 +483,93 @@ fn upload_inner(
                             continue;
                         }
                     };
-                    match dispatch_metadata_ack(msg, &info, &args.format_details, &mut out_tx) {
-                        Ok((new_info, new_pb)) => {
+                    match dispatch_metadata_ack(msg, &info, &config.format_details, &mut out_tx) {
+                        Ok(new_info) => {
                             info = new_info;
-                            progress_bar = new_pb;
+                            progress.report(0, info.compressed_len as usize);
                         }
                         Err(e) => {
                             tracing::error!("[v2] problem with metadata ack: {e}");
                         }
                     }
                 }
+                MessageType::PipelinedMetadataAck => {
+                    let msg: device::PipelinedMetadataAck = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v3] failed to deserialize incoming message (PipelinedMetadataAck) from bytes {msg:?}: {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    if protocol != SupportedProtocol::V3 {
+                        tracing::warn!("[v2] ignoring V3/PipelinedMetadataAck under {protocol:?}");
+                        continue;
+                    }
+                    let window_size = msg.window_size as usize;
+                    let msg = device::MetadataAck {
+                        chunk_size: msg.chunk_size,
+                        metadata: msg.metadata,
+                    };
+                    match dispatch_metadata_ack(msg, &info, &config.format_details, &mut out_tx) {
+                        Ok(new_info) => {
+                            tracing::info!("[v3] streaming with a window of {window_size} chunks");
+                            info = Info {
+                                window_size,
+                                ..new_info
+                            };
+                            progress.report(0, info.compressed_len as usize);
+                            next_unsent = 0;
+                        }
+                        Err(e) => {
+                            tracing::error!("[v3] problem with metadata ack: {e}");
+                        }
+                    }
+                }
+                MessageType::Resume => {
+                    let msg: device::Resume = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v2] failed to deserialize incoming message (Resume): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    let next = msg.next as usize;
+                    payload.select_resumed(base_image.take(), &msg.metadata, &mut info);
+                    match dispatch_resume(msg, &info, &config.format_details, protocol, &mut out_tx)
+                    {
+                        Ok(new_info) => {
+                            info = new_info;
+                            progress.report(next * info.chunk_size, info.compressed_len as usize);
+                            next_unsent = next;
+                        }
+                        Err(e) => {
+                            tracing::warn!("[v2] starting over: {e}");
+                        }
+                    }
+                }
+                MessageType::ChunkAck => {
+                    let msg: device::ChunkAck = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v3] failed to deserialize incoming message (ChunkAck): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    dispatch_chunk_ack(
+                        msg,
+                        &info,
+                        payload.compressed(),
+                        &mut out_tx,
+                        progress,
+                        &mut next_unsent,
+                    );
+                }
                 MessageType::ChunkReq => {
                     let msg: device::ChunkReq = match postcard::from_bytes(&msg) {
                         Ok(x) => x,
@@ -266,7 +580,59 @@ fn upload_inner(
                             continue;
                         }
                     };
-                    dispatch_chunk_req(msg, &info, &compressed, &mut out_tx, &progress_bar);
+                    dispatch_chunk_req(msg, &info, payload.compressed(), &mut out_tx, progress);
+                }
+                MessageType::Persisting => {
+                    let msg: device::Persisting = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v2] failed to deserialize incoming message (Persisting): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    tracing::debug!(
+                        "[v2] device has written {} of {} bytes to its SD card",
+                        msg.written,
+                        msg.len
+                    );
+                }
+                MessageType::Persisted => {
+                    let msg: device::Persisted = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v2] failed to deserialize incoming message (Persisted): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    tracing::info!("[v2] device kept the program in slot {}", msg.slot);
+                }
+                MessageType::StorageFailed => {
+                    let msg: device::StorageFailed = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v2] failed to deserialize incoming message (StorageFailed): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    return Err(Error::Storage(msg.error).into());
+                }
+                MessageType::BootRefused => {
+                    let msg: device::BootRefused = match postcard::from_bytes(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::error!(
+                                "[v2] failed to deserialize incoming message (BootRefused): {e}"
+                            );
+                            continue;
+                        }
+                    };
+                    return Err(Error::Refused(msg.error.to_string()).into());
                 }
                 MessageType::Booting => {
                     let out_msg = host::BootingAck {};
@@ -274,16 +640,27 @@ fn upload_inner(
                         tracing::error!("[v2] failed to send {msg:?}: {e}, continuing.");
                     }
                     tracing::info!("[v2] device is booting");
+                    progress.report(info.compressed_len as usize, info.compressed_len as usize);
+                    if let Some(path) = &config.image_cache {
+                        if let Err(e) = cache::store(path, uncompressed) {
+                            tracing::warn!("[v2] failed to keep program for delta uploads: {e}");
+                        }
+                    }
                     break;
                 }
                 t => {
                     tracing::error!("[v2] unrecognized message type: {t:?}, ignoring.");
                 }
             },
-            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Empty) => {
+                if last_heard.elapsed() > link_timeout {
+                    tracing::error!("[v2] no response from device in {link_timeout:?}");
+                    return Err(Error::LinkLost.into());
+                }
+            }
             Err(TryRecvError::Disconnected) => {
                 tracing::error!("[v2] device disconnected");
-                bail!("internal message queue disconnected");
+                return Err(Error::LinkLost.into());
             }
         }
     }
@@ -294,6 +671,7 @@ fn dispatch_metadata_req(
     _msg: device::MetadataReq,
     info: &Info,
     format_details: &FormatDetails,
+    base: Option<[u8; 32]>,
     tx: &mut Tx,
 ) {
     tracing::info!("[v2] received V2/MetadataReq");
@@ -303,8 +681,21 @@ fn dispatch_metadata_req(
         inflated_crc: info.decompressed_crc,
         inflated_len: info.decompressed_len,
         format_details: format_details.clone(),
+        compression: info.compression,
+        persist: info.persist,
+        signature: info.signature,
+    };
+    let result = match base {
+        Some(base) => send(
+            &host::DeltaMetadata {
+                base,
+                metadata: msg,
+            },
+            tx,
+        ),
+        None => send(&msg, tx),
     };
-    if let Err(e) = send(&msg, tx) {
+    if let Err(e) = result {
         tracing::error!("[v2] failed to send {msg:?}: {e}, continuing.");
     }
 }
@@ -313,20 +704,52 @@ fn dispatch_metadata_ack(
     info: &Info,
     expected_format_details: &FormatDetails,
     tx: &mut Tx,
-) -> Result<(Info, ProgressBar)> {
+) -> Result<Info> {
     tracing::info!("[v2] received V2/MetadataAck");
+    let ok = check_metadata(&msg.metadata, info, expected_format_details);
+    let out_msg = &host::MetadataAckAck { is_ok: ok };
+    if let Err(e) = send(out_msg, tx) {
+        tracing::error!("[v2] failed to send {out_msg:?}: {e}, continuing.");
+        return Err(e);
+    }
+    if ok {
+        // let num_compressed_chunks = (info.compressed_len as usize + msg.chunk_size as usize - 1)
+        //     / (msg.chunk_size as usize);
+
+        Ok(Info {
+            chunk_size: msg.chunk_size as usize,
+            // num_compressed_chunks,
+            ..*info
+        })
+    } else {
+        bail!("incorrect metadata ack")
+    }
+}
+
+/// Whether `metadata`, as echoed by the device, describes the program we're uploading.
+fn check_metadata(
+    metadata: &host::Metadata,
+    info: &Info,
+    expected_format_details: &FormatDetails,
+) -> bool {
     let host::Metadata {
         deflated_crc,
         deflated_len,
         inflated_crc,
         inflated_len,
         format_details,
-    } = msg.metadata.clone();
+        compression,
+        persist,
+        signature,
+    } = *metadata;
     let deflated_crc_ok = deflated_crc == info.compressed_crc;
     let deflated_len_ok = deflated_len == info.compressed_len;
     let inflated_crc_ok = inflated_crc == info.decompressed_crc;
     let inflated_len_ok = inflated_len == info.decompressed_len;
     let format_details_ok = &format_details == expected_format_details;
+    let compression_ok = compression == info.compression;
+    let persist_ok = persist == info.persist;
+    let signature_ok = signature == info.signature;
     if !deflated_crc_ok {
         tracing::error!(
             "[v2] compressed CRC mismatch: expected {:08x} received {:08x}",
@@ -360,34 +783,101 @@ fn dispatch_metadata_ack(
             "[v2] format details mismatch: expected {expected_format_details:?} received {format_details:?}"
         );
     }
-    let ok = deflated_crc_ok
+    if !compression_ok {
+        tracing::error!(
+            "[v2] compression mismatch: expected {} received {compression}",
+            info.compression
+        );
+    }
+    if !persist_ok {
+        tracing::error!(
+            "[v2] slot mismatch: expected {:?} received {persist:?}",
+            info.persist
+        );
+    }
+    if !signature_ok {
+        tracing::error!("[v2] signature mismatch");
+    }
+    deflated_crc_ok
         && deflated_len_ok
         && inflated_crc_ok
         && inflated_len_ok
-        && format_details_ok;
-    let out_msg = &host::MetadataAckAck { is_ok: ok };
-    if let Err(e) = send(out_msg, tx) {
-        tracing::error!("[v2] failed to send {out_msg:?}: {e}, continuing.");
-        return Err(e);
+        && format_details_ok
+        && compression_ok
+        && persist_ok
+        && signature_ok
+}
+
+fn dispatch_resume(
+    msg: device::Resume,
+    info: &Info,
+    expected_format_details: &FormatDetails,
+    protocol: SupportedProtocol,
+    tx: &mut Tx,
+) -> Result<Info> {
+    tracing::info!(
+        "[v2] received V2/Resume(next={}, inflated={})",
+        msg.next,
+        msg.inflated
+    );
+    let pipelined = msg.window_size != 0;
+    let ok = check_metadata(&msg.metadata, info, expected_format_details)
+        && pipelined == (protocol == SupportedProtocol::V3);
+    let out_msg = &host::ResumeAck { is_ok: ok };
+    send(out_msg, tx)?;
+    if !ok {
+        bail!("device offered to resume a different transfer");
     }
-    if ok {
-        // let num_compressed_chunks = (info.compressed_len as usize + msg.chunk_size as usize - 1)
-        //     / (msg.chunk_size as usize);
-        let pb = ProgressBar::new(info.compressed_len as u64);
-        pb.set_style(ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:60.cyan/blue} [{bytes:}/{total_bytes}] {bytes_per_sec}",
-        )?);
-
-        Ok((
-            Info {
-                chunk_size: msg.chunk_size as usize,
-                // num_compressed_chunks,
-                ..*info
-            },
-            pb,
-        ))
-    } else {
-        bail!("incorrect metadata ack")
+    tracing::info!("[v2] resuming upload at chunk {}", msg.next);
+
+    let info = Info {
+        chunk_size: msg.chunk_size as usize,
+        window_size: msg.window_size as usize,
+        ..*info
+    };
+    Ok(info)
+}
+
+fn dispatch_chunk_ack(
+    msg: device::ChunkAck,
+    info: &Info,
+    compressed_data: &[u8],
+    tx: &mut Tx,
+    progress: &mut ProgressHook,
+    next_unsent: &mut usize,
+) {
+    tracing::trace!(
+        "[v3] received V3/ChunkAck(next={}, received={:032b})",
+        msg.next,
+        msg.received
+    );
+    if info.chunk_size == 0 || info.window_size == 0 {
+        tracing::warn!("[v3] received V3/ChunkAck before V3/PipelinedMetadataAck, ignoring");
+        return;
+    }
+    let chunk_count = compressed_data.len().div_ceil(info.chunk_size);
+    let acked = msg.next as usize;
+
+    progress.report(acked * info.chunk_size, compressed_data.len());
+
+    // slide the window forward; retransmissions are only done on an explicit ChunkReq
+    let window_end = (acked + info.window_size).min(chunk_count);
+    for which in (*next_unsent).max(acked)..window_end {
+        send_chunk(which, info, compressed_data, tx);
+    }
+    *next_unsent = (*next_unsent).max(window_end);
+}
+
+fn send_chunk(which: usize, info: &Info, compressed_data: &[u8], tx: &mut Tx) {
+    let chunk_begin = which * info.chunk_size;
+    let chunk_end = (chunk_begin + info.chunk_size).min(compressed_data.len());
+
+    let out_msg = &host::Chunk {
+        which: which as u32,
+        bytes: &compressed_data[chunk_begin..chunk_end],
+    };
+    if let Err(e) = send(out_msg, tx) {
+        tracing::error!("[v2] failed to send chunk {which}: {e}, continuing.");
     }
 }
 
@@ -396,35 +886,40 @@ fn dispatch_chunk_req(
     info: &Info,
     compressed_data: &[u8],
     tx: &mut Tx,
-    progress_bar: &ProgressBar,
+    progress: &mut ProgressHook,
 ) {
     tracing::trace!("[v2] received V2/ChunkReq(which={})", msg.which);
     let chunk_idx = msg.which as usize;
     let chunk_begin = chunk_idx * info.chunk_size;
-    let chunk_end = (chunk_begin + info.chunk_size).min(compressed_data.len());
-
-    progress_bar.update(|s| s.set_pos(chunk_begin as u64));
 
-    let out_msg = &host::Chunk {
-        which: msg.which,
-        bytes: &compressed_data[chunk_begin..chunk_end],
-    };
-    if let Err(e) = send(out_msg, tx) {
-        tracing::error!("[v2] failed to send {msg:?}: {e}, continuing.");
+    if info.window_size == 0 {
+        // under V3, progress is tracked through ChunkAcks instead
+        progress.report(chunk_begin, compressed_data.len());
     }
+
+    send_chunk(chunk_idx, info, compressed_data, tx);
 }
 
-pub fn upload(args: &Args, tty: &mut Tty) -> Result<()> {
+pub fn upload(
+    config: &Config,
+    tty: &mut dyn Transport,
+    protocol: SupportedProtocol,
+    hooks: &mut Hooks,
+) -> Result<()> {
+    let Hooks { progress, output } = hooks;
     let close = Arc::new(AtomicBool::new(false));
     let (out_tx, out_rx) = mpsc::channel();
     let (in_tx, in_rx) = mpsc::channel();
-    let succeeded = std::thread::scope(|scope| {
+    let baud_rate = tty.baud_rate();
+    let link_timeout = link_timeout(baud_rate);
+    let result = std::thread::scope(|scope| {
         let close2 = Arc::clone(&close);
         let jh = scope.spawn(|| {
             drive(
                 out_rx,
                 Decoder {
                     received_messages: in_tx,
+                    output,
                     decoder: FrameLayer::new(COBS_XOR),
                     frame_header: None,
                     buffer: vec![],
@@ -434,13 +929,16 @@ pub fn upload(args: &Args, tty: &mut Tty) -> Result<()> {
             )
         });
 
-        let r = match upload_inner(args, out_tx, in_rx) {
-            Ok(_) => true,
-            Err(e) => {
-                tracing::error!("[v2] upload failed: {e}");
-                false
-            }
-        };
+        let r = upload_inner(
+            config,
+            protocol,
+            baud_rate,
+            link_timeout,
+            out_tx,
+            in_rx,
+            progress,
+        )
+        .inspect_err(|e| tracing::error!("[v2] upload failed: {e}"));
 
         close.store(true, Ordering::SeqCst);
         if let Err(e) = jh.join().unwrap() {
@@ -448,11 +946,12 @@ pub fn upload(args: &Args, tty: &mut Tty) -> Result<()> {
         }
         r
     });
+    if let Err(e) = result {
+        // the serial adapter may well be gone, in which case there's no baud rate to reset
+        let _ = tty.set_baud_rate(INITIAL_BAUD_RATE);
+        return Err(e.wrap_err("[v2] aborting"));
+    }
     tty.set_baud_rate(INITIAL_BAUD_RATE)?;
     tracing::info!("[v2] switching to echo mode");
-    if !succeeded {
-        tracing::error!("[v2] aborting");
-        std::process::exit(1);
-    }
     Ok(())
 }
@@ -0,0 +1,107 @@
+//! Loopback test of `okdude serve`, running as a process of its own, with this one as the client
+//! and a pseudoterminal standing in for the serial port that it serves.
+use nix::pty::{openpty, OpenptyResult, Winsize};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use okdude::remote::Remote;
+use okdude::transport::ClearBuffer;
+use okdude::Transport;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::fd::OwnedFd;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Kills `okdude serve` however the test ends.
+struct Server(Child);
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Read from `remote` until `len` bytes have arrived.
+fn read_remote(remote: &mut Remote, len: usize) -> Vec<u8> {
+    let start = Instant::now();
+    let mut received = vec![];
+    while received.len() < len && start.elapsed() < Duration::from_secs(5) {
+        let mut buf = [0; 64];
+        match remote.read(&mut buf) {
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => panic!("failed to read from okdude serve: {e}"),
+        }
+    }
+    received
+}
+
+/// Read from the device's end of the pseudoterminal until `len` bytes have arrived.
+fn read_device(master: &OwnedFd, len: usize) -> Vec<u8> {
+    let mut received = vec![0; len];
+    let mut master = std::fs::File::from(master.try_clone().unwrap());
+    master
+        .read_exact(&mut received)
+        .expect("failed to read from pseudoterminal");
+    received
+}
+
+#[test]
+fn serves_serial_port_over_tcp() {
+    let OpenptyResult { master, slave } =
+        openpty(None::<&Winsize>, None::<&Termios>).expect("failed to open pseudoterminal");
+    let mut termios = tcgetattr(&slave).expect("failed to get pseudoterminal attributes");
+    cfmakeraw(&mut termios);
+    tcsetattr(&slave, SetArg::TCSANOW, &termios).expect("failed to make pseudoterminal raw");
+    let path = nix::unistd::ttyname(&slave).expect("failed to get pseudoterminal name");
+
+    let mut server = Server(
+        Command::new(env!("CARGO_BIN_EXE_okdude"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .arg(&path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start okdude serve"),
+    );
+    let mut banner = String::new();
+    BufReader::new(server.0.stdout.take().unwrap())
+        .read_line(&mut banner)
+        .expect("failed to read what okdude serve is listening on");
+    let address = banner
+        .trim()
+        .rsplit_once(" on ")
+        .map(|(_, address)| address.to_string())
+        .unwrap_or_else(|| panic!("unexpected banner {banner:?}"));
+
+    let mut remote =
+        Remote::connect(&address, Duration::from_millis(100)).expect("failed to connect");
+    // 0xff is telnet's escape
+    remote.write_all(&[1, 0xff, 2]).unwrap();
+    assert_eq!(read_device(&master, 3), [1, 0xff, 2]);
+    nix::unistd::write(&master, &[0xff, 3, 0xff]).unwrap();
+    assert_eq!(read_remote(&mut remote, 3), [0xff, 3, 0xff]);
+
+    remote
+        .set_baud_rate(921_600)
+        .expect("failed to change baud rate");
+    assert_eq!(remote.baud_rate(), 921_600);
+    remote.write_all(b"after").unwrap();
+    assert_eq!(read_device(&master, 5), b"after");
+
+    nix::unistd::write(&master, b"stale").unwrap();
+    // give it time to get through, so that it is the purge that drops it
+    std::thread::sleep(Duration::from_millis(100));
+    remote.clear(ClearBuffer::Input).expect("failed to purge");
+    nix::unistd::write(&master, b"fresh").unwrap();
+    assert_eq!(read_remote(&mut remote, 5), b"fresh");
+
+    // a pseudoterminal has no control lines to pulse, but the server answers anyway
+    remote.reset().expect("failed to reset");
+
+    // the port is opened afresh for whoever connects next
+    drop(remote);
+    let mut remote =
+        Remote::connect(&address, Duration::from_millis(100)).expect("failed to reconnect");
+    assert_eq!(remote.baud_rate(), okboot_common::INITIAL_BAUD_RATE);
+    remote.write_all(b"again").unwrap();
+    assert_eq!(read_device(&master, 5), b"again");
+    drop(slave);
+}
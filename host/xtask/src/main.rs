@@ -43,6 +43,10 @@ struct RunTask {
     name: String,
     #[arg(short = 'd', long = "device")]
     device: Option<String>,
+    /// Further arguments for okdude, e.g. `-- --run-until --timeout 60` to run the program as a
+    /// test
+    #[arg(last = true)]
+    okdude_args: Vec<OsString>,
 }
 impl RunTask {
     fn run(self) -> Result<()> {
@@ -50,11 +54,12 @@ impl RunTask {
             name: self.name.clone(),
         }
         .run()?;
-        let mut args = vec![elf_file.as_path()];
+        let mut args = vec![elf_file.as_os_str().to_os_string()];
         if let Some(device) = &self.device {
-            args.push(Path::new("-d"));
-            args.push(Path::new(device));
+            args.push(OsString::from("-d"));
+            args.push(OsString::from(device));
         }
+        args.extend(self.okdude_args);
         if let Err(e) = duct::cmd("okdude", args)
             .env("RUST_LOG", "okdude=info")
             .unchecked()
@@ -64,9 +69,11 @@ impl RunTask {
             .exit_ok()
         {
             tracing::error!(
-                "Failed to upload {:?}: okdude ({e})",
+                "Failed to run {:?}: okdude ({e})",
                 console::style(elf_file.file_name().unwrap()).green()
-            )
+            );
+            // with `--run-until`, this is the program's own exit code
+            exit(e.code().map_or(1, i32::from));
         } else {
             tracing::info!(
                 "Successfully ran {:?}",